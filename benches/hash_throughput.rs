@@ -54,6 +54,14 @@ fn bench_hashing(c: &mut Criterion) {
                 hasher.finalize()
             })
         });
+
+        group.bench_with_input(BenchmarkId::new("blake3", label), &data, |b, data| {
+            b.iter(|| {
+                let mut hasher = blake3::Hasher::new();
+                hasher.update(data);
+                hasher.finalize()
+            })
+        });
     }
     group.finish();
 }
@@ -0,0 +1,158 @@
+//! Tamper-evident audit log.
+//!
+//! With `--audit-key-file`, every log line ends with an HMAC-SHA256 over the line
+//! and the MAC of the line before it, so editing, inserting or removing a line
+//! breaks the chain from that point on. The chain continues across runs that
+//! append to the same log.
+
+use anyhow::{bail, Context, Result};
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+use std::fs;
+use std::path::Path;
+
+/// Separates a log line from its MAC
+pub const MAC_SEPARATOR: &str = " #mac:";
+
+/// Reads the audit key: the content of `path`, without a trailing newline
+pub fn read_audit_key(path: &Path) -> Result<Vec<u8>> {
+    let content =
+        fs::read(path).with_context(|| format!("Failed to read audit key file {:?}", path))?;
+    let key = content
+        .strip_suffix(b"\n")
+        .map(|key| key.strip_suffix(b"\r").unwrap_or(key))
+        .unwrap_or(&content);
+    if key.is_empty() {
+        bail!("Audit key file {:?} is empty", path);
+    }
+    Ok(key.to_vec())
+}
+
+/// MAC of `line` following the line whose MAC is `previous` (empty for the first)
+pub fn line_mac(key: &[u8], previous: &str, line: &str) -> String {
+    let mut mac = Hmac::<Sha256>::new_from_slice(key).expect("HMAC accepts keys of any length");
+    mac.update(previous.as_bytes());
+    mac.update(b"\n");
+    mac.update(line.as_bytes());
+    hex::encode(mac.finalize().into_bytes())
+}
+
+/// Key and position of the chain a logger appends to
+pub struct AuditChain {
+    key: Vec<u8>,
+    /// MAC of the last chained line of the log
+    last_mac: String,
+}
+
+impl AuditChain {
+    /// Continues the chain of the log at `log_path`, or starts one if the log has
+    /// no chained line yet
+    pub fn resume(key: Vec<u8>, log_path: &Path) -> Result<Self> {
+        let content = match fs::read_to_string(log_path) {
+            Ok(content) => content,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => String::new(),
+            Err(e) => return Err(e).with_context(|| format!("Failed to read {:?}", log_path)),
+        };
+        let last_mac = content
+            .lines()
+            .rev()
+            .find_map(|line| line.rsplit_once(MAC_SEPARATOR))
+            .map(|(_, mac)| mac.to_string())
+            .unwrap_or_default();
+        Ok(Self { key, last_mac })
+    }
+
+    /// MAC the next line would carry
+    pub fn next_mac(&self, line: &str) -> String {
+        line_mac(&self.key, &self.last_mac, line)
+    }
+
+    /// Records `mac` as that of the line just written
+    pub fn advance(&mut self, mac: String) {
+        self.last_mac = mac;
+    }
+
+    pub fn last_mac(&self) -> &str {
+        &self.last_mac
+    }
+}
+
+/// Outcome of an intact chain
+#[derive(Debug)]
+pub struct ChainReport {
+    /// Lines covered by the chain
+    pub lines: usize,
+    /// MAC of the last line, to compare with the one printed at the end of the run
+    pub last_mac: String,
+}
+
+/// Checks the MAC chain of the log at `path`. Lines written before the chain
+/// started are skipped; after that, every line must carry the MAC that follows
+/// from its predecessor.
+pub fn verify_audit_log(path: &Path, key: &[u8]) -> Result<ChainReport> {
+    let content =
+        fs::read_to_string(path).with_context(|| format!("Failed to read log {:?}", path))?;
+    let mut report = ChainReport {
+        lines: 0,
+        last_mac: String::new(),
+    };
+    for (index, line) in content.lines().enumerate() {
+        let Some((text, mac)) = line.rsplit_once(MAC_SEPARATOR) else {
+            if report.lines == 0 {
+                continue;
+            }
+            bail!("Audit chain broken at line {}: no MAC", index + 1);
+        };
+        if line_mac(key, &report.last_mac, text) != mac {
+            bail!("Audit chain broken at line {}: MAC mismatch", index + 1);
+        }
+        report.lines += 1;
+        report.last_mac = mac.to_string();
+    }
+    if report.lines == 0 {
+        bail!("No audit chain in {:?}", path);
+    }
+    Ok(report)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::utils::Logger;
+
+    #[test]
+    fn test_audit_chain_detects_tampering() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let log_path = dir.path().join("audit.log");
+        fs::write(&log_path, "[2024-01-01 00:00:00] Before the chain\n")?;
+        let key = b"secret".to_vec();
+
+        // Two runs appending to the same log form one chain
+        for run in 0..2 {
+            let logger =
+                Logger::new(log_path.to_str().unwrap()).with_audit_chain(Some(key.clone()))?;
+            for i in 0..3 {
+                logger.log(&format!("Run {} line {}", run, i))?;
+            }
+            logger.seal_audit_chain()?;
+        }
+        let report = verify_audit_log(&log_path, &key)?;
+        assert_eq!(report.lines, 8);
+        assert!(verify_audit_log(&log_path, b"wrong key").is_err());
+
+        let content = fs::read_to_string(&log_path)?;
+        let tampered = content.replacen("Run 0 line 1", "Run 0 line 9", 1);
+        fs::write(&log_path, &tampered)?;
+        let error = verify_audit_log(&log_path, &key).unwrap_err();
+        assert!(error.to_string().contains("line 3"), "{}", error);
+
+        // Removing a line breaks the chain too
+        let removed: Vec<&str> = content
+            .lines()
+            .filter(|l| !l.contains("Run 1 line 0"))
+            .collect();
+        fs::write(&log_path, removed.join("\n") + "\n")?;
+        assert!(verify_audit_log(&log_path, &key).is_err());
+        Ok(())
+    }
+}
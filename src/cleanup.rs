@@ -1,14 +1,154 @@
+use crate::db::Database;
+use crate::filter::has_exclude_marker;
 use crate::pipeline::PipelineConfig;
-use crate::utils::Logger;
+use crate::scan::UnicodeForm;
+use crate::utils::{is_same_file, Logger};
 use anyhow::Result;
+use filetime::FileTime;
+use std::collections::{BTreeSet, HashSet};
+use std::ffi::OsStr;
 use std::fs;
+use std::io::{self, BufRead, IsTerminal, Write};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Mutex;
+use std::thread;
+use std::time::Duration;
 use walkdir::WalkDir;
 
-pub fn run_cleanup(config: &PipelineConfig, logger: &Logger) -> Result<()> {
-    println!("Starting cleanup phase...");
-    let mut deleted_count = 0;
+/// Delay between the two passes of a safe cleanup
+const SAFE_CLEANUP_DELAY: Duration = Duration::from_secs(2);
 
-    for entry in WalkDir::new(&config.dest_dir) {
+/// Deletes the extra files of every destination, recording each deletion in the
+/// database. A run interrupted midway is resumed by the next one, which leaves
+/// the files it already deleted alone even if they have reappeared.
+pub fn run_cleanup(config: &PipelineConfig, db: &Mutex<Database>, logger: &Logger) -> Result<()> {
+    let run_token = db.lock().unwrap().begin_cleanup()?;
+    // Confirmation is only asked of a person at a terminal
+    let stdin = io::stdin();
+    let mut answers = stdin.is_terminal().then(|| stdin.lock());
+    // Mirrors are cleaned up like the primary destination
+    for dest_dir in std::iter::once(&config.dest_dir).chain(&config.mirror_dirs) {
+        let dest_config = PipelineConfig {
+            dest_dir: dest_dir.clone(),
+            ..config.clone()
+        };
+        let answers = answers.as_mut().map(|lock| lock as &mut dyn BufRead);
+        cleanup_destination(&dest_config, db, &run_token, logger, answers)?;
+    }
+    db.lock().unwrap().finish_cleanup()?;
+    Ok(())
+}
+
+/// Deletes the destination copies of the files at `removed` (relative paths) that
+/// vanished from the source while watching, through the checks of `run_cleanup`:
+/// the source must still be absent, `--confirm-delete` asks first and each deletion
+/// is recorded. A copy that is the very file a path in `queued` is written to is
+/// kept, as a case-only rename leaves both names on one file of a case-insensitive
+/// destination. Returns the number of files deleted.
+pub fn delete_removed(
+    config: &PipelineConfig,
+    db: &Mutex<Database>,
+    logger: &Logger,
+    removed: &[PathBuf],
+    queued: &[PathBuf],
+) -> Result<usize> {
+    let run_token = db.lock().unwrap().begin_cleanup()?;
+    let stdin = io::stdin();
+    let mut answers = stdin.is_terminal().then(|| stdin.lock());
+    let mut deleted = 0;
+    for dest_dir in std::iter::once(&config.dest_dir).chain(&config.mirror_dirs) {
+        let dest_config = PipelineConfig {
+            dest_dir: dest_dir.clone(),
+            ..config.clone()
+        };
+        let mut candidates = Vec::new();
+        for relative_path in removed {
+            let dest_path = dest_dir.join(relative_path);
+            let Ok(metadata) = fs::symlink_metadata(&dest_path) else {
+                continue;
+            };
+            if metadata.is_dir() {
+                continue;
+            }
+            if queued
+                .iter()
+                .any(|queued_path| is_same_file(&metadata, &dest_dir.join(queued_path)))
+            {
+                logger.log(&format!(
+                    "Kept (written under another name): {:?}",
+                    dest_path
+                ))?;
+                continue;
+            }
+            candidates.push((dest_path, config.source_dir.join(relative_path)));
+        }
+
+        let answers = answers.as_mut().map(|lock| lock as &mut dyn BufRead);
+        if dest_config.confirm_delete
+            && !candidates.is_empty()
+            && !confirm_deletion(&candidates, logger, answers)?
+        {
+            candidates.clear();
+        }
+        deleted += verify_and_delete(&candidates, &dest_config, logger, &|dest_path| {
+            db.lock()
+                .unwrap()
+                .record_deletion(&run_token, &dest_path.to_string_lossy())
+        })?
+        .len();
+
+        // Children come before their directories, which go once emptied
+        for relative_path in removed.iter().rev() {
+            let dest_path = dest_dir.join(relative_path);
+            if fs::symlink_metadata(&dest_path).is_ok_and(|metadata| metadata.is_dir()) {
+                let _ = fs::remove_dir(&dest_path);
+            }
+        }
+    }
+    db.lock().unwrap().finish_cleanup()?;
+    Ok(deleted)
+}
+
+/// Extra files (dest path, source path) of every destination with their sizes, as
+/// `run_cleanup` would find them, without deleting anything
+pub fn list_deletions(config: &PipelineConfig) -> Result<Vec<(PathBuf, u64)>> {
+    let mut extras = Vec::new();
+    for dest_dir in std::iter::once(&config.dest_dir).chain(&config.mirror_dirs) {
+        let dest_config = PipelineConfig {
+            dest_dir: dest_dir.clone(),
+            ..config.clone()
+        };
+        for (dest_path, _) in find_extras(&dest_config)? {
+            let size = fs::metadata(&dest_path)?.len();
+            extras.push((dest_path, size));
+        }
+    }
+    Ok(extras)
+}
+
+/// Files of the destination with no counterpart in the source, as
+/// (dest path, source path)
+pub fn find_extras(config: &PipelineConfig) -> Result<Vec<(PathBuf, PathBuf)>> {
+    let mut extras = Vec::new();
+    // Directories excluded by a marker in the source, and paths excluded by
+    // `--exclude`, are not the sync's to clean
+    let walker = WalkDir::new(&config.dest_dir)
+        .into_iter()
+        .filter_entry(|entry| {
+            !entry.file_type().is_dir()
+                || entry.depth() == 0
+                || entry
+                    .path()
+                    .strip_prefix(&config.dest_dir)
+                    .is_ok_and(|relative_path| {
+                        !has_exclude_marker(
+                            &config.source_dir.join(relative_path),
+                            &config.exclude_if_present,
+                        ) && !config.path_filter.skips_dir(relative_path)
+                    })
+        });
+    for entry in walker {
         let entry = entry?;
         if entry.file_type().is_dir() {
             continue;
@@ -16,36 +156,240 @@ pub fn run_cleanup(config: &PipelineConfig, logger: &Logger) -> Result<()> {
 
         let dest_path = entry.path();
         let relative_path = dest_path.strip_prefix(&config.dest_dir)?;
+        if config.path_filter.is_excluded(relative_path) {
+            continue;
+        }
         let source_path = config.source_dir.join(relative_path);
 
         // Live check against source
-        if !source_path.exists() {
+        if !source_exists(&source_path, config) {
             // Double check it's not a transient error or race condition?
             // Spec says: "Only if the live check confirms absence is the file deleted."
             // Simple exists() check is the live check.
 
-            if let Err(e) = fs::remove_file(dest_path) {
-                eprintln!("Failed to delete extra file {:?}: {}", dest_path, e);
-                logger.log(&format!("Failed to delete extra: {:?} ({})", dest_path, e))?;
-            } else {
-                println!("Deleted extra file: {:?}", relative_path);
-                logger.log(&format!("Deleted extra: {:?}", dest_path))?;
-                deleted_count += 1;
-            }
+            extras.push((dest_path.to_path_buf(), source_path));
+        }
+    }
+    Ok(extras)
+}
+
+/// Cleans up one destination. `answers` supplies the replies to `--confirm-delete`
+/// prompts; without it, deletions needing confirmation are refused.
+fn cleanup_destination(
+    config: &PipelineConfig,
+    db: &Mutex<Database>,
+    run_token: &str,
+    logger: &Logger,
+    answers: Option<&mut dyn BufRead>,
+) -> Result<()> {
+    println!("Starting cleanup phase for {:?}...", config.dest_dir);
+    // Extra files (dest path, source path), deleted once the walk is done, except
+    // those this run already deleted before being interrupted
+    let handled: HashSet<String> = db
+        .lock()
+        .unwrap()
+        .get_deletions(run_token)?
+        .into_iter()
+        .map(|(path, _)| path)
+        .collect();
+    let mut candidates = find_extras(config)?;
+    candidates.retain(|(dest_path, _)| !handled.contains(dest_path.to_string_lossy().as_ref()));
+
+    if config.confirm_delete
+        && !candidates.is_empty()
+        && !confirm_deletion(&candidates, logger, answers)?
+    {
+        candidates.clear();
+    }
+
+    if config.safe_cleanup {
+        // Give a transiently unavailable source time to come back
+        thread::sleep(SAFE_CLEANUP_DELAY);
+    }
+    let deleted = verify_and_delete(&candidates, config, logger, &|dest_path| {
+        db.lock()
+            .unwrap()
+            .record_deletion(run_token, &dest_path.to_string_lossy())
+    })?;
+
+    if config.preserve_dir_times {
+        restore_dir_times(&deleted, config, logger)?;
+    }
+
+    println!("Cleanup completed. Deleted {} files.", deleted.len());
+    Ok(())
+}
+
+/// Whether `source_path` exists, or any path under the source directory that differs
+/// from it only in letter case (with `ignore_case`) or Unicode normalization (with
+/// `normalize_unicode`)
+fn source_exists(source_path: &Path, config: &PipelineConfig) -> bool {
+    if source_path.exists() {
+        return true;
+    }
+    if !config.ignore_case && config.normalize_unicode == UnicodeForm::None {
+        return false;
+    }
+    let Ok(relative_path) = source_path.strip_prefix(&config.source_dir) else {
+        return false;
+    };
+    // Resolve one component at a time, preferring an exact match at each level
+    let mut current = config.source_dir.clone();
+    for component in relative_path.components() {
+        let name = component.as_os_str();
+        let exact = current.join(name);
+        if exact.exists() {
+            current = exact;
+            continue;
         }
+        let folded = fold_name(name, config);
+        let Ok(entries) = fs::read_dir(&current) else {
+            return false;
+        };
+        let matched = entries
+            .filter_map(|entry| entry.ok())
+            .find(|entry| fold_name(&entry.file_name(), config) == folded);
+        match matched {
+            Some(entry) => current = entry.path(),
+            None => return false,
+        }
+    }
+    true
+}
+
+/// `name` as `source_exists` compares it when no exact match exists
+fn fold_name(name: &OsStr, config: &PipelineConfig) -> String {
+    let name = config
+        .normalize_unicode
+        .normalize_str(&name.to_string_lossy());
+    if config.ignore_case {
+        name.to_lowercase()
+    } else {
+        name
     }
+}
 
-    println!("Cleanup completed. Deleted {} files.", deleted_count);
+/// Gives directories that lost files the mtime of their source directory again
+fn restore_dir_times(deleted: &[PathBuf], config: &PipelineConfig, logger: &Logger) -> Result<()> {
+    let dirs: BTreeSet<&Path> = deleted.iter().filter_map(|path| path.parent()).collect();
+    for dir in dirs {
+        let relative_path = dir.strip_prefix(&config.dest_dir)?;
+        // Directories absent from the source have no time to restore
+        let Ok(metadata) = fs::metadata(config.source_dir.join(relative_path)) else {
+            continue;
+        };
+        let mtime = FileTime::from_last_modification_time(&metadata);
+        if let Err(e) = filetime::set_file_mtime(dir, mtime) {
+            logger.log_error(&format!(
+                "Failed to restore directory mtime: {:?} ({})",
+                dir, e
+            ))?;
+        }
+    }
     Ok(())
 }
 
+/// Lists the extra files and asks whether to delete them, defaulting to no
+fn confirm_deletion(
+    candidates: &[(PathBuf, PathBuf)],
+    logger: &Logger,
+    answers: Option<&mut dyn BufRead>,
+) -> Result<bool> {
+    let Some(answers) = answers else {
+        logger.log_error(&format!(
+            "Kept {} extra files: deletion requires confirmation but stdin is not a terminal",
+            candidates.len()
+        ))?;
+        println!("Deletion requires confirmation; no terminal to ask, nothing deleted.");
+        return Ok(false);
+    };
+
+    println!("Extra files to delete:");
+    for (dest_path, _) in candidates {
+        println!("  {:?}", dest_path);
+    }
+    print!("Delete {} files? [y/N] ", candidates.len());
+    io::stdout().flush()?;
+
+    let mut answer = String::new();
+    answers.read_line(&mut answer)?;
+    let confirmed = matches!(answer.trim(), "y" | "Y" | "yes" | "Yes");
+    if !confirmed {
+        logger.log(&format!(
+            "Kept {} extra files: deletion not confirmed",
+            candidates.len()
+        ))?;
+    }
+    Ok(confirmed)
+}
+
+/// Deletes candidates whose source is still absent, spread over
+/// `cleanup_threads` workers, calling `record` for each deleted file.
+/// Returns the deleted paths, sorted.
+fn verify_and_delete(
+    candidates: &[(PathBuf, PathBuf)],
+    config: &PipelineConfig,
+    logger: &Logger,
+    record: &(dyn Fn(&Path) -> rusqlite::Result<()> + Sync),
+) -> Result<Vec<PathBuf>> {
+    // Index of the next candidate to claim
+    let next = AtomicUsize::new(0);
+    let deleted = Mutex::new(Vec::new());
+    thread::scope(|scope| {
+        let workers: Vec<_> = (0..config.cleanup_threads.max(1))
+            .map(|_| {
+                scope.spawn(|| -> Result<()> {
+                    while let Some((dest_path, source_path)) =
+                        candidates.get(next.fetch_add(1, Ordering::Relaxed))
+                    {
+                        if source_exists(source_path, config) {
+                            logger.log(&format!("Kept (source reappeared): {:?}", dest_path))?;
+                            continue;
+                        }
+                        let relative_path = dest_path.strip_prefix(&config.dest_dir)?;
+                        if delete_extra(dest_path, relative_path, logger)? {
+                            record(dest_path)?;
+                            deleted.lock().unwrap().push(dest_path.clone());
+                        }
+                    }
+                    Ok(())
+                })
+            })
+            .collect();
+        workers
+            .into_iter()
+            .try_for_each(|worker| worker.join().unwrap())
+    })?;
+
+    let mut deleted = deleted.into_inner().unwrap();
+    deleted.sort();
+    Ok(deleted)
+}
+
+/// Deletes an extra destination file, logging the outcome. Returns true if deleted.
+fn delete_extra(dest_path: &Path, relative_path: &Path, logger: &Logger) -> Result<bool> {
+    if let Err(e) = fs::remove_file(dest_path) {
+        eprintln!("Failed to delete extra file {:?}: {}", dest_path, e);
+        logger.log_error(&format!("Failed to delete extra: {:?} ({})", dest_path, e))?;
+        Ok(false)
+    } else {
+        println!("Deleted extra file: {:?}", relative_path);
+        logger.log(&format!("Deleted extra: {:?}", dest_path))?;
+        Ok(true)
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
-    use crate::pipeline::HashAlgorithm;
+    use crate::filter::PathFilter;
     use std::fs::File;
     use std::path::PathBuf;
 
+    fn test_db() -> Mutex<Database> {
+        Mutex::new(Database::new(":memory:").unwrap())
+    }
+
     #[test]
     fn test_cleanup() -> Result<()> {
         let source_dir = PathBuf::from("test_cleanup_source");
@@ -69,15 +413,12 @@ mod tests {
         let config = PipelineConfig {
             source_dir: source_dir.clone(),
             dest_dir: dest_dir.clone(),
-            bw_limit: None,
-            db_path: "test_cleanup.db".to_string(),
-            log_path: log_path.to_string(),
-            hash_algo: HashAlgorithm::Sha256,
             block_size: 5 * 1024 * 1024,
+            ..Default::default()
         };
         let logger = Logger::new(log_path);
 
-        run_cleanup(&config, &logger)?;
+        run_cleanup(&config, &test_db(), &logger)?;
 
         assert!(dest_dir.join("keep.txt").exists());
         assert!(!dest_dir.join("extra.txt").exists());
@@ -90,4 +431,446 @@ mod tests {
 
         Ok(())
     }
+
+    #[test]
+    fn test_list_deletions_matches_cleanup() -> Result<()> {
+        let source_dir = tempfile::tempdir()?;
+        let dest_dir = tempfile::tempdir()?;
+        let log_dir = tempfile::tempdir()?;
+
+        fs::create_dir_all(source_dir.path().join("sub"))?;
+        fs::create_dir_all(dest_dir.path().join("sub/old"))?;
+        for name in ["keep.txt", "sub/keep.txt"] {
+            fs::write(source_dir.path().join(name), b"source")?;
+            fs::write(dest_dir.path().join(name), b"copy")?;
+        }
+        for (name, size) in [
+            ("extra.txt", 3),
+            ("sub/extra.bin", 10),
+            ("sub/old/a.txt", 0),
+        ] {
+            fs::write(dest_dir.path().join(name), vec![0u8; size])?;
+        }
+
+        let config = PipelineConfig {
+            source_dir: source_dir.path().to_path_buf(),
+            dest_dir: dest_dir.path().to_path_buf(),
+            block_size: 1024,
+            ..Default::default()
+        };
+        let mut listed = list_deletions(&config)?;
+        listed.sort();
+        let expected: Vec<(PathBuf, u64)> = [
+            ("extra.txt", 3),
+            ("sub/extra.bin", 10),
+            ("sub/old/a.txt", 0),
+        ]
+        .iter()
+        .map(|(name, size)| (dest_dir.path().join(name), *size))
+        .collect();
+        assert_eq!(listed, expected);
+
+        // Listing deletes nothing; a real cleanup deletes exactly the listed files
+        assert!(listed.iter().all(|(path, _)| path.exists()));
+        let logger = Logger::new(log_dir.path().join("cleanup.log").to_str().unwrap());
+        run_cleanup(&config, &test_db(), &logger)?;
+        let remaining: BTreeSet<PathBuf> = WalkDir::new(dest_dir.path())
+            .into_iter()
+            .filter_map(|entry| entry.ok())
+            .filter(|entry| entry.file_type().is_file())
+            .map(|entry| entry.into_path())
+            .collect();
+        let kept: BTreeSet<PathBuf> = ["keep.txt", "sub/keep.txt"]
+            .iter()
+            .map(|name| dest_dir.path().join(name))
+            .collect();
+        assert_eq!(remaining, kept);
+        Ok(())
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_cleanup_deletes_non_utf8_name() -> Result<()> {
+        use std::os::unix::ffi::OsStrExt;
+        let source_dir = tempfile::tempdir()?;
+        let dest_dir = tempfile::tempdir()?;
+        let log_dir = tempfile::tempdir()?;
+        let extra = dest_dir.path().join(OsStr::from_bytes(b"extra-\xff.txt"));
+        File::create(&extra)?;
+
+        let config = PipelineConfig {
+            source_dir: source_dir.path().to_path_buf(),
+            dest_dir: dest_dir.path().to_path_buf(),
+            block_size: 1024,
+            ..Default::default()
+        };
+        let logger = Logger::new(log_dir.path().join("cleanup.log").to_str().unwrap());
+        let db = test_db();
+        run_cleanup(&config, &db, &logger)?;
+        assert!(!extra.exists());
+        Ok(())
+    }
+
+    #[test]
+    fn test_safe_cleanup_keeps_reappeared_file() -> Result<()> {
+        let source_dir = tempfile::tempdir()?;
+        let dest_dir = tempfile::tempdir()?;
+        let log_path = dest_dir.path().join("cleanup.log");
+
+        File::create(dest_dir.path().join("flaky.txt"))?;
+        File::create(dest_dir.path().join("gone.txt"))?;
+
+        let config = PipelineConfig {
+            source_dir: source_dir.path().to_path_buf(),
+            dest_dir: dest_dir.path().to_path_buf(),
+            block_size: 5 * 1024 * 1024,
+            safe_cleanup: true,
+            ..Default::default()
+        };
+        let logger = Logger::new(log_path.to_str().unwrap());
+
+        // First pass found both files absent from source
+        let candidates: Vec<(PathBuf, PathBuf)> = ["flaky.txt", "gone.txt"]
+            .iter()
+            .map(|name| (dest_dir.path().join(name), source_dir.path().join(name)))
+            .collect();
+
+        // Source file reappears before the second pass
+        File::create(source_dir.path().join("flaky.txt"))?;
+
+        assert_eq!(
+            verify_and_delete(&candidates, &config, &logger, &|_| Ok(()))?,
+            vec![dest_dir.path().join("gone.txt")]
+        );
+        assert!(dest_dir.path().join("flaky.txt").exists());
+        assert!(!dest_dir.path().join("gone.txt").exists());
+        Ok(())
+    }
+
+    #[test]
+    fn test_cleanup_preserves_dir_times() -> Result<()> {
+        let source_dir = tempfile::tempdir()?;
+        let dest_dir = tempfile::tempdir()?;
+        let log_path = dest_dir.path().join("cleanup.log");
+
+        fs::create_dir_all(source_dir.path().join("sub"))?;
+        fs::create_dir_all(dest_dir.path().join("sub"))?;
+        File::create(dest_dir.path().join("sub/extra.txt"))?;
+        let source_mtime = FileTime::from_unix_time(1_000_000, 0);
+        filetime::set_file_mtime(source_dir.path().join("sub"), source_mtime)?;
+        filetime::set_file_mtime(dest_dir.path().join("sub"), source_mtime)?;
+
+        let config = PipelineConfig {
+            source_dir: source_dir.path().to_path_buf(),
+            dest_dir: dest_dir.path().to_path_buf(),
+            preserve_dir_times: true,
+            ..Default::default()
+        };
+        run_cleanup(
+            &config,
+            &test_db(),
+            &Logger::new(log_path.to_str().unwrap()),
+        )?;
+
+        assert!(!dest_dir.path().join("sub/extra.txt").exists());
+        let dest_mtime =
+            FileTime::from_last_modification_time(&fs::metadata(dest_dir.path().join("sub"))?);
+        assert_eq!(dest_mtime, source_mtime);
+        Ok(())
+    }
+
+    #[test]
+    fn test_confirm_delete() -> Result<()> {
+        let source_dir = tempfile::tempdir()?;
+        let dest_dir = tempfile::tempdir()?;
+        let log_dir = tempfile::tempdir()?;
+        let logger = Logger::new(log_dir.path().join("cleanup.log").to_str().unwrap());
+        let extra = dest_dir.path().join("extra.txt");
+        File::create(&extra)?;
+
+        let config = PipelineConfig {
+            source_dir: source_dir.path().to_path_buf(),
+            dest_dir: dest_dir.path().to_path_buf(),
+            confirm_delete: true,
+            ..Default::default()
+        };
+        let db = test_db();
+
+        // Declined, then no terminal to ask: kept both times
+        cleanup_destination(
+            &config,
+            &db,
+            "test",
+            &logger,
+            Some(&mut io::Cursor::new("n\n")),
+        )?;
+        assert!(extra.exists());
+        cleanup_destination(&config, &db, "test", &logger, None)?;
+        assert!(extra.exists());
+
+        cleanup_destination(
+            &config,
+            &db,
+            "test",
+            &logger,
+            Some(&mut io::Cursor::new("y\n")),
+        )?;
+        assert!(!extra.exists());
+        Ok(())
+    }
+
+    #[test]
+    fn test_parallel_cleanup() -> Result<()> {
+        let source_dir = tempfile::tempdir()?;
+        let dest_dir = tempfile::tempdir()?;
+        let log_dir = tempfile::tempdir()?;
+
+        for dir in 0..4 {
+            let sub = format!("dir{}", dir);
+            fs::create_dir_all(source_dir.path().join(&sub))?;
+            fs::create_dir_all(dest_dir.path().join(&sub))?;
+            File::create(source_dir.path().join(&sub).join("keep.txt"))?;
+            File::create(dest_dir.path().join(&sub).join("keep.txt"))?;
+            for i in 0..25 {
+                File::create(dest_dir.path().join(&sub).join(format!("extra{}.txt", i)))?;
+            }
+        }
+
+        let config = PipelineConfig {
+            source_dir: source_dir.path().to_path_buf(),
+            dest_dir: dest_dir.path().to_path_buf(),
+            cleanup_threads: 8,
+            ..Default::default()
+        };
+        let logger = Logger::new(log_dir.path().join("cleanup.log").to_str().unwrap());
+        run_cleanup(&config, &test_db(), &logger)?;
+
+        let remaining: Vec<PathBuf> = WalkDir::new(dest_dir.path())
+            .into_iter()
+            .filter_map(|entry| entry.ok())
+            .filter(|entry| entry.file_type().is_file())
+            .map(|entry| entry.into_path())
+            .collect();
+        assert_eq!(remaining.len(), 4);
+        assert!(remaining.iter().all(|path| path.ends_with("keep.txt")));
+        let log = fs::read_to_string(log_dir.path().join("cleanup.log"))?;
+        assert_eq!(log.matches("Deleted extra").count(), 100);
+        Ok(())
+    }
+
+    #[test]
+    fn test_ignore_case_keeps_case_variant() -> Result<()> {
+        let source_dir = tempfile::tempdir()?;
+        let dest_dir = tempfile::tempdir()?;
+        let log_dir = tempfile::tempdir()?;
+        let logger = Logger::new(log_dir.path().join("cleanup.log").to_str().unwrap());
+
+        // Simulates a case-insensitive destination holding the synced file under another case
+        fs::create_dir_all(source_dir.path().join("Docs"))?;
+        fs::create_dir_all(dest_dir.path().join("docs"))?;
+        File::create(source_dir.path().join("Docs").join("Foo.txt"))?;
+        let synced = dest_dir.path().join("docs").join("foo.txt");
+        let extra = dest_dir.path().join("docs").join("bar.txt");
+        File::create(&synced)?;
+        File::create(&extra)?;
+
+        let mut config = PipelineConfig {
+            source_dir: source_dir.path().to_path_buf(),
+            dest_dir: dest_dir.path().to_path_buf(),
+            ignore_case: true,
+            ..Default::default()
+        };
+        run_cleanup(&config, &test_db(), &logger)?;
+        assert!(synced.exists());
+        assert!(!extra.exists());
+
+        config.ignore_case = false;
+        run_cleanup(&config, &test_db(), &logger)?;
+        assert!(!synced.exists());
+        Ok(())
+    }
+
+    #[test]
+    fn test_normalize_unicode_keeps_decomposed_copy() -> Result<()> {
+        let source_dir = tempfile::tempdir()?;
+        let dest_dir = tempfile::tempdir()?;
+        let log_dir = tempfile::tempdir()?;
+        let logger = Logger::new(log_dir.path().join("cleanup.log").to_str().unwrap());
+
+        File::create(source_dir.path().join("caf\u{e9}.txt"))?;
+        let synced = dest_dir.path().join("cafe\u{301}.txt");
+        File::create(&synced)?;
+
+        let mut config = PipelineConfig {
+            source_dir: source_dir.path().to_path_buf(),
+            dest_dir: dest_dir.path().to_path_buf(),
+            normalize_unicode: UnicodeForm::Nfc,
+            ..Default::default()
+        };
+        run_cleanup(&config, &test_db(), &logger)?;
+        assert!(synced.exists());
+
+        config.normalize_unicode = UnicodeForm::None;
+        run_cleanup(&config, &test_db(), &logger)?;
+        assert!(!synced.exists());
+        Ok(())
+    }
+
+    #[test]
+    fn test_cleanup_keeps_excluded_directories() -> Result<()> {
+        let source_dir = tempfile::tempdir()?;
+        let dest_dir = tempfile::tempdir()?;
+        let log_dir = tempfile::tempdir()?;
+        let logger = Logger::new(log_dir.path().join("cleanup.log").to_str().unwrap());
+
+        // Files gone from a marked source directory stay in the destination
+        fs::create_dir_all(source_dir.path().join("private"))?;
+        File::create(source_dir.path().join("private").join(".nobackup"))?;
+        fs::create_dir_all(dest_dir.path().join("private"))?;
+        let kept = dest_dir.path().join("private").join("old.txt");
+        let extra = dest_dir.path().join("old.txt");
+        File::create(&kept)?;
+        File::create(&extra)?;
+
+        let config = PipelineConfig {
+            source_dir: source_dir.path().to_path_buf(),
+            dest_dir: dest_dir.path().to_path_buf(),
+            exclude_if_present: vec![".nobackup".to_string()],
+            ..Default::default()
+        };
+        run_cleanup(&config, &test_db(), &logger)?;
+        assert!(kept.exists());
+        assert!(!extra.exists());
+        Ok(())
+    }
+
+    #[test]
+    fn test_cleanup_keeps_excluded_paths() -> Result<()> {
+        let source_dir = tempfile::tempdir()?;
+        let dest_dir = tempfile::tempdir()?;
+        let log_dir = tempfile::tempdir()?;
+        let logger = Logger::new(log_dir.path().join("cleanup.log").to_str().unwrap());
+
+        // Destination copies of excluded paths are not extras, whatever the source has
+        fs::create_dir_all(dest_dir.path().join("node_modules").join("pkg"))?;
+        let kept_dir = dest_dir
+            .path()
+            .join("node_modules")
+            .join("pkg")
+            .join("a.js");
+        let kept_file = dest_dir.path().join("build.tmp");
+        let extra = dest_dir.path().join("old.txt");
+        File::create(&kept_dir)?;
+        File::create(&kept_file)?;
+        File::create(&extra)?;
+
+        let config = PipelineConfig {
+            source_dir: source_dir.path().to_path_buf(),
+            dest_dir: dest_dir.path().to_path_buf(),
+            path_filter: PathFilter::new(&["node_modules".into(), "*.tmp".into()], &[]),
+            ..Default::default()
+        };
+        run_cleanup(&config, &test_db(), &logger)?;
+        assert!(kept_dir.exists());
+        assert!(kept_file.exists());
+        assert!(!extra.exists());
+        Ok(())
+    }
+
+    #[test]
+    fn test_cleanup_records_deletions_and_resumes() -> Result<()> {
+        let source_dir = tempfile::tempdir()?;
+        let dest_dir = tempfile::tempdir()?;
+        let log_dir = tempfile::tempdir()?;
+        let logger = Logger::new(log_dir.path().join("cleanup.log").to_str().unwrap());
+        let db = test_db();
+
+        let handled = dest_dir.path().join("handled.txt");
+        let extra = dest_dir.path().join("extra.txt");
+        File::create(&handled)?;
+        File::create(&extra)?;
+
+        // An interrupted run already deleted `handled`, which has since reappeared
+        let token = db.lock().unwrap().begin_cleanup()?;
+        db.lock()
+            .unwrap()
+            .record_deletion(&token, handled.to_str().unwrap())?;
+
+        let config = PipelineConfig {
+            source_dir: source_dir.path().to_path_buf(),
+            dest_dir: dest_dir.path().to_path_buf(),
+            ..Default::default()
+        };
+        run_cleanup(&config, &db, &logger)?;
+
+        assert!(handled.exists());
+        assert!(!extra.exists());
+        let recorded: Vec<String> = db
+            .lock()
+            .unwrap()
+            .get_deletions(&token)?
+            .into_iter()
+            .map(|(path, _)| path)
+            .collect();
+        let mut expected = vec![
+            extra.to_str().unwrap().to_string(),
+            handled.to_str().unwrap().to_string(),
+        ];
+        expected.sort();
+        assert_eq!(recorded, expected);
+
+        // The finished run's token is retired, so the next run starts afresh
+        let next = db.lock().unwrap().begin_cleanup()?;
+        assert_ne!(next, token);
+        run_cleanup(&config, &db, &logger)?;
+        assert!(!handled.exists());
+        Ok(())
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_delete_removed_checks_like_cleanup() -> Result<()> {
+        let source_dir = tempfile::tempdir()?;
+        let dest_dir = tempfile::tempdir()?;
+        let log_dir = tempfile::tempdir()?;
+        let logger = Logger::new(log_dir.path().join("cleanup.log").to_str().unwrap());
+        let db = test_db();
+
+        // `gone` is truly removed and `back` reappeared in the source; `Renamed` is
+        // the same file as the freshly written `renamed`, as after a case-only
+        // rename on a case-insensitive destination
+        let gone = dest_dir.path().join("gone.txt");
+        let back = dest_dir.path().join("back.txt");
+        let renamed = dest_dir.path().join("renamed.txt");
+        let old_name = dest_dir.path().join("Renamed.txt");
+        File::create(&gone)?;
+        File::create(&back)?;
+        File::create(source_dir.path().join("back.txt"))?;
+        File::create(&renamed)?;
+        fs::hard_link(&renamed, &old_name)?;
+
+        let config = PipelineConfig {
+            source_dir: source_dir.path().to_path_buf(),
+            dest_dir: dest_dir.path().to_path_buf(),
+            ..Default::default()
+        };
+        let token = db.lock().unwrap().begin_cleanup()?;
+        let removed = ["gone.txt", "back.txt", "Renamed.txt"].map(PathBuf::from);
+        let deleted = delete_removed(
+            &config,
+            &db,
+            &logger,
+            &removed,
+            &[PathBuf::from("renamed.txt")],
+        )?;
+
+        assert_eq!(deleted, 1);
+        assert!(!gone.exists());
+        assert!(back.exists());
+        assert!(old_name.exists());
+        let recorded = db.lock().unwrap().get_deletions(&token)?;
+        assert_eq!(recorded.len(), 1);
+        assert_eq!(recorded[0].0, gone.to_str().unwrap());
+        Ok(())
+    }
 }
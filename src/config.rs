@@ -0,0 +1,360 @@
+//! Default options from a TOML configuration file.
+//!
+//! Keys are the long flag names (e.g. `bwlimit`, `block-size`). Values from the
+//! file apply only to options not given on the command line.
+
+use anyhow::{bail, Context, Result};
+use clap::parser::ValueSource;
+use clap::{ArgMatches, CommandFactory, FromArgMatches};
+use serde::{Deserialize, Deserializer};
+use std::ffi::OsString;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use crate::pipeline::HashAlgorithm;
+use crate::scan::UnicodeForm;
+use crate::utils::IoClass;
+use crate::Args;
+
+/// Configuration file read from the current directory when `--config` is not given
+pub const DEFAULT_CONFIG_FILE: &str = "hsync.toml";
+
+/// Options settable from the configuration file; unset keys keep the CLI defaults
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields, rename_all = "kebab-case")]
+pub struct FileConfig {
+    pub source: Option<PathBuf>,
+    #[serde(deserialize_with = "one_or_many")]
+    pub dest: Option<Vec<PathBuf>>,
+    pub relative: Option<bool>,
+    pub db: Option<String>,
+    pub db_timeout: Option<u64>,
+    pub log: Option<String>,
+    pub log_utc: Option<bool>,
+    pub error_log: Option<String>,
+    pub transfer_log: Option<String>,
+    pub bwlimit: Option<String>,
+    pub bwlimit_new: Option<String>,
+    pub bwlimit_update: Option<String>,
+    pub max_bandwidth_per_file: Option<String>,
+    pub daily_budget: Option<String>,
+    pub bwlimit_ramp: Option<u64>,
+    pub shared_limit_file: Option<PathBuf>,
+    pub control_file: Option<PathBuf>,
+    pub checksum: Option<HashAlgorithm>,
+    pub checksum_override: Option<Vec<String>>,
+    pub checksum_command: Option<String>,
+    pub verify_dest: Option<bool>,
+    pub verify_size: Option<bool>,
+    pub abort_after_mismatches: Option<u64>,
+    pub encrypt: Option<bool>,
+    pub password_file: Option<PathBuf>,
+    pub audit_key_file: Option<PathBuf>,
+    pub size_only: Option<bool>,
+    pub checksum_compare: Option<bool>,
+    pub perms_matter: Option<bool>,
+    pub delete_extras: Option<bool>,
+    pub list_deletions: Option<bool>,
+    pub dry_run: Option<bool>,
+    pub cleanup_threads: Option<usize>,
+    pub max_open_files: Option<usize>,
+    pub confirm_delete: Option<bool>,
+    pub preserve_dir_times: Option<bool>,
+    pub safe_cleanup: Option<bool>,
+    pub ignore_case: Option<bool>,
+    pub normalize_unicode: Option<UnicodeForm>,
+    pub trust_dest_db: Option<bool>,
+    pub ignore_existing: Option<bool>,
+    pub compare_dest_hash: Option<bool>,
+    pub refresh_metadata: Option<bool>,
+    pub existing: Option<bool>,
+    pub reverse: Option<bool>,
+    pub copy_links: Option<bool>,
+    pub safe_links: Option<bool>,
+    pub copy_unsafe_links: Option<bool>,
+    pub no_hash_on_copy: Option<bool>,
+    pub delay_updates: Option<bool>,
+    pub file_timeout: Option<u64>,
+    pub only_write_changed: Option<bool>,
+    pub rehash: Option<bool>,
+    pub store_block_hashes: Option<bool>,
+    pub ordered: Option<bool>,
+    pub dedupe_identical: Option<bool>,
+    pub group_by_dir: Option<bool>,
+    pub preserve_fileflags: Option<bool>,
+    pub io_class: Option<IoClass>,
+    pub append: Option<bool>,
+    pub whole_file: Option<bool>,
+    pub numeric_progress: Option<u64>,
+    pub stats_interval: Option<u64>,
+    pub progress_template: Option<String>,
+    pub progress_no_eta: Option<bool>,
+    pub done_file: Option<PathBuf>,
+    pub metrics_file: Option<PathBuf>,
+    pub force_resync: Option<bool>,
+    pub rescan: Option<bool>,
+    pub block_size: Option<String>,
+    pub block_size_for: Option<Vec<String>>,
+    pub queue_capacity: Option<usize>,
+    pub watch: Option<bool>,
+    pub watch_debounce_ms: Option<u64>,
+    pub retry_attempts: Option<u32>,
+    pub retry_interval_seconds: Option<u64>,
+    pub newer_than: Option<String>,
+    pub magic_filter: Option<Vec<String>>,
+    pub exclude_if_present: Option<Vec<String>>,
+    pub exclude: Option<Vec<String>>,
+    pub include: Option<Vec<String>>,
+    pub fail_on_inaccessible: Option<bool>,
+    pub max_scan_memory: Option<String>,
+    pub report_tree_sizes: Option<usize>,
+    pub compact_db: Option<bool>,
+}
+
+impl FileConfig {
+    pub fn load(path: &Path) -> Result<Self> {
+        let content = fs::read_to_string(path)
+            .with_context(|| format!("Failed to read config file {:?}", path))?;
+        toml::from_str(&content).with_context(|| format!("Invalid config file {:?}", path))
+    }
+}
+
+/// Accepts a single path or a list of paths
+fn one_or_many<'de, D: Deserializer<'de>>(
+    deserializer: D,
+) -> std::result::Result<Option<Vec<PathBuf>>, D::Error> {
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum OneOrMany {
+        One(PathBuf),
+        Many(Vec<PathBuf>),
+    }
+    Ok(Some(match OneOrMany::deserialize(deserializer)? {
+        OneOrMany::One(path) => vec![path],
+        OneOrMany::Many(paths) => paths,
+    }))
+}
+
+/// Whether a value taken from the file turns its option on, the way giving the
+/// flag would: `false` and empty lists leave it off
+trait IsSet {
+    fn is_set(&self) -> bool;
+}
+
+impl IsSet for bool {
+    fn is_set(&self) -> bool {
+        *self
+    }
+}
+
+impl<T> IsSet for Vec<T> {
+    fn is_set(&self) -> bool {
+        !self.is_empty()
+    }
+}
+
+macro_rules! always_set {
+    ($($type:ty),*) => {
+        $(impl IsSet for $type {
+            fn is_set(&self) -> bool {
+                true
+            }
+        })*
+    };
+}
+
+always_set!(String, u32, u64, usize, HashAlgorithm, UnicodeForm);
+
+/// Copies file values into `args` for options that were not given on the command
+/// line, recording in `$from_file` the ids of the options the file turned on
+macro_rules! apply_defaults {
+    ($args:ident, $matches:ident, $config:ident, $from_file:ident,
+     options: [$($option:ident),*], values: [$($value:ident),*]) => {
+        $(if let Some(value) = $config.$option {
+            if !from_command_line(&$matches, stringify!($option)) {
+                $args.$option = Some(value);
+                $from_file.push(stringify!($option));
+            }
+        })*
+        $(if let Some(value) = $config.$value {
+            if !from_command_line(&$matches, stringify!($value)) {
+                if value.is_set() {
+                    $from_file.push(stringify!($value));
+                }
+                $args.$value = value;
+            }
+        })*
+    };
+}
+
+fn from_command_line(matches: &ArgMatches, id: &str) -> bool {
+    matches.value_source(id) == Some(ValueSource::CommandLine)
+}
+
+/// Merges `config` into arguments parsed from `matches`, command line taking precedence
+pub fn merge(matches: &ArgMatches, config: FileConfig) -> Result<Args> {
+    let mut args = Args::from_arg_matches(matches)?;
+    let mut from_file = Vec::new();
+    apply_defaults!(args, matches, config, from_file,
+        options: [
+            source, error_log, transfer_log, bwlimit, bwlimit_new, bwlimit_update,
+            max_bandwidth_per_file, daily_budget, bwlimit_ramp, shared_limit_file, control_file,
+            checksum_command, numeric_progress, stats_interval, progress_template, done_file,
+            newer_than, file_timeout, max_scan_memory, password_file, report_tree_sizes,
+            abort_after_mismatches, max_open_files, metrics_file, audit_key_file, io_class
+        ],
+        values: [
+            dest, relative, db, db_timeout, log, log_utc, checksum, checksum_override, verify_dest,
+            verify_size, encrypt, size_only, checksum_compare, perms_matter, delete_extras,
+            cleanup_threads, confirm_delete, preserve_dir_times, safe_cleanup, ignore_case,
+            ignore_existing, existing, reverse, no_hash_on_copy, delay_updates, only_write_changed,
+            rehash, store_block_hashes, append, whole_file, force_resync, rescan, block_size,
+            queue_capacity, retry_attempts, retry_interval_seconds, compact_db, progress_no_eta,
+            copy_links, safe_links, copy_unsafe_links, magic_filter, fail_on_inaccessible,
+            list_deletions, dry_run, refresh_metadata, compare_dest_hash, block_size_for, ordered,
+            normalize_unicode, watch, watch_debounce_ms, trust_dest_db, dedupe_identical,
+            exclude_if_present, exclude, include, group_by_dir, preserve_fileflags
+        ]
+    );
+    check_conflicts(matches, &from_file)?;
+    Ok(args)
+}
+
+/// Applies the command line's conflicts to options set from the file: clap only
+/// checks flags it parsed itself
+fn check_conflicts(matches: &ArgMatches, from_file: &[&str]) -> Result<()> {
+    let command = Args::command();
+    let present = |id: &str| from_command_line(matches, id) || from_file.contains(&id);
+    for arg in command.get_arguments() {
+        let id = arg.get_id().as_str();
+        if !present(id) {
+            continue;
+        }
+        for other in command.get_arg_conflicts_with(arg) {
+            let other_id = other.get_id().as_str();
+            // Conflicts between two flags were already rejected by clap
+            if present(other_id) && (from_file.contains(&id) || from_file.contains(&other_id)) {
+                let origin = |id: &str| {
+                    if from_file.contains(&id) {
+                        "config file"
+                    } else {
+                        "command line"
+                    }
+                };
+                bail!(
+                    "--{} ({}) cannot be used with --{} ({})",
+                    arg.get_long().unwrap_or(id),
+                    origin(id),
+                    other.get_long().unwrap_or(other_id),
+                    origin(other_id)
+                );
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Parses the command line like `Args::parse`, then fills unset options from
+/// `--config` or, if present, `hsync.toml` in the current directory.
+pub fn parse_args<I, T>(itr: I) -> Result<Args>
+where
+    I: IntoIterator<Item = T>,
+    T: Into<OsString> + Clone,
+{
+    let matches = Args::command().get_matches_from(itr);
+    let config_path = match matches.get_one::<PathBuf>("config") {
+        Some(path) => Some(path.clone()),
+        None => Some(PathBuf::from(DEFAULT_CONFIG_FILE)).filter(|path| path.exists()),
+    };
+    let config = match config_path {
+        Some(path) => FileConfig::load(&path)?,
+        None => FileConfig::default(),
+    };
+    merge(&matches, config)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_config_file_defaults_and_overrides() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let config_path = dir.path().join("hsync.toml");
+        fs::write(
+            &config_path,
+            "source = \"/data/src\"\n\
+             dest = \"/data/dst\"\n\
+             bwlimit = \"20M\"\n\
+             block-size = \"1M\"\n\
+             checksum = \"blake2b\"\n\
+             delete-extras = true\n",
+        )?;
+
+        let args = parse_args([
+            "hsync".as_ref(),
+            "--config".as_ref(),
+            config_path.as_os_str(),
+            "--bwlimit".as_ref(),
+            "5M".as_ref(),
+        ])?;
+
+        assert_eq!(args.source, Some(PathBuf::from("/data/src")));
+        assert_eq!(args.dest, vec![PathBuf::from("/data/dst")]);
+        assert_eq!(args.block_size, "1M");
+        assert!(matches!(args.checksum, HashAlgorithm::Blake2b));
+        assert!(args.delete_extras);
+        // Explicit flags win over the file
+        assert_eq!(args.bwlimit.as_deref(), Some("5M"));
+        // Options absent from both keep the CLI defaults
+        assert_eq!(args.retry_attempts, 10);
+        Ok(())
+    }
+
+    #[test]
+    fn test_config_file_conflicting_options() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let config_path = dir.path().join("hsync.toml");
+        let parse = |extra: &[&str]| {
+            let mut args: Vec<OsString> = vec![
+                "hsync".into(),
+                "--config".into(),
+                config_path.clone().into(),
+            ];
+            args.extend(extra.iter().map(OsString::from));
+            parse_args(args)
+        };
+
+        // Both in the file
+        fs::write(
+            &config_path,
+            "encrypt = true\n\
+             password-file = \"pw\"\n\
+             block-size-for = [\"*.mkv=64M\"]\n",
+        )?;
+        let error = parse(&[]).unwrap_err().to_string();
+        assert!(error.contains("--block-size-for"), "{}", error);
+        assert!(error.contains("--encrypt"), "{}", error);
+
+        // One in the file, the other on the command line
+        fs::write(&config_path, "block-size-for = [\"*.mkv=64M\"]\n")?;
+        assert!(parse(&["--encrypt", "--password-file", "pw"]).is_err());
+
+        // Turned off in the file, an option conflicts with nothing
+        fs::write(
+            &config_path,
+            "encrypt = false\nblock-size-for = [\"*.mkv=64M\"]\n",
+        )?;
+        assert_eq!(parse(&[])?.block_size_for, vec!["*.mkv=64M".to_string()]);
+        Ok(())
+    }
+
+    #[test]
+    fn test_config_file_unknown_key() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let config_path = dir.path().join("hsync.toml");
+        fs::write(&config_path, "bandwidth = \"20M\"\n")?;
+        assert!(FileConfig::load(&config_path).is_err());
+        Ok(())
+    }
+}
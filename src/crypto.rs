@@ -0,0 +1,325 @@
+//! Encryption of destination files at rest.
+//!
+//! Each file is stored as a header followed by the blocks it was transferred in,
+//! each sealed with AES-256-GCM:
+//!
+//! ```text
+//! magic "HSYNCENC" | version (1) | block size (u32 LE) | salt (16) | nonce prefix (7)
+//! block 0 ciphertext + tag | block 1 ciphertext + tag | ...
+//! ```
+//!
+//! The key is derived from the password with Argon2id and the salt in the header.
+//! A block's nonce is the file's random prefix, the block index (u32 BE) and a
+//! final byte set only on the last block, so blocks cannot be reordered, dropped
+//! or cut off at the end without failing authentication.
+//!
+//! Because each block's nonce depends only on the prefix and its index, an
+//! interrupted transfer can resume after its last written block: the key is
+//! derived again from the salt recorded for the file, and the following blocks
+//! are sealed exactly as they would have been.
+
+use aes_gcm::aead::rand_core::RngCore;
+use aes_gcm::aead::{Aead, KeyInit, Nonce, OsRng};
+use aes_gcm::{Aes256Gcm, Key};
+use anyhow::{anyhow, bail, Context, Result};
+use argon2::Argon2;
+use std::collections::hash_map::Entry;
+use std::collections::HashMap;
+use std::fs::{self, File};
+use std::io::{self, BufReader, BufWriter, Read, Write};
+use std::path::Path;
+use walkdir::WalkDir;
+
+const MAGIC: &[u8; 8] = b"HSYNCENC";
+const VERSION: u8 = 1;
+/// Random per-run salt of the key derivation
+pub const SALT_LEN: usize = 16;
+/// Random per-file part of the block nonces
+pub const NONCE_PREFIX_LEN: usize = 7;
+/// Authentication tag appended to each block
+const TAG_LEN: u64 = 16;
+/// Length of the header preceding the first block
+pub const HEADER_LEN: u64 = (MAGIC.len() + 1 + 4 + SALT_LEN + NONCE_PREFIX_LEN) as u64;
+
+/// Encrypts blocks with a key derived from a password, for one run
+#[derive(Clone)]
+pub struct Cipher {
+    cipher: Aes256Gcm,
+    salt: [u8; SALT_LEN],
+    /// Kept to derive the key of files started under another salt
+    password: Vec<u8>,
+}
+
+impl Cipher {
+    /// Derives a key from the password with a fresh random salt
+    pub fn new(password: &[u8]) -> Result<Self> {
+        let mut salt = [0u8; SALT_LEN];
+        OsRng.fill_bytes(&mut salt);
+        Self::with_salt(password, salt)
+    }
+
+    fn with_salt(password: &[u8], salt: [u8; SALT_LEN]) -> Result<Self> {
+        let mut key = [0u8; 32];
+        Argon2::default()
+            .hash_password_into(password, &salt, &mut key)
+            .map_err(|e| anyhow!("Key derivation failed: {}", e))?;
+        Ok(Self {
+            cipher: Aes256Gcm::new(Key::<Aes256Gcm>::from_slice(&key)),
+            salt,
+            password: password.to_vec(),
+        })
+    }
+
+    /// Salt the key was derived with
+    pub fn salt(&self) -> [u8; SALT_LEN] {
+        self.salt
+    }
+
+    /// Cipher for the same password under `salt`, e.g. to resume a file an earlier
+    /// run started. Deriving a new key is slow, so callers should reuse the result.
+    pub fn for_salt(&self, salt: [u8; SALT_LEN]) -> Result<Self> {
+        if salt == self.salt {
+            return Ok(self.clone());
+        }
+        Self::with_salt(&self.password, salt)
+    }
+
+    /// Returns a random nonce prefix for a new file
+    pub fn new_nonce_prefix() -> [u8; NONCE_PREFIX_LEN] {
+        let mut prefix = [0u8; NONCE_PREFIX_LEN];
+        OsRng.fill_bytes(&mut prefix);
+        prefix
+    }
+
+    /// Header written in front of the first block of a file
+    pub fn header(&self, block_size: usize, prefix: &[u8; NONCE_PREFIX_LEN]) -> Vec<u8> {
+        header_bytes(block_size, &self.salt, prefix)
+    }
+
+    /// Seals block `index` of a file, returning the ciphertext with its tag
+    pub fn encrypt_block(
+        &self,
+        prefix: &[u8; NONCE_PREFIX_LEN],
+        index: u64,
+        last: bool,
+        data: &[u8],
+    ) -> Result<Vec<u8>> {
+        self.cipher
+            .encrypt(&block_nonce(prefix, index, last)?, data)
+            .map_err(|_| anyhow!("Encryption failed"))
+    }
+
+    fn decrypt_block(
+        &self,
+        prefix: &[u8; NONCE_PREFIX_LEN],
+        index: u64,
+        last: bool,
+        data: &[u8],
+    ) -> Result<Vec<u8>> {
+        self.cipher
+            .decrypt(&block_nonce(prefix, index, last)?, data)
+            .map_err(|_| anyhow!("Decryption failed: wrong password or damaged file"))
+    }
+}
+
+fn header_bytes(
+    block_size: usize,
+    salt: &[u8; SALT_LEN],
+    prefix: &[u8; NONCE_PREFIX_LEN],
+) -> Vec<u8> {
+    let mut header = Vec::with_capacity(HEADER_LEN as usize);
+    header.extend_from_slice(MAGIC);
+    header.push(VERSION);
+    header.extend_from_slice(&(block_size as u32).to_le_bytes());
+    header.extend_from_slice(salt);
+    header.extend_from_slice(prefix);
+    header
+}
+
+/// Whether `path` starts with the header of a file sealed in blocks of `block_size`
+/// under `salt` and `prefix`, i.e. whether its blocks may be continued
+pub fn has_header(
+    path: &Path,
+    block_size: usize,
+    salt: &[u8; SALT_LEN],
+    prefix: &[u8; NONCE_PREFIX_LEN],
+) -> bool {
+    let mut header = [0u8; HEADER_LEN as usize];
+    File::open(path)
+        .and_then(|mut file| file.read_exact(&mut header))
+        .is_ok()
+        && header[..] == header_bytes(block_size, salt, prefix)[..]
+}
+
+fn block_nonce(
+    prefix: &[u8; NONCE_PREFIX_LEN],
+    index: u64,
+    last: bool,
+) -> Result<Nonce<Aes256Gcm>> {
+    let index = u32::try_from(index).map_err(|_| anyhow!("File has too many blocks to encrypt"))?;
+    let mut nonce = [0u8; 12];
+    nonce[..NONCE_PREFIX_LEN].copy_from_slice(prefix);
+    nonce[NONCE_PREFIX_LEN..11].copy_from_slice(&index.to_be_bytes());
+    nonce[11] = last as u8;
+    Ok(*Nonce::<Aes256Gcm>::from_slice(&nonce))
+}
+
+/// Reads the password from the first line of `path`
+pub fn read_password_file(path: &Path) -> Result<Vec<u8>> {
+    let content = fs::read_to_string(path)
+        .with_context(|| format!("Failed to read password file {:?}", path))?;
+    let password = content.lines().next().unwrap_or_default();
+    if password.is_empty() {
+        bail!("Password file {:?} is empty", path);
+    }
+    Ok(password.as_bytes().to_vec())
+}
+
+/// Size at rest of a file of `size` bytes sent in blocks of `block_size`.
+/// An empty file still has one (empty) block.
+pub fn encrypted_len(size: u64, block_size: usize) -> u64 {
+    let blocks = size.div_ceil(block_size as u64).max(1);
+    HEADER_LEN + size + blocks * TAG_LEN
+}
+
+/// Offset at rest of the plaintext at `offset`, which starts block `index`
+pub fn encrypted_offset(offset: u64, index: u64) -> u64 {
+    HEADER_LEN + offset + index * TAG_LEN
+}
+
+/// Decrypts `input` to `output`. Directories are decrypted file by file into the
+/// same layout. Returns the number of files decrypted.
+pub fn decrypt_path(password: &[u8], input: &Path, output: &Path) -> Result<u64> {
+    // Files of one run share a salt, so each key is derived only once
+    let mut ciphers = HashMap::new();
+    if !input.is_dir() {
+        decrypt_file(password, &mut ciphers, input, output)?;
+        return Ok(1);
+    }
+    let mut count = 0;
+    for entry in WalkDir::new(input) {
+        let entry = entry?;
+        if entry.file_type().is_dir() {
+            continue;
+        }
+        let target = output.join(entry.path().strip_prefix(input)?);
+        if let Some(parent) = target.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        decrypt_file(password, &mut ciphers, entry.path(), &target)?;
+        count += 1;
+    }
+    Ok(count)
+}
+
+fn decrypt_file(
+    password: &[u8],
+    ciphers: &mut HashMap<[u8; SALT_LEN], Cipher>,
+    input: &Path,
+    output: &Path,
+) -> Result<()> {
+    let mut reader = BufReader::new(File::open(input)?);
+    let mut header = [0u8; HEADER_LEN as usize];
+    reader
+        .read_exact(&mut header)
+        .with_context(|| format!("{:?} is not an encrypted hsync file", input))?;
+    if &header[..MAGIC.len()] != MAGIC {
+        bail!("{:?} is not an encrypted hsync file", input);
+    }
+    if header[MAGIC.len()] != VERSION {
+        bail!(
+            "{:?} uses unsupported format version {}",
+            input,
+            header[MAGIC.len()]
+        );
+    }
+    let block_size = u32::from_le_bytes(header[9..13].try_into()?) as usize;
+    let salt: [u8; SALT_LEN] = header[13..13 + SALT_LEN].try_into()?;
+    let prefix: [u8; NONCE_PREFIX_LEN] = header[13 + SALT_LEN..].try_into()?;
+    let cipher = match ciphers.entry(salt) {
+        Entry::Occupied(entry) => entry.into_mut(),
+        Entry::Vacant(entry) => entry.insert(Cipher::with_salt(password, salt)?),
+    };
+
+    let mut writer = BufWriter::new(File::create(output)?);
+    let chunk_len = block_size + TAG_LEN as usize;
+    let mut chunk = read_chunk(&mut reader, chunk_len)?;
+    let mut index = 0;
+    loop {
+        // Only the block followed by nothing is the last one
+        let next = read_chunk(&mut reader, chunk_len)?;
+        let last = next.is_empty();
+        let plaintext = cipher
+            .decrypt_block(&prefix, index, last, &chunk)
+            .with_context(|| format!("Block {} of {:?}", index, input))?;
+        writer.write_all(&plaintext)?;
+        if last {
+            break;
+        }
+        chunk = next;
+        index += 1;
+    }
+    writer.flush()?;
+    Ok(())
+}
+
+/// Reads up to `len` bytes, fewer only at the end of the input
+fn read_chunk(reader: &mut impl Read, len: usize) -> io::Result<Vec<u8>> {
+    let mut chunk = Vec::with_capacity(len);
+    reader.take(len as u64).read_to_end(&mut chunk)?;
+    Ok(chunk)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Writes `data` encrypted the way the consumer does, in blocks of `block_size`
+    fn encrypt_to(cipher: &Cipher, data: &[u8], block_size: usize, path: &Path) -> Result<()> {
+        let prefix = Cipher::new_nonce_prefix();
+        let mut out = cipher.header(block_size, &prefix);
+        let blocks: Vec<&[u8]> = if data.is_empty() {
+            vec![data]
+        } else {
+            data.chunks(block_size).collect()
+        };
+        for (index, block) in blocks.iter().enumerate() {
+            let last = index + 1 == blocks.len();
+            out.extend(cipher.encrypt_block(&prefix, index as u64, last, block)?);
+        }
+        fs::write(path, out)?;
+        Ok(())
+    }
+
+    #[test]
+    fn test_encrypt_decrypt_round_trip() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let cipher = Cipher::new(b"secret")?;
+        let plaintext: Vec<u8> = (0..10_000u32).map(|i| (i % 251) as u8).collect();
+
+        for (name, data) in [("data.bin", &plaintext[..]), ("empty.bin", &[][..])] {
+            let encrypted = dir.path().join(name);
+            encrypt_to(&cipher, data, 4096, &encrypted)?;
+            let at_rest = fs::read(&encrypted)?;
+            assert_eq!(at_rest.len() as u64, encrypted_len(data.len() as u64, 4096));
+            assert!(!data.is_empty() || at_rest.len() as u64 == HEADER_LEN + TAG_LEN);
+            assert!(!at_rest
+                .windows(64)
+                .any(|w| data.windows(64).any(|p| p == w)));
+
+            let restored = dir.path().join(format!("{}.out", name));
+            decrypt_path(b"secret", &encrypted, &restored)?;
+            assert_eq!(fs::read(&restored)?, data);
+        }
+
+        // Wrong password and truncation are both rejected
+        let encrypted = dir.path().join("data.bin");
+        let restored = dir.path().join("bad.out");
+        assert!(decrypt_path(b"wrong", &encrypted, &restored).is_err());
+        let at_rest = fs::read(&encrypted)?;
+        let last_block_len = (plaintext.len() % 4096) + TAG_LEN as usize;
+        fs::write(&encrypted, &at_rest[..at_rest.len() - last_block_len])?;
+        assert!(decrypt_path(b"secret", &encrypted, &restored).is_err());
+        Ok(())
+    }
+}
@@ -1,8 +1,13 @@
-use rusqlite::{params, Connection, Result};
+use rusqlite::{params, Connection, OptionalExtension, Result};
+use std::collections::HashMap;
 use std::path::Path;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// How long a statement waits for another connection's lock before failing
+pub const DEFAULT_BUSY_TIMEOUT: Duration = Duration::from_secs(5);
 
 /// File sync status in the database
-#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
 pub enum FileStatus {
     Pending, // Needs to be transferred
     Synced,  // Already transferred or confirmed in-sync
@@ -24,6 +29,33 @@ impl FileStatus {
     }
 }
 
+/// Why the last scan queued a file
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PendingReason {
+    DestMissing, // No destination copy exists
+    Changed,     // The destination copy differs from the source
+    SameFile,    // The destination is the source file itself; left until the next scan
+}
+
+impl PendingReason {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            PendingReason::DestMissing => "dest_missing",
+            PendingReason::Changed => "changed",
+            PendingReason::SameFile => "same_file",
+        }
+    }
+
+    #[allow(clippy::should_implement_trait)]
+    pub fn from_str(s: &str) -> Self {
+        match s {
+            "dest_missing" => PendingReason::DestMissing,
+            "same_file" => PendingReason::SameFile,
+            _ => PendingReason::Changed,
+        }
+    }
+}
+
 /// Represents a file record from the database
 #[derive(Debug, Clone)]
 pub struct FileRecord {
@@ -32,21 +64,180 @@ pub struct FileRecord {
     pub modified_date: i64,
     pub size: u64,
     pub status: FileStatus,
-    pub atime: i64,
+    /// Source creation time, or access time where the platform has none
+    pub created: i64,
     pub ctime: i64,
     pub permissions: u32,
     pub hash: Option<String>,
+    /// Destination mtime recorded when hsync last wrote or verified the file
+    pub dest_mtime: Option<i64>,
+    /// Hash of the destination content read back after writing
+    pub dest_hash: Option<String>,
+    /// Why the file is pending, if recorded by the scan
+    pub pending_reason: Option<PendingReason>,
+    /// Transfer attempts after which the file was still pending
+    pub attempts: u32,
+    /// Algorithm that produced `hash`, when recorded
+    pub hash_algo: Option<String>,
+}
+
+/// Hashes stored when a file is marked synced
+#[derive(Debug, Default, Clone, Copy)]
+pub struct SyncedHashes<'a> {
+    /// Source content hash, if computed
+    pub hash: Option<&'a str>,
+    /// Algorithm that produced `hash`; None for a custom checksum command
+    pub hash_algo: Option<&'a str>,
+    /// Hash of the destination content read back after writing
+    pub dest_hash: Option<&'a str>,
 }
 
 pub struct Database {
     conn: Connection,
+    /// Source and destination prefixes stripped from stored paths (compact schema)
+    prefixes: Option<(String, String)>,
+}
+
+/// Error for a file that cannot be stored relative to the compact path prefixes
+fn outside_prefixes(
+    source_path: &str,
+    dest_path: &str,
+    source_prefix: &str,
+    dest_prefix: &str,
+) -> rusqlite::Error {
+    rusqlite::Error::ToSqlConversionFailure(
+        format!(
+            "{:?} -> {:?} lies outside the compact path prefixes {:?} and {:?}",
+            source_path, dest_path, source_prefix, dest_prefix
+        )
+        .into(),
+    )
 }
 
 impl Database {
     pub fn new<P: AsRef<Path>>(path: P) -> Result<Self> {
+        Self::with_busy_timeout(path, DEFAULT_BUSY_TIMEOUT)
+    }
+
+    /// Opens the database, waiting up to `timeout` whenever another connection holds a lock
+    pub fn with_busy_timeout<P: AsRef<Path>>(path: P, timeout: Duration) -> Result<Self> {
         let conn = Connection::open(path)?;
+        conn.busy_timeout(timeout)?;
         Self::init(&conn)?;
-        Ok(Self { conn })
+        let prefixes = Self::load_prefixes(&conn)?;
+        Ok(Self { conn, prefixes })
+    }
+
+    fn load_prefixes(conn: &Connection) -> Result<Option<(String, String)>> {
+        let get = |key: &str| -> Result<Option<String>> {
+            let mut stmt = conn.prepare("SELECT value FROM settings WHERE key = ?1")?;
+            let mut rows = stmt.query(params![key])?;
+            match rows.next()? {
+                Some(row) => row.get(0),
+                None => Ok(None),
+            }
+        };
+        Ok(match (get("source_prefix")?, get("dest_prefix")?) {
+            (Some(s), Some(d)) => Some((s, d)),
+            _ => None,
+        })
+    }
+
+    /// An in-memory copy of the database, for runs whose changes must not persist
+    pub fn scratch_copy(&self) -> Result<Self> {
+        let mut conn = Connection::open_in_memory()?;
+        rusqlite::backup::Backup::new(&self.conn, &mut conn)?.run_to_completion(
+            256,
+            Duration::ZERO,
+            None,
+        )?;
+        Ok(Self {
+            conn,
+            prefixes: self.prefixes.clone(),
+        })
+    }
+
+    /// Store paths relative to the given prefixes, keeping each prefix only once.
+    /// Existing rows are rewritten to the new prefixes; fails, changing nothing, if
+    /// any row lies outside them, as reading it back would prepend them all the same.
+    pub fn enable_compact_paths(&mut self, source_prefix: &str, dest_prefix: &str) -> Result<()> {
+        let tx = self.conn.transaction()?;
+        // Expand rows stored under previous prefixes back to full paths
+        if let Some((old_source, old_dest)) = &self.prefixes {
+            tx.execute(
+                "UPDATE files SET source_path = ?1 || source_path, dest_path = ?2 || dest_path",
+                params![old_source, old_dest],
+            )?;
+        }
+        let outside: Option<(String, String)> = tx
+            .query_row(
+                "SELECT source_path, dest_path FROM files
+                 WHERE substr(source_path, 1, length(?1)) != ?1
+                    OR substr(dest_path, 1, length(?2)) != ?2
+                 LIMIT 1",
+                params![source_prefix, dest_prefix],
+                |row| Ok((row.get(0)?, row.get(1)?)),
+            )
+            .optional()?;
+        if let Some((source_path, dest_path)) = outside {
+            return Err(outside_prefixes(
+                &source_path,
+                &dest_path,
+                source_prefix,
+                dest_prefix,
+            ));
+        }
+        for (column, prefix) in [("source_path", source_prefix), ("dest_path", dest_prefix)] {
+            tx.execute(
+                &format!(
+                    "UPDATE files SET {0} = substr({0}, length(?1) + 1)
+                     WHERE substr({0}, 1, length(?1)) = ?1",
+                    column
+                ),
+                params![prefix],
+            )?;
+        }
+        // Their keys are not rewritten: without them, pending files go to every destination
+        tx.execute("DELETE FROM synced_dests", [])?;
+        tx.execute(
+            "INSERT OR REPLACE INTO settings (key, value)
+             VALUES ('source_prefix', ?1), ('dest_prefix', ?2)",
+            params![source_prefix, dest_prefix],
+        )?;
+        tx.commit()?;
+        self.prefixes = Some((source_prefix.to_string(), dest_prefix.to_string()));
+        Ok(())
+    }
+
+    /// Rebuild the database file to reclaim space after large changes
+    pub fn vacuum(&self) -> Result<()> {
+        // Page size only takes effect on VACUUM; small pages suit short rows
+        self.conn.execute_batch("PRAGMA page_size = 4096; VACUUM;")
+    }
+
+    /// Strips the stored prefix from a source path (no-op without compact schema)
+    fn compact_source<'a>(&self, path: &'a str) -> &'a str {
+        match &self.prefixes {
+            Some((prefix, _)) => path.strip_prefix(prefix.as_str()).unwrap_or(path),
+            None => path,
+        }
+    }
+
+    fn compact_dest<'a>(&self, path: &'a str) -> &'a str {
+        match &self.prefixes {
+            Some((_, prefix)) => path.strip_prefix(prefix.as_str()).unwrap_or(path),
+            None => path,
+        }
+    }
+
+    /// Reconstructs full source and destination paths from stored values
+    fn expand_paths(&self, source: String, dest: String) -> (String, String) {
+        match &self.prefixes {
+            Some((source_prefix, dest_prefix)) => {
+                (source_prefix.clone() + &source, dest_prefix.clone() + &dest)
+            }
+            None => (source, dest),
+        }
     }
 
     /// Begin a transaction for batch operations
@@ -76,15 +267,119 @@ impl Database {
             )",
             [],
         )?;
+        // Columns added after the initial schema
+        Self::add_column_if_missing(conn, "dest_mtime", "INTEGER")?;
+        Self::add_column_if_missing(conn, "dest_hash", "TEXT")?;
+        Self::add_column_if_missing(conn, "pending_reason", "TEXT")?;
+        Self::add_column_if_missing(conn, "attempts", "INTEGER NOT NULL DEFAULT 0")?;
+        Self::add_column_if_missing(conn, "nonce", "TEXT")?;
+        Self::add_column_if_missing(conn, "encrypted_size", "INTEGER")?;
+        Self::add_column_if_missing(conn, "hash_algo", "TEXT")?;
+        Self::add_column_if_missing(conn, "salt", "TEXT")?;
+        Self::add_column_if_missing(conn, "resume_offset", "INTEGER")?;
         // Index on status for efficient pending queries (count, sum, list)
         conn.execute(
             "CREATE INDEX IF NOT EXISTS idx_files_status ON files(status)",
             [],
         )?;
+        // Per-window hashes of file contents, groundwork for block-level delta transfers
+        conn.execute(
+            "CREATE TABLE IF NOT EXISTS block_hashes (
+                source_path TEXT NOT NULL,
+                block_index INTEGER NOT NULL,
+                block_size INTEGER NOT NULL,
+                hash TEXT NOT NULL,
+                PRIMARY KEY (source_path, block_index)
+            )",
+            [],
+        )?;
+        // Content hashes of source and destination files, valid while the file keeps
+        // the recorded mtime and size
+        conn.execute(
+            "CREATE TABLE IF NOT EXISTS hash_cache (
+                path TEXT NOT NULL,
+                algo TEXT NOT NULL,
+                mtime INTEGER NOT NULL,
+                size INTEGER NOT NULL,
+                hash TEXT NOT NULL,
+                PRIMARY KEY (path, algo)
+            )",
+            [],
+        )?;
+        // Extra destination files deleted by cleanup, per cleanup run
+        conn.execute(
+            "CREATE TABLE IF NOT EXISTS deletions (
+                run_token TEXT NOT NULL,
+                path TEXT NOT NULL,
+                deleted_at INTEGER NOT NULL,
+                PRIMARY KEY (run_token, path)
+            )",
+            [],
+        )?;
+        // Destinations already holding a file that is still pending for others, so
+        // its transfer can leave them alone. Any other destination of a pending file
+        // is pending; every destination of a synced file is synced.
+        conn.execute(
+            "CREATE TABLE IF NOT EXISTS synced_dests (
+                source_path TEXT NOT NULL,
+                dest_dir TEXT NOT NULL,
+                PRIMARY KEY (source_path, dest_dir)
+            )",
+            [],
+        )?;
+        // Source symlinks (relative path, target) the scan found to recreate as links
+        conn.execute(
+            "CREATE TABLE IF NOT EXISTS pending_links (
+                relative_path TEXT PRIMARY KEY,
+                target TEXT NOT NULL
+            )",
+            [],
+        )?;
+        // Order in which the last ordered run handed out its files
+        conn.execute(
+            "CREATE TABLE IF NOT EXISTS transfer_plan (
+                position INTEGER PRIMARY KEY,
+                source_path TEXT NOT NULL
+            )",
+            [],
+        )?;
+        // Bytes written per local day (YYYY-MM-DD), for --daily-budget
+        conn.execute(
+            "CREATE TABLE IF NOT EXISTS daily_transfer (
+                day TEXT PRIMARY KEY,
+                bytes INTEGER NOT NULL
+            )",
+            [],
+        )?;
+        // Key/value store for database-wide settings (e.g. compact path prefixes)
+        conn.execute(
+            "CREATE TABLE IF NOT EXISTS settings (
+                key TEXT PRIMARY KEY,
+                value TEXT
+            )",
+            [],
+        )?;
         Ok(())
     }
 
-    /// Insert or update a file record, preserving hash if file hasn't changed
+    /// Adds a column to the files table when opening a database from an older version
+    fn add_column_if_missing(conn: &Connection, name: &str, decl: &str) -> Result<()> {
+        let exists: bool = conn.query_row(
+            "SELECT COUNT(*) > 0 FROM pragma_table_info('files') WHERE name = ?1",
+            params![name],
+            |row| row.get(0),
+        )?;
+        if !exists {
+            conn.execute(
+                &format!("ALTER TABLE files ADD COLUMN {} {}", name, decl),
+                [],
+            )?;
+        }
+        Ok(())
+    }
+
+    /// Insert or update a file record, preserving its transfer state if the file hasn't
+    /// changed
     pub fn upsert_file(
         &self,
         source_path: &str,
@@ -96,22 +391,55 @@ impl Database {
         size: u64,
         status: FileStatus,
     ) -> Result<()> {
-        // Check if file exists with same mtime and size - if so, preserve hash
-        let existing_hash: Option<String> = self
-            .conn
-            .query_row(
-                "SELECT hash FROM files WHERE source_path = ?1 AND modified_date = ?2 AND size = ?3",
-                params![source_path, modified, size],
-                |row| row.get(0),
-            )
-            .ok()
-            .flatten();
+        if let Some((source_prefix, dest_prefix)) = &self.prefixes {
+            if !source_path.starts_with(source_prefix.as_str())
+                || !dest_path.starts_with(dest_prefix.as_str())
+            {
+                return Err(outside_prefixes(
+                    source_path,
+                    dest_path,
+                    source_prefix,
+                    dest_prefix,
+                ));
+            }
+        }
+        let source_path = self.compact_source(source_path);
+        let dest_path = self.compact_dest(dest_path);
 
+        // While the file keeps its mtime and size, its hashes (with the algorithm that
+        // produced them), failed attempts, pending reason and encryption state are
+        // preserved. The recorded destination mtime is always carried over.
         self.conn.execute(
-            "INSERT OR REPLACE INTO files (
+            "INSERT INTO files (
                 source_path, dest_path, created_date, changed_date, modified_date,
-                permissions, hash, size, status
-            ) VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9)",
+                permissions, size, status
+            ) VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8)
+            ON CONFLICT(source_path) DO UPDATE SET
+                dest_path = excluded.dest_path,
+                created_date = excluded.created_date,
+                changed_date = excluded.changed_date,
+                modified_date = excluded.modified_date,
+                permissions = excluded.permissions,
+                size = excluded.size,
+                status = excluded.status,
+                hash = CASE WHEN modified_date = excluded.modified_date
+                    AND size = excluded.size THEN hash END,
+                hash_algo = CASE WHEN modified_date = excluded.modified_date
+                    AND size = excluded.size THEN hash_algo END,
+                dest_hash = CASE WHEN modified_date = excluded.modified_date
+                    AND size = excluded.size THEN dest_hash END,
+                pending_reason = CASE WHEN modified_date = excluded.modified_date
+                    AND size = excluded.size THEN pending_reason END,
+                attempts = CASE WHEN modified_date = excluded.modified_date
+                    AND size = excluded.size THEN attempts ELSE 0 END,
+                salt = CASE WHEN modified_date = excluded.modified_date
+                    AND size = excluded.size THEN salt END,
+                nonce = CASE WHEN modified_date = excluded.modified_date
+                    AND size = excluded.size THEN nonce END,
+                encrypted_size = CASE WHEN modified_date = excluded.modified_date
+                    AND size = excluded.size THEN encrypted_size END,
+                resume_offset = CASE WHEN modified_date = excluded.modified_date
+                    AND size = excluded.size THEN resume_offset END",
             params![
                 source_path,
                 dest_path,
@@ -119,19 +447,216 @@ impl Database {
                 changed,
                 modified,
                 permissions,
-                existing_hash,
                 size,
                 status.as_str()
             ],
         )?;
+        self.forget_synced_dests(source_path)?;
+        Ok(())
+    }
+
+    /// Mark a file as synced and store its hash (if computed) with the name of the
+    /// algorithm that produced it, plus the independently computed destination hash
+    /// when available
+    pub fn mark_synced(&self, source_path: &str, hashes: SyncedHashes) -> Result<()> {
+        self.conn.execute(
+            "UPDATE files SET status = 'synced', hash = ?2, hash_algo = ?3, dest_hash = ?4,
+                pending_reason = NULL, attempts = 0
+             WHERE source_path = ?1",
+            params![
+                self.compact_source(source_path),
+                hashes.hash,
+                hashes.hash_algo,
+                hashes.dest_hash
+            ],
+        )?;
+        self.forget_synced_dests(self.compact_source(source_path))?;
+        Ok(())
+    }
+
+    /// Queue a synced file for transfer again, keeping its hashes
+    pub fn mark_pending(&self, source_path: &str, reason: PendingReason) -> Result<()> {
+        self.conn.execute(
+            "UPDATE files SET status = 'pending', pending_reason = ?2 WHERE source_path = ?1",
+            params![self.compact_source(source_path), reason.as_str()],
+        )?;
+        self.forget_synced_dests(self.compact_source(source_path))?;
+        Ok(())
+    }
+
+    /// Queue a file whose destination cannot be trusted, e.g. cut short by a crash or
+    /// failing verification, for a transfer from the start. An `--encrypt` transfer
+    /// keeps its progress, as it is only resumed once the destination still holds
+    /// its header and sealed blocks
+    pub fn requeue_interrupted(&self, source_path: &str) -> Result<()> {
+        self.conn.execute(
+            "UPDATE files SET status = 'pending', pending_reason = ?2,
+                resume_offset = CASE WHEN salt IS NULL THEN NULL ELSE resume_offset END
+             WHERE source_path = ?1",
+            params![
+                self.compact_source(source_path),
+                PendingReason::Changed.as_str()
+            ],
+        )?;
+        self.forget_synced_dests(self.compact_source(source_path))?;
+        Ok(())
+    }
+
+    /// Forget a file that no longer exists in the source
+    pub fn remove_file(&self, source_path: &str) -> Result<()> {
+        self.conn.execute(
+            "DELETE FROM files WHERE source_path = ?1",
+            params![self.compact_source(source_path)],
+        )?;
+        self.forget_synced_dests(self.compact_source(source_path))?;
         Ok(())
     }
 
-    /// Mark a file as synced and store its hash
-    pub fn mark_synced(&self, source_path: &str, hash: &str) -> Result<()> {
+    /// Record that `dest_dir` already holds a file still pending for other destinations
+    pub fn mark_dest_synced(&self, source_path: &str, dest_dir: &str) -> Result<()> {
         self.conn.execute(
-            "UPDATE files SET status = 'synced', hash = ?2 WHERE source_path = ?1",
-            params![source_path, hash],
+            "INSERT OR IGNORE INTO synced_dests (source_path, dest_dir) VALUES (?1, ?2)",
+            params![self.compact_source(source_path), dest_dir],
+        )?;
+        Ok(())
+    }
+
+    /// Whether `dest_dir` holds the current version of a file
+    pub fn dest_synced(&self, source_path: &str, dest_dir: &str) -> Result<bool> {
+        self.conn.query_row(
+            "SELECT EXISTS (SELECT 1 FROM files WHERE source_path = ?1 AND status = 'synced')
+                 OR EXISTS (SELECT 1 FROM synced_dests WHERE source_path = ?1 AND dest_dir = ?2)",
+            params![self.compact_source(source_path), dest_dir],
+            |row| row.get(0),
+        )
+    }
+
+    /// Number of files `dest_dir` still lacks
+    pub fn pending_count_for_dest(&self, dest_dir: &str) -> Result<u64> {
+        let count: i64 = self.conn.query_row(
+            "SELECT COUNT(*) FROM files
+             WHERE status = 'pending' AND NOT EXISTS (
+                 SELECT 1 FROM synced_dests
+                 WHERE synced_dests.source_path = files.source_path AND dest_dir = ?1
+             )",
+            params![dest_dir],
+            |row| row.get(0),
+        )?;
+        Ok(count as u64)
+    }
+
+    /// Drops the per-destination state of a file (stored path) whose status changed
+    fn forget_synced_dests(&self, stored_source_path: &str) -> Result<()> {
+        self.conn.execute(
+            "DELETE FROM synced_dests WHERE source_path = ?1",
+            params![stored_source_path],
+        )?;
+        Ok(())
+    }
+
+    /// Record why a pending file was queued
+    pub fn set_pending_reason(&self, source_path: &str, reason: PendingReason) -> Result<()> {
+        self.conn.execute(
+            "UPDATE files SET pending_reason = ?2 WHERE source_path = ?1",
+            params![self.compact_source(source_path), reason.as_str()],
+        )?;
+        Ok(())
+    }
+
+    /// Queue every synced file for transfer again, keeping its hashes.
+    /// Returns the number of files reset.
+    pub fn reset_all_to_pending(&self) -> Result<u64> {
+        let count = self.conn.execute(
+            "UPDATE files SET status = 'pending' WHERE status = 'synced'",
+            [],
+        )?;
+        self.conn.execute("DELETE FROM synced_dests", [])?;
+        Ok(count as u64)
+    }
+
+    /// Count a failed transfer attempt against a file still pending after it
+    pub fn record_failed_attempt(&self, source_path: &str) -> Result<()> {
+        self.conn.execute(
+            "UPDATE files SET attempts = attempts + 1
+             WHERE source_path = ?1 AND status = 'pending'",
+            params![self.compact_source(source_path)],
+        )?;
+        Ok(())
+    }
+
+    /// Record the key salt, nonce prefix and size at rest of a file written with
+    /// `--encrypt`, once it is complete
+    pub fn set_encryption(
+        &self,
+        source_path: &str,
+        salt: &str,
+        nonce: &str,
+        encrypted_size: u64,
+    ) -> Result<()> {
+        self.conn.execute(
+            "UPDATE files SET salt = ?2, nonce = ?3, encrypted_size = ?4, resume_offset = NULL
+             WHERE source_path = ?1",
+            params![
+                self.compact_source(source_path),
+                salt,
+                nonce,
+                encrypted_size
+            ],
+        )?;
+        Ok(())
+    }
+
+    /// Record that the plaintext up to `offset` of a file being written with
+    /// `--encrypt` is at rest, sealed under `salt` and `nonce`
+    pub fn set_encryption_progress(
+        &self,
+        source_path: &str,
+        salt: &str,
+        nonce: &str,
+        offset: u64,
+    ) -> Result<()> {
+        self.conn.execute(
+            "UPDATE files SET salt = ?2, nonce = ?3, resume_offset = ?4 WHERE source_path = ?1",
+            params![self.compact_source(source_path), salt, nonce, offset],
+        )?;
+        Ok(())
+    }
+
+    /// Key salt, nonce prefix and committed plaintext offset of an interrupted
+    /// encrypted transfer
+    pub fn get_encryption_progress(
+        &self,
+        source_path: &str,
+    ) -> Result<Option<(String, String, u64)>> {
+        self.conn
+            .query_row(
+                "SELECT salt, nonce, resume_offset FROM files
+                 WHERE source_path = ?1 AND resume_offset IS NOT NULL",
+                params![self.compact_source(source_path)],
+                |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?)),
+            )
+            .optional()
+    }
+
+    /// Nonce prefix and size at rest recorded for an encrypted file
+    pub fn get_encryption(&self, source_path: &str) -> Result<Option<(String, u64)>> {
+        let mut stmt = self.conn.prepare(
+            "SELECT nonce, encrypted_size FROM files WHERE source_path = ?1 AND nonce IS NOT NULL",
+        )?;
+        let mut rows = stmt.query(params![self.compact_source(source_path)])?;
+
+        if let Some(row) = rows.next()? {
+            Ok(Some((row.get(0)?, row.get(1)?)))
+        } else {
+            Ok(None)
+        }
+    }
+
+    /// Record the destination mtime observed after hsync last wrote or verified the file
+    pub fn set_dest_mtime(&self, source_path: &str, dest_mtime: i64) -> Result<()> {
+        self.conn.execute(
+            "UPDATE files SET dest_mtime = ?2 WHERE source_path = ?1",
+            params![self.compact_source(source_path), dest_mtime],
         )?;
         Ok(())
     }
@@ -156,25 +681,112 @@ impl Database {
         Ok(total as u64)
     }
 
+    /// Get file counts grouped by status in a single query
+    pub fn count_by_status(&self) -> Result<HashMap<FileStatus, u64>> {
+        let mut stmt = self
+            .conn
+            .prepare("SELECT status, COUNT(*) FROM files GROUP BY status")?;
+        let rows = stmt.query_map([], |row| {
+            Ok((
+                FileStatus::from_str(&row.get::<_, String>(0)?),
+                row.get::<_, i64>(1)? as u64,
+            ))
+        })?;
+
+        let mut counts = HashMap::new();
+        for row in rows {
+            let (status, count) = row?;
+            *counts.entry(status).or_insert(0) += count;
+        }
+        Ok(counts)
+    }
+
     /// Get all pending files (the backlog)
     pub fn get_pending_files(&self) -> Result<Vec<FileRecord>> {
-        let mut stmt = self.conn.prepare(
+        self.query_files("WHERE status = 'pending'")
+    }
+
+    /// Get all pending files sorted by source path, so that the same database state
+    /// always yields the same sequence
+    pub fn get_pending_files_ordered(&self) -> Result<Vec<FileRecord>> {
+        self.query_files("WHERE status = 'pending' ORDER BY source_path")
+    }
+
+    /// Get all pending files clustered by parent directory, directories and the files
+    /// within each in path order, so that a transfer works through one directory at
+    /// a time
+    pub fn get_pending_files_by_dir(&self) -> Result<Vec<FileRecord>> {
+        // Trimming every trailing character that is not a separator leaves the
+        // directory prefix
+        self.query_files(
+            "WHERE status = 'pending' \
+             ORDER BY rtrim(source_path, replace(source_path, '/', '')), source_path",
+        )
+    }
+
+    /// Forget the recorded transfer plan, before a new ordered run records its own
+    pub fn clear_transfer_plan(&self) -> Result<()> {
+        self.conn.execute("DELETE FROM transfer_plan", [])?;
+        Ok(())
+    }
+
+    /// Append `source_paths` to the transfer plan in the given order
+    pub fn append_transfer_plan<'a>(
+        &self,
+        source_paths: impl IntoIterator<Item = &'a str>,
+    ) -> Result<()> {
+        let mut stmt = self
+            .conn
+            .prepare("INSERT INTO transfer_plan (source_path) VALUES (?1)")?;
+        for path in source_paths {
+            stmt.execute(params![path])?;
+        }
+        Ok(())
+    }
+
+    /// Source paths of the recorded transfer plan, in transfer order
+    pub fn get_transfer_plan(&self) -> Result<Vec<String>> {
+        let mut stmt = self
+            .conn
+            .prepare("SELECT source_path FROM transfer_plan ORDER BY position")?;
+        let rows = stmt.query_map([], |row| row.get(0))?;
+        rows.collect()
+    }
+
+    /// Get all synced files
+    pub fn get_synced_files(&self) -> Result<Vec<FileRecord>> {
+        self.query_files("WHERE status = 'synced'")
+    }
+
+    /// Loads file records matching the given WHERE clause
+    fn query_files(&self, filter: &str) -> Result<Vec<FileRecord>> {
+        let mut stmt = self.conn.prepare(&format!(
             "SELECT source_path, dest_path, modified_date, size, status,
-                    changed_date, created_date, permissions, hash
-             FROM files WHERE status = 'pending'",
-        )?;
+                    changed_date, created_date, permissions, hash, dest_mtime, dest_hash,
+                    pending_reason, attempts, hash_algo
+             FROM files {}",
+            filter
+        ))?;
 
         let rows = stmt.query_map([], |row| {
+            let (source_path, dest_path) = self.expand_paths(row.get(0)?, row.get(1)?);
             Ok(FileRecord {
-                source_path: row.get(0)?,
-                dest_path: row.get(1)?,
+                source_path,
+                dest_path,
                 modified_date: row.get(2)?,
                 size: row.get::<_, i64>(3)? as u64,
                 status: FileStatus::from_str(&row.get::<_, String>(4)?),
                 ctime: row.get(5)?,
-                atime: row.get(6)?,
+                created: row.get(6)?,
                 permissions: row.get(7)?,
                 hash: row.get(8)?,
+                dest_mtime: row.get(9)?,
+                dest_hash: row.get(10)?,
+                pending_reason: row
+                    .get::<_, Option<String>>(11)?
+                    .map(|reason| PendingReason::from_str(&reason)),
+                attempts: row.get(12)?,
+                hash_algo: row.get(13)?,
             })
         })?;
 
@@ -196,20 +808,271 @@ impl Database {
     /// Remove files from DB that are no longer in source (for cleanup phase)
     pub fn get_all_dest_paths(&self) -> Result<Vec<String>> {
         let mut stmt = self.conn.prepare("SELECT dest_path FROM files")?;
-        let rows = stmt.query_map([], |row| row.get(0))?;
+        let rows = stmt.query_map([], |row| row.get::<_, String>(0))?;
 
         let mut paths = Vec::new();
         for row in rows {
-            paths.push(row?);
+            paths.push(self.expand_paths(String::new(), row?).1);
         }
         Ok(paths)
     }
 
+    /// Replace the stored block hash list of a file
+    pub fn replace_block_hashes(
+        &self,
+        source_path: &str,
+        block_size: u64,
+        hashes: &[String],
+    ) -> Result<()> {
+        let source_path = self.compact_source(source_path);
+        self.conn.execute(
+            "DELETE FROM block_hashes WHERE source_path = ?1",
+            params![source_path],
+        )?;
+        let mut stmt = self.conn.prepare(
+            "INSERT INTO block_hashes (source_path, block_index, block_size, hash)
+             VALUES (?1, ?2, ?3, ?4)",
+        )?;
+        for (index, hash) in hashes.iter().enumerate() {
+            stmt.execute(params![source_path, index as i64, block_size as i64, hash])?;
+        }
+        Ok(())
+    }
+
+    /// Stored block hashes of a file in block order, with their block size
+    pub fn get_block_hashes(&self, source_path: &str) -> Result<Vec<(u64, String)>> {
+        let mut stmt = self.conn.prepare(
+            "SELECT block_size, hash FROM block_hashes WHERE source_path = ?1
+             ORDER BY block_index",
+        )?;
+        let rows = stmt.query_map(params![self.compact_source(source_path)], |row| {
+            Ok((row.get::<_, i64>(0)? as u64, row.get(1)?))
+        })?;
+        rows.collect()
+    }
+
+    /// Cached `algo` hash of the file at `path`, if it was computed while the file
+    /// had this mtime and size
+    pub fn cached_hash(
+        &self,
+        path: &str,
+        mtime: i64,
+        size: u64,
+        algo: &str,
+    ) -> Result<Option<String>> {
+        self.conn
+            .query_row(
+                "SELECT hash FROM hash_cache
+                 WHERE path = ?1 AND algo = ?2 AND mtime = ?3 AND size = ?4",
+                params![path, algo, mtime, size],
+                |row| row.get(0),
+            )
+            .optional()
+    }
+
+    /// Cache the `algo` hash of the file at `path` for its current mtime and size,
+    /// replacing any hash cached for an earlier version
+    pub fn cache_hash(
+        &self,
+        path: &str,
+        mtime: i64,
+        size: u64,
+        algo: &str,
+        hash: &str,
+    ) -> Result<()> {
+        self.conn.execute(
+            "INSERT OR REPLACE INTO hash_cache (path, algo, mtime, size, hash)
+             VALUES (?1, ?2, ?3, ?4, ?5)",
+            params![path, algo, mtime, size, hash],
+        )?;
+        Ok(())
+    }
+
+    /// Token of the cleanup run in progress: the one left unfinished by an interrupted
+    /// run, so it resumes, or a new one
+    pub fn begin_cleanup(&self) -> Result<String> {
+        let existing: Option<String> = self
+            .conn
+            .query_row(
+                "SELECT value FROM settings WHERE key = 'cleanup_token'",
+                [],
+                |row| row.get(0),
+            )
+            .optional()?;
+        if let Some(token) = existing {
+            return Ok(token);
+        }
+        let token = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_nanos()
+            .to_string();
+        self.conn.execute(
+            "INSERT INTO settings (key, value) VALUES ('cleanup_token', ?1)",
+            params![token],
+        )?;
+        Ok(token)
+    }
+
+    /// Mark the cleanup run in progress as complete; its deletions stay recorded
+    pub fn finish_cleanup(&self) -> Result<()> {
+        self.conn
+            .execute("DELETE FROM settings WHERE key = 'cleanup_token'", [])?;
+        Ok(())
+    }
+
+    /// Whether the last run on this database shut down cleanly; true for a new database
+    pub fn clean_shutdown(&self) -> Result<bool> {
+        let clean: Option<String> = self
+            .conn
+            .query_row(
+                "SELECT value FROM settings WHERE key = 'clean_shutdown'",
+                [],
+                |row| row.get(0),
+            )
+            .optional()?;
+        Ok(clean.as_deref() != Some("0"))
+    }
+
+    /// Record whether the run in progress may be considered shut down cleanly
+    pub fn set_clean_shutdown(&self, clean: bool) -> Result<()> {
+        self.conn.execute(
+            "INSERT OR REPLACE INTO settings (key, value) VALUES ('clean_shutdown', ?1)",
+            params![if clean { "1" } else { "0" }],
+        )?;
+        Ok(())
+    }
+
+    /// Source path of the file being written, if any
+    pub fn current_file(&self) -> Result<Option<String>> {
+        self.conn
+            .query_row(
+                "SELECT value FROM settings WHERE key = 'current_file'",
+                [],
+                |row| row.get(0),
+            )
+            .optional()
+    }
+
+    /// Record the file being written, or that none is
+    pub fn set_current_file(&self, source_path: Option<&str>) -> Result<()> {
+        match source_path {
+            Some(path) => self.conn.execute(
+                "INSERT OR REPLACE INTO settings (key, value) VALUES ('current_file', ?1)",
+                params![path],
+            )?,
+            None => self
+                .conn
+                .execute("DELETE FROM settings WHERE key = 'current_file'", [])?,
+        };
+        Ok(())
+    }
+
+    /// Ask the sync running on this database, possibly in another process, to stop
+    /// after its current file
+    pub fn request_cancel(&self) -> Result<()> {
+        self.conn.execute(
+            "INSERT OR REPLACE INTO settings (key, value) VALUES ('cancel_requested', '1')",
+            [],
+        )?;
+        Ok(())
+    }
+
+    /// Whether a cancellation was requested and not yet taken
+    pub fn cancel_requested(&self) -> Result<bool> {
+        let requested: Option<String> = self
+            .conn
+            .query_row(
+                "SELECT value FROM settings WHERE key = 'cancel_requested'",
+                [],
+                |row| row.get(0),
+            )
+            .optional()?;
+        Ok(requested.is_some())
+    }
+
+    /// Clear the cancellation request, returning whether there was one
+    pub fn take_cancel_request(&self) -> Result<bool> {
+        let removed = self
+            .conn
+            .execute("DELETE FROM settings WHERE key = 'cancel_requested'", [])?;
+        Ok(removed > 0)
+    }
+
+    /// Add `bytes` to those written on `day`
+    pub fn add_transferred_bytes(&self, day: &str, bytes: u64) -> Result<()> {
+        self.conn.execute(
+            "INSERT INTO daily_transfer (day, bytes) VALUES (?1, ?2)
+             ON CONFLICT(day) DO UPDATE SET bytes = bytes + excluded.bytes",
+            params![day, bytes as i64],
+        )?;
+        Ok(())
+    }
+
+    /// Bytes written on `day`, across all runs
+    pub fn transferred_bytes(&self, day: &str) -> Result<u64> {
+        let bytes: Option<i64> = self
+            .conn
+            .query_row(
+                "SELECT bytes FROM daily_transfer WHERE day = ?1",
+                params![day],
+                |row| row.get(0),
+            )
+            .optional()?;
+        Ok(bytes.unwrap_or(0) as u64)
+    }
+
+    /// Record that cleanup run `run_token` deleted the destination file at `path`
+    pub fn record_deletion(&self, run_token: &str, path: &str) -> Result<()> {
+        self.conn.execute(
+            "INSERT OR REPLACE INTO deletions (run_token, path, deleted_at)
+             VALUES (?1, ?2, CAST(strftime('%s', 'now') AS INTEGER))",
+            params![run_token, path],
+        )?;
+        Ok(())
+    }
+
+    /// Paths deleted by cleanup run `run_token`, with their deletion times
+    pub fn get_deletions(&self, run_token: &str) -> Result<Vec<(String, i64)>> {
+        let mut stmt = self
+            .conn
+            .prepare("SELECT path, deleted_at FROM deletions WHERE run_token = ?1 ORDER BY path")?;
+        let rows = stmt.query_map(params![run_token], |row| Ok((row.get(0)?, row.get(1)?)))?;
+        rows.collect()
+    }
+
+    /// Queue a source symlink to be recreated as a link in the destinations
+    pub fn queue_link(&self, relative_path: &str, target: &str) -> Result<()> {
+        self.conn.execute(
+            "INSERT OR REPLACE INTO pending_links (relative_path, target) VALUES (?1, ?2)",
+            params![relative_path, target],
+        )?;
+        Ok(())
+    }
+
+    /// Symlinks (relative path, target) still to be recreated, by relative path
+    pub fn get_pending_links(&self) -> Result<Vec<(String, String)>> {
+        let mut stmt = self
+            .conn
+            .prepare("SELECT relative_path, target FROM pending_links ORDER BY relative_path")?;
+        let rows = stmt.query_map([], |row| Ok((row.get(0)?, row.get(1)?)))?;
+        rows.collect()
+    }
+
+    /// Forget a queued symlink once every destination holds it
+    pub fn remove_link(&self, relative_path: &str) -> Result<()> {
+        self.conn.execute(
+            "DELETE FROM pending_links WHERE relative_path = ?1",
+            params![relative_path],
+        )?;
+        Ok(())
+    }
+
     pub fn get_file_hash(&self, source_path: &str) -> Result<Option<String>> {
         let mut stmt = self
             .conn
             .prepare("SELECT hash FROM files WHERE source_path = ?1")?;
-        let mut rows = stmt.query(params![source_path])?;
+        let mut rows = stmt.query(params![self.compact_source(source_path)])?;
 
         if let Some(row) = rows.next()? {
             Ok(row.get(0)?)
@@ -217,6 +1080,19 @@ impl Database {
             Ok(None)
         }
     }
+
+    /// Stored hash of a file with the name of the algorithm that produced it. None
+    /// also for hashes stored before algorithms were recorded.
+    pub fn get_file_hash_with_algo(&self, source_path: &str) -> Result<Option<(String, String)>> {
+        self.conn
+            .query_row(
+                "SELECT hash, hash_algo FROM files
+                 WHERE source_path = ?1 AND hash IS NOT NULL AND hash_algo IS NOT NULL",
+                params![self.compact_source(source_path)],
+                |row| Ok((row.get(0)?, row.get(1)?)),
+            )
+            .optional()
+    }
 }
 
 #[cfg(test)]
@@ -241,7 +1117,13 @@ mod tests {
         assert_eq!(db.pending_count()?, 1);
 
         // Mark as synced
-        db.mark_synced("/src/file1", "abc123hash")?;
+        db.mark_synced(
+            "/src/file1",
+            SyncedHashes {
+                hash: Some("abc123hash"),
+                ..Default::default()
+            },
+        )?;
 
         assert_eq!(db.pending_count()?, 0);
 
@@ -266,7 +1148,13 @@ mod tests {
             1024,
             FileStatus::Pending,
         )?;
-        db.mark_synced("/src/file1", "originalhash")?;
+        db.mark_synced(
+            "/src/file1",
+            SyncedHashes {
+                hash: Some("originalhash"),
+                ..Default::default()
+            },
+        )?;
 
         // Re-upsert with same mtime and size - hash should be preserved
         db.upsert_file(
@@ -309,7 +1197,13 @@ mod tests {
             1024,
             FileStatus::Pending,
         )?;
-        db.mark_synced("/src/file1", "newhash")?;
+        db.mark_synced(
+            "/src/file1",
+            SyncedHashes {
+                hash: Some("newhash"),
+                ..Default::default()
+            },
+        )?;
 
         // Re-upsert with different size - hash should be cleared
         db.upsert_file(
@@ -370,6 +1264,308 @@ mod tests {
         Ok(())
     }
 
+    #[test]
+    fn test_count_by_status() -> Result<()> {
+        let db = Database::new(":memory:")?;
+
+        // Empty database has no groups
+        assert!(db.count_by_status()?.is_empty());
+
+        for (i, status) in [
+            FileStatus::Pending,
+            FileStatus::Synced,
+            FileStatus::Pending,
+            FileStatus::Synced,
+            FileStatus::Synced,
+        ]
+        .iter()
+        .enumerate()
+        {
+            db.upsert_file(
+                &format!("/src/file{}", i),
+                &format!("/dest/file{}", i),
+                100,
+                200,
+                300,
+                0o644,
+                1024,
+                *status,
+            )?;
+        }
+
+        let counts = db.count_by_status()?;
+        assert_eq!(counts.get(&FileStatus::Pending), Some(&2));
+        assert_eq!(counts.get(&FileStatus::Synced), Some(&3));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_compact_paths() -> Result<()> {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("compact.db");
+        let source_prefix = "/mnt/source/a/very/long/shared/prefix/";
+        let dest_prefix = "/mnt/dest/another/long/shared/prefix/";
+
+        let mut db = Database::new(&path)?;
+        // A row written before compaction is migrated in place
+        db.upsert_file(
+            &format!("{}file0", source_prefix),
+            &format!("{}file0", dest_prefix),
+            100,
+            200,
+            300,
+            0o644,
+            1024,
+            FileStatus::Pending,
+        )?;
+        db.enable_compact_paths(source_prefix, dest_prefix)?;
+        for i in 1..100 {
+            db.upsert_file(
+                &format!("{}dir/file{}", source_prefix, i),
+                &format!("{}dir/file{}", dest_prefix, i),
+                100,
+                200,
+                300,
+                0o644,
+                1024,
+                FileStatus::Pending,
+            )?;
+        }
+        db.mark_synced(
+            &format!("{}dir/file1", source_prefix),
+            SyncedHashes {
+                hash: Some("hash1"),
+                ..Default::default()
+            },
+        )?;
+        db.vacuum()?;
+        drop(db);
+
+        // Only suffixes are stored
+        let conn = Connection::open(&path)?;
+        let stored: String = conn.query_row(
+            "SELECT source_path FROM files WHERE hash = 'hash1'",
+            [],
+            |row| row.get(0),
+        )?;
+        assert_eq!(stored, "dir/file1");
+        drop(conn);
+
+        // Reopening restores the prefixes and full paths
+        let db = Database::new(&path)?;
+        let pending = db.get_pending_files()?;
+        assert_eq!(pending.len(), 99);
+        assert!(pending
+            .iter()
+            .any(|f| f.source_path == format!("{}file0", source_prefix)
+                && f.dest_path == format!("{}file0", dest_prefix)));
+        assert!(pending
+            .iter()
+            .all(|f| f.source_path.starts_with(source_prefix)
+                && f.dest_path.starts_with(dest_prefix)));
+        assert_eq!(
+            db.get_file_hash(&format!("{}dir/file1", source_prefix))?,
+            Some("hash1".to_string())
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_compact_paths_refuse_rows_outside_prefix() -> Result<()> {
+        let mut db = Database::new(":memory:")?;
+        db.upsert_file(
+            "/mnt/source/a/file",
+            "/mnt/dest/a/file",
+            0,
+            0,
+            0,
+            0o644,
+            10,
+            FileStatus::Pending,
+        )?;
+        db.upsert_file(
+            "/mnt/other/file",
+            "/mnt/dest/other/file",
+            0,
+            0,
+            0,
+            0o644,
+            10,
+            FileStatus::Pending,
+        )?;
+
+        // The row of another source keeps the database uncompacted
+        assert!(db
+            .enable_compact_paths("/mnt/source/", "/mnt/dest/")
+            .is_err());
+        let mut paths: Vec<String> = db
+            .get_pending_files()?
+            .into_iter()
+            .map(|f| f.source_path)
+            .collect();
+        paths.sort();
+        assert_eq!(paths, ["/mnt/other/file", "/mnt/source/a/file"]);
+
+        // Switching prefixes on a compacted database checks the same way, and
+        // no row outside the prefixes gets in afterwards
+        db.remove_file("/mnt/other/file")?;
+        db.enable_compact_paths("/mnt/source/", "/mnt/dest/")?;
+        assert!(db
+            .enable_compact_paths("/mnt/source/a/", "/mnt/dest/")
+            .is_ok());
+        assert!(db
+            .enable_compact_paths("/mnt/source/b/", "/mnt/dest/")
+            .is_err());
+        assert!(db
+            .upsert_file(
+                "/mnt/other/file",
+                "/mnt/dest/other/file",
+                0,
+                0,
+                0,
+                0o644,
+                10,
+                FileStatus::Pending,
+            )
+            .is_err());
+        let pending = db.get_pending_files()?;
+        assert_eq!(pending.len(), 1);
+        assert_eq!(pending[0].source_path, "/mnt/source/a/file");
+        assert_eq!(pending[0].dest_path, "/mnt/dest/a/file");
+        Ok(())
+    }
+
+    #[test]
+    fn test_reset_all_to_pending() -> Result<()> {
+        let db = Database::new(":memory:")?;
+        for i in 0..3 {
+            let path = format!("/src/file{}", i);
+            db.upsert_file(&path, "/dst/file", 0, 0, 0, 0o644, 10, FileStatus::Pending)?;
+            db.mark_synced(
+                &path,
+                SyncedHashes {
+                    hash: Some(&format!("hash{}", i)),
+                    dest_hash: Some("dest"),
+                    ..Default::default()
+                },
+            )?;
+        }
+        assert_eq!(db.pending_count()?, 0);
+
+        assert_eq!(db.reset_all_to_pending()?, 3);
+
+        let pending = db.get_pending_files()?;
+        assert_eq!(pending.len(), 3);
+        for file in pending {
+            let i = file.source_path.strip_prefix("/src/file").unwrap();
+            assert_eq!(file.hash, Some(format!("hash{}", i)));
+            assert_eq!(file.dest_hash.as_deref(), Some("dest"));
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_pending_attempts() -> Result<()> {
+        let db = Database::new(":memory:")?;
+        for path in ["/src/stubborn", "/src/done"] {
+            db.upsert_file(path, "/dst/file", 0, 0, 0, 0o644, 10, FileStatus::Pending)?;
+        }
+
+        // Both fail the first attempt; only one is tried and fails again
+        db.record_failed_attempt("/src/stubborn")?;
+        db.record_failed_attempt("/src/done")?;
+        db.mark_synced("/src/done", SyncedHashes::default())?;
+        db.record_failed_attempt("/src/stubborn")?;
+        db.record_failed_attempt("/src/done")?;
+
+        let pending = db.get_pending_files()?;
+        assert_eq!(pending.len(), 1);
+        assert_eq!(pending[0].attempts, 2);
+        assert_eq!(db.get_synced_files()?[0].attempts, 0);
+        Ok(())
+    }
+
+    #[test]
+    fn test_upsert_keeps_transfer_state_while_unchanged() -> Result<()> {
+        let db = Database::new(":memory:")?;
+        let upsert = |mtime| {
+            db.upsert_file(
+                "/src/f",
+                "/dst/f",
+                0,
+                0,
+                mtime,
+                0o644,
+                10,
+                FileStatus::Pending,
+            )
+        };
+        upsert(100)?;
+        db.record_failed_attempt("/src/f")?;
+        db.set_pending_reason("/src/f", PendingReason::Changed)?;
+        db.set_encryption_progress("/src/f", "salt", "nonce", 4096)?;
+
+        // A rescan of the unchanged file keeps its attempts and resume data
+        upsert(100)?;
+        let file = &db.get_pending_files()?[0];
+        assert_eq!(file.attempts, 1);
+        assert_eq!(file.pending_reason, Some(PendingReason::Changed));
+        let progress = Some(("salt".to_string(), "nonce".to_string(), 4096));
+        assert_eq!(db.get_encryption_progress("/src/f")?, progress);
+
+        // A changed file starts over
+        upsert(200)?;
+        let file = &db.get_pending_files()?[0];
+        assert_eq!(file.attempts, 0);
+        assert_eq!(file.pending_reason, None);
+        assert_eq!(db.get_encryption_progress("/src/f")?, None);
+        Ok(())
+    }
+
+    #[test]
+    fn test_dest_mtime_preserved_on_upsert() -> Result<()> {
+        let db = Database::new(":memory:")?;
+
+        db.upsert_file(
+            "/src/f",
+            "/dest/f",
+            100,
+            200,
+            300,
+            0o644,
+            1024,
+            FileStatus::Pending,
+        )?;
+        db.mark_synced(
+            "/src/f",
+            SyncedHashes {
+                hash: Some("hash"),
+                ..Default::default()
+            },
+        )?;
+        db.set_dest_mtime("/src/f", 300)?;
+
+        // Rescan keeps the recorded destination mtime
+        db.upsert_file(
+            "/src/f",
+            "/dest/f",
+            100,
+            200,
+            300,
+            0o644,
+            1024,
+            FileStatus::Synced,
+        )?;
+        let synced = db.get_synced_files()?;
+        assert_eq!(synced.len(), 1);
+        assert_eq!(synced[0].dest_mtime, Some(300));
+        assert_eq!(synced[0].hash.as_deref(), Some("hash"));
+
+        Ok(())
+    }
+
     #[test]
     fn test_pending_total_bytes() -> Result<()> {
         let db = Database::new(":memory:")?;
@@ -413,4 +1609,124 @@ mod tests {
 
         Ok(())
     }
+
+    #[test]
+    fn test_hash_cache_across_sessions() -> Result<()> {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("cache.db");
+        {
+            let db = Database::new(&path)?;
+            db.cache_hash("/src/a", 100, 5, "sha256", "cachedhash")?;
+        }
+
+        // A later session reuses the hash while mtime and size are unchanged
+        let db = Database::new(&path)?;
+        assert_eq!(
+            db.cached_hash("/src/a", 100, 5, "sha256")?,
+            Some("cachedhash".to_string())
+        );
+        assert_eq!(db.cached_hash("/src/a", 101, 5, "sha256")?, None);
+        assert_eq!(db.cached_hash("/src/a", 100, 6, "sha256")?, None);
+        assert_eq!(db.cached_hash("/src/a", 100, 5, "md5")?, None);
+
+        // A new version replaces the cached hash
+        db.cache_hash("/src/a", 200, 7, "sha256", "newhash")?;
+        assert_eq!(db.cached_hash("/src/a", 100, 5, "sha256")?, None);
+        assert_eq!(
+            db.cached_hash("/src/a", 200, 7, "sha256")?,
+            Some("newhash".to_string())
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_hash_algo_after_upgrade_from_old_schema() -> Result<()> {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("old.db");
+        {
+            // Schema of a version that recorded no algorithm with the hash
+            let conn = Connection::open(&path)?;
+            conn.execute(
+                "CREATE TABLE files (
+                    source_path TEXT PRIMARY KEY,
+                    dest_path TEXT NOT NULL,
+                    created_date INTEGER,
+                    changed_date INTEGER,
+                    modified_date INTEGER,
+                    permissions INTEGER,
+                    hash TEXT,
+                    size INTEGER,
+                    status TEXT NOT NULL DEFAULT 'pending'
+                )",
+                [],
+            )?;
+            conn.execute(
+                "INSERT INTO files VALUES ('/src/old', '/dest/old', 0, 300, 300, 420, 'oldhash',
+                    1024, 'synced')",
+                [],
+            )?;
+        }
+
+        let db = Database::new(&path)?;
+        assert_eq!(db.get_file_hash("/src/old")?, Some("oldhash".to_string()));
+        assert_eq!(db.get_file_hash_with_algo("/src/old")?, None);
+
+        db.mark_synced(
+            "/src/old",
+            SyncedHashes {
+                hash: Some("newhash"),
+                hash_algo: Some("sha256"),
+                ..Default::default()
+            },
+        )?;
+        let labeled = Some(("newhash".to_string(), "sha256".to_string()));
+        assert_eq!(db.get_file_hash_with_algo("/src/old")?, labeled);
+
+        // A rescan of the unchanged file keeps the algorithm with the hash
+        db.upsert_file(
+            "/src/old",
+            "/dest/old",
+            0,
+            300,
+            300,
+            0o644,
+            1024,
+            FileStatus::Synced,
+        )?;
+        assert_eq!(db.get_file_hash_with_algo("/src/old")?, labeled);
+        db.upsert_file(
+            "/src/old",
+            "/dest/old",
+            0,
+            400,
+            400,
+            0o644,
+            1024,
+            FileStatus::Pending,
+        )?;
+        assert_eq!(db.get_file_hash_with_algo("/src/old")?, None);
+        Ok(())
+    }
+
+    #[test]
+    fn test_busy_timeout_waits_for_lock() -> Result<()> {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("busy.db");
+        let first = Database::new(&path)?;
+        let second = Database::with_busy_timeout(&path, Duration::from_secs(10))?;
+
+        // Hold a write lock on the first connection for a moment
+        first.begin_transaction()?;
+        first.upsert_file("/src/a", "/dest/a", 0, 0, 0, 0o644, 1, FileStatus::Pending)?;
+        let holder = std::thread::spawn(move || {
+            std::thread::sleep(Duration::from_millis(300));
+            first.commit_transaction()
+        });
+
+        // The second writer waits for the lock instead of failing with SQLITE_BUSY
+        second.upsert_file("/src/b", "/dest/b", 0, 0, 0, 0o644, 1, FileStatus::Pending)?;
+        holder.join().unwrap()?;
+        assert_eq!(second.pending_count()?, 2);
+        Ok(())
+    }
 }
@@ -0,0 +1,287 @@
+//! Path filtering rules for the source scan.
+//!
+//! Supports gitignore-like `.hsyncignore` files discovered per directory, and
+//! `--exclude`/`--include` globs given on the command line.
+
+use std::fs;
+use std::path::{Path, PathBuf};
+
+/// Name of the per-directory ignore files honored during the source scan
+pub const IGNORE_FILE_NAME: &str = ".hsyncignore";
+
+/// Whether `dir` holds one of the `--exclude-if-present` marker files, which
+/// exclude the directory and everything below it
+pub fn has_exclude_marker(dir: &Path, markers: &[String]) -> bool {
+    markers.iter().any(|marker| dir.join(marker).exists())
+}
+
+/// Matches `text` against a glob pattern.
+/// `*` and `?` do not cross `/`; `**` matches across directories.
+pub fn glob_match(pattern: &str, text: &str) -> bool {
+    fn matches(p: &[char], t: &[char]) -> bool {
+        match p.first() {
+            None => t.is_empty(),
+            Some('*') if p.get(1) == Some(&'*') => {
+                // "**/" may also match zero directories
+                (p.get(2) == Some(&'/') && matches(&p[3..], t))
+                    || (0..=t.len()).any(|i| matches(&p[2..], &t[i..]))
+            }
+            Some('*') => {
+                for i in 0..=t.len() {
+                    if matches(&p[1..], &t[i..]) {
+                        return true;
+                    }
+                    if t.get(i) == Some(&'/') {
+                        break;
+                    }
+                }
+                false
+            }
+            Some('?') => t.first().is_some_and(|&c| c != '/') && matches(&p[1..], &t[1..]),
+            Some(c) => t.first() == Some(c) && matches(&p[1..], &t[1..]),
+        }
+    }
+    let p: Vec<char> = pattern.chars().collect();
+    let t: Vec<char> = text.chars().collect();
+    matches(&p, &t)
+}
+
+/// `--exclude` and `--include` globs, matched against paths relative to the source.
+/// A glob containing `/` matches the relative path, others match a name at any
+/// depth. Excluding a directory excludes everything below it; an `--include`
+/// glob matching the path or one of its directories keeps it all the same.
+#[derive(Debug, Clone, Default)]
+pub struct PathFilter {
+    exclude: Vec<String>,
+    include: Vec<String>,
+}
+
+impl PathFilter {
+    pub fn new(exclude: &[String], include: &[String]) -> Self {
+        let normalize = |globs: &[String]| -> Vec<String> {
+            globs
+                .iter()
+                .map(|glob| {
+                    glob.trim_start_matches('/')
+                        .trim_end_matches('/')
+                        .to_string()
+                })
+                .filter(|glob| !glob.is_empty())
+                .collect()
+        };
+        Self {
+            exclude: normalize(exclude),
+            include: normalize(include),
+        }
+    }
+
+    /// Whether the file or directory at `relative_path` is left out of the sync
+    pub fn is_excluded(&self, relative_path: &Path) -> bool {
+        if self.exclude.is_empty() {
+            return false;
+        }
+        let relative = relative_path.to_string_lossy().replace('\\', "/");
+        let matches = |globs: &[String]| {
+            globs
+                .iter()
+                .any(|glob| matches_path_or_ancestor(glob, &relative))
+        };
+        matches(&self.exclude) && !matches(&self.include)
+    }
+
+    /// Whether the whole subtree of the directory at `relative_path` can be skipped:
+    /// it is excluded and no `--include` glob could bring back a path below it
+    pub fn skips_dir(&self, relative_path: &Path) -> bool {
+        self.include.is_empty() && self.is_excluded(relative_path)
+    }
+}
+
+/// Whether `glob` matches `relative` (`/`-separated) or one of its directories
+fn matches_path_or_ancestor(glob: &str, relative: &str) -> bool {
+    let anchored = glob.contains('/');
+    let mut end = 0;
+    for component in relative.split('/') {
+        end += component.len();
+        let target = if anchored {
+            &relative[..end]
+        } else {
+            component
+        };
+        if glob_match(glob, target) {
+            return true;
+        }
+        end += 1;
+    }
+    false
+}
+
+/// A single line of an ignore file
+struct IgnoreRule {
+    pattern: String,
+    /// `!pattern` re-includes a path excluded by an earlier or shallower rule
+    negate: bool,
+    /// `pattern/` only matches directories
+    dir_only: bool,
+    /// Patterns containing `/` match the path relative to the ignore file's directory,
+    /// others match the file name at any depth
+    anchored: bool,
+}
+
+impl IgnoreRule {
+    fn parse(line: &str) -> Option<Self> {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            return None;
+        }
+        let (negate, line) = match line.strip_prefix('!') {
+            Some(rest) => (true, rest),
+            None => (false, line),
+        };
+        let (dir_only, line) = match line.strip_suffix('/') {
+            Some(rest) => (true, rest),
+            None => (false, line),
+        };
+        let anchored = line.contains('/');
+        Some(Self {
+            pattern: line.trim_start_matches('/').to_string(),
+            negate,
+            dir_only,
+            anchored,
+        })
+    }
+}
+
+/// Rules from one ignore file, applying to paths below `base`
+struct IgnoreFile {
+    base: PathBuf,
+    rules: Vec<IgnoreRule>,
+}
+
+/// Stack of ignore files for the directories currently being descended.
+/// Deeper files and later rules take precedence.
+#[derive(Default)]
+pub struct IgnoreStack {
+    files: Vec<IgnoreFile>,
+}
+
+impl IgnoreStack {
+    /// Drops rules of directories that are not ancestors of `path`.
+    /// Must be called for each walked entry in depth-first order.
+    pub fn unwind(&mut self, path: &Path) {
+        while let Some(top) = self.files.last() {
+            if path.parent().is_some_and(|p| p.starts_with(&top.base)) {
+                break;
+            }
+            self.files.pop();
+        }
+    }
+
+    /// Loads the ignore file of a directory being entered, if present.
+    pub fn enter_dir(&mut self, dir: &Path) {
+        let Ok(content) = fs::read_to_string(dir.join(IGNORE_FILE_NAME)) else {
+            return;
+        };
+        let rules: Vec<IgnoreRule> = content.lines().filter_map(IgnoreRule::parse).collect();
+        if !rules.is_empty() {
+            self.files.push(IgnoreFile {
+                base: dir.to_path_buf(),
+                rules,
+            });
+        }
+    }
+
+    /// Returns whether `path` is excluded by the active rules.
+    pub fn is_ignored(&self, path: &Path, is_dir: bool) -> bool {
+        let mut ignored = false;
+        for file in &self.files {
+            let Ok(relative) = path.strip_prefix(&file.base) else {
+                continue;
+            };
+            let relative = relative.to_string_lossy().replace('\\', "/");
+            let name = path
+                .file_name()
+                .map(|n| n.to_string_lossy())
+                .unwrap_or_default();
+            for rule in &file.rules {
+                if rule.dir_only && !is_dir {
+                    continue;
+                }
+                let target = if rule.anchored {
+                    &relative[..]
+                } else {
+                    &name[..]
+                };
+                if glob_match(&rule.pattern, target) {
+                    ignored = !rule.negate;
+                }
+            }
+        }
+        ignored
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_glob_match() {
+        assert!(glob_match("*.txt", "file.txt"));
+        assert!(!glob_match("*.txt", "dir/file.txt"));
+        assert!(glob_match("file?.bin", "file1.bin"));
+        assert!(!glob_match("file?.bin", "file10.bin"));
+        assert!(glob_match("logs/*.log", "logs/app.log"));
+        assert!(glob_match("**/*.tmp", "a/b/c.tmp"));
+        assert!(glob_match("**/*.tmp", "c.tmp"));
+        assert!(glob_match("cache/**", "cache/x/y"));
+        assert!(!glob_match("cache/**", "other/x"));
+        assert!(glob_match("exact", "exact"));
+        assert!(!glob_match("exact", "exactly"));
+    }
+
+    #[test]
+    fn test_path_filter() {
+        let filter = PathFilter::new(
+            &[
+                "node_modules".into(),
+                ".git/".into(),
+                "*.tmp".into(),
+                "/build/out".into(),
+            ],
+            &["keep.tmp".into(), "node_modules/local/**".into()],
+        );
+        // Names match at any depth, excluding what lies below them
+        assert!(filter.is_excluded(Path::new("node_modules")));
+        assert!(filter.is_excluded(Path::new("web/node_modules/pkg/index.js")));
+        assert!(filter.is_excluded(Path::new(".git/config")));
+        assert!(filter.is_excluded(Path::new("a/b/scratch.tmp")));
+        assert!(!filter.is_excluded(Path::new("src/node_modules.rs")));
+        // Globs with a slash match from the source root
+        assert!(filter.is_excluded(Path::new("build/out/app")));
+        assert!(!filter.is_excluded(Path::new("x/build/out/app")));
+        // An include wins over an exclude matching the same path
+        assert!(!filter.is_excluded(Path::new("a/keep.tmp")));
+        assert!(!filter.is_excluded(Path::new("node_modules/local/lib.js")));
+        assert!(!filter.skips_dir(Path::new("node_modules")));
+
+        let filter = PathFilter::new(&["node_modules".into()], &[]);
+        assert!(filter.skips_dir(Path::new("web/node_modules")));
+        assert!(!PathFilter::default().is_excluded(Path::new("any")));
+    }
+
+    #[test]
+    fn test_ignore_rule_parse() {
+        assert!(IgnoreRule::parse("# comment").is_none());
+        assert!(IgnoreRule::parse("   ").is_none());
+
+        let rule = IgnoreRule::parse("!build/").unwrap();
+        assert!(rule.negate);
+        assert!(rule.dir_only);
+        assert!(!rule.anchored);
+        assert_eq!(rule.pattern, "build");
+
+        let rule = IgnoreRule::parse("/docs/*.pdf").unwrap();
+        assert!(rule.anchored);
+        assert_eq!(rule.pattern, "docs/*.pdf");
+    }
+}
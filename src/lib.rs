@@ -1,66 +1,388 @@
+pub mod audit;
 pub mod cleanup;
+pub mod config;
+pub mod crypto;
 pub mod db;
+pub mod filter;
 pub mod pipeline;
+pub mod ratelimit;
 pub mod scan;
+pub mod selftest;
 pub mod utils;
+pub mod verify;
+pub mod watch;
+pub mod watchdog;
 
-use anyhow::Result;
-use clap::Parser;
-use crossbeam_channel::bounded;
-use std::path::PathBuf;
-use std::sync::{Arc, Mutex};
+use anyhow::{Context, Result};
+use clap::{Parser, Subcommand, ValueEnum};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex, Once, Weak};
 use std::thread;
-use std::time::Duration;
+use std::time::{Duration, Instant};
 
-use cleanup::run_cleanup;
-use db::Database;
-use pipeline::{run_consumer, run_producer, Block, HashAlgorithm, PipelineConfig};
-use scan::run_scan;
-use utils::{parse_bandwidth, Logger};
+use audit::{read_audit_key, verify_audit_log};
+use cleanup::{list_deletions, run_cleanup};
+use crypto::{decrypt_path, read_password_file, Cipher};
+use db::{Database, FileStatus};
+use filter::PathFilter;
+use pipeline::{
+    apply_staged_updates, daily_budget_exhausted, discard_staged_updates, progress_style,
+    transfer_backlog, HashAlgorithm, PipelineConfig, SyncStats, TransferControl,
+};
+use scan::{create_pending_links, run_scan, ScanOptions, UnicodeForm, UnsafeLinks};
+use selftest::run_selftest;
+use utils::{
+    ensure_writable, format_bytes, parse_bandwidth, parse_block_size_override,
+    parse_checksum_override, parse_date, relative_dest_dir, set_io_class, IoClass, LockFile,
+    Logger, Semaphore,
+};
+use verify::{requeue_mismatched, run_verify, verify_manifest};
+use watch::{run_watch, WatchOptions};
+use watchdog::Watchdog;
 
 #[derive(Parser, Debug, Clone)]
 #[command(author, version, about, long_about = None)]
 pub struct Args {
-    /// Path to source directory
+    #[command(subcommand)]
+    pub command: Option<Command>,
+
+    /// TOML file with default options (defaults to ./hsync.toml if present)
+    #[arg(long)]
+    pub config: Option<PathBuf>,
+
+    /// Path to source directory (required unless set in the config file)
+    #[arg(long)]
+    pub source: Option<PathBuf>,
+
+    /// Path to destination directory (required unless set in the config file).
+    /// Repeat to mirror the source to several destinations in one pass.
     #[arg(long)]
-    pub source: PathBuf,
+    pub dest: Vec<PathBuf>,
 
-    /// Path to destination directory
+    /// Preserve the full source path under the destination (like rsync -R)
     #[arg(long)]
-    pub dest: PathBuf,
+    pub relative: bool,
 
     /// Local database file path
     #[arg(long, default_value = "hsync.db")]
     pub db: String,
 
+    /// Seconds to wait for a database locked by another connection before failing
+    #[arg(long, default_value_t = 5)]
+    pub db_timeout: u64,
+
     /// Audit log file path
     #[arg(long, default_value = "hsync.log")]
     pub log: String,
 
+    /// Timestamp audit log entries in UTC instead of local time
+    #[arg(long)]
+    pub log_utc: bool,
+
+    /// File also receiving the warnings and errors of the audit log (skips, failures,
+    /// retries), for monitoring
+    #[arg(long)]
+    pub error_log: Option<String>,
+
+    /// File receiving the relative path of each transferred file, one per line
+    #[arg(long)]
+    pub transfer_log: Option<String>,
+
+    /// Abort the scan when the source file list would need more memory than this (e.g., 512M, 2G)
+    #[arg(long)]
+    pub max_scan_memory: Option<String>,
+
+    /// After the scan, print the source size of each subdirectory down to DEPTH
+    /// levels (1 if omitted), largest first
+    #[arg(long, value_name = "DEPTH", num_args = 0..=1, default_missing_value = "1")]
+    pub report_tree_sizes: Option<usize>,
+
     /// Maximum transfer speed (e.g., 20M, 512K, 1G, or raw bytes)
     #[arg(long)]
     pub bwlimit: Option<String>,
 
-    /// Checksum algorithm to use
+    /// Seconds over which the bandwidth limit ramps up from a fraction to --bwlimit
+    #[arg(long)]
+    pub bwlimit_ramp: Option<u64>,
+
+    /// Maximum transfer speed for files new to the destination, instead of --bwlimit
+    #[arg(long)]
+    pub bwlimit_new: Option<String>,
+
+    /// Maximum transfer speed for updates of changed files, instead of --bwlimit
+    #[arg(long)]
+    pub bwlimit_update: Option<String>,
+
+    /// Maximum transfer speed of any single file, applied under the other limits
+    #[arg(long)]
+    pub max_bandwidth_per_file: Option<String>,
+
+    /// Maximum bytes written per day, across runs (e.g. 5G); the rest waits for the next day
+    #[arg(long)]
+    pub daily_budget: Option<String>,
+
+    /// File shared by several hsync processes to keep their combined rate under --bwlimit
+    #[arg(long, requires = "bwlimit", conflicts_with_all = ["bwlimit_new", "bwlimit_update"])]
+    pub shared_limit_file: Option<PathBuf>,
+
+    /// Control file: writing `pause` into it holds the transfer until changed
+    #[arg(long)]
+    pub control_file: Option<PathBuf>,
+
+    /// Checksum algorithm to use (`hsync algorithms` lists them)
     #[arg(long, value_enum, default_value_t = HashAlgorithm::Sha256)]
     pub checksum: HashAlgorithm,
 
+    /// Use another checksum algorithm for source files matching a glob (GLOB=ALGO,
+    /// repeatable, first match wins)
+    #[arg(long, value_name = "GLOB=ALGO")]
+    pub checksum_override: Vec<String>,
+
+    /// External command computing the checksum from stdin (overrides --checksum)
+    #[arg(long)]
+    pub checksum_command: Option<String>,
+
+    /// Re-read each written file and store its hash separately as the destination hash
+    #[arg(long)]
+    pub verify_dest: bool,
+
+    /// Abort the whole run, leaving the remaining files pending, once --verify-dest
+    /// has found this many mismatches (a likely failing destination)
+    #[arg(long, value_name = "N", requires = "verify_dest")]
+    pub abort_after_mismatches: Option<u64>,
+
+    /// Check the size of each written file before marking it synced, a cheap guard
+    /// against short writes and truncation
+    #[arg(long)]
+    pub verify_size: bool,
+
+    /// Encrypt files at rest in the destination (AES-256-GCM, key derived from --password-file)
+    #[arg(
+        long,
+        requires = "password_file",
+        conflicts_with_all = ["append", "only_write_changed", "verify_dest", "checksum_compare"]
+    )]
+    pub encrypt: bool,
+
+    /// File whose first line is the password for --encrypt and the decrypt command
+    #[arg(long)]
+    pub password_file: Option<PathBuf>,
+
+    /// Key file for a tamper-evident audit log: each log line carries an HMAC-SHA256
+    /// chained to the previous line, checked by the verify-log command
+    #[arg(long)]
+    pub audit_key_file: Option<PathBuf>,
+
     /// Enable deletion of extra files in destination
     #[arg(long)]
     pub delete_extras: bool,
 
+    /// List the destination files --delete-extras would delete, with their sizes, then
+    /// exit without transferring or deleting anything
+    #[arg(long)]
+    pub list_deletions: bool,
+
+    /// Scan, then list the files that would be transferred and, with --delete-extras,
+    /// deleted, without writing to any destination
+    #[arg(long, conflicts_with_all = ["watch", "list_deletions"])]
+    pub dry_run: bool,
+
+    /// Consider files with matching sizes synced, ignoring mtime differences
+    #[arg(long, conflicts_with = "checksum_compare")]
+    pub size_only: bool,
+
+    /// Decide whether same-size files differ by comparing their contents, not mtimes
+    #[arg(long)]
+    pub checksum_compare: bool,
+
+    /// Number of threads deleting extra files in parallel (helps on high-latency storage)
+    #[arg(long, default_value_t = 1)]
+    pub cleanup_threads: usize,
+
+    /// Maximum number of destination files open at once (at least 1), to stay below
+    /// the open file limit
+    #[arg(long, value_name = "N")]
+    pub max_open_files: Option<usize>,
+
+    /// I/O scheduling class of the process (Linux), e.g. idle to yield the disks to
+    /// interactive workloads
+    #[arg(long, value_enum)]
+    pub io_class: Option<IoClass>,
+
+    /// List extra files and ask for confirmation on the terminal before deleting them
+    #[arg(long)]
+    pub confirm_delete: bool,
+
+    /// After cleanup, restore the source mtime of destination directories that lost files
+    #[arg(long)]
+    pub preserve_dir_times: bool,
+
+    /// Transfer files whose permission mode differs from the source and apply the source mode
+    #[arg(long)]
+    pub perms_matter: bool,
+
+    /// Verify cleanup candidates in a second pass before deleting them
+    #[arg(long)]
+    pub safe_cleanup: bool,
+
+    /// Match destination files to source files case-insensitively during cleanup
+    #[arg(long)]
+    pub ignore_case: bool,
+
+    /// Unicode normalization form in which source and destination names are matched,
+    /// for syncs between NFC (Linux) and NFD (macOS) file systems
+    #[arg(long, value_enum, default_value_t = UnicodeForm::None)]
+    pub normalize_unicode: UnicodeForm,
+
+    /// Hash destination files that look synced and re-transfer those not matching the
+    /// stored source hash
+    #[arg(long, conflicts_with = "encrypt")]
+    pub compare_dest_hash: bool,
+
+    /// Only transfer files missing from the destination, never overwrite existing ones
+    #[arg(long)]
+    pub ignore_existing: bool,
+
+    /// Apply the source mtime and permissions to destination files already in sync,
+    /// without transferring their content
+    #[arg(long)]
+    pub refresh_metadata: bool,
+
+    /// Only update files already in the destination, never create new ones
+    #[arg(long, conflicts_with = "ignore_existing")]
+    pub existing: bool,
+
+    /// Follow symlinks in the source and copy the files and directories they point to
+    #[arg(long)]
+    pub copy_links: bool,
+
+    /// Keep symlinks inside the source tree as symlinks; skip those pointing outside it
+    #[arg(long, conflicts_with_all = ["copy_links", "copy_unsafe_links"])]
+    pub safe_links: bool,
+
+    /// Keep symlinks inside the source tree as symlinks; copy the files that those
+    /// pointing outside it lead to
+    #[arg(long, conflicts_with = "copy_links")]
+    pub copy_unsafe_links: bool,
+
+    /// Restore: copy files missing or older in the source back from the destination
+    #[arg(long)]
+    pub reverse: bool,
+
+    /// Stage all transferred files under temporary names and move them into place at the end
+    #[arg(long)]
+    pub delay_updates: bool,
+
+    /// Mirror the immutable and append-only flags (chattr +i, +a) of source files onto
+    /// their destination copies (Linux, needs CAP_LINUX_IMMUTABLE)
+    #[arg(long, conflicts_with = "delay_updates")]
+    pub preserve_fileflags: bool,
+
+    /// Print a plain progress percentage line every N seconds instead of the progress bar
+    #[arg(long, value_name = "SECS")]
+    pub numeric_progress: Option<u64>,
+
+    /// Append a cumulative stats snapshot (files, bytes, rate, ETA) to the log every N seconds
+    #[arg(long, value_name = "SECS")]
+    pub stats_interval: Option<u64>,
+
+    /// Progress bar template (indicatif syntax, e.g. "{wide_bar} {bytes}/{total_bytes}")
+    #[arg(long)]
+    pub progress_template: Option<String>,
+
+    /// Leave the ETA out of the progress display
+    #[arg(long)]
+    pub progress_no_eta: bool,
+
+    /// File written with the run's stats only after a fully successful sync
+    #[arg(long)]
+    pub done_file: Option<PathBuf>,
+
+    /// File written with the run's metrics in Prometheus text format when it ends
+    #[arg(long)]
+    pub metrics_file: Option<PathBuf>,
+
+    /// Skip rewriting blocks the destination already holds (less wear on SSDs and CoW filesystems)
+    #[arg(long)]
+    pub only_write_changed: bool,
+
+    /// Abandon a file (leaving it pending) when reading or writing one of its blocks
+    /// takes longer than this many seconds
+    #[arg(long, value_name = "SECS")]
+    pub file_timeout: Option<u64>,
+
+    /// Recompute the hash of every transferred file, ignoring hashes kept from earlier runs
+    #[arg(long)]
+    pub rehash: bool,
+
+    /// Store a hash of every --block-size window of each transferred file
+    #[arg(long)]
+    pub store_block_hashes: bool,
+
+    /// Transfer the content of identical pending files once and copy it at the
+    /// destination to the others, hashing same-size files before the transfer
+    #[arg(long, conflicts_with = "encrypt")]
+    pub dedupe_identical: bool,
+
+    /// Transfer pending files in source path order and record that order in the database,
+    /// so runs from the same database state replay the same sequence
+    #[arg(long)]
+    pub ordered: bool,
+
+    /// Transfer pending files one source directory after another, for read and write
+    /// locality on spinning disks
+    #[arg(long)]
+    pub group_by_dir: bool,
+
+    /// Send only the new tail of files that grew past an identical destination prefix
+    #[arg(long, conflicts_with = "delay_updates")]
+    pub append: bool,
+
+    /// Always transfer complete files, overriding --append and --only-write-changed
+    #[arg(long)]
+    pub whole_file: bool,
+
+    /// Do not hash files copied only because the destination lacks them (no later verify)
+    #[arg(long)]
+    pub no_hash_on_copy: bool,
+
+    /// Transfer every file recorded as synced again, without rescanning (hashes are kept)
+    #[arg(long, conflicts_with = "rescan")]
+    pub force_resync: bool,
+
     /// Force a full rescan, ignoring any existing backlog
     #[arg(long)]
     pub rescan: bool,
 
+    /// Scan without walking the destination: files recorded as synced are assumed to be
+    /// as hsync left them, only the others are looked up. For destinations that only
+    /// hsync changes.
+    #[arg(long, conflicts_with = "reverse")]
+    pub trust_dest_db: bool,
+
     /// Block size for file transfer (e.g., 1M, 512K)
     #[arg(long, default_value = "5M")]
     pub block_size: String,
 
+    /// Block size for files matching a glob, as GLOB=SIZE (e.g. "*.mkv=64M"); repeatable,
+    /// the first match wins
+    #[arg(long, value_name = "GLOB=SIZE", conflicts_with = "encrypt")]
+    pub block_size_for: Vec<String>,
+
     /// Number of block queue slots for pipeline buffering
     #[arg(long, default_value_t = 20)]
     pub queue_capacity: usize,
 
+    /// After the sync, keep watching the source and sync changed files as they
+    /// appear; with --delete-extras, removed files are deleted from the destination
+    #[arg(long, conflicts_with_all = ["reverse", "list_deletions"])]
+    pub watch: bool,
+
+    /// Milliseconds without new source events before --watch syncs the changes
+    #[arg(long, default_value_t = 500)]
+    pub watch_debounce_ms: u64,
+
     /// Total transfer attempts (including initial attempt)
     #[arg(long, default_value_t = 10)]
     pub retry_attempts: u32,
@@ -68,23 +390,504 @@ pub struct Args {
     /// Seconds to wait between retry attempts
     #[arg(long, default_value_t = 60)]
     pub retry_interval_seconds: u64,
+
+    /// Only sync source files modified on or after this date (YYYY-MM-DD)
+    #[arg(long)]
+    pub newer_than: Option<String>,
+
+    /// Skip source directories containing a file of this name, with everything below
+    /// them, and keep their destination copies out of cleanup. Repeatable.
+    #[arg(long, value_name = "FILE")]
+    pub exclude_if_present: Vec<String>,
+
+    /// Leave out source paths matching this glob, relative to the source (e.g.
+    /// node_modules, '*.tmp', 'build/cache/**'). Repeatable.
+    #[arg(long, value_name = "GLOB")]
+    pub exclude: Vec<String>,
+
+    /// Keep source paths matching this glob even if an --exclude glob matches them.
+    /// Repeatable.
+    #[arg(long, value_name = "GLOB")]
+    pub include: Vec<String>,
+
+    /// Only sync source files whose content, detected from their first bytes, is of one
+    /// of these comma-separated types: MIME types (image/png), categories (image, video)
+    /// or extensions (pdf)
+    #[arg(long, value_name = "TYPES", value_delimiter = ',')]
+    pub magic_filter: Vec<String>,
+
+    /// Abort when a source directory cannot be read, instead of skipping its files
+    #[arg(long)]
+    pub fail_on_inaccessible: bool,
+
+    /// Store paths in the database relative to source/destination to save space
+    #[arg(long)]
+    pub compact_db: bool,
+}
+
+#[derive(Subcommand, Debug, Clone)]
+pub enum Command {
+    /// Re-hash synced destination files and compare them with the stored hashes
+    Verify {
+        /// Only verify files whose destination mtime changed since hsync recorded it
+        #[arg(long)]
+        changed_only: bool,
+        /// Check the destination against this `path<TAB>hash` manifest instead of the database
+        #[arg(long, conflicts_with = "changed_only")]
+        manifest: Option<PathBuf>,
+    },
+    /// Check hash algorithms, size parsing and a small sync on this platform
+    Selftest,
+    /// List the files the database still records as not synced, e.g. after a failed run
+    ListFailures,
+    /// Ask the sync running on --db, possibly in another process, to stop cleanly
+    /// after its current file, leaving the rest of the backlog pending
+    Cancel,
+    /// List the checksum algorithms this build supports, with their digest lengths
+    Algorithms,
+    /// Check the MAC chain of the audit log (--log) with --audit-key-file
+    VerifyLog,
+    /// Decrypt files written with --encrypt, a single file or a whole tree, for restore
+    Decrypt {
+        /// Encrypted file or directory
+        input: PathBuf,
+        /// Where to write the decrypted file or tree
+        output: PathBuf,
+    },
 }
 
 pub fn run(args: Args) -> Result<()> {
+    run_sync(args, false).map(|_| ())
+}
+
+/// Like `run`, additionally returning where the transfer spent its time
+pub fn run_with_stats(args: Args) -> Result<SyncStats> {
+    run_sync(args, true)
+}
+
+fn run_sync(args: Args, collect_stats: bool) -> Result<SyncStats> {
+    let run_start = Instant::now();
+    // Before any thread is spawned, so that they all inherit the class
+    if let Some(class) = args.io_class {
+        match set_io_class(class) {
+            Ok(()) => {}
+            Err(e) if e.kind() == std::io::ErrorKind::Unsupported => {
+                eprintln!("Warning: --io-class is not supported on this platform; ignored");
+            }
+            Err(e) => return Err(e).context("Failed to set the I/O scheduling class"),
+        }
+    }
+    let mut stats = SyncStats {
+        hash_algorithm: args.checksum_command.is_none().then_some(args.checksum),
+        ..Default::default()
+    };
+
+    if let Some(Command::Selftest) = args.command {
+        let report = run_selftest();
+        for (check, passed) in &report.checks {
+            println!("{} {}", if *passed { "PASS" } else { "FAIL" }, check);
+        }
+        let failed = report.failed();
+        if failed > 0 {
+            return Err(anyhow::anyhow!("Selftest failed: {} checks failed", failed));
+        }
+        println!("Selftest passed.");
+        return Ok(stats);
+    }
+
+    if let Some(Command::Algorithms) = args.command {
+        for algo in HashAlgorithm::value_variants() {
+            let name = algo
+                .to_possible_value()
+                .map(|value| value.get_name().to_string());
+            println!(
+                "{}\t{} bits ({} hex digits)",
+                name.unwrap_or_default(),
+                algo.hex_len() * 4,
+                algo.hex_len()
+            );
+        }
+        return Ok(stats);
+    }
+
+    if let Some(Command::Decrypt { input, output }) = &args.command {
+        let password_file = args
+            .password_file
+            .as_ref()
+            .context("decrypt requires --password-file")?;
+        let count = decrypt_path(&read_password_file(password_file)?, input, output)?;
+        println!("Decrypted {} files.", count);
+        return Ok(stats);
+    }
+
+    if let Some(Command::VerifyLog) = args.command {
+        let key_file = args
+            .audit_key_file
+            .as_ref()
+            .context("verify-log requires --audit-key-file")?;
+        let report = verify_audit_log(Path::new(&args.log), &read_audit_key(key_file)?)?;
+        println!(
+            "Audit log intact: {} lines, final MAC {}.",
+            report.lines, report.last_mac
+        );
+        return Ok(stats);
+    }
+
+    if let Some(Command::Cancel) = args.command {
+        let db = Database::with_busy_timeout(&args.db, Duration::from_secs(args.db_timeout))?;
+        db.request_cancel()?;
+        println!("Cancellation requested; the running sync stops after its current file.");
+        return Ok(stats);
+    }
+
+    if let Some(Command::ListFailures) = args.command {
+        let db = Database::with_busy_timeout(&args.db, Duration::from_secs(args.db_timeout))?;
+        list_failures(&db)?;
+        return Ok(stats);
+    }
+
+    if let Some(Command::Verify {
+        manifest: Some(manifest),
+        ..
+    }) = &args.command
+    {
+        // Only the destination and the hashing settings matter here
+        let config = PipelineConfig {
+            dest_dir: args.dest.first().context("--dest is required")?.clone(),
+            hash_algo: args.checksum,
+            checksum_overrides: checksum_overrides(&args)?,
+            checksum_command: args.checksum_command.clone(),
+            block_size: parse_bandwidth(&args.block_size)? as usize,
+            ..Default::default()
+        };
+        let logger = open_logger(&args)?;
+        let report = verify_manifest(&config, manifest, &logger)?;
+        println!(
+            "Verified {} files, {} mismatched, {} missing.",
+            report.verified.len(),
+            report.mismatched.len(),
+            report.missing.len()
+        );
+        let failed = report.mismatched.len() + report.missing.len();
+        if failed > 0 {
+            return Err(anyhow::anyhow!("Verification failed for {} files", failed));
+        }
+        return Ok(stats);
+    }
+
+    // Source and destination are only optional for subcommands that do not sync
+    let source = args.source.clone().context("--source is required")?;
+    if args.dest.is_empty() {
+        return Err(anyhow::anyhow!("--dest is required"));
+    }
+    if cfg!(not(target_os = "linux")) && args.preserve_fileflags {
+        return Err(anyhow::anyhow!(
+            "--preserve-fileflags is only supported on Linux"
+        ));
+    }
+
     // Parse bandwidth limit if provided
     let bw_limit = args
         .bwlimit
         .as_ref()
         .map(|s| parse_bandwidth(s))
         .transpose()?;
+    let bw_limit_new = args
+        .bwlimit_new
+        .as_ref()
+        .map(|s| parse_bandwidth(s))
+        .transpose()?;
+    let bw_limit_update = args
+        .bwlimit_update
+        .as_ref()
+        .map(|s| parse_bandwidth(s))
+        .transpose()?;
+
+    let bw_limit_per_file = args
+        .max_bandwidth_per_file
+        .as_ref()
+        .map(|s| parse_bandwidth(s))
+        .transpose()?;
+    if bw_limit_per_file == Some(0) {
+        return Err(anyhow::anyhow!("--max-bandwidth-per-file must be positive"));
+    }
+    if args.stats_interval == Some(0) {
+        return Err(anyhow::anyhow!("--stats-interval must be positive"));
+    }
+    let progress_style = args
+        .progress_template
+        .as_deref()
+        .map(|template| progress_style(Some(template), args.progress_no_eta))
+        .transpose()
+        .map_err(|e| anyhow::anyhow!("Invalid --progress-template: {}", e))?;
+    let daily_budget = args
+        .daily_budget
+        .as_ref()
+        .map(|s| parse_bandwidth(s))
+        .transpose()?;
 
     // Parse block size
     let block_size = parse_bandwidth(&args.block_size)? as usize;
 
     let queue_capacity = args.queue_capacity;
 
-    let db = Arc::new(Mutex::new(Database::new(&args.db)?));
-    let logger = Arc::new(Logger::new(&args.log));
+    // With --relative, files land under the full source path inside the destination
+    let mut dest_dirs = if args.relative {
+        args.dest
+            .iter()
+            .map(|dest| relative_dest_dir(&source, dest))
+            .collect::<Result<Vec<_>>>()?
+    } else {
+        args.dest.clone()
+    };
+    // The first destination is the one tracked in the database, the rest mirror it
+    let dest_dir = dest_dirs.remove(0);
+    let mirror_dirs = dest_dirs;
+
+    // A restore runs the same machinery with the directions flipped. Its state lives
+    // in memory so the forward database keeps describing the forward direction.
+    let (source, dest_dir, db_path) = if args.reverse {
+        if !mirror_dirs.is_empty() {
+            return Err(anyhow::anyhow!("--reverse restores from a single --dest"));
+        }
+        if args.delete_extras || args.encrypt {
+            return Err(anyhow::anyhow!(
+                "--reverse cannot be combined with --delete-extras or --encrypt"
+            ));
+        }
+        println!("Reverse mode: restoring {:?} from {:?}", source, dest_dir);
+        (dest_dir, source, ":memory:".to_string())
+    } else {
+        (source, dest_dir, args.db.clone())
+    };
+
+    let scan_options = ScanOptions {
+        newer_than: args.newer_than.as_deref().map(parse_date).transpose()?,
+        magic_filter: args.magic_filter.clone(),
+        exclude_if_present: args.exclude_if_present.clone(),
+        path_filter: PathFilter::new(&args.exclude, &args.include),
+        fail_on_inaccessible: args.fail_on_inaccessible,
+        ignore_existing: args.ignore_existing,
+        existing: args.existing,
+        follow_links: args.copy_links,
+        unsafe_links: if args.safe_links {
+            Some(UnsafeLinks::Skip)
+        } else if args.copy_unsafe_links {
+            Some(UnsafeLinks::Copy)
+        } else {
+            None
+        },
+        // Restoring must not roll back files changed since the backup
+        update_only: args.reverse,
+        mirror_dirs: mirror_dirs.clone(),
+        checksum_compare: args.checksum_compare.then_some(block_size),
+        perms_matter: args.perms_matter,
+        size_only: args.size_only,
+        refresh_metadata: args.refresh_metadata,
+        normalize_unicode: args.normalize_unicode,
+        trust_dest_db: args.trust_dest_db,
+        max_memory: args
+            .max_scan_memory
+            .as_deref()
+            .map(|size| {
+                parse_bandwidth(size)
+                    .map_err(|_| anyhow::anyhow!("Invalid --max-scan-memory value: '{}'", size))
+            })
+            .transpose()?,
+        encrypted_block_size: args.encrypt.then_some(block_size),
+        report_tree_depth: args.report_tree_sizes,
+        dry_run: args.dry_run,
+    };
+
+    // Key derivation is deliberately slow, so it happens once per run
+    let cipher = if args.encrypt {
+        let password_file = args
+            .password_file
+            .as_ref()
+            .context("--encrypt requires --password-file")?;
+        Some(Cipher::new(&read_password_file(password_file)?)?)
+    } else {
+        None
+    };
+
+    let config = PipelineConfig {
+        source_dir: source.clone(),
+        dest_dir: dest_dir.clone(),
+        mirror_dirs: mirror_dirs.clone(),
+        bw_limit,
+        bw_limit_new,
+        bw_limit_update,
+        bw_limit_per_file,
+        daily_budget,
+        bw_ramp: args.bwlimit_ramp.map(Duration::from_secs),
+        db_path: db_path.clone(),
+        log_path: args.log.clone(),
+        hash_algo: args.checksum,
+        checksum_overrides: checksum_overrides(&args)?,
+        checksum_command: args.checksum_command.clone(),
+        block_size,
+        block_size_overrides: args
+            .block_size_for
+            .iter()
+            .map(|s| parse_block_size_override(s))
+            .collect::<Result<_>>()?,
+        safe_cleanup: args.safe_cleanup,
+        ignore_case: args.ignore_case,
+        normalize_unicode: args.normalize_unicode,
+        exclude_if_present: args.exclude_if_present.clone(),
+        path_filter: PathFilter::new(&args.exclude, &args.include),
+        existing: args.existing,
+        transfer_log: args.transfer_log.clone(),
+        shared_limit_file: args.shared_limit_file.clone(),
+        verify_dest: args.verify_dest,
+        verify_size: args.verify_size,
+        abort_after_mismatches: args.abort_after_mismatches,
+        control_file: args.control_file.clone(),
+        numeric_progress: args.numeric_progress.map(Duration::from_secs),
+        stats_interval: args.stats_interval.map(Duration::from_secs),
+        progress_style,
+        progress_no_eta: args.progress_no_eta,
+        no_hash_on_copy: args.no_hash_on_copy,
+        delay_updates: args.delay_updates,
+        append: args.append,
+        whole_file: args.whole_file,
+        confirm_delete: args.confirm_delete,
+        cleanup_threads: args.cleanup_threads,
+        preserve_dir_times: args.preserve_dir_times,
+        store_block_hashes: args.store_block_hashes,
+        dedupe_identical: args.dedupe_identical,
+        preserve_fileflags: args.preserve_fileflags,
+        ordered: args.ordered,
+        group_by_dir: args.group_by_dir,
+        rehash: args.rehash,
+        only_write_changed: args.only_write_changed,
+        perms_matter: args.perms_matter,
+        fail_on_inaccessible: args.fail_on_inaccessible,
+        open_files: args
+            .max_open_files
+            .map(|limit| Arc::new(Semaphore::new(limit))),
+        watchdog: args
+            .file_timeout
+            .map(|secs| Arc::new(Watchdog::new(Duration::from_secs(secs)))),
+        cipher,
+        collect_stats,
+    };
+
+    // Preview of --delete-extras: nothing is transferred or deleted
+    if args.list_deletions {
+        let extras = list_deletions(&config)?;
+        for (path, size) in &extras {
+            println!("Would delete: {:?} ({})", path, format_bytes(*size));
+        }
+        println!(
+            "{} extra files ({}) would be deleted.",
+            extras.len(),
+            format_bytes(extras.iter().map(|(_, size)| size).sum())
+        );
+        return Ok(stats);
+    }
+
+    // Two runs sharing a database would interleave their backlogs
+    let _db_lock = if db_path == ":memory:" {
+        None
+    } else {
+        let lock_path = PathBuf::from(format!("{}.lock", db_path));
+        Some(LockFile::try_acquire(&lock_path)?.ok_or_else(|| {
+            anyhow::anyhow!(
+                "Database {:?} is in use by another hsync process (lock file {:?})",
+                db_path,
+                lock_path
+            )
+        })?)
+    };
+    let mut database = Database::with_busy_timeout(&db_path, Duration::from_secs(args.db_timeout))?;
+    // A dry run scans into a throwaway copy, leaving the recorded state as it was
+    if args.dry_run {
+        database = database.scratch_copy()?;
+    }
+    if args.compact_db {
+        // Joining an empty path appends the trailing separator
+        let prefix = |root: &Path| {
+            root.join("")
+                .to_str()
+                .map(str::to_string)
+                .ok_or_else(|| anyhow::anyhow!("--compact-db needs a UTF-8 root, got {:?}", root))
+        };
+        database
+            .enable_compact_paths(&prefix(&source)?, &prefix(&dest_dir)?)
+            .context("--compact-db needs every recorded file under --source and --dest")?;
+    }
+    let db = Arc::new(Mutex::new(database));
+    let logger = Arc::new(open_logger(&args)?);
+
+    if let Some(Command::Verify { changed_only, .. }) = args.command {
+        if args.encrypt {
+            return Err(anyhow::anyhow!(
+                "verify cannot check encrypted destinations; decrypt them and compare instead"
+            ));
+        }
+        let report = run_verify(&config, &db, &logger, changed_only)?;
+        println!(
+            "Verified {} files, {} mismatched.",
+            report.verified.len(),
+            report.mismatched.len()
+        );
+        if !report.mismatched.is_empty() {
+            return Err(anyhow::anyhow!(
+                "Verification failed for {} files",
+                report.mismatched.len()
+            ));
+        }
+        return Ok(stats);
+    }
+
+    // A marker left by an earlier run must not signal completion of this one
+    if let Some(done_file) = args.done_file.as_ref().filter(|_| !args.dry_run) {
+        match std::fs::remove_file(done_file) {
+            Err(e) if e.kind() != std::io::ErrorKind::NotFound => {
+                return Err(anyhow::anyhow!(
+                    "Failed to remove stale done file {:?}: {}",
+                    done_file,
+                    e
+                ));
+            }
+            _ => {}
+        }
+    }
+
+    // Fail early with a clear message rather than on the first write; a dry run
+    // writes nothing, not even the probe
+    if !args.dry_run {
+        for dir in std::iter::once(&dest_dir).chain(&mirror_dirs) {
+            ensure_writable(dir)?;
+        }
+    }
+
+    // A cancellation requested while no sync was running is not meant for this one
+    db.lock().unwrap().take_cancel_request()?;
+
+    // A run that crashed or failed may have left the file it was writing half
+    // written, with size and mtime that could pass a scan: transfer it again, an
+    // encrypted one from its last block sealed at rest
+    {
+        let db_guard = db.lock().unwrap();
+        if !db_guard.clean_shutdown()? {
+            if let Some(path) = db_guard.current_file()? {
+                db_guard.requeue_interrupted(&path)?;
+                let msg = format!("Previous run did not exit cleanly; re-queued {}", path);
+                println!("{}", msg);
+                logger.log(&msg)?;
+            }
+        }
+        db_guard.set_current_file(None)?;
+        db_guard.set_clean_shutdown(false)?;
+    }
+
+    // Re-queued files are picked up below as a backlog to resume
+    if args.force_resync {
+        let reset = db.lock().unwrap().reset_all_to_pending()?;
+        println!("Forcing re-transfer of {} synced files.", reset);
+    }
 
     // Determine mode: resume from backlog or perform fresh scan
     let should_scan = if args.rescan {
@@ -105,39 +908,39 @@ pub fn run(args: Args) -> Result<()> {
 
     if should_scan {
         println!("Scanning source and destination directories...");
-        let pending = run_scan(&args.source, &args.dest, &db)?;
+        let mut pending = run_scan(&source, &dest_dir, &db, &scan_options)?;
+        if args.compare_dest_hash {
+            println!("Comparing synced destination files with their stored hashes...");
+            let requeued = requeue_mismatched(&config, &db, &logger)?;
+            if requeued > 0 {
+                println!("{} corrupted destination files queued again.", requeued);
+            }
+            pending += requeued;
+        }
 
-        if pending == 0 {
+        if pending == 0 && !args.dry_run {
             println!("All files are already synced.");
+            create_links(&config, &db, &logger)?;
             if args.delete_extras {
-                let config = PipelineConfig {
-                    source_dir: args.source.clone(),
-                    dest_dir: args.dest.clone(),
-                    bw_limit,
-                    db_path: args.db.clone(),
-                    log_path: args.log.clone(),
-                    hash_algo: args.checksum,
-                    block_size,
-                };
-                run_cleanup(&config, &logger)?;
+                run_cleanup(&config, &db, &logger)?;
             }
-            println!("Sync completed.");
-            return Ok(());
+            stats.duration = run_start.elapsed();
+            finish_run(&config, &db, &logger, &args, &stats)?;
+            watch_if_requested(&config, &db, &logger, &args)?;
+            return Ok(stats);
         }
     }
 
-    // Transfer phase: process the backlog with retry logic
-    let config = PipelineConfig {
-        source_dir: args.source.clone(),
-        dest_dir: args.dest.clone(),
-        bw_limit,
-        db_path: args.db.clone(),
-        log_path: args.log.clone(),
-        hash_algo: args.checksum,
-        block_size,
-    };
+    if args.dry_run {
+        report_dry_run(&config, &db, &logger, &args)?;
+        return Ok(stats);
+    }
 
+    // Transfer phase: process the backlog with retry logic
     let mut last_error: Option<anyhow::Error> = None;
+    // Interrupt/pause flags checked by the consumer between blocks and pacing slices
+    let control = Arc::new(TransferControl::default());
+    let interruptible = interrupt_on_signal(&control);
 
     for attempt in 1..=args.retry_attempts {
         // Check if there are still pending files
@@ -164,32 +967,40 @@ pub fn run(args: Args) -> Result<()> {
                 args.retry_interval_seconds
             );
             eprintln!("{}", msg);
-            let _ = logger.log(&msg);
+            let _ = logger.log_error(&msg);
             thread::sleep(Duration::from_secs(args.retry_interval_seconds));
         }
 
-        let (sender, receiver) = bounded::<Block>(queue_capacity);
-
-        let producer_db = db.clone();
-        let producer_logger = logger.clone();
-        let producer_config = config.clone();
-        let producer_handle = thread::spawn(move || -> Result<()> {
-            run_producer(producer_config, sender, producer_db, producer_logger)
-        });
+        let (producer_result, consumer_result) =
+            transfer_backlog(&config, &db, &logger, queue_capacity, &control);
 
-        let consumer_db = db.clone();
-        let consumer_logger = logger.clone();
-        let bw_limit = config.bw_limit;
-        let consumer_handle = thread::spawn(move || -> Result<()> {
-            run_consumer(receiver, consumer_db, consumer_logger, bw_limit)
-        });
+        // Stage timings of an attempt count even if the other thread failed
+        for attempt_stats in [&producer_result, &consumer_result].into_iter().flatten() {
+            stats.merge(attempt_stats);
+        }
 
-        let producer_result = producer_handle.join().unwrap();
-        let consumer_result = consumer_handle.join().unwrap();
+        // Interrupted by a signal: the file cut short goes back to the backlog
+        if control.interrupted.load(Ordering::SeqCst) {
+            let pending = {
+                let db_guard = db.lock().unwrap();
+                if let Some(path) = db_guard.current_file()? {
+                    db_guard.requeue_interrupted(&path)?;
+                    db_guard.set_current_file(None)?;
+                }
+                db_guard.pending_count()?
+            };
+            discard_staged_updates(std::mem::take(&mut *control.staged.lock().unwrap()), &db)?;
+            let msg = format!("Transfer interrupted: {} files left pending", pending);
+            eprintln!("{}", msg);
+            let _ = logger.log_error(&msg);
+            stats.duration = run_start.elapsed();
+            write_metrics(&db, &args, &stats)?;
+            return Err(anyhow::anyhow!(msg));
+        }
 
         // Check for errors from either thread
         match (producer_result, consumer_result) {
-            (Ok(()), Ok(())) => {
+            (Ok(_), Ok(_)) => {
                 last_error = None;
             }
             (Err(e), _) => {
@@ -203,6 +1014,19 @@ pub fn run(args: Args) -> Result<()> {
         if last_error.is_none() {
             break;
         }
+        // Retrying would keep hammering a failing destination
+        if control.mismatch_limit_reached(&config) {
+            let msg = format!(
+                "Transfer aborted after {} destination hash mismatches",
+                control.mismatches.load(std::sync::atomic::Ordering::SeqCst)
+            );
+            eprintln!("{}", msg);
+            let _ = logger.log_error(&msg);
+            discard_staged_updates(std::mem::take(&mut *control.staged.lock().unwrap()), &db)?;
+            stats.duration = run_start.elapsed();
+            write_metrics(&db, &args, &stats)?;
+            return Err(anyhow::anyhow!(msg));
+        }
     }
 
     // Check if retries were exhausted with an error
@@ -217,15 +1041,296 @@ pub fn run(args: Args) -> Result<()> {
                 args.retry_attempts, e
             );
             eprintln!("{}", msg);
-            let _ = logger.log(&msg);
+            let _ = logger.log_error(&msg);
+            // A failed run swaps nothing into place
+            discard_staged_updates(std::mem::take(&mut *control.staged.lock().unwrap()), &db)?;
+            stats.duration = run_start.elapsed();
+            write_metrics(&db, &args, &stats)?;
             return Err(anyhow::anyhow!(msg));
         }
     }
 
+    // Past the transfer, a signal ends the process as usual
+    drop(interruptible);
+
+    apply_staged_updates(
+        std::mem::take(&mut *control.staged.lock().unwrap()),
+        &logger,
+    )?;
+    create_links(&config, &db, &logger)?;
+
+    if daily_budget_exhausted(&config, &db)? {
+        let pending = db.lock().unwrap().pending_count()?;
+        if pending > 0 {
+            let msg = format!(
+                "Daily transfer budget exhausted: {} files left pending for another day",
+                pending
+            );
+            println!("{}", msg);
+            logger.log(&msg)?;
+        }
+    }
+
+    // A cancelled run stops here, leaving cleanup and watching to a later one
+    if db.lock().unwrap().take_cancel_request()? {
+        println!("Sync cancelled.");
+        stats.duration = run_start.elapsed();
+        finish_run(&config, &db, &logger, &args, &stats)?;
+        return Ok(stats);
+    }
+
     if args.delete_extras {
-        run_cleanup(&config, &logger)?;
+        run_cleanup(&config, &db, &logger)?;
+    }
+
+    stats.duration = run_start.elapsed();
+    finish_run(&config, &db, &logger, &args, &stats)?;
+    watch_if_requested(&config, &db, &logger, &args)?;
+    Ok(stats)
+}
+
+/// Transfers in progress in this process, all interrupted by Ctrl-C or SIGTERM
+static RUNNING: Mutex<Vec<Weak<TransferControl>>> = Mutex::new(Vec::new());
+
+/// A transfer registered with the signal handler until dropped
+struct Interruptible(Weak<TransferControl>);
+
+impl Drop for Interruptible {
+    fn drop(&mut self) {
+        RUNNING
+            .lock()
+            .unwrap()
+            .retain(|control| !control.ptr_eq(&self.0));
     }
+}
 
+/// Has Ctrl-C and SIGTERM stop the transfer of `control` at its next check, leaving
+/// the rest of the backlog pending. A second signal, or one with no transfer in
+/// progress, exits at once.
+fn interrupt_on_signal(control: &Arc<TransferControl>) -> Interruptible {
+    static INSTALL: Once = Once::new();
+    INSTALL.call_once(|| {
+        let signalled = AtomicBool::new(false);
+        // A handler installed by an embedding program is left in place
+        let _ = ctrlc::set_handler(move || {
+            let running: Vec<_> = RUNNING
+                .lock()
+                .unwrap()
+                .iter()
+                .filter_map(Weak::upgrade)
+                .collect();
+            if running.is_empty() || signalled.swap(true, Ordering::SeqCst) {
+                std::process::exit(130);
+            }
+            eprintln!("Interrupted: stopping after the current block (again to exit now)");
+            for control in running {
+                control.interrupted.store(true, Ordering::SeqCst);
+            }
+        });
+    });
+    RUNNING.lock().unwrap().push(Arc::downgrade(control));
+    Interruptible(Arc::downgrade(control))
+}
+
+/// Writes the symlinks queued by the scan into the destination and mirrors
+fn create_links(config: &PipelineConfig, db: &Mutex<Database>, logger: &Logger) -> Result<()> {
+    let dest_dirs: Vec<&Path> = std::iter::once(&config.dest_dir)
+        .chain(&config.mirror_dirs)
+        .map(PathBuf::as_path)
+        .collect();
+    let created = create_pending_links(&dest_dirs, db, logger)?;
+    if created > 0 {
+        println!("{} symlinks created or updated.", created);
+    }
+    Ok(())
+}
+
+/// The audit logger configured by `--log`, `--log-utc`, `--error-log` and
+/// `--audit-key-file`
+fn open_logger(args: &Args) -> Result<Logger> {
+    let audit_key = args
+        .audit_key_file
+        .as_deref()
+        .map(read_audit_key)
+        .transpose()?;
+    Logger::with_utc(&args.log, args.log_utc)
+        .with_error_log(args.error_log.as_deref())
+        .with_audit_chain(audit_key)
+}
+
+/// Keeps syncing source changes with `--watch`, until the process is stopped
+fn watch_if_requested(
+    config: &PipelineConfig,
+    db: &Arc<Mutex<Database>>,
+    logger: &Arc<Logger>,
+    args: &Args,
+) -> Result<()> {
+    if !args.watch {
+        return Ok(());
+    }
+    let options = WatchOptions {
+        debounce: Duration::from_millis(args.watch_debounce_ms),
+        delete: args.delete_extras,
+        queue_capacity: args.queue_capacity,
+        ..Default::default()
+    };
+    // Watching writes files too; being killed while at it is not a clean shutdown
+    db.lock().unwrap().set_clean_shutdown(false)?;
+    run_watch(config, db, logger, &options)?;
+    db.lock().unwrap().set_clean_shutdown(true)?;
+    Ok(())
+}
+
+/// Parses the `--checksum-override` values in order
+fn checksum_overrides(args: &Args) -> Result<Vec<(String, HashAlgorithm)>> {
+    args.checksum_override
+        .iter()
+        .map(|s| parse_checksum_override(s))
+        .collect()
+}
+
+/// Prints every file not yet synced with its size, pending reason and failed attempts
+fn list_failures(db: &Database) -> Result<()> {
+    let mut files = db.get_pending_files()?;
+    files.sort_by(|a, b| a.source_path.cmp(&b.source_path));
+    for file in &files {
+        println!(
+            "Pending: {} ({}, reason: {}, failed attempts: {})",
+            file.source_path,
+            format_bytes(file.size),
+            file.pending_reason
+                .map_or("unknown", |reason| reason.as_str()),
+            file.attempts
+        );
+    }
+    println!(
+        "{} files not synced ({}).",
+        files.len(),
+        format_bytes(files.iter().map(|file| file.size).sum())
+    );
+    Ok(())
+}
+
+/// Prints the pending files and, with `--delete-extras`, the extra destination files
+/// that a sync would transfer and delete, with the totals of both
+fn report_dry_run(
+    config: &PipelineConfig,
+    db: &Arc<Mutex<Database>>,
+    logger: &Logger,
+    args: &Args,
+) -> Result<()> {
+    let pending = db.lock().unwrap().get_pending_files()?;
+    for file in &pending {
+        println!(
+            "Would transfer: {:?} ({})",
+            file.source_path,
+            format_bytes(file.size)
+        );
+    }
+    let deletions = if args.delete_extras {
+        list_deletions(config)?
+    } else {
+        Vec::new()
+    };
+    for (path, size) in &deletions {
+        println!("Would delete: {:?} ({})", path, format_bytes(*size));
+    }
+    let msg = format!(
+        "Dry run: {} files ({}) would be transferred, {} files ({}) would be deleted.",
+        pending.len(),
+        format_bytes(pending.iter().map(|file| file.size).sum()),
+        deletions.len(),
+        format_bytes(deletions.iter().map(|(_, size)| size).sum())
+    );
+    println!("{}", msg);
+    logger.log(&msg)?;
+    if let Some(mac) = logger.seal_audit_chain()? {
+        println!("Audit log final MAC: {}", mac);
+    }
+    Ok(())
+}
+
+/// Seals the audit chain, compacts the database if requested, prints per-status
+/// file totals (and the files each destination lacks, with mirrors) and writes the
+/// done file if nothing is left pending
+fn finish_run(
+    config: &PipelineConfig,
+    db: &Arc<Mutex<Database>>,
+    logger: &Logger,
+    args: &Args,
+    stats: &SyncStats,
+) -> Result<()> {
+    db.lock().unwrap().set_clean_shutdown(true)?;
+    if let Some(mac) = logger.seal_audit_chain()? {
+        println!("Audit log final MAC: {}", mac);
+    }
+    let db_guard = db.lock().unwrap();
+    if args.compact_db {
+        db_guard.vacuum()?;
+    }
+    let counts = db_guard.count_by_status()?;
+    let count = |status| counts.get(&status).copied().unwrap_or(0);
+    println!(
+        "Files: {} synced, {} pending.",
+        count(FileStatus::Synced),
+        count(FileStatus::Pending)
+    );
+    if !config.mirror_dirs.is_empty() {
+        for dir in std::iter::once(&config.dest_dir).chain(&config.mirror_dirs) {
+            let pending = db_guard.pending_count_for_dest(dir.to_str().unwrap())?;
+            println!("  {:?}: {} pending", dir, pending);
+        }
+    }
+    if let Some(done_file) = &args.done_file {
+        if count(FileStatus::Pending) == 0 {
+            std::fs::write(
+                done_file,
+                format!(
+                    "files_synced={}\nfiles_pending={}\nblocks_transferred={}\nduration_seconds={:.3}\n",
+                    count(FileStatus::Synced),
+                    count(FileStatus::Pending),
+                    stats.blocks,
+                    stats.duration.as_secs_f64()
+                ),
+            )?;
+        }
+    }
+    drop(db_guard);
+    if let Some(rate) = stats
+        .achieved_rate()
+        .filter(|_| stats.bytes_transferred > 0)
+    {
+        let limit = args
+            .bwlimit
+            .as_deref()
+            .and_then(|limit| parse_bandwidth(limit).ok())
+            .map(|limit| format!(" (--bwlimit {}/s)", format_bytes(limit)))
+            .unwrap_or_default();
+        println!(
+            "Transferred {} in {:.1}s: average {}/s{}",
+            format_bytes(stats.bytes_transferred),
+            stats.transfer_time.as_secs_f64(),
+            format_bytes(rate as u64),
+            limit
+        );
+    }
+    write_metrics(db, args, stats)?;
     println!("Sync completed.");
     Ok(())
 }
+
+/// Writes the run's metrics to `--metrics-file`, if set. The file is replaced in
+/// one step so a collector never reads it half written.
+fn write_metrics(db: &Arc<Mutex<Database>>, args: &Args, stats: &SyncStats) -> Result<()> {
+    let Some(metrics_file) = &args.metrics_file else {
+        return Ok(());
+    };
+    let pending = db.lock().unwrap().pending_count()?;
+    let mut temp_name = metrics_file.as_os_str().to_owned();
+    temp_name.push(".tmp");
+    std::fs::write(&temp_name, stats.prometheus_metrics(pending))
+        .with_context(|| format!("Failed to write metrics file {:?}", metrics_file))?;
+    std::fs::rename(&temp_name, metrics_file)
+        .with_context(|| format!("Failed to write metrics file {:?}", metrics_file))?;
+    Ok(())
+}
@@ -1,8 +1,8 @@
 use anyhow::Result;
-use clap::Parser;
-use hsync::{run, Args};
+use hsync::config::parse_args;
+use hsync::run;
 
 fn main() -> Result<()> {
-    let args = Args::parse();
+    let args = parse_args(std::env::args_os())?;
     run(args)
 }
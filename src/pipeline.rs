@@ -1,59 +1,509 @@
-use crate::db::Database;
-use crate::utils::{format_bytes, Logger};
-use anyhow::{Context, Result};
+use crate::crypto::{
+    encrypted_len, encrypted_offset, has_header, Cipher, NONCE_PREFIX_LEN, SALT_LEN,
+};
+use crate::db::{Database, FileRecord, PendingReason, SyncedHashes};
+use crate::filter::{glob_match, PathFilter};
+use crate::ratelimit::{effective_limit, paced_sleep, SharedLimiter};
+use crate::scan::{read_block, UnicodeForm};
+use crate::utils::{
+    creation_time, effective_mode, file_flags, format_bytes, is_same_file, set_creation_time,
+    set_file_flags, Logger, Semaphore, SemaphorePermit, TransferLog,
+};
+use crate::watchdog::Watchdog;
+use anyhow::{anyhow, Context, Result};
 use blake2::{Blake2b512, Digest as Blake2Digest};
 use clap::ValueEnum;
-use crossbeam_channel::{Receiver, Sender};
+use crossbeam_channel::{bounded, Receiver, RecvTimeoutError, Sender};
 use filetime::{set_file_times, FileTime};
 use indicatif::{ProgressBar, ProgressState, ProgressStyle};
 use md5::Md5;
+use serde::Deserialize;
 use sha1::Sha1;
 use sha2::Sha256;
+use std::collections::{HashMap, HashSet, VecDeque};
 use std::fs::{self, File, OpenOptions};
-use std::io::{Read, Seek, SeekFrom, Write};
-use std::path::PathBuf;
+use std::io::{IsTerminal, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::process::{Child, ChildStdin, Command, Stdio};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
 use std::thread;
 use std::time::{Duration, Instant};
 
-#[derive(Debug, Clone, Copy, ValueEnum)]
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, ValueEnum, Deserialize)]
+#[serde(rename_all = "lowercase")]
 pub enum HashAlgorithm {
     Md5,
     Sha1,
+    #[default]
     Sha256,
     Blake2b,
+    Blake3,
+}
+
+impl HashAlgorithm {
+    /// Length of the hex-encoded digest
+    pub fn hex_len(self) -> usize {
+        match self {
+            HashAlgorithm::Md5 => 32,
+            HashAlgorithm::Sha1 => 40,
+            HashAlgorithm::Sha256 => 64,
+            HashAlgorithm::Blake2b => 128,
+            HashAlgorithm::Blake3 => 64,
+        }
+    }
+
+    /// Algorithm of a hash stored before the database recorded algorithms. The
+    /// algorithms of that time are told apart by digest length; BLAKE3, sharing
+    /// SHA-256's, came later and is never assumed.
+    pub fn of_unlabeled_hash(hash: &str) -> Option<Self> {
+        [Self::Md5, Self::Sha1, Self::Sha256, Self::Blake2b]
+            .into_iter()
+            .find(|algo| algo.hex_len() == hash.len())
+    }
+
+    /// Name as accepted by `--checksum` and stored in the database
+    pub fn name(self) -> &'static str {
+        match self {
+            HashAlgorithm::Md5 => "md5",
+            HashAlgorithm::Sha1 => "sha1",
+            HashAlgorithm::Sha256 => "sha256",
+            HashAlgorithm::Blake2b => "blake2b",
+            HashAlgorithm::Blake3 => "blake3",
+        }
+    }
+}
+
+/// State shared between `run` and the threads of an in-flight transfer
+#[derive(Debug, Default)]
+pub struct TransferControl {
+    /// Stops the consumer at its next check
+    pub interrupted: AtomicBool,
+    /// Holds the consumer between blocks until cleared
+    pub paused: AtomicBool,
+    /// Completed files awaiting the final swap with `delay_updates`
+    pub staged: Mutex<Vec<StagedFile>>,
+    /// Destination hash mismatches found by `verify_dest` across all attempts
+    pub mismatches: AtomicU64,
+}
+
+impl TransferControl {
+    /// Whether the mismatches so far reached `abort_after_mismatches`
+    pub fn mismatch_limit_reached(&self, config: &PipelineConfig) -> bool {
+        config
+            .abort_after_mismatches
+            .is_some_and(|limit| self.mismatches.load(Ordering::SeqCst) >= limit)
+    }
+}
+
+/// A fully written file kept under a temporary name until the final swap
+#[derive(Debug)]
+pub struct StagedFile {
+    pub staged_path: PathBuf,
+    pub dest_path: PathBuf,
+    /// Source file the staged copy was made from, requeued if the swap is discarded
+    pub source_path: PathBuf,
+}
+
+/// Temporary name next to `dest_path`, so the final rename stays on one filesystem
+fn staged_path(dest_path: &Path) -> PathBuf {
+    let name = dest_path
+        .file_name()
+        .map(|n| n.to_string_lossy())
+        .unwrap_or_default();
+    dest_path.with_file_name(format!(".{}.hsync-staged", name))
+}
+
+/// Renames all staged files into place
+pub fn apply_staged_updates(staged: Vec<StagedFile>, logger: &Logger) -> Result<()> {
+    if staged.is_empty() {
+        return Ok(());
+    }
+    let count = staged.len();
+    for file in staged {
+        fs::rename(&file.staged_path, &file.dest_path).with_context(|| {
+            format!(
+                "Failed to move {:?} into place at {:?}",
+                file.staged_path, file.dest_path
+            )
+        })?;
+    }
+    logger.log(&format!("Applied {} staged updates", count))?;
+    Ok(())
+}
+
+/// Runs one producer/consumer pass over the pending backlog through a queue of
+/// `queue_capacity` blocks. Returns the result of each side, producer first.
+pub fn transfer_backlog(
+    config: &PipelineConfig,
+    db: &Arc<Mutex<Database>>,
+    logger: &Arc<Logger>,
+    queue_capacity: usize,
+    control: &Arc<TransferControl>,
+) -> (Result<SyncStats>, Result<SyncStats>) {
+    let (sender, receiver) = bounded::<Block>(queue_capacity);
+
+    let producer_db = db.clone();
+    let producer_logger = logger.clone();
+    let producer_config = config.clone();
+    let producer_handle = thread::spawn(move || -> Result<SyncStats> {
+        run_producer(producer_config, sender, producer_db, producer_logger)
+    });
+
+    let consumer_db = db.clone();
+    let consumer_logger = logger.clone();
+    let consumer_config = config.clone();
+    let consumer_control = control.clone();
+    let consumer_handle = thread::spawn(move || -> Result<SyncStats> {
+        run_consumer(
+            consumer_config,
+            receiver,
+            consumer_db,
+            consumer_logger,
+            consumer_control,
+        )
+    });
+
+    let producer_result = producer_handle.join().unwrap();
+    let consumer_result = consumer_handle.join().unwrap();
+    // A consumer error cuts short the file it was writing, unless it was interrupted
+    if consumer_result.is_err() && !control.interrupted.load(Ordering::SeqCst) {
+        let db = db.lock().unwrap();
+        if let Ok(Some(path)) = db.current_file() {
+            let _ = db.record_failed_attempt(&path);
+        }
+    }
+    (producer_result, consumer_result)
+}
+
+/// Removes staged files after a failed run, leaving the destination untouched.
+/// Their sources were marked synced when staged, so they go back to pending.
+pub fn discard_staged_updates(staged: Vec<StagedFile>, db: &Mutex<Database>) -> Result<()> {
+    let db = db.lock().unwrap();
+    for file in staged {
+        let _ = fs::remove_file(&file.staged_path);
+        db.requeue_interrupted(&file.source_path.to_string_lossy())?;
+    }
+    Ok(())
+}
+
+/// Time spent in each pipeline stage, accumulated when `PipelineConfig::collect_stats` is set
+#[derive(Debug, Clone, Default)]
+pub struct SyncStats {
+    /// Algorithm `hash_time` applies to; `None` when an external checksum command is used
+    pub hash_algorithm: Option<HashAlgorithm>,
+    pub hash_time: Duration,
+    pub read_time: Duration,
+    pub write_time: Duration,
+    /// Time slept to honor the bandwidth limit
+    pub sleep_time: Duration,
+    /// Blocks written to the destination
+    pub blocks: u64,
+    /// Blocks not rewritten because every destination already held them
+    pub unchanged_blocks: u64,
+    /// Bytes written to the destination (at rest, counted once for all mirrors)
+    pub bytes_transferred: u64,
+    /// Files taken from the backlog for transfer, once per attempt
+    pub files_started: u64,
+    /// Files written completely and marked synced
+    pub files_transferred: u64,
+    /// Wall-clock time the consumer spent transferring, pacing sleeps included
+    pub transfer_time: Duration,
+    /// Wall-clock duration of the whole run
+    pub duration: Duration,
+}
+
+impl SyncStats {
+    /// Adds the stage totals of another (partial) run
+    pub fn merge(&mut self, other: &SyncStats) {
+        self.hash_time += other.hash_time;
+        self.read_time += other.read_time;
+        self.write_time += other.write_time;
+        self.sleep_time += other.sleep_time;
+        self.blocks += other.blocks;
+        self.unchanged_blocks += other.unchanged_blocks;
+        self.bytes_transferred += other.bytes_transferred;
+        self.files_started += other.files_started;
+        self.files_transferred += other.files_transferred;
+        self.transfer_time += other.transfer_time;
+    }
+
+    /// Average bytes per second actually written over the transfer time, to check
+    /// against `--bwlimit`
+    pub fn achieved_rate(&self) -> Option<f64> {
+        let secs = self.transfer_time.as_secs_f64();
+        (secs > 0.0).then(|| self.bytes_transferred as f64 / secs)
+    }
+
+    /// Transfer attempts that did not end with the file synced: skipped, rejected
+    /// by verification, timed out or cut short by an error
+    pub fn files_failed(&self) -> u64 {
+        self.files_started.saturating_sub(self.files_transferred)
+    }
+
+    /// The run's totals in the Prometheus text exposition format, e.g. for the
+    /// node_exporter textfile collector
+    pub fn prometheus_metrics(&self, pending_files: u64) -> String {
+        let metrics = [
+            (
+                "hsync_files_transferred_total",
+                "counter",
+                "Files transferred to the destination.",
+                self.files_transferred as f64,
+            ),
+            (
+                "hsync_bytes_transferred_total",
+                "counter",
+                "Bytes written to the destination.",
+                self.bytes_transferred as f64,
+            ),
+            (
+                "hsync_files_failed_total",
+                "counter",
+                "File transfer attempts that did not complete.",
+                self.files_failed() as f64,
+            ),
+            (
+                "hsync_run_duration_seconds",
+                "gauge",
+                "Duration of the run.",
+                self.duration.as_secs_f64(),
+            ),
+            (
+                "hsync_pending_files",
+                "gauge",
+                "Files still pending transfer after the run.",
+                pending_files as f64,
+            ),
+        ];
+        metrics
+            .iter()
+            .map(|(name, kind, help, value)| {
+                format!(
+                    "# HELP {} {}\n# TYPE {} {}\n{} {}\n",
+                    name, help, name, kind, name, value
+                )
+            })
+            .collect()
+    }
+}
+
+/// Starts timing a stage, only when stats are collected
+fn stage_start(config: &PipelineConfig) -> Option<Instant> {
+    config.collect_stats.then(Instant::now)
+}
+
+/// Adds the time since `stage_start` to a stage total
+fn stage_end(start: Option<Instant>, total: &mut Duration) {
+    if let Some(start) = start {
+        *total += start.elapsed();
+    }
 }
 
 #[derive(Debug)]
 pub struct Block {
     pub data: Vec<u8>,
     pub offset: u64,
-    pub dest_path: PathBuf,
+    /// Primary destination first, followed by the mirrors
+    pub dest_paths: Vec<PathBuf>,
     pub source_path: PathBuf, // Needed for DB
     pub atime: i64,
+    /// Source creation time, restored where the platform allows
+    pub created: Option<i64>,
+    /// Why the file was queued; selects the new-file or update bandwidth limit
+    pub pending_reason: Option<PendingReason>,
     pub mtime: i64,
     pub ctime: i64,
     pub permissions: u32,
     pub is_last_block: bool,
     pub file_hash: Option<String>,
     pub file_size: u64,
+    /// Sent without data when the producer gives up on a file after sending some of
+    /// its blocks; the consumer discards what it wrote of that file
+    pub aborted: bool,
+    /// Source file with identical content transferred earlier in the run: this block
+    /// carries no data, and its destinations are copied from that file's destination
+    pub copy_from: Option<PathBuf>,
+    /// `PRESERVED_FILE_FLAGS` of the source, applied once the file is complete;
+    /// `None` unless `preserve_fileflags` is set
+    pub file_flags: Option<u32>,
 }
 
-#[derive(Clone)]
+impl Block {
+    /// Marker telling the consumer that the rest of `source_path` will not follow
+    fn aborted(source_path: &Path, dest_paths: &[PathBuf]) -> Self {
+        Block {
+            data: vec![],
+            offset: 0,
+            dest_paths: dest_paths.to_vec(),
+            source_path: source_path.to_path_buf(),
+            atime: 0,
+            created: None,
+            pending_reason: None,
+            mtime: 0,
+            ctime: 0,
+            permissions: 0,
+            is_last_block: false,
+            file_hash: None,
+            file_size: 0,
+            aborted: true,
+            copy_from: None,
+            file_flags: None,
+        }
+    }
+}
+
+#[derive(Clone, Default)]
 pub struct PipelineConfig {
     pub source_dir: PathBuf,
     pub dest_dir: PathBuf,
+    /// Further destinations receiving the same blocks as `dest_dir`
+    pub mirror_dirs: Vec<PathBuf>,
     pub bw_limit: Option<u64>, // bytes per second
+    /// Limits replacing `bw_limit` for files new to the destination and for updates
+    pub bw_limit_new: Option<u64>,
+    pub bw_limit_update: Option<u64>,
+    /// Ceiling for the rate at which any single file is written, under the limits above
+    pub bw_limit_per_file: Option<u64>,
+    /// Bytes that may be written per local day, counted across runs; files are
+    /// left pending once the day's budget is used up
+    pub daily_budget: Option<u64>,
+    /// Warmup window over which the bandwidth limit ramps up
+    pub bw_ramp: Option<Duration>,
     #[allow(dead_code)]
     pub db_path: String,
     #[allow(dead_code)]
     pub log_path: String,
     pub hash_algo: HashAlgorithm,
+    /// Algorithms replacing `hash_algo` for source paths (relative, `/`-separated)
+    /// matching a glob; the first matching glob wins
+    pub checksum_overrides: Vec<(String, HashAlgorithm)>,
+    /// External command used instead of `hash_algo` when set
+    pub checksum_command: Option<String>,
     pub block_size: usize,
+    /// Block sizes replacing `block_size` for source paths (relative, `/`-separated)
+    /// matching a glob; the first match wins
+    pub block_size_overrides: Vec<(String, usize)>,
+    /// Two-pass cleanup: collect candidates, then re-verify before deleting
+    pub safe_cleanup: bool,
+    /// Treat a source file differing only in letter case as the same file in cleanup
+    pub ignore_case: bool,
+    /// Treat a source file whose name differs only in Unicode normalization as the
+    /// same file in cleanup
+    pub normalize_unicode: UnicodeForm,
+    /// Marker files whose source directories are left out of the sync; cleanup
+    /// leaves the destination copies of those directories alone
+    pub exclude_if_present: Vec<String>,
+    /// `--exclude`/`--include` globs; cleanup leaves excluded destination paths alone
+    pub path_filter: PathFilter,
+    /// Only update files already in the destination, never create new ones (`--existing`)
+    pub existing: bool,
+    /// File receiving the relative path of each transferred file
+    pub transfer_log: Option<String>,
+    /// File through which several processes share `bw_limit` as a global cap
+    pub shared_limit_file: Option<PathBuf>,
+    /// Re-read each written file and store its hash as the destination hash
+    pub verify_dest: bool,
+    /// Check that each written file has the expected size before marking it synced
+    pub verify_size: bool,
+    /// Abort the run, without retrying, once `verify_dest` found this many mismatches
+    pub abort_after_mismatches: Option<u64>,
+    /// File whose content `pause` holds the transfer between blocks
+    pub control_file: Option<PathBuf>,
+    /// Print plain progress lines at this interval instead of the progress bar
+    pub numeric_progress: Option<Duration>,
+    /// Progress bar style replacing the default one, built by `progress_style` from
+    /// `--progress-template`
+    pub progress_style: Option<ProgressStyle>,
+    /// Leave the ETA out of the default progress bar and the backlog message
+    pub progress_no_eta: bool,
+    /// Log a cumulative stats snapshot at this interval
+    pub stats_interval: Option<Duration>,
+    /// Skip hashing files queued only because the destination lacks them
+    pub no_hash_on_copy: bool,
+    /// Write to temporary names and leave the renames to `apply_staged_updates`
+    pub delay_updates: bool,
+    /// Send only the tail of files that grew past a matching destination prefix
+    pub append: bool,
+    /// Stream and write every file in full, disabling the incremental modes above
+    pub whole_file: bool,
+    /// Ask on the terminal before deleting extra files
+    pub confirm_delete: bool,
+    /// Worker threads deleting extra files in parallel (at least one)
+    pub cleanup_threads: usize,
+    /// Restore source directory mtimes on directories that lost files in cleanup
+    pub preserve_dir_times: bool,
+    /// Store a hash of every `block_size` window of each transferred file
+    pub store_block_hashes: bool,
+    /// Transfer content shared by several pending files once, copying the first
+    /// destination file to the others
+    pub dedupe_identical: bool,
+    /// Mirror the immutable and append-only flags of source files (Linux)
+    pub preserve_fileflags: bool,
+    /// Transfer pending files in source path order and record the order in the database
+    pub ordered: bool,
+    /// Transfer pending files one source directory after another
+    pub group_by_dir: bool,
+    /// Recompute every hash instead of reusing stored ones
+    pub rehash: bool,
+    /// Compare each block with the destination and skip writing it when identical
+    pub only_write_changed: bool,
+    /// Give written files the source permission mode
+    pub perms_matter: bool,
+    /// Encrypts every block before it is written (`--encrypt`)
+    pub cipher: Option<Cipher>,
+    /// Abort instead of skipping the files of an unreadable source directory
+    pub fail_on_inaccessible: bool,
+    /// Caps destination files open at once, whether written, read back or copied,
+    /// including writes still running after the watchdog gave up on them
+    /// (`--max-open-files`)
+    pub open_files: Option<Arc<Semaphore>>,
+    /// Abandons a file, leaving it pending, when a block read or write makes no
+    /// progress in time (`--file-timeout`)
+    pub watchdog: Option<Arc<Watchdog>>,
+    /// Accumulate per-stage timings into the returned `SyncStats`
+    pub collect_stats: bool,
+}
+
+impl PipelineConfig {
+    /// Algorithm hashing `source_path`: the first matching override, else `hash_algo`
+    pub fn hash_algo_for(&self, source_path: &Path) -> HashAlgorithm {
+        let relative = self.glob_path(source_path);
+        self.checksum_overrides
+            .iter()
+            .find(|(glob, _)| glob_match(glob, &relative))
+            .map_or(self.hash_algo, |(_, algo)| *algo)
+    }
+
+    /// Block size reading and sending `source_path`: the first matching override,
+    /// else `block_size`
+    pub fn block_size_for(&self, source_path: &Path) -> usize {
+        let relative = self.glob_path(source_path);
+        self.block_size_overrides
+            .iter()
+            .find(|(glob, _)| glob_match(glob, &relative))
+            .map_or(self.block_size, |(_, size)| *size)
+    }
+
+    /// Waits for a slot to open a destination file under `open_files`, held until
+    /// the returned permit is dropped
+    fn open_slot(&self) -> Option<SemaphorePermit<'_>> {
+        self.open_files.as_deref().map(Semaphore::acquire)
+    }
+
+    /// `source_path` relative to the source directory with `/` separators, as
+    /// override globs see it
+    fn glob_path(&self, source_path: &Path) -> String {
+        let relative = source_path
+            .strip_prefix(&self.source_dir)
+            .unwrap_or(source_path);
+        relative.to_string_lossy().replace('\\', "/")
+    }
 }
 
-trait DynDigest: Send {
+pub(crate) trait DynDigest: Send {
     fn update(&mut self, data: &[u8]);
-    fn finalize_hex(&self) -> String;
+    fn finalize_hex(&mut self) -> Result<String>;
 }
 
 struct Md5Wrapper(Md5);
@@ -61,8 +511,8 @@ impl DynDigest for Md5Wrapper {
     fn update(&mut self, data: &[u8]) {
         self.0.update(data);
     }
-    fn finalize_hex(&self) -> String {
-        hex::encode(self.0.clone().finalize())
+    fn finalize_hex(&mut self) -> Result<String> {
+        Ok(hex::encode(self.0.clone().finalize()))
     }
 }
 
@@ -71,8 +521,8 @@ impl DynDigest for Sha1Wrapper {
     fn update(&mut self, data: &[u8]) {
         self.0.update(data);
     }
-    fn finalize_hex(&self) -> String {
-        hex::encode(self.0.clone().finalize())
+    fn finalize_hex(&mut self) -> Result<String> {
+        Ok(hex::encode(self.0.clone().finalize()))
     }
 }
 
@@ -81,8 +531,8 @@ impl DynDigest for Sha256Wrapper {
     fn update(&mut self, data: &[u8]) {
         self.0.update(data);
     }
-    fn finalize_hex(&self) -> String {
-        hex::encode(self.0.clone().finalize())
+    fn finalize_hex(&mut self) -> Result<String> {
+        Ok(hex::encode(self.0.clone().finalize()))
     }
 }
 
@@ -91,17 +541,566 @@ impl DynDigest for Blake2bWrapper {
     fn update(&mut self, data: &[u8]) {
         Blake2Digest::update(&mut self.0, data);
     }
-    fn finalize_hex(&self) -> String {
-        hex::encode(self.0.clone().finalize())
+    fn finalize_hex(&mut self) -> Result<String> {
+        Ok(hex::encode(self.0.clone().finalize()))
+    }
+}
+
+struct Blake3Wrapper(blake3::Hasher);
+impl DynDigest for Blake3Wrapper {
+    fn update(&mut self, data: &[u8]) {
+        self.0.update(data);
+    }
+    fn finalize_hex(&mut self) -> Result<String> {
+        Ok(self.0.finalize().to_hex().to_string())
+    }
+}
+
+/// Yields a hash stored for an unchanged source instead of hashing the bytes again
+struct StoredHash(String);
+impl DynDigest for StoredHash {
+    fn update(&mut self, _data: &[u8]) {}
+    fn finalize_hex(&mut self) -> Result<String> {
+        Ok(self.0.clone())
+    }
+}
+
+/// The stored hash of a record, if the source still has the recorded mtime and
+/// size, the hash comes from the algorithm now selected for the file (judged by
+/// its length when no algorithm was recorded), and rehashing was not requested
+fn reusable_hash(
+    record: &FileRecord,
+    mtime: i64,
+    size: u64,
+    config: &PipelineConfig,
+) -> Option<String> {
+    if config.rehash || config.checksum_command.is_some() {
+        return None;
+    }
+    let hash = record.hash.as_ref()?;
+    let algo = config.hash_algo_for(Path::new(&record.source_path));
+    let same_algo = match &record.hash_algo {
+        Some(name) => name == algo.name(),
+        None => HashAlgorithm::of_unlabeled_hash(hash) == Some(algo),
+    };
+    let unchanged = record.modified_date == mtime && record.size == size;
+    (unchanged && same_algo).then(|| hash.clone())
+}
+
+/// Content hashes of the pending files sharing their size with another pending file,
+/// keyed by source path: stored or cached hashes when still valid, otherwise computed
+/// (and cached). Files that cannot be hashed are left out.
+fn shared_content_hashes(
+    pending_files: &[FileRecord],
+    config: &PipelineConfig,
+    db: &Mutex<Database>,
+    logger: &Logger,
+) -> Result<HashMap<String, String>> {
+    let mut by_size: HashMap<u64, Vec<&FileRecord>> = HashMap::new();
+    for record in pending_files.iter().filter(|record| record.size > 0) {
+        by_size.entry(record.size).or_default().push(record);
+    }
+    let mut hashes = HashMap::new();
+    for record in by_size
+        .into_values()
+        .filter(|group| group.len() > 1)
+        .flatten()
+    {
+        let (mtime, size) = (record.modified_date, record.size);
+        let source_path = Path::new(&record.source_path);
+        let algo = config.hash_algo_for(source_path);
+        let mut hash = reusable_hash(record, mtime, size, config);
+        if hash.is_none() && !config.rehash {
+            hash = db
+                .lock()
+                .unwrap()
+                .cached_hash(&record.source_path, mtime, size, algo.name())?;
+        }
+        let hash = match hash {
+            Some(hash) => hash,
+            None => match hash_file_with(source_path, algo, config) {
+                Ok(hash) => {
+                    if config.checksum_command.is_none() {
+                        db.lock().unwrap().cache_hash(
+                            &record.source_path,
+                            mtime,
+                            size,
+                            algo.name(),
+                            &hash,
+                        )?;
+                    }
+                    hash
+                }
+                Err(e) => {
+                    let _ = logger.log_error(&format!(
+                        "Not deduplicating (checksum error): {:?} - {}",
+                        source_path, e
+                    ));
+                    continue;
+                }
+            },
+        };
+        hashes.insert(record.source_path.clone(), hash);
+    }
+    Ok(hashes)
+}
+
+/// Hasher for a pending file: its stored hash when still valid, none when hashing
+/// is skipped, otherwise a fresh checksum
+fn file_hasher(
+    record: &FileRecord,
+    mtime: i64,
+    size: u64,
+    config: &PipelineConfig,
+    db: &Mutex<Database>,
+) -> Result<Option<Box<dyn DynDigest>>> {
+    // An unchanged source (e.g. pending only because the destination is
+    // missing) keeps its stored hash and is streamed without re-hashing
+    if let Some(hash) = reusable_hash(record, mtime, size, config) {
+        return Ok(Some(Box::new(StoredHash(hash))));
+    }
+    // So does one hashed before under the same mtime and size, e.g. by an earlier
+    // run that stored no hash in its file record
+    if !config.rehash && config.checksum_command.is_none() {
+        let algo = config.hash_algo_for(Path::new(&record.source_path));
+        let cached =
+            db.lock()
+                .unwrap()
+                .cached_hash(&record.source_path, mtime, size, algo.name())?;
+        if let Some(hash) = cached {
+            return Ok(Some(Box::new(StoredHash(hash))));
+        }
+    }
+    // With no_hash_on_copy, a copy to a missing destination is not hashed at all
+    if config.no_hash_on_copy && record.pending_reason == Some(PendingReason::DestMissing) {
+        return Ok(None);
+    }
+    Ok(Some(match &config.checksum_command {
+        Some(command) => Box::new(CommandHasher::spawn(command)?),
+        None => create_hasher(config.hash_algo_for(Path::new(&record.source_path))),
+    }))
+}
+
+/// Runs `call` for the transfer of `source_path` under the watchdog, if any,
+/// returning None if the watchdog gave up on the file. A call given up on hands its
+/// result to `undo` once it returns.
+fn watched<T: Send + 'static>(
+    config: &PipelineConfig,
+    source_path: &Path,
+    call: impl FnOnce(&dyn Fn() -> bool) -> T + Send + 'static,
+    undo: impl FnOnce(T) + Send + 'static,
+) -> Option<T> {
+    match &config.watchdog {
+        Some(watchdog) => watchdog.run(source_path, call, undo),
+        None => Some(call(&|| false)),
+    }
+}
+
+/// Undoes a block write that returned after the watchdog gave up on its file, so
+/// that it cannot land under a later attempt: each copy is cut back to the block's
+/// offset, or removed when the block started it
+fn undo_late_write(
+    block: &Block,
+    write_paths: &[PathBuf],
+    open_files: Option<&Semaphore>,
+    logger: &Logger,
+) {
+    for path in write_paths {
+        let _permit = open_files.map(Semaphore::acquire);
+        let result = if block.offset == 0 || block.copy_from.is_some() {
+            fs::remove_file(path)
+        } else {
+            OpenOptions::new().write(true).open(path).and_then(|file| {
+                if file.metadata()?.len() > block.offset {
+                    file.set_len(block.offset)?;
+                }
+                Ok(())
+            })
+        };
+        match result {
+            Ok(()) => {
+                let _ = logger.log_error(&format!(
+                    "Undid late write (transfer of {:?} given up on): {:?}",
+                    block.source_path, path
+                ));
+            }
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+            Err(e) => {
+                let _ =
+                    logger.log_error(&format!("Failed to undo late write to {:?}: {}", path, e));
+            }
+        }
+    }
+}
+
+/// Reports a file given up on by `--file-timeout`; it stays pending
+fn abandon_file(source_path: &Path, config: &PipelineConfig, logger: &Logger) {
+    let timeout = config
+        .watchdog
+        .as_ref()
+        .map_or(0.0, |watchdog| watchdog.timeout().as_secs_f64());
+    let msg = format!(
+        "Abandoned (no progress within {}s): {:?}",
+        timeout, source_path
+    );
+    eprintln!("{}", msg);
+    let _ = logger.log_error(&msg);
+}
+
+/// Counts a failed attempt against a file the consumer gives up on; it stays pending
+/// and is no longer the file in progress
+fn fail_file(db: &Mutex<Database>, source_path: &Path) -> Result<()> {
+    let db = db.lock().unwrap();
+    db.record_failed_attempt(source_path.to_str().unwrap())?;
+    db.set_current_file(None)?;
+    Ok(())
+}
+
+/// Undoes the writes of a file the producer gave up on midway, so that no partial
+/// copy passes for a complete one: each destination written from `start` on is cut
+/// back to that length, or removed when written from the beginning (always the case
+/// for staged copies). Encrypted files are kept, as their recorded progress lets the
+/// next run resume them.
+fn discard_partial(block: &Block, start: u64, config: &PipelineConfig, logger: &Logger) {
+    if config.cipher.is_some() {
+        return;
+    }
+    for dest_path in &block.dest_paths {
+        let path = if config.delay_updates {
+            staged_path(dest_path)
+        } else {
+            dest_path.clone()
+        };
+        let _permit = config.open_slot();
+        let result = if start == 0 {
+            fs::remove_file(&path)
+        } else {
+            OpenOptions::new()
+                .write(true)
+                .open(&path)
+                .and_then(|file| file.set_len(start))
+        };
+        match result {
+            Ok(()) => {
+                let _ = logger.log_error(&format!(
+                    "Discarded partial copy (transfer of {:?} stopped midway): {:?}",
+                    block.source_path, path
+                ));
+            }
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+            Err(e) => {
+                let _ =
+                    logger.log_error(&format!("Failed to discard partial copy {:?}: {}", path, e));
+            }
+        }
+    }
+}
+
+/// Hashes a stream in fixed `block_size` windows, whatever the read sizes
+struct BlockHashes {
+    algo: HashAlgorithm,
+    block_size: usize,
+    current: Box<dyn DynDigest>,
+    current_len: usize,
+    hashes: Vec<String>,
+}
+
+impl BlockHashes {
+    fn new(algo: HashAlgorithm, block_size: usize) -> Self {
+        Self {
+            algo,
+            block_size,
+            current: create_hasher(algo),
+            current_len: 0,
+            hashes: Vec::new(),
+        }
+    }
+
+    fn update(&mut self, mut data: &[u8]) -> Result<()> {
+        while !data.is_empty() {
+            let len = (self.block_size - self.current_len).min(data.len());
+            self.current.update(&data[..len]);
+            self.current_len += len;
+            data = &data[len..];
+            if self.current_len == self.block_size {
+                self.finish_window()?;
+            }
+        }
+        Ok(())
+    }
+
+    fn finish_window(&mut self) -> Result<()> {
+        let mut window = std::mem::replace(&mut self.current, create_hasher(self.algo));
+        self.hashes.push(window.finalize_hex()?);
+        self.current_len = 0;
+        Ok(())
+    }
+
+    /// Hashes of all windows, the last one possibly short
+    fn finish(mut self) -> Result<Vec<String>> {
+        if self.current_len > 0 {
+            self.finish_window()?;
+        }
+        Ok(self.hashes)
+    }
+}
+
+/// Hashes the remaining windows and stores the complete list for the file
+fn store_block_hashes(
+    block_hashes: BlockHashes,
+    source_path: &Path,
+    db: &std::sync::Mutex<Database>,
+) -> Result<()> {
+    let block_size = block_hashes.block_size as u64;
+    let hashes = block_hashes.finish()?;
+    db.lock()
+        .unwrap()
+        .replace_block_hashes(source_path.to_str().unwrap(), block_size, &hashes)?;
+    Ok(())
+}
+
+/// Length of the prefix `--append` may keep: every destination must have the
+/// same non-empty length, shorter than the source
+fn appendable_length(dest_paths: &[PathBuf], size: u64) -> Option<u64> {
+    let len = fs::metadata(&dest_paths[0]).ok()?.len();
+    let same_len = dest_paths
+        .iter()
+        .all(|path| fs::metadata(path).is_ok_and(|m| m.is_file() && m.len() == len));
+    (same_len && len > 0 && len < size).then_some(len)
+}
+
+/// Key salt, nonce prefix and committed plaintext offset recorded for a file whose
+/// `--encrypt` transfer was interrupted
+fn encryption_progress(
+    db: &Mutex<Database>,
+    source_path: &Path,
+) -> Option<([u8; SALT_LEN], [u8; NONCE_PREFIX_LEN], u64)> {
+    let (salt, nonce, offset) = db
+        .lock()
+        .unwrap()
+        .get_encryption_progress(source_path.to_str()?)
+        .ok()??;
+    Some((
+        hex::decode(salt).ok()?.try_into().ok()?,
+        hex::decode(nonce).ok()?.try_into().ok()?,
+        offset,
+    ))
+}
+
+/// Plaintext length an interrupted `--encrypt` transfer may continue from: the
+/// source is unchanged since the scan, and every destination still starts with
+/// the file's header and holds the blocks sealed up to that length
+fn encrypted_resume_length(
+    record: &FileRecord,
+    dest_paths: &[PathBuf],
+    size: u64,
+    mtime: i64,
+    config: &PipelineConfig,
+    db: &Mutex<Database>,
+) -> Option<u64> {
+    if config.cipher.is_none() || config.delay_updates || config.whole_file {
+        return None;
+    }
+    if record.size != size || record.modified_date != mtime {
+        return None;
+    }
+    let source_path = Path::new(&record.source_path);
+    let (salt, prefix, offset) = encryption_progress(db, source_path)?;
+    let block_size = config.block_size_for(source_path);
+    if offset == 0 || offset >= size || offset % block_size as u64 != 0 {
+        return None;
+    }
+    let at_rest = encrypted_offset(offset, offset / block_size as u64);
+    dest_paths
+        .iter()
+        .all(|path| {
+            has_header(path, block_size, &salt, &prefix)
+                && fs::metadata(path).is_ok_and(|m| m.len() >= at_rest)
+        })
+        .then_some(offset)
+}
+
+/// Moves `source` past its first `len` bytes, reading them only to feed `file_hasher`
+fn skip_prefix(
+    source: &mut File,
+    len: u64,
+    config: &PipelineConfig,
+    file_hasher: &mut Option<Box<dyn DynDigest>>,
+) -> Result<()> {
+    let Some(hasher) = file_hasher.as_mut() else {
+        source.seek(SeekFrom::Start(len))?;
+        return Ok(());
+    };
+    let mut buffer = vec![0u8; config.block_size];
+    let mut remaining = len;
+    while remaining > 0 {
+        let chunk = &mut buffer[..remaining.min(config.block_size as u64) as usize];
+        source.read_exact(chunk)?;
+        hasher.update(chunk);
+        remaining -= chunk.len() as u64;
+    }
+    Ok(())
+}
+
+/// Whether the first `len` bytes of `source` match every destination, compared by
+/// hash. The source bytes read are also fed to `file_hasher`.
+fn prefix_matches(
+    source: &mut File,
+    dest_paths: &[PathBuf],
+    len: u64,
+    config: &PipelineConfig,
+    file_hasher: &mut Option<Box<dyn DynDigest>>,
+) -> Result<bool> {
+    let mut buffer = vec![0u8; config.block_size];
+    let mut source_hasher = create_hasher(config.hash_algo);
+    let mut remaining = len;
+    while remaining > 0 {
+        let chunk = &mut buffer[..remaining.min(config.block_size as u64) as usize];
+        source.read_exact(chunk)?;
+        source_hasher.update(chunk);
+        if let Some(hasher) = file_hasher.as_mut() {
+            hasher.update(chunk);
+        }
+        remaining -= chunk.len() as u64;
+    }
+    let source_hash = source_hasher.finalize_hex()?;
+
+    for dest_path in dest_paths {
+        let _permit = config.open_slot();
+        let mut dest = File::open(dest_path)?.take(len);
+        let mut dest_hasher = create_hasher(config.hash_algo);
+        loop {
+            let bytes_read = dest.read(&mut buffer)?;
+            if bytes_read == 0 {
+                break;
+            }
+            dest_hasher.update(&buffer[..bytes_read]);
+        }
+        if dest_hasher.finalize_hex()? != source_hash {
+            return Ok(false);
+        }
+    }
+    Ok(true)
+}
+
+/// Hashes by piping file bytes to an external command's stdin; the trimmed
+/// stdout is the hash. Runs the command through the platform shell.
+struct CommandHasher {
+    child: Child,
+    stdin: Option<ChildStdin>,
+    // Stdout is drained on a thread so a chatty command cannot block our writes
+    stdout: Option<thread::JoinHandle<std::io::Result<String>>>,
+    write_error: Option<std::io::Error>,
+}
+
+impl CommandHasher {
+    fn spawn(command: &str) -> Result<Self> {
+        #[cfg(unix)]
+        let mut cmd = Command::new("sh");
+        #[cfg(unix)]
+        cmd.arg("-c");
+        #[cfg(not(unix))]
+        let mut cmd = Command::new("cmd");
+        #[cfg(not(unix))]
+        cmd.arg("/C");
+
+        let mut child = cmd
+            .arg(command)
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .spawn()
+            .with_context(|| format!("Failed to spawn checksum command '{}'", command))?;
+        let stdin = child.stdin.take();
+        let mut stdout = child.stdout.take().unwrap();
+        let reader = thread::spawn(move || {
+            let mut output = String::new();
+            stdout.read_to_string(&mut output)?;
+            Ok(output)
+        });
+        Ok(Self {
+            child,
+            stdin,
+            stdout: Some(reader),
+            write_error: None,
+        })
+    }
+}
+
+impl DynDigest for CommandHasher {
+    fn update(&mut self, data: &[u8]) {
+        if self.write_error.is_some() {
+            return;
+        }
+        if let Some(stdin) = self.stdin.as_mut() {
+            if let Err(e) = stdin.write_all(data) {
+                self.write_error = Some(e);
+            }
+        }
+    }
+
+    fn finalize_hex(&mut self) -> Result<String> {
+        // Closing stdin signals end of input to the command
+        drop(self.stdin.take());
+        let output = self
+            .stdout
+            .take()
+            .ok_or_else(|| anyhow!("Checksum command already finalized"))?
+            .join()
+            .map_err(|_| anyhow!("Checksum command reader panicked"))??;
+        let status = self.child.wait()?;
+        if let Some(e) = self.write_error.take() {
+            return Err(anyhow!("Failed to write to checksum command: {}", e));
+        }
+        if !status.success() {
+            return Err(anyhow!("Checksum command failed: {}", status));
+        }
+        let hash = output.trim();
+        if hash.is_empty() {
+            return Err(anyhow!("Checksum command produced no output"));
+        }
+        Ok(hash.to_string())
+    }
+}
+
+impl Drop for CommandHasher {
+    /// A command not waited on, as the hash was abandoned or failed midway, is
+    /// killed and reaped rather than left running or as a zombie
+    fn drop(&mut self) {
+        if let Ok(None) = self.child.try_wait() {
+            let _ = self.child.kill();
+            let _ = self.child.wait();
+        }
     }
 }
 
-fn create_hasher(algo: HashAlgorithm) -> Box<dyn DynDigest> {
+/// Input for the known-answer hash vectors below
+pub(crate) const HASH_KNOWN_ANSWER_INPUT: &[u8] = b"hello";
+
+/// Known-answer vectors for each algorithm, used by tests and `selftest`
+pub(crate) const HASH_KNOWN_ANSWERS: [(HashAlgorithm, &str); 5] = [
+    (HashAlgorithm::Md5, "5d41402abc4b2a76b9719d911017c592"),
+    (HashAlgorithm::Sha1, "aaf4c61ddcc5e8a2dabede0f3b482cd9aea9434d"),
+    (
+        HashAlgorithm::Sha256,
+        "2cf24dba5fb0a30e26e83b2ac5b9e29e1b161e5c1fa7425e73043362938b9824",
+    ),
+    (
+        HashAlgorithm::Blake2b,
+        "e4cfa39a3d37be31c59609e807970799caa68a19bfaa15135f165085e01d41a65ba1e1b146aeb6bd0092b49eac214c103ccfa3a365954bbbe52f74a2b3620c94",
+    ),
+    (
+        HashAlgorithm::Blake3,
+        "ea8f163db38682925e4491c5e58d4bb3506ef8c14eb78a86e908c5624a67200f",
+    ),
+];
+
+pub(crate) fn create_hasher(algo: HashAlgorithm) -> Box<dyn DynDigest> {
     match algo {
         HashAlgorithm::Md5 => Box::new(Md5Wrapper(Md5::new())),
         HashAlgorithm::Sha1 => Box::new(Sha1Wrapper(Sha1::new())),
         HashAlgorithm::Sha256 => Box::new(Sha256Wrapper(Sha256::new())),
         HashAlgorithm::Blake2b => Box::new(Blake2bWrapper(Blake2b512::new())),
+        HashAlgorithm::Blake3 => Box::new(Blake3Wrapper(blake3::Hasher::new())),
     }
 }
 
@@ -126,80 +1125,437 @@ fn format_duration(duration: Duration) -> String {
     }
 }
 
-/// Producer that reads files from the database backlog (pending files).
-pub fn run_producer(
-    config: PipelineConfig,
-    sender: Sender<Block>,
-    db: std::sync::Arc<std::sync::Mutex<Database>>,
-    logger: std::sync::Arc<Logger>,
-) -> Result<()> {
-    let mut total_bytes_sent = 0u64;
-    let mut files_transferred = 0u64;
-    let transfer_start = Instant::now();
+/// How often a paused consumer re-checks whether it may continue
+const PAUSE_POLL_INTERVAL: Duration = Duration::from_millis(500);
 
-    // Get pending files and total bytes from database
-    let (pending_files, total_pending_bytes) = {
-        let db_guard = db.lock().unwrap();
-        (
-            db_guard.get_pending_files()?,
-            db_guard.pending_total_bytes()?,
-        )
+/// Hashes a whole file with the configured algorithm or checksum command
+pub fn hash_file(path: &Path, config: &PipelineConfig) -> Result<String> {
+    hash_file_with(path, config.hash_algo, config)
+}
+
+/// Hashes a whole file with `algo`, or with the checksum command when one is set
+pub fn hash_file_with(path: &Path, algo: HashAlgorithm, config: &PipelineConfig) -> Result<String> {
+    let mut hasher = match &config.checksum_command {
+        Some(command) => Box::new(CommandHasher::spawn(command)?) as Box<dyn DynDigest>,
+        None => create_hasher(algo),
     };
+    let mut file = File::open(path)?;
+    let mut buffer = vec![0u8; config.block_size];
+    loop {
+        let bytes_read = file.read(&mut buffer)?;
+        if bytes_read == 0 {
+            break;
+        }
+        hasher.update(&buffer[..bytes_read]);
+    }
+    hasher.finalize_hex()
+}
 
-    let total_files = pending_files.len();
-    if total_files == 0 {
-        println!("No files to transfer.");
-        return Ok(());
+/// Finalizes the file hash, logging and returning `None` if it failed
+fn finalize_hash(
+    hasher: &mut dyn DynDigest,
+    logger: &Logger,
+    source_path: &Path,
+) -> Option<String> {
+    match hasher.finalize_hex() {
+        Ok(hash) => Some(hash),
+        Err(e) => {
+            let _ = logger.log_error(&format!(
+                "Skipping (checksum error): {:?} - {}",
+                source_path, e
+            ));
+            None
+        }
     }
+}
 
-    println!(
-        "Transferring {} files ({})...",
-        total_files,
-        format_bytes(total_pending_bytes)
-    );
+/// Number of repeatedly failing files named when a retry starts
+const RETRY_SAMPLE_SIZE: usize = 5;
 
-    // Per-file progress bar for ETA and bandwidth display
-    let pb = ProgressBar::new(0);
-    pb.set_style(
-        ProgressStyle::default_bar()
-            .with_key(
-                "eta_formatted",
-                |state: &ProgressState, w: &mut dyn std::fmt::Write| {
-                    write!(w, "{}", format_duration(state.eta())).unwrap()
-                },
-            )
-            .template("{spinner:.green} {msg}\n[{bar:40.cyan/blue}] {bytes}/{total_bytes} ({bytes_per_sec}, ETA: {eta_formatted})")?
-            .progress_chars("=>-"),
-    );
+/// Logs how many pending files survived earlier attempts, naming the most stubborn ones
+fn log_retried_files(pending_files: &[FileRecord], logger: &Logger) {
+    let mut retried: Vec<&FileRecord> = pending_files.iter().filter(|f| f.attempts > 0).collect();
+    if retried.is_empty() {
+        return;
+    }
+    retried.sort_by_key(|f| std::cmp::Reverse(f.attempts));
+    let sample: Vec<String> = retried
+        .iter()
+        .take(RETRY_SAMPLE_SIZE)
+        .map(|f| format!("{} (failed attempts: {})", f.source_path, f.attempts))
+        .collect();
+    let _ = logger.log_error(&format!(
+        "Retrying {} files still pending after earlier attempts, e.g. {}",
+        retried.len(),
+        sample.join(", ")
+    ));
+}
 
-    for file_record in pending_files {
-        let source_path = PathBuf::from(&file_record.source_path);
-        let dest_path = PathBuf::from(&file_record.dest_path);
+/// How often the producer looks for files added to the backlog while it runs
+const BACKLOG_REFRESH_INTERVAL: Duration = Duration::from_secs(60);
 
-        // Compute relative path for display
-        let relative_path = source_path
-            .strip_prefix(&config.source_dir)
-            .unwrap_or(&source_path);
+/// Pending files handed out by the producer, topped up with files that became
+/// pending after the run started (e.g. found by a scan still in progress)
+struct Backlog {
+    queue: VecDeque<FileRecord>,
+    /// Source paths already handed out or queued this run
+    seen: HashSet<String>,
+    last_refresh: Instant,
+    /// Files and bytes known to the run so far
+    files: usize,
+    bytes: u64,
+}
 
-        // Get fresh metadata from source (file may have changed since scan)
-        let metadata = match fs::metadata(&source_path) {
-            Ok(m) => m,
-            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
-                // File no longer exists in source - skip but don't remove from backlog
-                let _ = logger.log(&format!(
-                    "Skipping (source file no longer exists): {:?}",
-                    source_path
+impl Backlog {
+    fn new(pending_files: Vec<FileRecord>, bytes: u64) -> Self {
+        Self {
+            seen: pending_files
+                .iter()
+                .map(|f| f.source_path.clone())
+                .collect(),
+            files: pending_files.len(),
+            queue: pending_files.into(),
+            last_refresh: Instant::now(),
+            bytes,
+        }
+    }
+
+    /// Returns the next file to transfer, first re-querying the database when the
+    /// queue ran dry or the refresh interval has passed
+    fn next(
+        &mut self,
+        db: &Mutex<Database>,
+        config: &PipelineConfig,
+        logger: &Logger,
+    ) -> Result<Option<FileRecord>> {
+        if self.queue.is_empty() || self.last_refresh.elapsed() >= BACKLOG_REFRESH_INTERVAL {
+            self.refresh(db, config, logger)?;
+        }
+        Ok(self.queue.pop_front())
+    }
+
+    /// Queues pending files not seen yet this run
+    fn refresh(
+        &mut self,
+        db: &Mutex<Database>,
+        config: &PipelineConfig,
+        logger: &Logger,
+    ) -> Result<()> {
+        self.last_refresh = Instant::now();
+        let db = db.lock().unwrap();
+        let pending_files = pending_files(&db, config)?;
+        let queued = self.queue.len();
+        for file in pending_files {
+            if self.seen.insert(file.source_path.clone()) {
+                self.bytes += file.size;
+                self.queue.push_back(file);
+            }
+        }
+        let added = self.queue.len() - queued;
+        if config.ordered && added > 0 {
+            db.append_transfer_plan(self.queue.range(queued..).map(|f| f.source_path.as_str()))?;
+        }
+        if added > 0 {
+            self.files += added;
+            let _ = logger.log(&format!(
+                "Picked up {} files added to the backlog during the run",
+                added
+            ));
+        }
+        Ok(())
+    }
+}
+
+/// Pending files, clustered by directory when `group_by_dir`, otherwise sorted by
+/// source path when `ordered`. Both orders are deterministic. Files recorded before
+/// `--exclude` left them out, or with no destination copy under `--existing`, are
+/// forgotten rather than transferred, and files whose destination is the source
+/// itself wait for the next scan.
+fn pending_files(db: &Database, config: &PipelineConfig) -> Result<Vec<FileRecord>> {
+    let mut files = if config.group_by_dir {
+        db.get_pending_files_by_dir()?
+    } else if config.ordered {
+        db.get_pending_files_ordered()?
+    } else {
+        db.get_pending_files()?
+    };
+    files.retain(|file| file.pending_reason != Some(PendingReason::SameFile));
+    let mut excluded = Vec::new();
+    files.retain(|file| {
+        let is_excluded = Path::new(&file.source_path)
+            .strip_prefix(&config.source_dir)
+            .is_ok_and(|relative_path| config.path_filter.is_excluded(relative_path))
+            || (config.existing && !Path::new(&file.dest_path).exists());
+        if is_excluded {
+            excluded.push(file.source_path.clone());
+        }
+        !is_excluded
+    });
+    for source_path in excluded {
+        db.remove_file(&source_path)?;
+    }
+    Ok(files)
+}
+
+/// Source directories that could not be read during the transfer, reported once
+/// each instead of once per file
+#[derive(Default)]
+struct InaccessibleDirs(HashSet<PathBuf>);
+
+impl InaccessibleDirs {
+    /// Whether `source_path` failed with `error` because a directory above it cannot
+    /// be read. The first such file reports the outermost unreadable directory below
+    /// the source root, or fails with `fail_on_inaccessible`.
+    fn check(
+        &mut self,
+        source_path: &Path,
+        error: &std::io::Error,
+        config: &PipelineConfig,
+        logger: &Logger,
+    ) -> Result<bool> {
+        if self.0.iter().any(|dir| source_path.starts_with(dir)) {
+            return Ok(true);
+        }
+        let Some(dir) = source_path
+            .ancestors()
+            .skip(1)
+            .take_while(|dir| dir.starts_with(&config.source_dir) && fs::read_dir(dir).is_err())
+            .last()
+        else {
+            return Ok(false);
+        };
+        if config.fail_on_inaccessible {
+            return Err(anyhow!(
+                "Source directory inaccessible: {:?} - {}",
+                dir,
+                error
+            ));
+        }
+        logger.log_error(&format!(
+            "Skipping directory (inaccessible): {:?} - {}",
+            dir, error
+        ))?;
+        self.0.insert(dir.to_path_buf());
+        Ok(true)
+    }
+}
+
+/// Interval of the plain progress lines printed instead of the bar off a terminal
+const PLAIN_PROGRESS_INTERVAL: Duration = Duration::from_secs(10);
+
+/// Default progress bar: file status line, then bytes, rate and ETA of the file
+const PROGRESS_TEMPLATE: &str = "{spinner:.green} {msg}\n[{bar:40.cyan/blue}] {bytes}/{total_bytes} ({bytes_per_sec}, ETA: {eta_formatted})";
+/// `--progress-no-eta` preset: the default bar without the ETA
+const PROGRESS_TEMPLATE_NO_ETA: &str =
+    "{spinner:.green} {msg}\n[{bar:40.cyan/blue}] {bytes}/{total_bytes} ({bytes_per_sec})";
+
+/// Style of the producer's progress bar: `template` if given, otherwise the default
+/// or, with `no_eta`, its preset without ETA. Templates may use `{eta_formatted}`.
+pub fn progress_style(
+    template: Option<&str>,
+    no_eta: bool,
+) -> Result<ProgressStyle, indicatif::style::TemplateError> {
+    let preset = if no_eta {
+        PROGRESS_TEMPLATE_NO_ETA
+    } else {
+        PROGRESS_TEMPLATE
+    };
+    Ok(ProgressStyle::default_bar()
+        .with_key(
+            "eta_formatted",
+            |state: &ProgressState, w: &mut dyn std::fmt::Write| {
+                write!(w, "{}", format_duration(state.eta())).unwrap()
+            },
+        )
+        .template(template.unwrap_or(preset))?
+        .progress_chars("=>-"))
+}
+
+/// Producer that reads files from the database backlog (pending files).
+/// Files becoming pending while it runs are transferred in the same run.
+pub fn run_producer(
+    config: PipelineConfig,
+    sender: Sender<Block>,
+    db: std::sync::Arc<std::sync::Mutex<Database>>,
+    logger: std::sync::Arc<Logger>,
+) -> Result<SyncStats> {
+    let mut stats = SyncStats::default();
+    let mut total_bytes_sent = 0u64;
+    let mut files_transferred = 0u64;
+    let transfer_start = Instant::now();
+
+    // Get pending files and total bytes from database
+    let (pending_files, total_pending_bytes) = {
+        let db_guard = db.lock().unwrap();
+        let pending_files = pending_files(&db_guard, &config)?;
+        if config.ordered {
+            db_guard.clear_transfer_plan()?;
+            db_guard.append_transfer_plan(pending_files.iter().map(|f| f.source_path.as_str()))?;
+        }
+        (pending_files, db_guard.pending_total_bytes()?)
+    };
+
+    let mut total_files = pending_files.len();
+    let mut total_pending_bytes = total_pending_bytes;
+    if total_files == 0 {
+        println!("No files to transfer.");
+        return Ok(stats);
+    }
+
+    println!(
+        "Transferring {} files ({})...",
+        total_files,
+        format_bytes(total_pending_bytes)
+    );
+    log_retried_files(&pending_files, &logger);
+
+    // Per-file progress bar for ETA and bandwidth display
+    // Plain periodic lines replace the animated bar when requested, or by default
+    // when stderr is not a terminal (log file, pipe)
+    let mut numeric_progress = config
+        .numeric_progress
+        .or_else(|| (!std::io::stderr().is_terminal()).then_some(PLAIN_PROGRESS_INTERVAL))
+        .map(NumericProgress::new);
+    let stats_snapshots = config
+        .stats_interval
+        .map(|interval| StatsSnapshots::start(interval, logger.clone()));
+    let pb = if numeric_progress.is_some() {
+        ProgressBar::hidden()
+    } else {
+        ProgressBar::new(0)
+    };
+    let style = config.progress_style.clone().unwrap_or_else(|| {
+        progress_style(None, config.progress_no_eta).expect("built-in progress template")
+    });
+    pb.set_style(style);
+    let mut rates = TransferRates::new(transfer_start);
+
+    // Refreshes the progress displays after a block is sent
+    let mut show_progress = |rates: &mut TransferRates,
+                             files_done: u64,
+                             total_files: usize,
+                             bytes_done: u64,
+                             total_bytes: u64,
+                             relative_path: &Path| {
+        if let Some(progress) = &mut numeric_progress {
+            progress.report(files_done, total_files, bytes_done, total_bytes, false);
+        }
+        if let Some(snapshots) = &stats_snapshots {
+            snapshots.update(files_done, total_files, bytes_done, total_bytes);
+        }
+
+        pb.set_message(status_message(
+            rates,
+            files_done,
+            total_files,
+            bytes_done,
+            total_bytes,
+            relative_path,
+            config.progress_no_eta,
+        ));
+    };
+
+    // Hashes of the pending files that may share their content with another one, and
+    // the first file of each hash sent in full
+    let shared_hashes = if config.dedupe_identical {
+        shared_content_hashes(&pending_files, &config, &db, &logger)?
+    } else {
+        HashMap::new()
+    };
+    let mut first_with_hash: HashMap<&str, PathBuf> = HashMap::new();
+
+    let mut backlog = Backlog::new(pending_files, total_pending_bytes);
+    let mut inaccessible_dirs = InaccessibleDirs::default();
+    // File taken from the backlog whose last block is not sent yet: still set when
+    // the next file is taken, its transfer failed
+    let mut unsent: Option<String> = None;
+    while let Some(file_record) = backlog.next(&db, &config, &logger)? {
+        if let Some(path) = unsent.take() {
+            db.lock().unwrap().record_failed_attempt(&path)?;
+        }
+        // Checked between files, so the file in progress is always completed
+        if db.lock().unwrap().cancel_requested()? {
+            let _ = logger.log("Transfer cancelled via the database; backlog left pending");
+            break;
+        }
+        if daily_budget_exhausted(&config, &db)? {
+            let _ = logger.log("Daily transfer budget exhausted; backlog left pending");
+            break;
+        }
+        stats.files_started += 1;
+        unsent = Some(file_record.source_path.clone());
+        // Totals grow when files are added to the backlog mid-run
+        total_files = backlog.files;
+        total_pending_bytes = backlog.bytes;
+
+        let source_path = PathBuf::from(&file_record.source_path);
+        let dest_path = PathBuf::from(&file_record.dest_path);
+
+        // A read or write given up on earlier is still running: a new attempt would race it
+        if config
+            .watchdog
+            .as_ref()
+            .is_some_and(|watchdog| watchdog.is_stuck(&source_path))
+        {
+            let _ = logger.log_error(&format!(
+                "Skipping (a transfer given up on is still running): {:?}",
+                source_path
+            ));
+            continue;
+        }
+
+        // Compute relative path for display
+        let relative_path = source_path
+            .strip_prefix(&config.source_dir)
+            .unwrap_or(&source_path);
+
+        // Mirrors share the primary destination's layout. Those the scan found up to
+        // date are left alone; the primary, whose state is recorded, is always written.
+        let mut dest_paths = vec![dest_path.clone()];
+        {
+            let db_guard = db.lock().unwrap();
+            for dir in &config.mirror_dirs {
+                if !db_guard.dest_synced(&file_record.source_path, dir.to_str().unwrap())? {
+                    dest_paths.push(dir.join(relative_path));
+                }
+            }
+        }
+
+        // Get fresh metadata from source (file may have changed since scan)
+        let metadata = match fs::metadata(&source_path) {
+            Ok(m) => m,
+            Err(e) if inaccessible_dirs.check(&source_path, &e, &config, &logger)? => continue,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+                // File no longer exists in source - skip but don't remove from backlog
+                let _ = logger.log_error(&format!(
+                    "Skipping (source file no longer exists): {:?}",
+                    source_path
                 ));
                 continue;
             }
             Err(e) => {
-                let _ = logger.log(&format!("Skipping (read error): {:?} - {}", source_path, e));
+                let _ =
+                    logger.log_error(&format!("Skipping (read error): {:?} - {}", source_path, e));
                 continue;
             }
         };
 
+        // Writing to the file being read would truncate it before it is copied. The
+        // file fails and is not picked up again until a scan finds it changed.
+        if let Some(path) = dest_paths.iter().find(|path| is_same_file(&metadata, path)) {
+            let _ = logger.log_error(&format!(
+                "Skipping (destination is the source file itself): {:?} -> {:?}",
+                source_path, path
+            ));
+            db.lock()
+                .unwrap()
+                .set_pending_reason(&file_record.source_path, PendingReason::SameFile)?;
+            continue;
+        }
+
         let mtime = FileTime::from_last_modification_time(&metadata).unix_seconds();
         let atime = FileTime::from_last_access_time(&metadata).unix_seconds();
+        let created = creation_time(&metadata);
         let ctime = mtime;
         let size = metadata.len();
 
@@ -208,247 +1564,3424 @@ pub fn run_producer(
         #[cfg(not(unix))]
         let permissions = 0u32;
 
-        // Reset progress bar for this file
-        pb.set_length(size);
-        pb.set_position(0);
-
-        // Calculate backlog ETA based on transfer rate so far
-        let backlog_eta = if total_bytes_sent > 0 {
-            let elapsed = transfer_start.elapsed();
-            let rate = total_bytes_sent as f64 / elapsed.as_secs_f64();
-            let remaining = total_pending_bytes.saturating_sub(total_bytes_sent);
-            if rate > 0.0 {
-                Some(Duration::from_secs_f64(remaining as f64 / rate))
-            } else {
-                None
+        let file_flags = if config.preserve_fileflags {
+            match file_flags(&source_path) {
+                Ok(flags) => Some(flags),
+                Err(e) => {
+                    let _ = logger.log_error(&format!(
+                        "Not preserving file flags (read error): {:?} - {}",
+                        source_path, e
+                    ));
+                    None
+                }
             }
         } else {
             None
         };
 
-        let eta_str = backlog_eta
-            .map(|d| format!(" Backlog ETA: {}", format_duration(d)))
-            .unwrap_or_default();
+        // A file identical to one already sent is copied at the destination instead.
+        // The hash only holds while the source is as it was when hashed.
+        let shared_hash = shared_hashes
+            .get(&file_record.source_path)
+            .filter(|_| file_record.modified_date == mtime && file_record.size == size);
+        if let Some(hash) = shared_hash {
+            if let Some(original) = first_with_hash.get(hash.as_str()) {
+                let block = Block {
+                    data: vec![],
+                    offset: size,
+                    dest_paths: dest_paths.clone(),
+                    source_path: source_path.clone(),
+                    atime,
+                    created,
+                    pending_reason: file_record.pending_reason,
+                    mtime,
+                    ctime,
+                    permissions,
+                    is_last_block: true,
+                    file_hash: Some(hash.clone()),
+                    file_size: size,
+                    aborted: false,
+                    copy_from: Some(original.clone()),
+                    file_flags,
+                };
+                sender.send(block).context("Failed to send block")?;
+                unsent = None;
+                // Nothing is read or sent for it
+                backlog.bytes -= size;
+                total_pending_bytes = backlog.bytes;
+                files_transferred += 1;
+                show_progress(
+                    &mut rates,
+                    files_transferred,
+                    total_files,
+                    total_bytes_sent,
+                    total_pending_bytes,
+                    relative_path,
+                );
+                continue;
+            }
+            first_with_hash.insert(hash.as_str(), source_path.clone());
+        }
+
+        // Reset progress bar for this file
+        pb.set_length(size);
+        pb.set_position(0);
 
-        pb.set_message(format!(
-            "[{}/{} Total: {}{}] {}",
-            files_transferred + 1,
+        pb.set_message(status_message(
+            &mut rates,
+            files_transferred,
             total_files,
-            format_bytes(total_bytes_sent),
-            eta_str,
-            relative_path.display()
+            total_bytes_sent,
+            total_pending_bytes,
+            relative_path,
+            config.progress_no_eta,
         ));
 
         let mut file = match File::open(&source_path) {
             Ok(f) => f,
+            Err(e) if inaccessible_dirs.check(&source_path, &e, &config, &logger)? => continue,
             Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
                 // File no longer exists - skip but don't remove from backlog
-                let _ = logger.log(&format!(
+                let _ = logger.log_error(&format!(
                     "Skipping (source file no longer exists): {:?}",
                     source_path
                 ));
                 continue;
             }
             Err(e) => {
-                let _ = logger.log(&format!("Skipping (open error): {:?} - {}", source_path, e));
+                let _ =
+                    logger.log_error(&format!("Skipping (open error): {:?} - {}", source_path, e));
                 continue;
             }
         };
-        let mut hasher = create_hasher(config.hash_algo);
+        let new_hasher = || {
+            file_hasher(&file_record, mtime, size, &config, &db).inspect_err(|e| {
+                let _ = logger.log_error(&format!(
+                    "Skipping (checksum error): {:?} - {}",
+                    source_path, e
+                ));
+            })
+        };
+        let Ok(mut hasher) = new_hasher() else {
+            continue;
+        };
         let mut offset = 0u64;
-        let mut file_bytes_sent = 0u64;
-        let mut buffer = vec![0u8; config.block_size];
+
+        // Staged files start empty, so there is no prefix to keep
+        let append_len = (config.append && !config.delay_updates && !config.whole_file)
+            .then(|| appendable_length(&dest_paths, size))
+            .flatten();
+        if let Some(len) = append_len {
+            match prefix_matches(&mut file, &dest_paths, len, &config, &mut hasher) {
+                Ok(true) => offset = len,
+                Ok(false) => {
+                    let _ = logger.log(&format!(
+                        "Append prefix differs, transferring in full: {:?}",
+                        source_path
+                    ));
+                    file.seek(SeekFrom::Start(0))?;
+                    let Ok(fresh) = new_hasher() else {
+                        continue;
+                    };
+                    hasher = fresh;
+                }
+                Err(e) => {
+                    let _ = logger
+                        .log_error(&format!("Skipping (read error): {:?} - {}", source_path, e));
+                    continue;
+                }
+            }
+        } else if let Some(len) =
+            encrypted_resume_length(&file_record, &dest_paths, size, mtime, &config, &db)
+        {
+            // The blocks already sealed at rest are kept; the rest follows them
+            if let Err(e) = skip_prefix(&mut file, len, &config, &mut hasher) {
+                let _ =
+                    logger.log_error(&format!("Skipping (read error): {:?} - {}", source_path, e));
+                continue;
+            }
+            let _ = logger.log(&format!(
+                "Resuming encrypted transfer at byte {}: {:?}",
+                len, source_path
+            ));
+            offset = len;
+        }
+        let mut file_bytes_sent = offset;
+        pb.set_position(offset);
+        let block_size = config.block_size_for(&source_path);
+        let mut buffer = vec![0u8; block_size];
+        // Only a file read from its start yields the complete list
+        let mut block_hashes = (config.store_block_hashes && offset == 0)
+            .then(|| BlockHashes::new(config.hash_algo, block_size));
+        // Whether blocks of this file went out without its last one
+        let mut incomplete = false;
 
         loop {
-            let bytes_read = file.read(&mut buffer)?;
+            let read_start = stage_start(&config);
+            let read = watched(
+                &config,
+                &source_path,
+                move |_| {
+                    // Whole blocks keep block boundaries at multiples of the block size
+                    let result = read_block(&mut file, &mut buffer);
+                    (file, buffer, result)
+                },
+                drop,
+            );
+            stage_end(read_start, &mut stats.read_time);
+            let Some((returned_file, returned_buffer, result)) = read else {
+                abandon_file(&source_path, &config, &logger);
+                break;
+            };
+            (file, buffer) = (returned_file, returned_buffer);
+            let bytes_read = match result {
+                Ok(bytes_read) => bytes_read,
+                Err(e) => {
+                    if incomplete {
+                        let _ = sender.send(Block::aborted(&source_path, &dest_paths));
+                    }
+                    db.lock()
+                        .unwrap()
+                        .record_failed_attempt(&file_record.source_path)?;
+                    return Err(e.into());
+                }
+            };
             if bytes_read == 0 {
                 // Handle empty file case
                 if size == 0 {
+                    // A failed checksum leaves the file pending for a later run
+                    let hash_start = stage_start(&config);
+                    let file_hash = hasher
+                        .as_mut()
+                        .map(|h| finalize_hash(h.as_mut(), &logger, &source_path));
+                    stage_end(hash_start, &mut stats.hash_time);
+                    if file_hash == Some(None) {
+                        break;
+                    }
+                    if let Some(block_hashes) = block_hashes.take() {
+                        store_block_hashes(block_hashes, &source_path, &db)?;
+                    }
                     let block = Block {
                         data: vec![],
                         offset: 0,
-                        dest_path: dest_path.clone(),
+                        dest_paths: dest_paths.clone(),
                         source_path: source_path.clone(),
                         atime,
+                        created,
+                        pending_reason: file_record.pending_reason,
                         mtime,
                         ctime,
                         permissions,
                         is_last_block: true,
-                        file_hash: Some(hasher.finalize_hex()),
+                        file_hash: file_hash.flatten(),
                         file_size: 0,
+                        aborted: false,
+                        copy_from: None,
+                        file_flags,
                     };
                     sender.send(block).context("Failed to send block")?;
+                    unsent = None;
+                    // No bytes to move the bar, but the file counter still advances
+                    show_progress(
+                        &mut rates,
+                        files_transferred,
+                        total_files,
+                        total_bytes_sent,
+                        total_pending_bytes,
+                        relative_path,
+                    );
                 }
                 break;
             }
 
             let chunk_data = buffer[0..bytes_read].to_vec();
-            hasher.update(&chunk_data);
+            let hash_start = stage_start(&config);
+            if let Some(hasher) = hasher.as_mut() {
+                hasher.update(&chunk_data);
+            }
+            if let Some(block_hashes) = block_hashes.as_mut() {
+                block_hashes.update(&chunk_data)?;
+            }
 
             let is_last = (offset + bytes_read as u64) == size;
-            let file_hash = if is_last {
-                Some(hasher.finalize_hex())
-            } else {
-                None
+            let file_hash = match hasher.as_mut() {
+                Some(hasher) if is_last => finalize_hash(hasher.as_mut(), &logger, &source_path),
+                _ => None,
             };
+            stage_end(hash_start, &mut stats.hash_time);
+            if is_last && hasher.is_some() && file_hash.is_none() {
+                break;
+            }
+            if let Some(block_hashes) = block_hashes.take_if(|_| is_last) {
+                store_block_hashes(block_hashes, &source_path, &db)?;
+            }
 
             let block = Block {
                 data: chunk_data,
                 offset,
-                dest_path: dest_path.clone(),
+                dest_paths: dest_paths.clone(),
                 source_path: source_path.clone(),
                 atime,
+                created,
+                pending_reason: file_record.pending_reason,
                 mtime,
                 ctime,
                 permissions,
                 is_last_block: is_last,
                 file_hash,
                 file_size: size,
+                aborted: false,
+                copy_from: None,
+                file_flags,
             };
 
             // Send block first - this may block due to backpressure from the
             // bandwidth-limited consumer. Update progress only after send
             // completes so the displayed rate reflects the actual throttled speed.
             sender.send(block).context("Failed to send block")?;
+            incomplete = !is_last;
+            if is_last {
+                unsent = None;
+            }
             offset += bytes_read as u64;
 
             total_bytes_sent += bytes_read as u64;
             file_bytes_sent += bytes_read as u64;
 
             pb.set_position(file_bytes_sent);
-
-            // Update backlog ETA during transfer
-            let backlog_eta = {
-                let elapsed = transfer_start.elapsed();
-                let rate = total_bytes_sent as f64 / elapsed.as_secs_f64();
-                let remaining = total_pending_bytes.saturating_sub(total_bytes_sent);
-                if rate > 0.0 {
-                    Some(Duration::from_secs_f64(remaining as f64 / rate))
-                } else {
-                    None
-                }
-            };
-
-            let eta_str = backlog_eta
-                .map(|d| format!(" Backlog ETA: {}", format_duration(d)))
-                .unwrap_or_default();
-
-            pb.set_message(format!(
-                "[{}/{} Total: {}{}] {}",
-                files_transferred + 1,
+            show_progress(
+                &mut rates,
+                files_transferred,
                 total_files,
-                format_bytes(total_bytes_sent),
-                eta_str,
-                relative_path.display()
-            ));
+                total_bytes_sent,
+                total_pending_bytes,
+                relative_path,
+            );
 
             if is_last {
                 break;
             }
         }
+        // Read timeout, failed checksum or a source that shrank: the consumer must
+        // not keep the part it has
+        if incomplete {
+            sender
+                .send(Block::aborted(&source_path, &dest_paths))
+                .context("Failed to send block")?;
+        }
 
         files_transferred += 1;
     }
+    if let Some(path) = unsent {
+        db.lock().unwrap().record_failed_attempt(&path)?;
+    }
+
+    if let Some(progress) = &mut numeric_progress {
+        progress.report(
+            files_transferred,
+            total_files,
+            total_bytes_sent,
+            total_pending_bytes,
+            true,
+        );
+    }
+    if let Some(snapshots) = stats_snapshots {
+        snapshots.update(
+            files_transferred,
+            total_files,
+            total_bytes_sent,
+            total_pending_bytes,
+        );
+        snapshots.finish();
+    }
 
     pb.finish_with_message(format!(
         "Finished. {} files transferred, {}",
         files_transferred,
         format_bytes(total_bytes_sent)
     ));
-    Ok(())
+    Ok(stats)
 }
 
-pub fn run_consumer(
-    receiver: Receiver<Block>,
-    db: std::sync::Arc<std::sync::Mutex<Database>>,
-    logger: std::sync::Arc<Logger>,
-    bw_limit: Option<u64>,
-) -> Result<()> {
-    let start_time = Instant::now();
-    let mut total_bytes_written = 0u64;
+/// Status line above the progress bar: file counter, bytes sent, current and
+/// average rates, backlog ETA (unless `no_eta`) and the file being sent
+fn status_message(
+    rates: &mut TransferRates,
+    files_done: u64,
+    total_files: usize,
+    bytes_done: u64,
+    total_bytes: u64,
+    relative_path: &Path,
+    no_eta: bool,
+) -> String {
+    // The backlog ETA follows the long-run average, steadier than the current rate
+    let now = Instant::now();
+    rates.record(now, bytes_done);
+    let average = rates.average(now, bytes_done);
+    let backlog_eta = (average > 0.0).then(|| {
+        let remaining = total_bytes.saturating_sub(bytes_done);
+        Duration::from_secs_f64(remaining as f64 / average)
+    });
+
+    let rate_str = if bytes_done > 0 {
+        format!(
+            " Now: {}/s Avg: {}/s",
+            format_bytes(rates.current() as u64),
+            format_bytes(average as u64)
+        )
+    } else {
+        String::new()
+    };
+    let eta_str = backlog_eta
+        .filter(|_| !no_eta)
+        .map(|d| format!(" Backlog ETA: {}", format_duration(d)))
+        .unwrap_or_default();
+
+    format!(
+        "[{}/{} Total: {}{}{}] {}",
+        files_done + 1,
+        total_files,
+        format_bytes(bytes_done),
+        rate_str,
+        eta_str,
+        relative_path.display()
+    )
+}
+
+/// Span of recent samples behind the current transfer rate
+const RATE_WINDOW: Duration = Duration::from_secs(5);
+
+/// Transfer rates shown in the status line: the current rate over the last
+/// `RATE_WINDOW`, which follows a momentarily slow file, and the average since the
+/// transfer started, which drives the backlog ETA
+struct TransferRates {
+    start: Instant,
+    /// (time, bytes done) samples, oldest first, covering at least `RATE_WINDOW`
+    samples: VecDeque<(Instant, u64)>,
+}
+
+impl TransferRates {
+    fn new(start: Instant) -> Self {
+        Self {
+            start,
+            samples: VecDeque::from([(start, 0)]),
+        }
+    }
+
+    fn record(&mut self, now: Instant, bytes_done: u64) {
+        self.samples.push_back((now, bytes_done));
+        while self
+            .samples
+            .get(1)
+            .is_some_and(|&(time, _)| now.duration_since(time) >= RATE_WINDOW)
+        {
+            self.samples.pop_front();
+        }
+    }
+
+    /// Bytes per second over the recent samples
+    fn current(&self) -> f64 {
+        match (self.samples.front(), self.samples.back()) {
+            (Some(&(first_time, first_bytes)), Some(&(last_time, last_bytes))) => {
+                let elapsed = last_time.duration_since(first_time).as_secs_f64();
+                if elapsed > 0.0 {
+                    last_bytes.saturating_sub(first_bytes) as f64 / elapsed
+                } else {
+                    0.0
+                }
+            }
+            _ => 0.0,
+        }
+    }
 
-    while let Ok(block) = receiver.recv() {
-        if let Some(parent) = block.dest_path.parent() {
-            fs::create_dir_all(parent)?;
+    /// Bytes per second since the transfer started
+    fn average(&self, now: Instant, bytes_done: u64) -> f64 {
+        let elapsed = now.duration_since(self.start).as_secs_f64();
+        if elapsed > 0.0 {
+            bytes_done as f64 / elapsed
+        } else {
+            0.0
         }
+    }
+}
 
-        let mut options = OpenOptions::new();
-        options.write(true).create(true);
+/// Periodic "Progress: N% (...)" lines for logs that cannot render the animated bar
+struct NumericProgress {
+    interval: Duration,
+    last_report: Option<Instant>,
+}
 
-        // Truncate if writing from the beginning (new file or overwrite)
-        if block.offset == 0 {
-            options.truncate(true);
+impl NumericProgress {
+    fn new(interval: Duration) -> Self {
+        Self {
+            interval,
+            last_report: None,
         }
+    }
 
-        let mut file = options.open(&block.dest_path)?;
+    /// Prints a line if the interval has elapsed since the last one, or if `force` is set
+    fn report(
+        &mut self,
+        files_done: u64,
+        total_files: usize,
+        bytes_done: u64,
+        total_bytes: u64,
+        force: bool,
+    ) {
+        if !force
+            && self
+                .last_report
+                .is_some_and(|last| last.elapsed() < self.interval)
+        {
+            return;
+        }
+        self.last_report = Some(Instant::now());
+        // An empty backlog counts as complete
+        let percent = (bytes_done.min(total_bytes) * 100)
+            .checked_div(total_bytes)
+            .unwrap_or(100);
+        println!(
+            "Progress: {}% ({}/{} files, {}/{})",
+            percent,
+            files_done,
+            total_files,
+            format_bytes(bytes_done),
+            format_bytes(total_bytes)
+        );
+    }
+}
 
-        file.seek(SeekFrom::Start(block.offset))?;
-        file.write_all(&block.data)?;
+/// Cumulative progress the producer publishes for `StatsSnapshots`
+#[derive(Default)]
+struct SnapshotCounters {
+    files_done: AtomicU64,
+    total_files: AtomicU64,
+    bytes_done: AtomicU64,
+    total_bytes: AtomicU64,
+}
 
-        // Rate Limiting on the write side to enable full-duplex streaming
-        let bytes_written = block.data.len() as u64;
-        total_bytes_written += bytes_written;
-        if let Some(limit) = bw_limit {
-            let expected_duration =
-                Duration::from_secs_f64(total_bytes_written as f64 / limit as f64);
-            let elapsed = start_time.elapsed();
-            if expected_duration > elapsed {
-                thread::sleep(expected_duration - elapsed);
-            }
+/// Periodic cumulative "Stats:" log lines in `key=value` form, for graphing long runs.
+/// A timer thread logs them, so they keep coming while the transfer is stalled; a
+/// final one is logged at the end.
+struct StatsSnapshots {
+    counters: Arc<SnapshotCounters>,
+    start: Instant,
+    logger: Arc<Logger>,
+    /// Dropped to stop the timer
+    stop: Option<Sender<()>>,
+    timer: Option<thread::JoinHandle<()>>,
+}
+
+impl StatsSnapshots {
+    fn start(interval: Duration, logger: Arc<Logger>) -> Self {
+        let counters = Arc::new(SnapshotCounters::default());
+        let start = Instant::now();
+        let (stop, stopped) = bounded::<()>(0);
+        let timer = {
+            let counters = counters.clone();
+            let logger = logger.clone();
+            thread::spawn(move || {
+                while let Err(RecvTimeoutError::Timeout) = stopped.recv_timeout(interval) {
+                    log_snapshot(&logger, start, &counters);
+                }
+            })
+        };
+        Self {
+            counters,
+            start,
+            logger,
+            stop: Some(stop),
+            timer: Some(timer),
         }
+    }
 
-        if block.is_last_block {
-            // Metadata Sync
-            let mtime = FileTime::from_unix_time(block.mtime, 0);
-            let atime = FileTime::from_unix_time(block.atime, 0);
-            set_file_times(&block.dest_path, atime, mtime)?;
+    /// Publishes the progress the next snapshot reports
+    fn update(&self, files_done: u64, total_files: usize, bytes_done: u64, total_bytes: u64) {
+        let counters = &self.counters;
+        counters.files_done.store(files_done, Ordering::Relaxed);
+        counters
+            .total_files
+            .store(total_files as u64, Ordering::Relaxed);
+        counters.bytes_done.store(bytes_done, Ordering::Relaxed);
+        counters.total_bytes.store(total_bytes, Ordering::Relaxed);
+    }
 
-            // Persistence - mark as synced with hash
-            let db_guard = db.lock().unwrap();
-            db_guard.mark_synced(
-                block.source_path.to_str().unwrap(),
-                block.file_hash.as_deref().unwrap_or(""),
-            )?;
+    /// Stops the timer and logs the final snapshot
+    fn finish(mut self) {
+        self.stop_timer();
+        log_snapshot(&self.logger, self.start, &self.counters);
+    }
 
-            // Audit
-            logger.log(&format!(
-                "Transferred: {:?} -> {:?} (Hash: {})",
-                block.source_path,
-                block.dest_path,
-                block.file_hash.as_deref().unwrap_or("?")
-            ))?;
+    fn stop_timer(&mut self) {
+        drop(self.stop.take());
+        if let Some(timer) = self.timer.take() {
+            let _ = timer.join();
         }
     }
-    Ok(())
 }
 
-#[cfg(test)]
-mod tests {
-    use super::*;
+impl Drop for StatsSnapshots {
+    fn drop(&mut self) {
+        self.stop_timer();
+    }
+}
 
-    #[test]
-    fn test_create_hasher() {
-        let mut h = create_hasher(HashAlgorithm::Md5);
-        h.update(b"hello");
-        assert_eq!(h.finalize_hex(), "5d41402abc4b2a76b9719d911017c592");
+/// Logs one `Stats:` line from the counters, with the rate since `start`
+fn log_snapshot(logger: &Logger, start: Instant, counters: &SnapshotCounters) {
+    let files_done = counters.files_done.load(Ordering::Relaxed);
+    let total_files = counters.total_files.load(Ordering::Relaxed);
+    let bytes_done = counters.bytes_done.load(Ordering::Relaxed);
+    let total_bytes = counters.total_bytes.load(Ordering::Relaxed);
+    let elapsed = start.elapsed().as_secs_f64();
+    let rate = if elapsed > 0.0 {
+        bytes_done as f64 / elapsed
+    } else {
+        0.0
+    };
+    // Unknown until something has been sent
+    let eta = if rate > 0.0 {
+        format!(
+            "{:.0}",
+            total_bytes.saturating_sub(bytes_done) as f64 / rate
+        )
+    } else {
+        "-".to_string()
+    };
+    let _ = logger.log(&format!(
+        "Stats: elapsed_secs={:.0} files_done={} files_total={} bytes_done={} \
+         bytes_total={} rate_bytes_per_sec={:.0} eta_secs={}",
+        elapsed, files_done, total_files, bytes_done, total_bytes, rate, eta
+    ));
+}
 
-        let mut h = create_hasher(HashAlgorithm::Sha1);
-        h.update(b"hello");
-        assert_eq!(h.finalize_hex(), "aaf4c61ddcc5e8a2dabede0f3b482cd9aea9434d");
+/// Writes a block at its offset into one destination file. With `only_changed`,
+/// the write is skipped when the file already holds the same bytes there. The file
+/// is opened only once `open_files`, if given, has a free slot.
+/// Returns whether the data was written.
+fn write_block(
+    path: &Path,
+    block: &Block,
+    only_changed: bool,
+    open_files: Option<&Semaphore>,
+) -> Result<bool> {
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    // Dropped after `file`, so the slot frees only once the file is closed
+    let _permit = open_files.map(Semaphore::acquire);
 
-        let mut h = create_hasher(HashAlgorithm::Sha256);
-        h.update(b"hello");
-        assert_eq!(
-            h.finalize_hex(),
-            "2cf24dba5fb0a30e26e83b2ac5b9e29e1b161e5c1fa7425e73043362938b9824"
-        );
+    let mut options = OpenOptions::new();
+    options.write(true).create(true).read(only_changed);
 
-        let mut h = create_hasher(HashAlgorithm::Blake2b);
-        h.update(b"hello");
-        assert_eq!(
-            h.finalize_hex(),
-            "e4cfa39a3d37be31c59609e807970799caa68a19bfaa15135f165085e01d41a65ba1e1b146aeb6bd0092b49eac214c103ccfa3a365954bbbe52f74a2b3620c94"
-        );
+    // Truncate if writing from the beginning (new file or overwrite),
+    // unless the existing content is to be compared
+    if block.offset == 0 && !only_changed {
+        options.truncate(true);
+    }
+
+    let mut file = options.open(path)?;
+
+    file.seek(SeekFrom::Start(block.offset))?;
+    let unchanged = only_changed && holds_data(&mut file, &block.data)?;
+    if !unchanged {
+        file.seek(SeekFrom::Start(block.offset))?;
+        file.write_all(&block.data)?;
+    }
+
+    // Trim any stale tail beyond the source size. A destination left short is not
+    // padded, so that a short write shows in its size rather than as zeros
+    if block.is_last_block && file.metadata()?.len() > block.file_size {
+        file.set_len(block.file_size)?;
+    }
+    Ok(!unchanged)
+}
+
+/// Copies the destination file written for an identical source file to `path`,
+/// once `open_files`, if given, has a free slot for the copy.
+/// Returns true, like a block that had to be written.
+fn copy_identical(
+    original_path: &Path,
+    path: &Path,
+    open_files: Option<&Semaphore>,
+) -> Result<bool> {
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    let _permit = open_files.map(Semaphore::acquire);
+    fs::copy(original_path, path)
+        .with_context(|| format!("Failed to copy {:?} to {:?}", original_path, path))?;
+    Ok(true)
+}
+
+/// Turns a plaintext block into the one written at rest: sealed, shifted past the
+/// header and the tags of earlier blocks, the first one carrying the header. Blocks
+/// start at multiples of `block_size`, the one the file was read with (recorded in
+/// the header), which gives each its index.
+fn seal_block(
+    cipher: &Cipher,
+    block_size: usize,
+    mut block: Block,
+    file_nonce: &mut [u8; NONCE_PREFIX_LEN],
+) -> Result<Block> {
+    let index = block.offset / block_size as u64;
+    let sealed = if block.offset == 0 {
+        *file_nonce = Cipher::new_nonce_prefix();
+        let mut data = cipher.header(block_size, file_nonce);
+        data.extend(cipher.encrypt_block(file_nonce, index, block.is_last_block, &block.data)?);
+        data
+    } else {
+        block.offset = encrypted_offset(block.offset, index);
+        cipher.encrypt_block(file_nonce, index, block.is_last_block, &block.data)?
+    };
+    block.data = sealed;
+    block.file_size = encrypted_len(block.file_size, block_size);
+    Ok(block)
+}
+
+/// Whether the bytes at the current position of `file` equal `data`
+fn holds_data(file: &mut File, data: &[u8]) -> Result<bool> {
+    let mut existing = vec![0u8; data.len()];
+    let mut filled = 0;
+    while filled < existing.len() {
+        match file.read(&mut existing[filled..])? {
+            0 => return Ok(false),
+            n => filled += n,
+        }
+    }
+    Ok(existing == data)
+}
+
+/// Whether a pause is requested via the shared flag or the control file
+fn is_paused(config: &PipelineConfig, control: &TransferControl) -> bool {
+    control.paused.load(Ordering::Relaxed)
+        || config
+            .control_file
+            .as_ref()
+            .and_then(|path| fs::read_to_string(path).ok())
+            .is_some_and(|content| content.trim() == "pause")
+}
+
+/// Blocks while the transfer is paused, logging pause and resume events.
+/// Returns `false` if interrupted while paused.
+fn wait_while_paused(
+    config: &PipelineConfig,
+    control: &TransferControl,
+    logger: &Logger,
+) -> Result<bool> {
+    if !is_paused(config, control) {
+        return Ok(true);
+    }
+    logger.log("Transfer paused")?;
+    while is_paused(config, control) {
+        if !paced_sleep(PAUSE_POLL_INTERVAL, &control.interrupted) {
+            return Ok(false);
+        }
+    }
+    logger.log("Transfer resumed")?;
+    Ok(true)
+}
+
+/// Local date under which `--daily-budget` counts the bytes written now
+fn budget_day() -> String {
+    chrono::Local::now().format("%Y-%m-%d").to_string()
+}
+
+/// Whether today's `daily_budget` is used up
+pub fn daily_budget_exhausted(config: &PipelineConfig, db: &Mutex<Database>) -> Result<bool> {
+    let Some(budget) = config.daily_budget else {
+        return Ok(false);
+    };
+    Ok(db.lock().unwrap().transferred_bytes(&budget_day())? >= budget)
+}
+
+/// Bandwidth limit for a block, by whether its file is new to the destination or an update
+fn block_limit(config: &PipelineConfig, reason: Option<PendingReason>) -> Option<u64> {
+    match reason {
+        Some(PendingReason::DestMissing) => config.bw_limit_new.or(config.bw_limit),
+        Some(PendingReason::Changed) => config.bw_limit_update.or(config.bw_limit),
+        // Never sent
+        Some(PendingReason::SameFile) | None => config.bw_limit,
+    }
+}
+
+pub fn run_consumer(
+    config: PipelineConfig,
+    receiver: Receiver<Block>,
+    db: std::sync::Arc<std::sync::Mutex<Database>>,
+    logger: std::sync::Arc<Logger>,
+    control: std::sync::Arc<TransferControl>,
+) -> Result<SyncStats> {
+    let mut stats = SyncStats::default();
+    let transfer_log = config.transfer_log.as_deref().map(TransferLog::new);
+    let shared_limiter = match (&config.shared_limit_file, config.bw_limit) {
+        (Some(path), Some(limit)) => Some(SharedLimiter::new(path, limit)),
+        _ => None,
+    };
+    let start_time = Instant::now();
+    // Time by which all bytes written so far are allowed under the limit
+    let mut schedule = Duration::ZERO;
+    // File whose remaining blocks are dropped after a write timed out
+    let mut abandoned: Option<PathBuf> = None;
+    // Key salt and nonce prefix of the file being encrypted, and that file
+    let mut file_salt = [0u8; SALT_LEN];
+    let mut file_nonce = [0u8; NONCE_PREFIX_LEN];
+    let mut sealed_file: Option<PathBuf> = None;
+    // Ciphers of files resumed under the salt of an earlier run
+    let mut resumed_ciphers: HashMap<[u8; SALT_LEN], Cipher> = HashMap::new();
+    // File being written and the offset of its first block written by this run
+    let mut written: Option<(PathBuf, u64)> = None;
+    // File paced by `bw_limit_per_file`, when its pacing started and the time by
+    // which its bytes written so far are allowed
+    let mut file_pace: Option<(PathBuf, Instant, Duration)> = None;
+    // With dedupe_identical, the primary write path of each file completed in this run
+    let mut completed: HashMap<PathBuf, PathBuf> = HashMap::new();
+
+    'blocks: while let Ok(block) = receiver.recv() {
+        if abandoned.as_ref() == Some(&block.source_path) {
+            continue;
+        }
+        if block.aborted {
+            if let Some((_, start)) = written.take_if(|(path, _)| *path == block.source_path) {
+                discard_partial(&block, start, &config, &logger);
+            }
+            continue;
+        }
+
+        // Ctrl-C or SIGTERM: stop between blocks, the file cut short stays pending
+        if control.interrupted.load(Ordering::SeqCst) {
+            return Err(anyhow!("Transfer interrupted"));
+        }
+
+        // Maintenance pause: hold the block (and the queue behind it) until resumed
+        let pause_start = start_time.elapsed();
+        if !wait_while_paused(&config, &control, &logger)? {
+            return Err(anyhow!("Transfer interrupted"));
+        }
+        // Time spent paused must not turn into a burst allowance
+        let paused = start_time.elapsed() - pause_start;
+        schedule += paused;
+        if let Some((_, _, file_schedule)) = &mut file_pace {
+            *file_schedule += paused;
+        }
+
+        // Encrypted, the block is replaced by its sealed form at its offset at rest
+        let plain_end = block.offset + block.data.len() as u64;
+        let block = match &config.cipher {
+            Some(cipher) => {
+                if block.offset == 0 {
+                    file_salt = cipher.salt();
+                } else if sealed_file.as_ref() != Some(&block.source_path) {
+                    // A resumed file continues under the key and nonce prefix it
+                    // was started with
+                    let (salt, prefix, _) = encryption_progress(&db, &block.source_path)
+                        .with_context(|| {
+                            format!("No encryption progress to resume {:?}", block.source_path)
+                        })?;
+                    if salt != cipher.salt() && !resumed_ciphers.contains_key(&salt) {
+                        resumed_ciphers.insert(salt, cipher.for_salt(salt)?);
+                    }
+                    (file_salt, file_nonce) = (salt, prefix);
+                }
+                sealed_file = Some(block.source_path.clone());
+                let cipher = resumed_ciphers.get(&file_salt).unwrap_or(cipher);
+                let block_size = config.block_size_for(&block.source_path);
+                seal_block(cipher, block_size, block, &mut file_nonce)?
+            }
+            None => block,
+        };
+
+        // With delay_updates, the destinations themselves stay untouched until the final swap
+        let write_paths: Vec<PathBuf> = block
+            .dest_paths
+            .iter()
+            .map(|path| {
+                if config.delay_updates {
+                    staged_path(path)
+                } else {
+                    path.clone()
+                }
+            })
+            .collect();
+
+        // A file identical to one written earlier is copied from its destination,
+        // provided that one made it
+        let copy_source = match &block.copy_from {
+            Some(original) => match completed.get(original) {
+                Some(original_path) => Some(original_path.clone()),
+                None => {
+                    logger.log_error(&format!(
+                        "Skipping (identical file {:?} was not transferred): {:?}",
+                        original, block.source_path
+                    ))?;
+                    fail_file(&db, &block.source_path)?;
+                    continue;
+                }
+            },
+            None => None,
+        };
+
+        // Files read ahead before the budget ran out stay pending for another day;
+        // a file already started is completed
+        let new_file = written.as_ref().map(|(path, _)| path) != Some(&block.source_path);
+        if new_file && daily_budget_exhausted(&config, &db)? {
+            abandoned = Some(block.source_path.clone());
+            continue;
+        }
+
+        // Recorded so that the next run re-queues the file if this one crashes
+        if new_file {
+            db.lock()
+                .unwrap()
+                .set_current_file(block.source_path.to_str())?;
+        }
+
+        // An immutable or append-only copy left by an earlier run would refuse the
+        // new content; the flags are restored once the file is complete
+        if config.preserve_fileflags && new_file {
+            for write_path in write_paths.iter().filter(|path| path.exists()) {
+                let _permit = config.open_slot();
+                set_file_flags(write_path, 0).with_context(|| {
+                    format!("Failed to clear the file flags of {:?}", write_path)
+                })?;
+            }
+        }
+
+        // The same block goes to every destination
+        let write_start = stage_start(&config);
+        let source_path = block.source_path.clone();
+        let only_changed = config.only_write_changed && !config.whole_file;
+        let open_files = config.open_files.clone();
+        let late_open_files = config.open_files.clone();
+        let late_logger = logger.clone();
+        let write = watched(
+            &config,
+            &source_path,
+            move |given_up| {
+                let mut written = false;
+                let result = write_paths.iter().try_for_each(|write_path| {
+                    // Nothing more is written for a file given up on
+                    if given_up() {
+                        return Ok(());
+                    }
+                    written |= match &copy_source {
+                        Some(original_path) => {
+                            copy_identical(original_path, write_path, open_files.as_deref())?
+                        }
+                        None => {
+                            write_block(write_path, &block, only_changed, open_files.as_deref())?
+                        }
+                    };
+                    anyhow::Ok(())
+                });
+                (block, write_paths, result.map(|_| written))
+            },
+            move |(block, write_paths, _)| {
+                undo_late_write(
+                    &block,
+                    &write_paths,
+                    late_open_files.as_deref(),
+                    &late_logger,
+                )
+            },
+        );
+        stage_end(write_start, &mut stats.write_time);
+        let Some((block, write_paths, result)) = write else {
+            abandon_file(&source_path, &config, &logger);
+            fail_file(&db, &source_path)?;
+            abandoned = Some(source_path);
+            continue;
+        };
+        if !result? {
+            stats.unchanged_blocks += 1;
+        }
+        if written.as_ref().map(|(path, _)| path) != Some(&block.source_path) {
+            written = Some((block.source_path.clone(), block.offset));
+        }
+        stats.blocks += 1;
+        stats.bytes_transferred += block.data.len() as u64;
+        if config.daily_budget.is_some() && block.copy_from.is_none() {
+            db.lock()
+                .unwrap()
+                .add_transferred_bytes(&budget_day(), block.data.len() as u64)?;
+        }
+
+        // Sealed blocks at rest let an interrupted transfer resume after them
+        if config.cipher.is_some() && !block.is_last_block && !config.delay_updates {
+            db.lock().unwrap().set_encryption_progress(
+                block.source_path.to_str().unwrap(),
+                &hex::encode(file_salt),
+                &hex::encode(file_nonce),
+                plain_end,
+            )?;
+        }
+
+        // Rate Limiting on the write side to enable full-duplex streaming
+        let bytes_written = block.data.len() as u64;
+        let sleep_start = stage_start(&config);
+        if let Some(limiter) = &shared_limiter {
+            // Global cap shared with other processes through the limit file
+            let wait = limiter.reserve(bytes_written)?;
+            if !paced_sleep(wait, &control.interrupted) {
+                return Err(anyhow!("Transfer interrupted"));
+            }
+        } else if let Some(limit) = block_limit(&config, block.pending_reason) {
+            schedule += Duration::from_secs_f64(
+                bytes_written as f64 / effective_limit(limit, config.bw_ramp, schedule),
+            );
+            let elapsed = start_time.elapsed();
+            if schedule > elapsed && !paced_sleep(schedule - elapsed, &control.interrupted) {
+                return Err(anyhow!("Transfer interrupted"));
+            }
+        }
+        // The per-file ceiling applies on top, counted from the file's first block
+        if let Some(limit) = config.bw_limit_per_file {
+            if file_pace
+                .as_ref()
+                .is_none_or(|(path, _, _)| *path != block.source_path)
+            {
+                file_pace = Some((block.source_path.clone(), Instant::now(), Duration::ZERO));
+            }
+            let (_, file_start, file_schedule) = file_pace.as_mut().unwrap();
+            *file_schedule += Duration::from_secs_f64(bytes_written as f64 / limit as f64);
+            let elapsed = file_start.elapsed();
+            if *file_schedule > elapsed
+                && !paced_sleep(*file_schedule - elapsed, &control.interrupted)
+            {
+                return Err(anyhow!("Transfer interrupted"));
+            }
+        }
+        stage_end(sleep_start, &mut stats.sleep_time);
+
+        if block.is_last_block {
+            // Primary destination, the one recorded in the database
+            let dest_path = &block.dest_paths[0];
+
+            // Cheap check for short writes and truncation: the size at rest (sealed,
+            // when encrypting) must be the file's
+            if config.verify_size {
+                for (write_path, path) in write_paths.iter().zip(&block.dest_paths) {
+                    let size = fs::metadata(write_path)?.len();
+                    if size != block.file_size {
+                        // Leave the file pending so it is transferred again
+                        logger.log_error(&format!(
+                            "Destination size mismatch: {:?} (expected {} bytes, found {})",
+                            path, block.file_size, size
+                        ))?;
+                        fail_file(&db, &block.source_path)?;
+                        continue 'blocks;
+                    }
+                }
+            }
+
+            // Optional read-back: hash each destination independently of the source
+            let dest_hash = if config.verify_dest {
+                let mut primary_hash = None;
+                for (write_path, path) in write_paths.iter().zip(&block.dest_paths) {
+                    let hash_start = stage_start(&config);
+                    let algo = config.hash_algo_for(&block.source_path);
+                    let read_back = {
+                        let _permit = config.open_slot();
+                        hash_file_with(write_path, algo, &config)
+                    };
+                    let dest_hash = match read_back {
+                        Ok(hash) => hash,
+                        Err(e) => {
+                            // Unverified, the file stays pending for the next attempt
+                            logger.log_error(&format!(
+                                "Failed to read back {:?} for verification: {}",
+                                write_path, e
+                            ))?;
+                            fail_file(&db, &block.source_path)?;
+                            continue 'blocks;
+                        }
+                    };
+                    stage_end(hash_start, &mut stats.hash_time);
+                    // Without a source hash, the read-back hash is stored as is
+                    if block
+                        .file_hash
+                        .as_ref()
+                        .is_some_and(|hash| *hash != dest_hash)
+                    {
+                        // Leave the file pending so it is transferred again
+                        logger.log_error(&format!(
+                            "Destination hash mismatch: {:?} (source {}, destination {})",
+                            path,
+                            block.file_hash.as_deref().unwrap_or("?"),
+                            dest_hash
+                        ))?;
+                        // No copy written this pass can be trusted, and none may be
+                        // resumed from: remove them all, staged copies included
+                        for write_path in &write_paths {
+                            if let Err(e) = fs::remove_file(write_path) {
+                                logger.log_error(&format!(
+                                    "Failed to remove corrupt copy {:?}: {}",
+                                    write_path, e
+                                ))?;
+                            }
+                        }
+                        db.lock()
+                            .unwrap()
+                            .requeue_interrupted(block.source_path.to_str().unwrap())?;
+                        fail_file(&db, &block.source_path)?;
+                        // Many mismatches point at a failing destination rather
+                        // than isolated corruption: stop writing to it
+                        control.mismatches.fetch_add(1, Ordering::SeqCst);
+                        if control.mismatch_limit_reached(&config) {
+                            return Err(anyhow!(
+                                "Aborted after {} destination hash mismatches",
+                                control.mismatches.load(Ordering::SeqCst)
+                            ));
+                        }
+                        continue 'blocks;
+                    }
+                    primary_hash.get_or_insert(dest_hash);
+                }
+                primary_hash
+            } else {
+                None
+            };
+
+            // Metadata Sync, only once the content is known to be good: a file left
+            // with the source mtime would pass a later size/mtime scan as synced
+            let mtime = FileTime::from_unix_time(block.mtime, 0);
+            let atime = FileTime::from_unix_time(block.atime, 0);
+            for write_path in &write_paths {
+                // Setting the creation time or the flags opens the file
+                let _permit = config.open_slot();
+                #[cfg(unix)]
+                if config.perms_matter {
+                    use std::os::unix::fs::PermissionsExt;
+                    let mode = effective_mode(block.permissions);
+                    fs::set_permissions(write_path, fs::Permissions::from_mode(mode))?;
+                }
+                set_file_times(write_path, atime, mtime)?;
+                if let Some(created) = block.created {
+                    set_creation_time(write_path, created)?;
+                }
+                // Last, as an immutable file takes no further changes
+                if let Some(flags) = block.file_flags {
+                    set_file_flags(write_path, flags).with_context(|| {
+                        format!("Failed to set the file flags of {:?}", write_path)
+                    })?;
+                }
+            }
+
+            // Persistence - mark as synced with hash, recording the resulting
+            // destination mtime so later verification can detect outside changes
+            let dest_metadata = fs::metadata(&write_paths[0])?;
+            let dest_mtime = FileTime::from_last_modification_time(&dest_metadata).unix_seconds();
+            // Files hashed by a checksum command, or not at all, record no algorithm
+            let hash_algo = (config.checksum_command.is_none() && block.file_hash.is_some())
+                .then(|| config.hash_algo_for(&block.source_path).name());
+            let db_guard = db.lock().unwrap();
+            db_guard.mark_synced(
+                block.source_path.to_str().unwrap(),
+                SyncedHashes {
+                    hash: block.file_hash.as_deref(),
+                    hash_algo,
+                    dest_hash: dest_hash.as_deref(),
+                },
+            )?;
+            db_guard.set_dest_mtime(block.source_path.to_str().unwrap(), dest_mtime)?;
+            db_guard.set_current_file(None)?;
+            // Later runs and verification skip re-hashing either side while unchanged.
+            // A staged file keeps its mtime and size when renamed into place.
+            if let Some(algo) = hash_algo {
+                if let Some(hash) = &block.file_hash {
+                    db_guard.cache_hash(
+                        block.source_path.to_str().unwrap(),
+                        block.mtime,
+                        block.file_size,
+                        algo,
+                        hash,
+                    )?;
+                }
+                if let Some(hash) = &dest_hash {
+                    db_guard.cache_hash(
+                        block.dest_paths[0].to_str().unwrap(),
+                        dest_mtime,
+                        dest_metadata.len(),
+                        algo,
+                        hash,
+                    )?;
+                }
+            }
+            if config.cipher.is_some() {
+                db_guard.set_encryption(
+                    block.source_path.to_str().unwrap(),
+                    &hex::encode(file_salt),
+                    &hex::encode(file_nonce),
+                    block.file_size,
+                )?;
+            }
+            drop(db_guard);
+            stats.files_transferred += 1;
+            if config.dedupe_identical {
+                completed.insert(block.source_path.clone(), write_paths[0].clone());
+            }
+
+            // Audit
+            for path in &block.dest_paths {
+                logger.log(&format!(
+                    "{}: {:?} -> {:?} (Hash: {})",
+                    if block.copy_from.is_some() {
+                        "Copied from identical file"
+                    } else {
+                        "Transferred"
+                    },
+                    block.source_path,
+                    path,
+                    block.file_hash.as_deref().unwrap_or("?")
+                ))?;
+            }
+
+            if let Some(transfer_log) = &transfer_log {
+                let relative_path = dest_path
+                    .strip_prefix(&config.dest_dir)
+                    .unwrap_or(dest_path);
+                transfer_log.append(&relative_path.display().to_string())?;
+            }
+
+            if config.delay_updates {
+                let mut staged = control.staged.lock().unwrap();
+                for (staged_path, path) in write_paths.into_iter().zip(block.dest_paths) {
+                    staged.push(StagedFile {
+                        staged_path,
+                        dest_path: path,
+                        source_path: block.source_path.clone(),
+                    });
+                }
+            }
+        }
+    }
+    stats.transfer_time = start_time.elapsed();
+    Ok(stats)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_create_hasher() {
+        for (algo, expected) in HASH_KNOWN_ANSWERS {
+            let mut h = create_hasher(algo);
+            h.update(HASH_KNOWN_ANSWER_INPUT);
+            assert_eq!(h.finalize_hex().unwrap(), expected);
+        }
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_command_hasher() {
+        let mut h = CommandHasher::spawn("sha1sum | cut -d' ' -f1").unwrap();
+        h.update(b"hel");
+        h.update(b"lo");
+        assert_eq!(
+            h.finalize_hex().unwrap(),
+            "aaf4c61ddcc5e8a2dabede0f3b482cd9aea9434d"
+        );
+
+        // Empty input still invokes the command
+        let mut h = CommandHasher::spawn("sha1sum | cut -d' ' -f1").unwrap();
+        assert_eq!(
+            h.finalize_hex().unwrap(),
+            "da39a3ee5e6b4b0d3255bfef95601890afd80709"
+        );
+
+        // Non-zero exit status fails the hash
+        let mut h = CommandHasher::spawn("cat > /dev/null; exit 3").unwrap();
+        h.update(b"hello");
+        assert!(h.finalize_hex().is_err());
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn test_command_hasher_reaps_abandoned_command() {
+        let h = CommandHasher::spawn("sleep 30").unwrap();
+        let proc_entry = PathBuf::from(format!("/proc/{}", h.child.id()));
+        assert!(proc_entry.exists());
+        // Neither running nor a zombie once the hasher is dropped unfinished
+        drop(h);
+        assert!(!proc_entry.exists());
+    }
+
+    #[test]
+    fn test_consumer_trims_stale_tail() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let dest_path = dir.path().join("dest.bin");
+        // Destination left larger by a previous run
+        fs::write(&dest_path, vec![0xAAu8; 1000])?;
+
+        let (sender, receiver) = crossbeam_channel::bounded(1);
+        sender.send(Block {
+            data: b"world".to_vec(),
+            offset: 5,
+            dest_paths: vec![dest_path.clone()],
+            source_path: dir.path().join("source.bin"),
+            atime: 0,
+            created: None,
+            pending_reason: None,
+            mtime: 0,
+            ctime: 0,
+            permissions: 0,
+            is_last_block: true,
+            file_hash: Some("hash".to_string()),
+            file_size: 10,
+            aborted: false,
+            copy_from: None,
+            file_flags: None,
+        })?;
+        drop(sender);
+
+        let db = std::sync::Arc::new(std::sync::Mutex::new(Database::new(":memory:")?));
+        let logger = std::sync::Arc::new(Logger::new(dir.path().join("log.txt").to_str().unwrap()));
+        run_consumer(
+            PipelineConfig::default(),
+            receiver,
+            db,
+            logger,
+            std::sync::Arc::new(TransferControl::default()),
+        )?;
+
+        assert_eq!(fs::metadata(&dest_path)?.len(), 10);
+        Ok(())
+    }
+
+    #[test]
+    fn test_consumer_limits_new_and_updated_files_separately() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let config = PipelineConfig {
+            bw_limit_new: Some(100_000),
+            bw_limit_update: Some(10_000),
+            ..Default::default()
+        };
+
+        // Time to write 5000 bytes of a file queued for the given reason
+        let transfer_time = |name: &str, reason: PendingReason| -> Result<Duration> {
+            let (sender, receiver) = crossbeam_channel::bounded(1);
+            sender.send(Block {
+                data: vec![0u8; 5000],
+                offset: 0,
+                dest_paths: vec![dir.path().join(name)],
+                source_path: dir.path().join("source.bin"),
+                atime: 0,
+                created: None,
+                pending_reason: Some(reason),
+                mtime: 0,
+                ctime: 0,
+                permissions: 0,
+                is_last_block: true,
+                file_hash: None,
+                file_size: 5000,
+                aborted: false,
+                copy_from: None,
+                file_flags: None,
+            })?;
+            drop(sender);
+
+            let start = Instant::now();
+            run_consumer(
+                config.clone(),
+                receiver,
+                std::sync::Arc::new(std::sync::Mutex::new(Database::new(":memory:")?)),
+                std::sync::Arc::new(Logger::new(dir.path().join("log.txt").to_str().unwrap())),
+                std::sync::Arc::new(TransferControl::default()),
+            )?;
+            Ok(start.elapsed())
+        };
+
+        // 5000 bytes take 0.05s at the new-file limit and 0.5s at the update limit
+        assert!(transfer_time("new.bin", PendingReason::DestMissing)? < Duration::from_millis(300));
+        assert!(
+            transfer_time("updated.bin", PendingReason::Changed)? >= Duration::from_millis(450)
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_consumer_caps_bandwidth_per_file() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        // No aggregate limit: only the per-file ceiling slows the file down
+        let config = PipelineConfig {
+            bw_limit_per_file: Some(100_000),
+            ..Default::default()
+        };
+        let (sender, receiver) = crossbeam_channel::unbounded();
+        for i in 0..10u64 {
+            sender.send(Block {
+                data: vec![0u8; 5000],
+                offset: i * 5000,
+                dest_paths: vec![dir.path().join("large.bin")],
+                source_path: dir.path().join("source.bin"),
+                atime: 0,
+                created: None,
+                pending_reason: Some(PendingReason::DestMissing),
+                mtime: 0,
+                ctime: 0,
+                permissions: 0,
+                is_last_block: i == 9,
+                file_hash: None,
+                file_size: 50_000,
+                aborted: false,
+                copy_from: None,
+                file_flags: None,
+            })?;
+        }
+        drop(sender);
+
+        let start = Instant::now();
+        run_consumer(
+            config,
+            receiver,
+            std::sync::Arc::new(std::sync::Mutex::new(Database::new(":memory:")?)),
+            std::sync::Arc::new(Logger::new(dir.path().join("log.txt").to_str().unwrap())),
+            std::sync::Arc::new(TransferControl::default()),
+        )?;
+
+        // 50000 bytes at 100000 bytes/s take at least 0.5s
+        let rate = 50_000.0 / start.elapsed().as_secs_f64();
+        assert!(rate <= 100_000.0 * 1.05, "rate {} above the ceiling", rate);
+        assert_eq!(fs::metadata(dir.path().join("large.bin"))?.len(), 50_000);
+        Ok(())
+    }
+
+    #[test]
+    fn test_verify_size_leaves_short_file_pending() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let source_path = dir.path().join("source.bin");
+        let dest_path = dir.path().join("dest.bin");
+        let logger = std::sync::Arc::new(Logger::new(dir.path().join("log.txt").to_str().unwrap()));
+
+        // A short write: the last block ends half way into the 10000-byte file
+        let transfer = |verify_size: bool| -> Result<u64> {
+            let db = std::sync::Arc::new(std::sync::Mutex::new(Database::new(":memory:")?));
+            db.lock().unwrap().upsert_file(
+                source_path.to_str().unwrap(),
+                dest_path.to_str().unwrap(),
+                0,
+                1_000_000,
+                1_000_000,
+                0o644,
+                10_000,
+                crate::db::FileStatus::Pending,
+            )?;
+            let (sender, receiver) = crossbeam_channel::unbounded();
+            sender.send(Block {
+                data: vec![1u8; 5000],
+                offset: 0,
+                dest_paths: vec![dest_path.clone()],
+                source_path: source_path.clone(),
+                atime: 1_000_000,
+                created: None,
+                pending_reason: Some(PendingReason::DestMissing),
+                mtime: 1_000_000,
+                ctime: 1_000_000,
+                permissions: 0o644,
+                is_last_block: true,
+                file_hash: None,
+                file_size: 10_000,
+                aborted: false,
+                copy_from: None,
+                file_flags: None,
+            })?;
+            drop(sender);
+            let config = PipelineConfig {
+                verify_size,
+                ..Default::default()
+            };
+            let control = std::sync::Arc::new(TransferControl::default());
+            run_consumer(config, receiver, db.clone(), logger.clone(), control)?;
+            let pending = db.lock().unwrap().pending_count()?;
+            Ok(pending)
+        };
+
+        // Unchecked, the truncated copy passes for synced
+        assert_eq!(transfer(false)?, 0);
+        fs::remove_file(&dest_path)?;
+
+        assert_eq!(transfer(true)?, 1);
+        let log = fs::read_to_string(dir.path().join("log.txt"))?;
+        assert!(log.contains("Destination size mismatch"), "{}", log);
+        // Without the source mtime, a later scan does not take it for synced either
+        let mtime = FileTime::from_last_modification_time(&fs::metadata(&dest_path)?);
+        assert_ne!(mtime.unix_seconds(), 1_000_000);
+        Ok(())
+    }
+
+    #[test]
+    fn test_consumer_only_write_changed() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let dest_path = dir.path().join("dest.bin");
+        let content: Vec<u8> = (0..10 * 1024u32).map(|i| i as u8).collect();
+        // Same size on disk, one block differs
+        let mut existing = content.clone();
+        existing[4 * 1024 + 7] ^= 0xff;
+        fs::write(&dest_path, &existing)?;
+
+        let (sender, receiver) = crossbeam_channel::bounded(10);
+        for (index, chunk) in content.chunks(1024).enumerate() {
+            sender.send(Block {
+                data: chunk.to_vec(),
+                offset: (index * 1024) as u64,
+                dest_paths: vec![dest_path.clone()],
+                source_path: dir.path().join("source.bin"),
+                atime: 0,
+                created: None,
+                pending_reason: Some(PendingReason::Changed),
+                mtime: 0,
+                ctime: 0,
+                permissions: 0,
+                is_last_block: index == 9,
+                file_hash: None,
+                file_size: content.len() as u64,
+                aborted: false,
+                copy_from: None,
+                file_flags: None,
+            })?;
+        }
+        drop(sender);
+
+        let config = PipelineConfig {
+            only_write_changed: true,
+            ..Default::default()
+        };
+        let stats = run_consumer(
+            config,
+            receiver,
+            std::sync::Arc::new(std::sync::Mutex::new(Database::new(":memory:")?)),
+            std::sync::Arc::new(Logger::new(dir.path().join("log.txt").to_str().unwrap())),
+            std::sync::Arc::new(TransferControl::default()),
+        )?;
+
+        assert_eq!(stats.blocks, 10);
+        assert_eq!(stats.unchanged_blocks, 9);
+        assert_eq!(fs::read(&dest_path)?, content);
+        Ok(())
+    }
+
+    #[test]
+    fn test_consumer_stores_dest_hash() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let source_path = dir.path().join("source.txt");
+        let dest_path = dir.path().join("dest.txt");
+        let hash = "2cf24dba5fb0a30e26e83b2ac5b9e29e1b161e5c1fa7425e73043362938b9824";
+
+        let db = std::sync::Arc::new(std::sync::Mutex::new(Database::new(":memory:")?));
+        db.lock().unwrap().upsert_file(
+            source_path.to_str().unwrap(),
+            dest_path.to_str().unwrap(),
+            0,
+            0,
+            0,
+            0o644,
+            5,
+            crate::db::FileStatus::Pending,
+        )?;
+
+        let (sender, receiver) = crossbeam_channel::bounded(1);
+        sender.send(Block {
+            data: b"hello".to_vec(),
+            offset: 0,
+            dest_paths: vec![dest_path.clone()],
+            source_path: source_path.clone(),
+            atime: 0,
+            created: None,
+            pending_reason: None,
+            mtime: 0,
+            ctime: 0,
+            permissions: 0,
+            is_last_block: true,
+            file_hash: Some(hash.to_string()),
+            file_size: 5,
+            aborted: false,
+            copy_from: None,
+            file_flags: None,
+        })?;
+        drop(sender);
+
+        let logger = std::sync::Arc::new(Logger::new(dir.path().join("log.txt").to_str().unwrap()));
+        let config = PipelineConfig {
+            block_size: 1024,
+            verify_dest: true,
+            ..Default::default()
+        };
+        run_consumer(
+            config,
+            receiver,
+            db.clone(),
+            logger,
+            std::sync::Arc::new(TransferControl::default()),
+        )?;
+
+        let synced = db.lock().unwrap().get_synced_files()?;
+        assert_eq!(synced.len(), 1);
+        assert_eq!(synced[0].dest_hash.as_deref(), Some(hash));
+        Ok(())
+    }
+
+    #[test]
+    fn test_consumer_verify_failure_keeps_file_unsynced() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let source_path = dir.path().join("source.txt");
+        let dest_path = dir.path().join("dest.txt");
+        let db = std::sync::Arc::new(std::sync::Mutex::new(Database::new(":memory:")?));
+        db.lock().unwrap().upsert_file(
+            source_path.to_str().unwrap(),
+            dest_path.to_str().unwrap(),
+            0,
+            1_000_000,
+            1_000_000,
+            0o644,
+            5,
+            crate::db::FileStatus::Pending,
+        )?;
+
+        // A source hash the written content cannot match, as if corrupted on the way
+        let (sender, receiver) = crossbeam_channel::bounded(1);
+        sender.send(Block {
+            data: b"hello".to_vec(),
+            offset: 0,
+            dest_paths: vec![dest_path.clone()],
+            source_path: source_path.clone(),
+            atime: 1_000_000,
+            created: None,
+            pending_reason: None,
+            mtime: 1_000_000,
+            ctime: 1_000_000,
+            permissions: 0,
+            is_last_block: true,
+            file_hash: Some("0".repeat(64)),
+            file_size: 5,
+            aborted: false,
+            copy_from: None,
+            file_flags: None,
+        })?;
+        drop(sender);
+
+        let logger = std::sync::Arc::new(Logger::new(dir.path().join("log.txt").to_str().unwrap()));
+        let config = PipelineConfig {
+            block_size: 1024,
+            verify_dest: true,
+            ..Default::default()
+        };
+        run_consumer(
+            config,
+            receiver,
+            db.clone(),
+            logger,
+            std::sync::Arc::new(TransferControl::default()),
+        )?;
+
+        // The bad copy is removed rather than left for a later scan to pass as synced
+        assert!(!dest_path.exists());
+        assert_eq!(db.lock().unwrap().pending_count()?, 1);
+        assert_eq!(db.lock().unwrap().get_pending_files()?[0].attempts, 1);
+        Ok(())
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_consumer_verify_read_error_leaves_file_pending() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let db = std::sync::Arc::new(std::sync::Mutex::new(Database::new(":memory:")?));
+        let (sender, receiver) = crossbeam_channel::bounded(2);
+        for name in ["a.txt", "b.txt"] {
+            let source_path = dir.path().join(name);
+            let dest_path = dir.path().join(format!("{}.dest", name));
+            db.lock().unwrap().upsert_file(
+                source_path.to_str().unwrap(),
+                dest_path.to_str().unwrap(),
+                0,
+                1_000_000,
+                1_000_000,
+                0o644,
+                5,
+                crate::db::FileStatus::Pending,
+            )?;
+            sender.send(Block {
+                data: b"hello".to_vec(),
+                offset: 0,
+                dest_paths: vec![dest_path],
+                source_path,
+                atime: 1_000_000,
+                created: None,
+                pending_reason: None,
+                mtime: 1_000_000,
+                ctime: 1_000_000,
+                permissions: 0,
+                is_last_block: true,
+                file_hash: Some("hash".to_string()),
+                file_size: 5,
+                aborted: false,
+                copy_from: None,
+                file_flags: None,
+            })?;
+        }
+        drop(sender);
+
+        // Reading back the destinations fails every time
+        let log_path = dir.path().join("log.txt");
+        let logger = std::sync::Arc::new(Logger::new(log_path.to_str().unwrap()));
+        let config = PipelineConfig {
+            block_size: 1024,
+            verify_dest: true,
+            checksum_command: Some("cat > /dev/null; exit 3".to_string()),
+            ..Default::default()
+        };
+        run_consumer(
+            config,
+            receiver,
+            db.clone(),
+            logger,
+            std::sync::Arc::new(TransferControl::default()),
+        )?;
+
+        // Both files were written and tried, and both stay pending
+        assert!(dir.path().join("b.txt.dest").exists());
+        assert_eq!(db.lock().unwrap().pending_count()?, 2);
+        let log = fs::read_to_string(&log_path)?;
+        assert_eq!(log.matches("for verification").count(), 2, "{}", log);
+        Ok(())
+    }
+
+    #[test]
+    fn test_consumer_aborts_after_mismatches() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let db = std::sync::Arc::new(std::sync::Mutex::new(Database::new(":memory:")?));
+
+        // Three files whose source hashes the written content cannot match
+        let (sender, receiver) = crossbeam_channel::bounded(3);
+        for name in ["a.txt", "b.txt", "c.txt"] {
+            let source_path = dir.path().join(name);
+            let dest_path = dir.path().join(format!("{}.dest", name));
+            db.lock().unwrap().upsert_file(
+                source_path.to_str().unwrap(),
+                dest_path.to_str().unwrap(),
+                0,
+                1_000_000,
+                1_000_000,
+                0o644,
+                5,
+                crate::db::FileStatus::Pending,
+            )?;
+            sender.send(Block {
+                data: b"hello".to_vec(),
+                offset: 0,
+                dest_paths: vec![dest_path],
+                source_path,
+                atime: 1_000_000,
+                created: None,
+                pending_reason: None,
+                mtime: 1_000_000,
+                ctime: 1_000_000,
+                permissions: 0,
+                is_last_block: true,
+                file_hash: Some("0".repeat(64)),
+                file_size: 5,
+                aborted: false,
+                copy_from: None,
+                file_flags: None,
+            })?;
+        }
+        drop(sender);
+
+        let logger = std::sync::Arc::new(Logger::new(dir.path().join("log.txt").to_str().unwrap()));
+        let config = PipelineConfig {
+            block_size: 1024,
+            verify_dest: true,
+            abort_after_mismatches: Some(2),
+            ..Default::default()
+        };
+        let control = std::sync::Arc::new(TransferControl::default());
+        let error = run_consumer(
+            config.clone(),
+            receiver,
+            db.clone(),
+            logger,
+            control.clone(),
+        )
+        .unwrap_err();
+
+        // Stopped at the second mismatch: the third file was never written
+        assert!(error.to_string().contains("2 destination hash mismatches"));
+        assert_eq!(control.mismatches.load(Ordering::SeqCst), 2);
+        assert!(control.mismatch_limit_reached(&config));
+        assert!(!dir.path().join("c.txt.dest").exists());
+        assert_eq!(db.lock().unwrap().pending_count()?, 3);
+        Ok(())
+    }
+
+    #[test]
+    fn test_producer_reuses_cached_hash_across_sessions() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let db_path = dir.path().join("hsync.db");
+        let source_path = dir.path().join("source.txt");
+        fs::write(&source_path, b"hello")?;
+        let mtime =
+            FileTime::from_last_modification_time(&fs::metadata(&source_path)?).unix_seconds();
+
+        // A cached hash no real hashing would produce proves it was reused
+        let cached = "0".repeat(64);
+        Database::new(&db_path)?.cache_hash(
+            source_path.to_str().unwrap(),
+            mtime,
+            5,
+            "sha256",
+            &cached,
+        )?;
+
+        // A new session with a fresh record that holds no hash of its own
+        let db = std::sync::Arc::new(std::sync::Mutex::new(Database::new(&db_path)?));
+        db.lock().unwrap().upsert_file(
+            source_path.to_str().unwrap(),
+            dir.path().join("dest.txt").to_str().unwrap(),
+            0,
+            mtime,
+            mtime,
+            0o644,
+            5,
+            crate::db::FileStatus::Pending,
+        )?;
+        let (sender, receiver) = crossbeam_channel::unbounded();
+        let logger = std::sync::Arc::new(Logger::new(dir.path().join("log.txt").to_str().unwrap()));
+        let config = PipelineConfig {
+            block_size: 1024,
+            ..Default::default()
+        };
+        run_producer(config.clone(), sender, db.clone(), logger.clone())?;
+        let block = receiver.recv()?;
+        assert!(block.is_last_block);
+        assert_eq!(block.file_hash.as_deref(), Some(cached.as_str()));
+
+        // Rehashing ignores the cache
+        let (sender, receiver) = crossbeam_channel::unbounded();
+        let config = PipelineConfig {
+            rehash: true,
+            ..config
+        };
+        run_producer(config, sender, db, logger)?;
+        assert_ne!(receiver.recv()?.file_hash, Some(cached));
+        Ok(())
+    }
+
+    #[test]
+    fn test_producer_reuses_stored_hash() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let source_path = dir.path().join("source.txt");
+        let dest_path = dir.path().join("dest.txt");
+        fs::write(&source_path, b"hello")?;
+        let mtime =
+            FileTime::from_last_modification_time(&fs::metadata(&source_path)?).unix_seconds();
+
+        // A stored hash no real hashing would produce proves it was reused
+        let stored = "0".repeat(64);
+        let db = std::sync::Arc::new(std::sync::Mutex::new(Database::new(":memory:")?));
+        {
+            let db_guard = db.lock().unwrap();
+            db_guard.upsert_file(
+                source_path.to_str().unwrap(),
+                dest_path.to_str().unwrap(),
+                0,
+                mtime,
+                mtime,
+                0o644,
+                5,
+                crate::db::FileStatus::Pending,
+            )?;
+            db_guard.mark_synced(
+                source_path.to_str().unwrap(),
+                SyncedHashes {
+                    hash: Some(&stored),
+                    ..Default::default()
+                },
+            )?;
+            // Destination went missing: same source, pending again
+            db_guard.upsert_file(
+                source_path.to_str().unwrap(),
+                dest_path.to_str().unwrap(),
+                0,
+                mtime,
+                mtime,
+                0o644,
+                5,
+                crate::db::FileStatus::Pending,
+            )?;
+        }
+
+        let (sender, receiver) = crossbeam_channel::bounded(4);
+        let logger = std::sync::Arc::new(Logger::new(dir.path().join("log.txt").to_str().unwrap()));
+        let config = PipelineConfig {
+            block_size: 1024,
+            ..Default::default()
+        };
+        run_producer(config.clone(), sender, db.clone(), logger.clone())?;
+        run_consumer(
+            config,
+            receiver,
+            db.clone(),
+            logger,
+            std::sync::Arc::new(TransferControl::default()),
+        )?;
+
+        assert_eq!(fs::read(&dest_path)?, b"hello");
+        let synced = db.lock().unwrap().get_synced_files()?;
+        assert_eq!(synced.len(), 1);
+        assert_eq!(synced[0].hash.as_deref(), Some(stored.as_str()));
+        Ok(())
+    }
+
+    #[test]
+    fn test_blake3_does_not_reuse_unlabeled_sha256_hash() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let source_path = dir.path().join("source.txt");
+        fs::write(&source_path, HASH_KNOWN_ANSWER_INPUT)?;
+        let mtime =
+            FileTime::from_last_modification_time(&fs::metadata(&source_path)?).unix_seconds();
+
+        // A hash stored before algorithms were recorded: 64 hex digits, like BLAKE3's
+        let db = std::sync::Arc::new(std::sync::Mutex::new(Database::new(":memory:")?));
+        {
+            let db_guard = db.lock().unwrap();
+            db_guard.upsert_file(
+                source_path.to_str().unwrap(),
+                dir.path().join("dest.txt").to_str().unwrap(),
+                0,
+                mtime,
+                mtime,
+                0o644,
+                5,
+                crate::db::FileStatus::Pending,
+            )?;
+            db_guard.mark_synced(
+                source_path.to_str().unwrap(),
+                SyncedHashes {
+                    hash: Some(&"0".repeat(64)),
+                    ..Default::default()
+                },
+            )?;
+            // Pending again, source unchanged
+            db_guard.upsert_file(
+                source_path.to_str().unwrap(),
+                dir.path().join("dest.txt").to_str().unwrap(),
+                0,
+                mtime,
+                mtime,
+                0o644,
+                5,
+                crate::db::FileStatus::Pending,
+            )?;
+        }
+
+        let (sender, receiver) = crossbeam_channel::bounded(4);
+        let logger = std::sync::Arc::new(Logger::new(dir.path().join("log.txt").to_str().unwrap()));
+        let config = PipelineConfig {
+            block_size: 1024,
+            hash_algo: HashAlgorithm::Blake3,
+            ..Default::default()
+        };
+        run_producer(config, sender, db, logger)?;
+        let block = receiver.recv()?;
+        let (_, blake3_hash) = HASH_KNOWN_ANSWERS[4];
+        assert_eq!(block.file_hash.as_deref(), Some(blake3_hash));
+        Ok(())
+    }
+
+    #[test]
+    fn test_transfer_rates_current_reacts_faster_than_average() {
+        let start = Instant::now();
+        let mut rates = TransferRates::new(start);
+        let mb = 1024 * 1024;
+
+        // A minute at 10 MB/s: both rates agree
+        for second in 1..=60 {
+            rates.record(start + Duration::from_secs(second), second * 10 * mb);
+        }
+        let now = start + Duration::from_secs(60);
+        assert_eq!(rates.current(), (10 * mb) as f64);
+        assert_eq!(rates.average(now, 600 * mb), (10 * mb) as f64);
+
+        // The link drops to 1 MB/s: the current rate shows it within the window,
+        // while the average barely moves
+        let mut bytes_done = 600 * mb;
+        for second in 61..=66 {
+            bytes_done += mb;
+            rates.record(start + Duration::from_secs(second), bytes_done);
+        }
+        let now = start + Duration::from_secs(66);
+        assert_eq!(rates.current(), mb as f64);
+        assert!(rates.average(now, bytes_done) > (9 * mb) as f64);
+
+        // Both are labeled in the status line
+        let message = status_message(
+            &mut rates,
+            3,
+            10,
+            bytes_done,
+            1000 * mb,
+            Path::new("f"),
+            false,
+        );
+        assert!(message.contains(" Now: ") && message.contains(" Avg: "));
+    }
+
+    #[test]
+    fn test_progress_template() -> Result<()> {
+        assert!(progress_style(None, false).is_ok());
+        assert!(progress_style(None, true).is_ok());
+        assert!(progress_style(Some("{msg} {wide_bar} {eta_formatted}"), false).is_ok());
+        assert!(progress_style(Some("{bar:x}"), false).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_producer_reports_empty_files() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let db = std::sync::Arc::new(std::sync::Mutex::new(Database::new(":memory:")?));
+        for i in 0..200 {
+            let source = dir.path().join(format!("empty{}.txt", i));
+            File::create(&source)?;
+            db.lock().unwrap().upsert_file(
+                source.to_str().unwrap(),
+                dir.path().join(format!("empty{}.out", i)).to_str().unwrap(),
+                0,
+                0,
+                0,
+                0o644,
+                0,
+                crate::db::FileStatus::Pending,
+            )?;
+        }
+
+        let (sender, receiver) = crossbeam_channel::unbounded();
+        let logger = std::sync::Arc::new(Logger::new(dir.path().join("log.txt").to_str().unwrap()));
+        let config = PipelineConfig {
+            block_size: 1024,
+            stats_interval: Some(Duration::from_secs(3600)),
+            ..Default::default()
+        };
+        run_producer(config, sender, db, logger)?;
+        assert_eq!(receiver.iter().count(), 200);
+
+        // The final snapshot counts every empty file
+        let log = fs::read_to_string(dir.path().join("log.txt"))?;
+        let snapshots: Vec<&str> = log.lines().filter(|line| line.contains("Stats:")).collect();
+        assert_eq!(snapshots.len(), 1);
+        assert!(snapshots[0].contains("files_done=200 files_total=200"));
+        Ok(())
+    }
+
+    #[test]
+    fn test_stats_snapshots_continue_while_stalled() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let source = dir.path().join("source.bin");
+        fs::write(&source, vec![7u8; 4096])?;
+        let db = std::sync::Arc::new(std::sync::Mutex::new(Database::new(":memory:")?));
+        db.lock().unwrap().upsert_file(
+            source.to_str().unwrap(),
+            dir.path().join("dest.bin").to_str().unwrap(),
+            0,
+            0,
+            0,
+            0o644,
+            4096,
+            crate::db::FileStatus::Pending,
+        )?;
+
+        // Nothing drains the queue for a while: the producer stalls on its second block
+        let (sender, receiver) = crossbeam_channel::bounded(1);
+        let log_path = dir.path().join("log.txt");
+        let logger = std::sync::Arc::new(Logger::new(log_path.to_str().unwrap()));
+        let config = PipelineConfig {
+            block_size: 1024,
+            stats_interval: Some(Duration::from_millis(50)),
+            ..Default::default()
+        };
+        let producer = std::thread::spawn(move || run_producer(config, sender, db, logger));
+        std::thread::sleep(Duration::from_millis(400));
+        let log = fs::read_to_string(&log_path)?;
+        let stalled = log.lines().filter(|line| line.contains("Stats:")).count();
+        assert!(stalled >= 3, "{}", log);
+
+        assert_eq!(receiver.iter().count(), 4);
+        producer.join().unwrap()?;
+        let log = fs::read_to_string(&log_path)?;
+        let last = log.lines().rfind(|line| line.contains("Stats:")).unwrap();
+        assert!(last.contains("files_done=1 files_total=1 bytes_done=4096"));
+        Ok(())
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn test_producer_skips_destination_hardlinked_to_source() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let source = dir.path().join("source.txt");
+        let dest = dir.path().join("dest.txt");
+        fs::write(&source, b"precious")?;
+        fs::hard_link(&source, &dest)?;
+        let db = std::sync::Arc::new(std::sync::Mutex::new(Database::new(":memory:")?));
+        db.lock().unwrap().upsert_file(
+            source.to_str().unwrap(),
+            dest.to_str().unwrap(),
+            0,
+            0,
+            0,
+            0o644,
+            8,
+            crate::db::FileStatus::Pending,
+        )?;
+
+        let (sender, receiver) = crossbeam_channel::unbounded();
+        let log_path = dir.path().join("log.txt");
+        let logger = std::sync::Arc::new(Logger::new(log_path.to_str().unwrap()));
+        let config = PipelineConfig {
+            block_size: 1024,
+            ..Default::default()
+        };
+        run_producer(config.clone(), sender, db.clone(), logger.clone())?;
+        run_consumer(
+            config.clone(),
+            receiver,
+            db.clone(),
+            logger,
+            std::sync::Arc::new(TransferControl::default()),
+        )?;
+
+        // Nothing was sent, so the shared file was never truncated
+        assert_eq!(fs::read(&source)?, b"precious");
+        assert_eq!(db.lock().unwrap().pending_count()?, 1);
+        assert!(fs::read_to_string(&log_path)?.contains("destination is the source file itself"));
+
+        // Failed with its own reason, it is not retried by later transfers
+        let pending = db.lock().unwrap().get_pending_files()?;
+        assert_eq!(pending[0].pending_reason, Some(PendingReason::SameFile));
+        assert_eq!(pending[0].attempts, 1);
+        let (sender, receiver) = crossbeam_channel::unbounded();
+        let logger = std::sync::Arc::new(Logger::new(log_path.to_str().unwrap()));
+        let stats = run_producer(config, sender, db.clone(), logger)?;
+        assert_eq!(stats.files_started, 0);
+        assert_eq!(receiver.iter().count(), 0);
+        assert_eq!(db.lock().unwrap().get_pending_files()?[0].attempts, 1);
+        Ok(())
+    }
+
+    #[test]
+    fn test_producer_uses_block_size_override() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let db = std::sync::Arc::new(std::sync::Mutex::new(Database::new(":memory:")?));
+        for name in ["disk.bin", "notes.txt"] {
+            let source = dir.path().join(name);
+            fs::write(&source, vec![7u8; 10_000])?;
+            db.lock().unwrap().upsert_file(
+                source.to_str().unwrap(),
+                dir.path().join(format!("{}.out", name)).to_str().unwrap(),
+                0,
+                0,
+                0,
+                0o644,
+                10_000,
+                crate::db::FileStatus::Pending,
+            )?;
+        }
+
+        let (sender, receiver) = crossbeam_channel::unbounded();
+        let logger = std::sync::Arc::new(Logger::new(dir.path().join("log.txt").to_str().unwrap()));
+        let config = PipelineConfig {
+            source_dir: dir.path().to_path_buf(),
+            block_size: 4096,
+            block_size_overrides: vec![("*.bin".to_string(), 1024)],
+            ..Default::default()
+        };
+        run_producer(config, sender, db, logger)?;
+
+        let mut sizes: std::collections::HashMap<String, Vec<usize>> = Default::default();
+        for block in receiver.iter() {
+            let name = block.source_path.file_name().unwrap().to_string_lossy();
+            sizes
+                .entry(name.into_owned())
+                .or_default()
+                .push(block.data.len());
+        }
+        let mut bin = vec![1024; 9];
+        bin.push(10_000 - 9 * 1024);
+        assert_eq!(sizes["disk.bin"], bin);
+        assert_eq!(sizes["notes.txt"], vec![4096, 4096, 10_000 - 2 * 4096]);
+        Ok(())
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_consumer_discards_file_abandoned_midway() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let source = dir.path().join("source.bin");
+        let dest = dir.path().join("dest.bin");
+        fs::write(&source, vec![7u8; 4096])?;
+        let db = std::sync::Arc::new(std::sync::Mutex::new(Database::new(":memory:")?));
+        db.lock().unwrap().upsert_file(
+            source.to_str().unwrap(),
+            dest.to_str().unwrap(),
+            0,
+            0,
+            0,
+            0o644,
+            4096,
+            crate::db::FileStatus::Pending,
+        )?;
+
+        // The checksum fails once the whole file was read, after three of the four
+        // blocks were sent
+        let log_path = dir.path().join("log.txt");
+        let logger = std::sync::Arc::new(Logger::new(log_path.to_str().unwrap()));
+        let config = PipelineConfig {
+            block_size: 1024,
+            checksum_command: Some("cat > /dev/null; exit 3".to_string()),
+            ..Default::default()
+        };
+        let (sender, receiver) = crossbeam_channel::unbounded();
+        run_producer(config.clone(), sender, db.clone(), logger.clone())?;
+        run_consumer(
+            config,
+            receiver,
+            db.clone(),
+            logger,
+            std::sync::Arc::new(TransferControl::default()),
+        )?;
+
+        assert!(!dest.exists());
+        assert_eq!(db.lock().unwrap().pending_count()?, 1);
+        assert!(fs::read_to_string(&log_path)?.contains("Discarded partial copy"));
+        Ok(())
+    }
+
+    #[test]
+    fn test_ordered_runs_replay_the_same_sequence() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let db = std::sync::Arc::new(std::sync::Mutex::new(Database::new(":memory:")?));
+        for name in ["d.txt", "b.txt", "e.txt", "a.txt", "c.txt"] {
+            let source = dir.path().join(name);
+            fs::write(&source, name)?;
+            db.lock().unwrap().upsert_file(
+                source.to_str().unwrap(),
+                dir.path().join(format!("{}.out", name)).to_str().unwrap(),
+                0,
+                0,
+                0,
+                0o644,
+                5,
+                crate::db::FileStatus::Pending,
+            )?;
+        }
+        let logger = std::sync::Arc::new(Logger::new(dir.path().join("log.txt").to_str().unwrap()));
+        let config = PipelineConfig {
+            block_size: 1024,
+            ordered: true,
+            ..Default::default()
+        };
+
+        // Without a consumer nothing is marked synced, so both runs start from the
+        // same database state
+        let run = || -> Result<Vec<String>> {
+            let (sender, receiver) = crossbeam_channel::unbounded();
+            run_producer(config.clone(), sender, db.clone(), logger.clone())?;
+            Ok(receiver
+                .iter()
+                .map(|block| block.source_path.to_string_lossy().into_owned())
+                .collect())
+        };
+        let first = run()?;
+        let second = run()?;
+
+        let mut sorted = first.clone();
+        sorted.sort();
+        assert_eq!(first.len(), 5);
+        assert_eq!(first, sorted);
+        assert_eq!(first, second);
+        assert_eq!(db.lock().unwrap().get_transfer_plan()?, first);
+        Ok(())
+    }
+
+    #[test]
+    fn test_group_by_dir_transfers_one_directory_at_a_time() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let db = std::sync::Arc::new(std::sync::Mutex::new(Database::new(":memory:")?));
+        // In plain path order, a/sub/y.txt would come between a/x.txt and a/z.txt
+        let names = ["b/w.txt", "a/z.txt", "a/sub/y.txt", "a/x.txt"];
+        for name in names {
+            let source = dir.path().join("source").join(name);
+            fs::create_dir_all(source.parent().unwrap())?;
+            fs::write(&source, name)?;
+            let mtime = FileTime::from_last_modification_time(&fs::metadata(&source)?);
+            db.lock().unwrap().upsert_file(
+                source.to_str().unwrap(),
+                dir.path().join("dest").join(name).to_str().unwrap(),
+                0,
+                mtime.unix_seconds(),
+                mtime.unix_seconds(),
+                0o644,
+                name.len() as u64,
+                crate::db::FileStatus::Pending,
+            )?;
+        }
+        let expected: Vec<PathBuf> = ["a/x.txt", "a/z.txt", "a/sub/y.txt", "b/w.txt"]
+            .iter()
+            .map(|name| dir.path().join("source").join(name))
+            .collect();
+        let grouped: Vec<PathBuf> = db
+            .lock()
+            .unwrap()
+            .get_pending_files_by_dir()?
+            .into_iter()
+            .map(|file| PathBuf::from(file.source_path))
+            .collect();
+        assert_eq!(grouped, expected);
+
+        let logger = std::sync::Arc::new(Logger::new(dir.path().join("log.txt").to_str().unwrap()));
+        let config = PipelineConfig {
+            block_size: 1024,
+            group_by_dir: true,
+            ..Default::default()
+        };
+        let (sender, receiver) = crossbeam_channel::unbounded();
+        run_producer(config.clone(), sender, db.clone(), logger.clone())?;
+        let blocks: Vec<Block> = receiver.iter().collect();
+        let sent: Vec<PathBuf> = blocks.iter().map(|b| b.source_path.clone()).collect();
+        assert_eq!(sent, expected);
+
+        let (sender, receiver) = crossbeam_channel::unbounded();
+        for block in blocks {
+            sender.send(block)?;
+        }
+        drop(sender);
+        run_consumer(
+            config,
+            receiver,
+            db.clone(),
+            logger,
+            std::sync::Arc::new(TransferControl::default()),
+        )?;
+        for name in names {
+            assert_eq!(
+                fs::read_to_string(dir.path().join("dest").join(name))?,
+                name
+            );
+        }
+        assert_eq!(db.lock().unwrap().pending_count()?, 0);
+        Ok(())
+    }
+
+    #[test]
+    fn test_cancel_request_stops_run_between_files() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let db = std::sync::Arc::new(std::sync::Mutex::new(Database::new(":memory:")?));
+        for i in 0..5 {
+            let source = dir.path().join(format!("{}.txt", i));
+            fs::write(&source, b"data")?;
+            let mtime = FileTime::from_last_modification_time(&fs::metadata(&source)?);
+            db.lock().unwrap().upsert_file(
+                source.to_str().unwrap(),
+                dir.path().join(format!("{}.out", i)).to_str().unwrap(),
+                0,
+                mtime.unix_seconds(),
+                mtime.unix_seconds(),
+                0o644,
+                4,
+                crate::db::FileStatus::Pending,
+            )?;
+        }
+        let logger = std::sync::Arc::new(Logger::new(dir.path().join("log.txt").to_str().unwrap()));
+        let config = PipelineConfig {
+            block_size: 1024,
+            ..Default::default()
+        };
+
+        // Each send waits for its receipt, so once the first file arrives the producer
+        // can start at most one more before it sees the request
+        let (sender, receiver) = crossbeam_channel::bounded(0);
+        let producer = {
+            let (config, db, logger) = (config.clone(), db.clone(), logger.clone());
+            thread::spawn(move || run_producer(config, sender, db, logger))
+        };
+        let mut blocks = vec![receiver.recv()?];
+        db.lock().unwrap().request_cancel()?;
+        blocks.extend(receiver.iter());
+        producer.join().unwrap()?;
+        assert!(blocks.len() <= 2, "{} files sent", blocks.len());
+
+        let (sender, receiver) = crossbeam_channel::unbounded();
+        let sent = blocks.len() as u64;
+        for block in blocks {
+            sender.send(block)?;
+        }
+        drop(sender);
+        run_consumer(
+            config,
+            receiver,
+            db.clone(),
+            logger,
+            std::sync::Arc::new(TransferControl::default()),
+        )?;
+
+        // The files sent are complete, the others still pending
+        let db = db.lock().unwrap();
+        assert_eq!(db.pending_count()?, 5 - sent);
+        assert!(db.take_cancel_request()?);
+        Ok(())
+    }
+
+    #[test]
+    fn test_daily_budget_leaves_backlog_pending() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let db = std::sync::Arc::new(std::sync::Mutex::new(Database::new(":memory:")?));
+        for i in 0..4 {
+            let source = dir.path().join(format!("{}.bin", i));
+            fs::write(&source, vec![i as u8; 2000])?;
+            let mtime = FileTime::from_last_modification_time(&fs::metadata(&source)?);
+            db.lock().unwrap().upsert_file(
+                source.to_str().unwrap(),
+                dir.path().join(format!("{}.out", i)).to_str().unwrap(),
+                0,
+                mtime.unix_seconds(),
+                mtime.unix_seconds(),
+                0o644,
+                2000,
+                crate::db::FileStatus::Pending,
+            )?;
+        }
+        let logger = std::sync::Arc::new(Logger::new(dir.path().join("log.txt").to_str().unwrap()));
+        let config = PipelineConfig {
+            block_size: 1024,
+            ordered: true,
+            daily_budget: Some(3000),
+            ..Default::default()
+        };
+
+        // Everything is read ahead while the budget is untouched
+        let (sender, receiver) = crossbeam_channel::unbounded();
+        run_producer(config.clone(), sender, db.clone(), logger.clone())?;
+        let control = std::sync::Arc::new(TransferControl::default());
+        run_consumer(
+            config.clone(),
+            receiver,
+            db.clone(),
+            logger.clone(),
+            control,
+        )?;
+
+        // The second file starts within the budget and is completed past it; the
+        // others wait for another day
+        assert!(dir.path().join("1.out").exists());
+        assert!(!dir.path().join("2.out").exists());
+        assert!(!dir.path().join("3.out").exists());
+        assert_eq!(db.lock().unwrap().pending_count()?, 2);
+        assert_eq!(db.lock().unwrap().transferred_bytes(&budget_day())?, 4000);
+        // Deferred files did not fail
+        let pending = db.lock().unwrap().get_pending_files()?;
+        assert!(pending.iter().all(|file| file.attempts == 0));
+
+        // A later run on the same day reads nothing
+        let (sender, receiver) = crossbeam_channel::unbounded();
+        run_producer(config, sender, db.clone(), logger)?;
+        assert!(receiver.try_recv().is_err());
+        assert_eq!(db.lock().unwrap().pending_count()?, 2);
+        Ok(())
+    }
+
+    #[test]
+    fn test_producer_counts_skipped_file_as_failed() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let db = std::sync::Arc::new(std::sync::Mutex::new(Database::new(":memory:")?));
+        let present = dir.path().join("present.txt");
+        fs::write(&present, "present")?;
+        for (name, size) in [("missing.txt", 7), ("present.txt", 7)] {
+            db.lock().unwrap().upsert_file(
+                dir.path().join(name).to_str().unwrap(),
+                dir.path().join("out").join(name).to_str().unwrap(),
+                0,
+                0,
+                0,
+                0o644,
+                size,
+                crate::db::FileStatus::Pending,
+            )?;
+        }
+        let logger = std::sync::Arc::new(Logger::new(dir.path().join("log.txt").to_str().unwrap()));
+        let config = PipelineConfig {
+            block_size: 1024,
+            ..Default::default()
+        };
+
+        let (sender, _receiver) = crossbeam_channel::unbounded();
+        run_producer(config, sender, db.clone(), logger)?;
+
+        // Only the file that could not be read counts a failed attempt
+        let pending = db.lock().unwrap().get_pending_files()?;
+        let attempts = |name: &str| {
+            pending
+                .iter()
+                .find(|file| file.source_path.ends_with(name))
+                .map(|file| file.attempts)
+        };
+        assert_eq!(attempts("missing.txt"), Some(1));
+        assert_eq!(attempts("present.txt"), Some(0));
+        Ok(())
+    }
+
+    #[test]
+    fn test_producer_forgets_newly_excluded_pending_files() -> Result<()> {
+        let source_dir = tempfile::tempdir()?;
+        let dest_dir = tempfile::tempdir()?;
+        let db = std::sync::Arc::new(std::sync::Mutex::new(Database::new(":memory:")?));
+        // Both were queued by an earlier run, before `--exclude` named the cache
+        for name in ["keep.txt", "cache.tmp"] {
+            let source = source_dir.path().join(name);
+            fs::write(&source, b"data")?;
+            let mtime = FileTime::from_last_modification_time(&fs::metadata(&source)?);
+            db.lock().unwrap().upsert_file(
+                source.to_str().unwrap(),
+                dest_dir.path().join(name).to_str().unwrap(),
+                0,
+                mtime.unix_seconds(),
+                mtime.unix_seconds(),
+                0o644,
+                4,
+                crate::db::FileStatus::Pending,
+            )?;
+        }
+        let logger = std::sync::Arc::new(Logger::new(
+            dest_dir.path().join("log.txt").to_str().unwrap(),
+        ));
+        let config = PipelineConfig {
+            source_dir: source_dir.path().to_path_buf(),
+            dest_dir: dest_dir.path().to_path_buf(),
+            block_size: 1024,
+            path_filter: PathFilter::new(&["*.tmp".to_string()], &[]),
+            ..Default::default()
+        };
+
+        let (sender, receiver) = crossbeam_channel::unbounded();
+        run_producer(config.clone(), sender, db.clone(), logger.clone())?;
+        run_consumer(
+            config,
+            receiver,
+            db.clone(),
+            logger,
+            std::sync::Arc::new(TransferControl::default()),
+        )?;
+
+        assert!(dest_dir.path().join("keep.txt").exists());
+        assert!(!dest_dir.path().join("cache.tmp").exists());
+        // The excluded file is no longer recorded at all
+        let counts = db.lock().unwrap().count_by_status()?;
+        assert_eq!(counts.values().sum::<u64>(), 1);
+        assert_eq!(counts.get(&crate::db::FileStatus::Synced), Some(&1));
+        Ok(())
+    }
+
+    #[test]
+    fn test_producer_forgets_pending_files_missing_under_existing() -> Result<()> {
+        let source_dir = tempfile::tempdir()?;
+        let dest_dir = tempfile::tempdir()?;
+        let db = std::sync::Arc::new(std::sync::Mutex::new(Database::new(":memory:")?));
+        // Both were queued by an earlier run without `--existing`; only one has a copy
+        fs::write(dest_dir.path().join("old.txt"), b"stale")?;
+        for name in ["old.txt", "new.txt"] {
+            let source = source_dir.path().join(name);
+            fs::write(&source, b"data")?;
+            db.lock().unwrap().upsert_file(
+                source.to_str().unwrap(),
+                dest_dir.path().join(name).to_str().unwrap(),
+                0,
+                0,
+                0,
+                0o644,
+                4,
+                crate::db::FileStatus::Pending,
+            )?;
+        }
+        let logger = std::sync::Arc::new(Logger::new(
+            dest_dir.path().join("log.txt").to_str().unwrap(),
+        ));
+        let config = PipelineConfig {
+            source_dir: source_dir.path().to_path_buf(),
+            dest_dir: dest_dir.path().to_path_buf(),
+            block_size: 1024,
+            existing: true,
+            ..Default::default()
+        };
+
+        let (sender, receiver) = crossbeam_channel::unbounded();
+        run_producer(config.clone(), sender, db.clone(), logger.clone())?;
+        run_consumer(
+            config,
+            receiver,
+            db.clone(),
+            logger,
+            std::sync::Arc::new(TransferControl::default()),
+        )?;
+
+        // The existing copy is updated; the missing one is neither created nor kept
+        assert_eq!(fs::read(dest_dir.path().join("old.txt"))?, b"data");
+        assert!(!dest_dir.path().join("new.txt").exists());
+        let counts = db.lock().unwrap().count_by_status()?;
+        assert_eq!(counts.values().sum::<u64>(), 1);
+        assert_eq!(counts.get(&crate::db::FileStatus::Synced), Some(&1));
+        Ok(())
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn test_preserve_fileflags() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let source = dir.path().join("archive.log");
+        let dest = dir.path().join("archive.out");
+        fs::write(&source, b"finalized")?;
+        // Marking the source append-only takes root; without it the flags read are none
+        let marked = set_file_flags(&source, 0x20).is_ok();
+        let Ok(source_flags) = file_flags(&source) else {
+            // The temporary directory's file system has no inode flags
+            return Ok(());
+        };
+        assert_eq!(source_flags, if marked { 0x20 } else { 0 });
+
+        let db = std::sync::Arc::new(std::sync::Mutex::new(Database::new(":memory:")?));
+        db.lock().unwrap().upsert_file(
+            source.to_str().unwrap(),
+            dest.to_str().unwrap(),
+            0,
+            0,
+            0,
+            0o644,
+            9,
+            crate::db::FileStatus::Pending,
+        )?;
+        let logger = std::sync::Arc::new(Logger::new(dir.path().join("log.txt").to_str().unwrap()));
+        let config = PipelineConfig {
+            block_size: 1024,
+            preserve_fileflags: true,
+            ..Default::default()
+        };
+        let (sender, receiver) = crossbeam_channel::unbounded();
+        run_producer(config.clone(), sender, db.clone(), logger.clone())?;
+        let blocks: Vec<Block> = receiver.try_iter().collect();
+        assert_eq!(blocks.len(), 1);
+        assert_eq!(blocks[0].file_flags, Some(source_flags));
+
+        if marked {
+            let (sender, receiver) = crossbeam_channel::unbounded();
+            sender.send(blocks.into_iter().next().unwrap())?;
+            drop(sender);
+            let result = run_consumer(
+                config,
+                receiver,
+                db,
+                logger,
+                std::sync::Arc::new(TransferControl::default()),
+            );
+            let dest_flags = file_flags(&dest);
+            // Flagged files would outlive the temporary directory
+            set_file_flags(&source, 0)?;
+            let _ = set_file_flags(&dest, 0);
+            result?;
+            assert_eq!(dest_flags?, 0x20);
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_producer_reports_inaccessible_dir_once() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let db = std::sync::Arc::new(std::sync::Mutex::new(Database::new(":memory:")?));
+        let mut sources = vec![dir.path().join("ok.txt")];
+        sources.extend((0..3).map(|i| dir.path().join(format!("gone/sub/{}.txt", i))));
+        fs::create_dir_all(dir.path().join("gone/sub"))?;
+        for (i, source) in sources.iter().enumerate() {
+            fs::write(source, b"data")?;
+            db.lock().unwrap().upsert_file(
+                source.to_str().unwrap(),
+                dir.path().join(format!("{}.out", i)).to_str().unwrap(),
+                0,
+                0,
+                0,
+                0o644,
+                4,
+                crate::db::FileStatus::Pending,
+            )?;
+        }
+        // The directory can no longer be listed, whoever runs the test
+        fs::remove_dir_all(dir.path().join("gone"))?;
+        fs::write(dir.path().join("gone"), b"")?;
+
+        let log_path = dir.path().join("log.txt");
+        let logger = std::sync::Arc::new(Logger::new(log_path.to_str().unwrap()));
+        let config = PipelineConfig {
+            source_dir: dir.path().to_path_buf(),
+            block_size: 1024,
+            ..Default::default()
+        };
+        let (sender, receiver) = crossbeam_channel::unbounded();
+        run_producer(config.clone(), sender, db.clone(), logger.clone())?;
+
+        // The rest is transferred; the directory is logged once, not per file
+        let sent: Vec<Block> = receiver.iter().collect();
+        assert_eq!(sent.len(), 1);
+        assert_eq!(sent[0].source_path, sources[0]);
+        let log = fs::read_to_string(&log_path)?;
+        let skips: Vec<&str> = log.lines().filter(|l| l.contains("Skipping")).collect();
+        assert_eq!(skips.len(), 1, "{}", log);
+        assert!(skips[0].contains("Skipping directory (inaccessible)"));
+        assert!(skips[0].contains(&format!("{:?}", dir.path().join("gone"))));
+
+        let (sender, _receiver) = crossbeam_channel::unbounded();
+        let config = PipelineConfig {
+            fail_on_inaccessible: true,
+            ..config
+        };
+        let error = run_producer(config, sender, db, logger).unwrap_err();
+        assert!(error.to_string().contains("Source directory inaccessible"));
+        Ok(())
+    }
+
+    #[test]
+    fn test_producer_picks_up_files_added_mid_run() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let first = dir.path().join("first.txt");
+        let late = dir.path().join("late.txt");
+        fs::write(&first, b"three blocks")?;
+        fs::write(&late, b"late")?;
+        let pending = crate::db::FileStatus::Pending;
+
+        let db = std::sync::Arc::new(std::sync::Mutex::new(Database::new(":memory:")?));
+        db.lock().unwrap().upsert_file(
+            first.to_str().unwrap(),
+            dir.path().join("first.out").to_str().unwrap(),
+            0,
+            0,
+            0,
+            0o644,
+            12,
+            pending,
+        )?;
+
+        // Rendezvous channel: the producer waits on every block handed over
+        let (sender, receiver) = crossbeam_channel::bounded(0);
+        let logger = std::sync::Arc::new(Logger::new(dir.path().join("log.txt").to_str().unwrap()));
+        let config = PipelineConfig {
+            block_size: 4,
+            ..Default::default()
+        };
+        let producer = {
+            let db = db.clone();
+            thread::spawn(move || run_producer(config, sender, db, logger))
+        };
+
+        // Another file becomes pending while the first is still being sent
+        let mut sources = vec![receiver.recv()?.source_path];
+        db.lock().unwrap().upsert_file(
+            late.to_str().unwrap(),
+            dir.path().join("late.out").to_str().unwrap(),
+            0,
+            0,
+            0,
+            0o644,
+            4,
+            pending,
+        )?;
+        sources.extend(receiver.iter().map(|block| block.source_path));
+        producer.join().unwrap()?;
+
+        assert_eq!(sources, vec![first.clone(), first.clone(), first, late]);
+        let log = fs::read_to_string(dir.path().join("log.txt"))?;
+        assert!(log.contains("Picked up 1 files added to the backlog during the run"));
+        Ok(())
+    }
+
+    #[test]
+    fn test_producer_rehash_replaces_stored_hash() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let source_path = dir.path().join("source.txt");
+        let dest_path = dir.path().join("dest.txt");
+        fs::write(&source_path, b"hello")?;
+        let mtime =
+            FileTime::from_last_modification_time(&fs::metadata(&source_path)?).unix_seconds();
+
+        // A wrong hash recorded for the unchanged file, which would otherwise be reused
+        let db = std::sync::Arc::new(std::sync::Mutex::new(Database::new(":memory:")?));
+        {
+            let db_guard = db.lock().unwrap();
+            let source = source_path.to_str().unwrap();
+            let dest = dest_path.to_str().unwrap();
+            let pending = crate::db::FileStatus::Pending;
+            db_guard.upsert_file(source, dest, 0, mtime, mtime, 0o644, 5, pending)?;
+            db_guard.mark_synced(
+                source,
+                SyncedHashes {
+                    hash: Some(&"0".repeat(64)),
+                    ..Default::default()
+                },
+            )?;
+            db_guard.upsert_file(source, dest, 0, mtime, mtime, 0o644, 5, pending)?;
+        }
+
+        let (sender, receiver) = crossbeam_channel::bounded(4);
+        let logger = std::sync::Arc::new(Logger::new(dir.path().join("log.txt").to_str().unwrap()));
+        let config = PipelineConfig {
+            block_size: 1024,
+            rehash: true,
+            ..Default::default()
+        };
+        run_producer(config.clone(), sender, db.clone(), logger.clone())?;
+        run_consumer(
+            config,
+            receiver,
+            db.clone(),
+            logger,
+            std::sync::Arc::new(TransferControl::default()),
+        )?;
+
+        let synced = db.lock().unwrap().get_synced_files()?;
+        assert_eq!(
+            synced[0].hash.as_deref(),
+            Some("2cf24dba5fb0a30e26e83b2ac5b9e29e1b161e5c1fa7425e73043362938b9824")
+        );
+        Ok(())
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn test_file_timeout_abandons_stalled_file() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        // Reading a FIFO whose writer never writes hangs like a dead network mount
+        let stalled = dir.path().join("stalled");
+        let status = std::process::Command::new("mkfifo")
+            .arg(&stalled)
+            .status()?;
+        assert!(status.success());
+        let writer_path = stalled.clone();
+        std::thread::spawn(move || {
+            let _writer = OpenOptions::new().write(true).open(writer_path);
+            std::thread::sleep(Duration::from_secs(3));
+        });
+        let healthy = dir.path().join("healthy.txt");
+        fs::write(&healthy, b"hello")?;
+
+        let db = std::sync::Arc::new(std::sync::Mutex::new(Database::new(":memory:")?));
+        for (source, size) in [(&stalled, 0), (&healthy, 5)] {
+            let dest = dir.path().join("dest").join(source.file_name().unwrap());
+            db.lock().unwrap().upsert_file(
+                source.to_str().unwrap(),
+                dest.to_str().unwrap(),
+                0,
+                0,
+                0,
+                0o644,
+                size,
+                crate::db::FileStatus::Pending,
+            )?;
+        }
+
+        let (sender, receiver) = crossbeam_channel::bounded(4);
+        let logger = std::sync::Arc::new(Logger::new(dir.path().join("log.txt").to_str().unwrap()));
+        let config = PipelineConfig {
+            block_size: 1024,
+            watchdog: Some(Arc::new(Watchdog::new(Duration::from_millis(200)))),
+            ..Default::default()
+        };
+        let start = Instant::now();
+        run_producer(config.clone(), sender, db.clone(), logger.clone())?;
+        run_consumer(
+            config,
+            receiver,
+            db.clone(),
+            logger,
+            std::sync::Arc::new(TransferControl::default()),
+        )?;
+
+        // Given up on well before the writer goes away
+        assert!(start.elapsed() < Duration::from_secs(2));
+        assert_eq!(fs::read(dir.path().join("dest/healthy.txt"))?, b"hello");
+        let pending = db.lock().unwrap().get_pending_files()?;
+        assert_eq!(pending.len(), 1);
+        assert!(pending[0].source_path.ends_with("stalled"));
+        let log = fs::read_to_string(dir.path().join("log.txt"))?;
+        assert!(log.contains("Abandoned (no progress within 0.2s)"));
+        Ok(())
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn test_file_timeout_undoes_late_write() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let source = dir.path().join("source.txt");
+        fs::write(&source, b"hello")?;
+        // Opening a FIFO with no reader for writing hangs like a dead network mount
+        let dest = dir.path().join("dest");
+        let status = std::process::Command::new("mkfifo").arg(&dest).status()?;
+        assert!(status.success());
+
+        let db = std::sync::Arc::new(std::sync::Mutex::new(Database::new(":memory:")?));
+        db.lock().unwrap().upsert_file(
+            source.to_str().unwrap(),
+            dest.to_str().unwrap(),
+            0,
+            0,
+            0,
+            0o644,
+            5,
+            crate::db::FileStatus::Pending,
+        )?;
+
+        let (sender, receiver) = crossbeam_channel::bounded(4);
+        let logger = std::sync::Arc::new(Logger::new(dir.path().join("log.txt").to_str().unwrap()));
+        let watchdog = Arc::new(Watchdog::new(Duration::from_millis(200)));
+        let config = PipelineConfig {
+            block_size: 1024,
+            watchdog: Some(watchdog.clone()),
+            ..Default::default()
+        };
+        run_producer(config.clone(), sender, db.clone(), logger.clone())?;
+        run_consumer(
+            config,
+            receiver,
+            db.clone(),
+            logger,
+            std::sync::Arc::new(TransferControl::default()),
+        )?;
+
+        // Given up on: the file stays pending, with the write still running
+        let pending = db.lock().unwrap().get_pending_files()?;
+        assert_eq!(pending.len(), 1);
+        assert_eq!(pending[0].attempts, 1);
+        assert!(watchdog.is_stuck(&source));
+
+        // Once the write returns, it is undone
+        let _reader = fs::File::open(&dest)?;
+        let start = Instant::now();
+        while watchdog.is_stuck(&source) {
+            assert!(start.elapsed() < Duration::from_secs(5));
+            std::thread::sleep(Duration::from_millis(10));
+        }
+        assert!(!dest.exists());
+        let log = fs::read_to_string(dir.path().join("log.txt"))?;
+        assert!(log.contains("Undid late write"));
+        Ok(())
+    }
+
+    #[test]
+    fn test_producer_stores_block_hashes() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let source_path = dir.path().join("source.bin");
+        let content: Vec<u8> = (0..10 * 1024 + 100u32).map(|i| i as u8).collect();
+        fs::write(&source_path, &content)?;
+
+        let db = std::sync::Arc::new(std::sync::Mutex::new(Database::new(":memory:")?));
+        db.lock().unwrap().upsert_file(
+            source_path.to_str().unwrap(),
+            dir.path().join("dest.bin").to_str().unwrap(),
+            0,
+            0,
+            0,
+            0o644,
+            content.len() as u64,
+            crate::db::FileStatus::Pending,
+        )?;
+
+        let (sender, receiver) = crossbeam_channel::bounded(16);
+        let logger = std::sync::Arc::new(Logger::new(dir.path().join("log.txt").to_str().unwrap()));
+        let config = PipelineConfig {
+            block_size: 1024,
+            store_block_hashes: true,
+            ..Default::default()
+        };
+        run_producer(config, sender, db.clone(), logger)?;
+        assert_eq!(receiver.len(), 11);
+
+        // Ten full blocks and a short last one
+        let block_hashes = db
+            .lock()
+            .unwrap()
+            .get_block_hashes(source_path.to_str().unwrap())?;
+        assert_eq!(block_hashes.len(), 11);
+        let mut tail_hasher = create_hasher(HashAlgorithm::Sha256);
+        tail_hasher.update(&content[10 * 1024..]);
+        assert_eq!(block_hashes[10], (1024, tail_hasher.finalize_hex()?));
+        Ok(())
+    }
+
+    #[test]
+    fn test_dedupe_identical_reads_content_once() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let content: Vec<u8> = (0..4096u32).map(|i| i as u8).collect();
+        let db = std::sync::Arc::new(std::sync::Mutex::new(Database::new(":memory:")?));
+        let mut dest_paths = Vec::new();
+        for name in ["a.bin", "b.bin"] {
+            let source_path = dir.path().join("source").join(name);
+            let dest_path = dir.path().join("dest").join(name);
+            fs::create_dir_all(source_path.parent().unwrap())?;
+            fs::write(&source_path, &content)?;
+            // Hashes only stand for sources still as the scan recorded them
+            let mtime =
+                FileTime::from_last_modification_time(&fs::metadata(&source_path)?).unix_seconds();
+            db.lock().unwrap().upsert_file(
+                source_path.to_str().unwrap(),
+                dest_path.to_str().unwrap(),
+                0,
+                mtime,
+                mtime,
+                0o644,
+                content.len() as u64,
+                crate::db::FileStatus::Pending,
+            )?;
+            dest_paths.push(dest_path);
+        }
+
+        let (sender, receiver) = crossbeam_channel::unbounded();
+        let logger = std::sync::Arc::new(Logger::new(dir.path().join("log.txt").to_str().unwrap()));
+        let config = PipelineConfig {
+            block_size: 1024,
+            dedupe_identical: true,
+            ..Default::default()
+        };
+        run_producer(config.clone(), sender, db.clone(), logger.clone())?;
+
+        // The content travels once; the second file is a copy instruction
+        let blocks: Vec<Block> = receiver.iter().collect();
+        let bytes_sent: usize = blocks.iter().map(|block| block.data.len()).sum();
+        assert_eq!(bytes_sent, content.len());
+        assert_eq!(blocks.iter().filter(|b| b.copy_from.is_some()).count(), 1);
+
+        let (sender, receiver) = crossbeam_channel::unbounded();
+        for block in blocks {
+            sender.send(block)?;
+        }
+        drop(sender);
+        run_consumer(
+            config,
+            receiver,
+            db.clone(),
+            logger,
+            std::sync::Arc::new(TransferControl::default()),
+        )?;
+        for dest_path in &dest_paths {
+            assert_eq!(fs::read(dest_path)?, content);
+        }
+        assert_eq!(db.lock().unwrap().pending_count()?, 0);
+        Ok(())
+    }
+
+    #[test]
+    fn test_max_open_files_covers_every_destination_open() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let content: Vec<u8> = (0..4096u32).map(|i| i as u8).collect();
+        let db = std::sync::Arc::new(std::sync::Mutex::new(Database::new(":memory:")?));
+        let mut dest_paths = Vec::new();
+        for name in ["a.bin", "b.bin"] {
+            let source_path = dir.path().join("source").join(name);
+            let dest_path = dir.path().join("dest").join(name);
+            fs::create_dir_all(source_path.parent().unwrap())?;
+            fs::write(&source_path, &content)?;
+            let mtime =
+                FileTime::from_last_modification_time(&fs::metadata(&source_path)?).unix_seconds();
+            db.lock().unwrap().upsert_file(
+                source_path.to_str().unwrap(),
+                dest_path.to_str().unwrap(),
+                0,
+                mtime,
+                mtime,
+                0o644,
+                content.len() as u64,
+                crate::db::FileStatus::Pending,
+            )?;
+            dest_paths.push(dest_path);
+        }
+
+        let (sender, receiver) = crossbeam_channel::unbounded();
+        let logger = std::sync::Arc::new(Logger::new(dir.path().join("log.txt").to_str().unwrap()));
+        let open_files = Arc::new(Semaphore::new(1));
+        let config = PipelineConfig {
+            block_size: 1024,
+            dedupe_identical: true,
+            verify_dest: true,
+            open_files: Some(open_files.clone()),
+            ..Default::default()
+        };
+        run_producer(config.clone(), sender, db.clone(), logger.clone())?;
+
+        // Nothing is opened while the only slot is taken
+        let held = open_files.acquire();
+        let consumer_db = db.clone();
+        let consumer = std::thread::spawn(move || {
+            run_consumer(
+                config,
+                receiver,
+                consumer_db,
+                logger,
+                std::sync::Arc::new(TransferControl::default()),
+            )
+        });
+        std::thread::sleep(Duration::from_millis(300));
+        assert!(!dest_paths[0].exists());
+
+        // With one slot, written, copied and read-back files take turns
+        drop(held);
+        consumer.join().unwrap()?;
+        for dest_path in &dest_paths {
+            assert_eq!(fs::read(dest_path)?, content);
+        }
+        assert_eq!(db.lock().unwrap().pending_count()?, 0);
+        Ok(())
+    }
+
+    #[test]
+    fn test_consumer_delay_updates() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let db = std::sync::Arc::new(std::sync::Mutex::new(Database::new(":memory:")?));
+        let (sender, receiver) = crossbeam_channel::bounded(2);
+        for name in ["a.txt", "b.txt"] {
+            let dest_path = dir.path().join(name);
+            fs::write(&dest_path, b"old")?;
+            sender.send(Block {
+                data: b"new content".to_vec(),
+                offset: 0,
+                dest_paths: vec![dest_path],
+                source_path: dir.path().join("source").join(name),
+                atime: 0,
+                created: None,
+                pending_reason: None,
+                mtime: 0,
+                ctime: 0,
+                permissions: 0,
+                is_last_block: true,
+                file_hash: Some("hash".to_string()),
+                file_size: 11,
+                aborted: false,
+                copy_from: None,
+                file_flags: None,
+            })?;
+        }
+        drop(sender);
+
+        let logger = std::sync::Arc::new(Logger::new(dir.path().join("log.txt").to_str().unwrap()));
+        let control = std::sync::Arc::new(TransferControl::default());
+        let config = PipelineConfig {
+            block_size: 1024,
+            delay_updates: true,
+            ..Default::default()
+        };
+        run_consumer(config, receiver, db, logger.clone(), control.clone())?;
+
+        // Nothing visible before the swap
+        for name in ["a.txt", "b.txt"] {
+            assert_eq!(fs::read(dir.path().join(name))?, b"old");
+        }
+
+        let staged = std::mem::take(&mut *control.staged.lock().unwrap());
+        assert_eq!(staged.len(), 2);
+        apply_staged_updates(staged, &logger)?;
+        for name in ["a.txt", "b.txt"] {
+            assert_eq!(fs::read(dir.path().join(name))?, b"new content");
+            assert!(!staged_path(&dir.path().join(name)).exists());
+        }
+        Ok(())
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_consumer_applies_default_mode_for_unspecified_permissions() -> Result<()> {
+        use std::os::unix::fs::PermissionsExt;
+        let dir = tempfile::tempdir()?;
+        let db = std::sync::Arc::new(std::sync::Mutex::new(Database::new(":memory:")?));
+        let dest_path = dir.path().join("from_windows.txt");
+        fs::write(&dest_path, b"old")?;
+        fs::set_permissions(&dest_path, fs::Permissions::from_mode(0o600))?;
+
+        // A scan on a platform without Unix modes stores 0
+        let (sender, receiver) = crossbeam_channel::bounded(1);
+        sender.send(Block {
+            data: b"hello".to_vec(),
+            offset: 0,
+            dest_paths: vec![dest_path.clone()],
+            source_path: dir.path().join("source.txt"),
+            atime: 1_000_000,
+            created: None,
+            pending_reason: None,
+            mtime: 1_000_000,
+            ctime: 1_000_000,
+            permissions: 0,
+            is_last_block: true,
+            file_hash: Some("hash".to_string()),
+            file_size: 5,
+            aborted: false,
+            copy_from: None,
+            file_flags: None,
+        })?;
+        drop(sender);
+
+        let logger = std::sync::Arc::new(Logger::new(dir.path().join("log.txt").to_str().unwrap()));
+        let config = PipelineConfig {
+            block_size: 1024,
+            perms_matter: true,
+            ..Default::default()
+        };
+        run_consumer(
+            config,
+            receiver,
+            db,
+            logger,
+            std::sync::Arc::new(TransferControl::default()),
+        )?;
+
+        let mode = fs::metadata(&dest_path)?.permissions().mode() & 0o7777;
+        assert_eq!(mode, crate::utils::DEFAULT_FILE_MODE);
+        Ok(())
+    }
+
+    #[test]
+    fn test_discarded_staged_updates_return_to_pending() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let db = std::sync::Arc::new(std::sync::Mutex::new(Database::new(":memory:")?));
+        let config = PipelineConfig {
+            block_size: 1024,
+            delay_updates: true,
+            verify_dest: true,
+            abort_after_mismatches: Some(1),
+            ..Default::default()
+        };
+        let reference = dir.path().join("reference.txt");
+        fs::write(&reference, b"hello")?;
+        let good_hash = hash_file(&reference, &config)?;
+
+        // A good file gets staged and marked synced, then a mismatch aborts the run
+        let (sender, receiver) = crossbeam_channel::bounded(2);
+        for (name, hash) in [("a.txt", good_hash), ("b.txt", "0".repeat(64))] {
+            let source_path = dir.path().join(name);
+            let dest_path = dir.path().join(format!("{}.dest", name));
+            fs::write(&dest_path, b"old")?;
+            db.lock().unwrap().upsert_file(
+                source_path.to_str().unwrap(),
+                dest_path.to_str().unwrap(),
+                0,
+                1_000_000,
+                1_000_000,
+                0o644,
+                5,
+                crate::db::FileStatus::Pending,
+            )?;
+            sender.send(Block {
+                data: b"hello".to_vec(),
+                offset: 0,
+                dest_paths: vec![dest_path],
+                source_path,
+                atime: 1_000_000,
+                created: None,
+                pending_reason: None,
+                mtime: 1_000_000,
+                ctime: 1_000_000,
+                permissions: 0,
+                is_last_block: true,
+                file_hash: Some(hash),
+                file_size: 5,
+                aborted: false,
+                copy_from: None,
+                file_flags: None,
+            })?;
+        }
+        drop(sender);
+
+        let logger = std::sync::Arc::new(Logger::new(dir.path().join("log.txt").to_str().unwrap()));
+        let control = std::sync::Arc::new(TransferControl::default());
+        assert!(run_consumer(config, receiver, db.clone(), logger, control.clone()).is_err());
+        assert_eq!(db.lock().unwrap().pending_count()?, 1);
+
+        // The swap never happens, so the staged file must be transferred again
+        let staged = std::mem::take(&mut *control.staged.lock().unwrap());
+        assert_eq!(staged.len(), 1);
+        discard_staged_updates(staged, &db)?;
+        let dest_path = dir.path().join("a.txt.dest");
+        assert_eq!(fs::read(&dest_path)?, b"old");
+        assert!(!staged_path(&dest_path).exists());
+        assert_eq!(db.lock().unwrap().pending_count()?, 2);
+        Ok(())
+    }
+
+    #[test]
+    fn test_resume_interrupted_encrypted_transfer() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let source = dir.path().join("source.bin");
+        let dest = dir.path().join("dest.bin");
+        let data: Vec<u8> = (0..10_740u32).map(|i| (i % 251) as u8).collect();
+        fs::write(&source, &data)?;
+        let mtime = FileTime::from_last_modification_time(&fs::metadata(&source)?).unix_seconds();
+
+        let db = std::sync::Arc::new(std::sync::Mutex::new(Database::new(":memory:")?));
+        db.lock().unwrap().upsert_file(
+            source.to_str().unwrap(),
+            dest.to_str().unwrap(),
+            0,
+            mtime,
+            mtime,
+            0o644,
+            data.len() as u64,
+            crate::db::FileStatus::Pending,
+        )?;
+        let logger = std::sync::Arc::new(Logger::new(dir.path().join("log.txt").to_str().unwrap()));
+        // Each run derives its key under a fresh salt
+        let run = |blocks_to_write: Option<usize>| -> Result<Vec<u64>> {
+            let config = PipelineConfig {
+                block_size: 1024,
+                cipher: Some(Cipher::new(b"secret")?),
+                ..Default::default()
+            };
+            let (sender, receiver) = crossbeam_channel::unbounded();
+            run_producer(config.clone(), sender, db.clone(), logger.clone())?;
+            let blocks: Vec<Block> = receiver.iter().collect();
+            let offsets = blocks.iter().map(|block| block.offset).collect();
+            let (sender, receiver) = crossbeam_channel::unbounded();
+            for block in blocks
+                .into_iter()
+                .take(blocks_to_write.unwrap_or(usize::MAX))
+            {
+                sender.send(block)?;
+            }
+            drop(sender);
+            run_consumer(
+                config,
+                receiver,
+                db.clone(),
+                logger.clone(),
+                std::sync::Arc::new(TransferControl::default()),
+            )?;
+            Ok(offsets)
+        };
+
+        // Interrupted after four of the eleven blocks
+        assert_eq!(run(Some(4))?.len(), 11);
+        assert_eq!(db.lock().unwrap().pending_count()?, 1);
+
+        // Only the remaining blocks are sent, sealed under the first run's key
+        let offsets = run(None)?;
+        assert_eq!(offsets, (4..11).map(|i| i * 1024).collect::<Vec<u64>>());
+        assert_eq!(db.lock().unwrap().pending_count()?, 0);
+        assert!(db
+            .lock()
+            .unwrap()
+            .get_encryption_progress(source.to_str().unwrap())?
+            .is_none());
+
+        let restored = dir.path().join("restored.bin");
+        crate::crypto::decrypt_path(b"secret", &dest, &restored)?;
+        assert_eq!(fs::read(&restored)?, data);
+        let synced = db.lock().unwrap().get_synced_files()?;
+        assert_eq!(
+            synced[0].hash,
+            Some(hash_file(
+                &source,
+                &PipelineConfig {
+                    block_size: 1024,
+                    ..Default::default()
+                }
+            )?)
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_encrypt_seals_with_overridden_block_size() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let source = dir.path().join("source.bin");
+        let dest = dir.path().join("dest.bin");
+        let data: Vec<u8> = (0..5000u32).map(|i| (i % 251) as u8).collect();
+        fs::write(&source, &data)?;
+        let mtime = FileTime::from_last_modification_time(&fs::metadata(&source)?).unix_seconds();
+
+        let db = std::sync::Arc::new(std::sync::Mutex::new(Database::new(":memory:")?));
+        db.lock().unwrap().upsert_file(
+            source.to_str().unwrap(),
+            dest.to_str().unwrap(),
+            0,
+            mtime,
+            mtime,
+            0o644,
+            data.len() as u64,
+            crate::db::FileStatus::Pending,
+        )?;
+        let logger = std::sync::Arc::new(Logger::new(dir.path().join("log.txt").to_str().unwrap()));
+        // Read in 1024-byte blocks, each must be sealed as its own block
+        let config = PipelineConfig {
+            source_dir: dir.path().to_path_buf(),
+            block_size: 4096,
+            block_size_overrides: vec![("*.bin".to_string(), 1024)],
+            cipher: Some(Cipher::new(b"secret")?),
+            ..Default::default()
+        };
+        let (sender, receiver) = crossbeam_channel::unbounded();
+        run_producer(config.clone(), sender, db.clone(), logger.clone())?;
+        let control = std::sync::Arc::new(TransferControl::default());
+        run_consumer(config, receiver, db.clone(), logger, control)?;
+
+        assert_eq!(
+            fs::metadata(&dest)?.len(),
+            crate::crypto::encrypted_len(data.len() as u64, 1024)
+        );
+        let restored = dir.path().join("restored.bin");
+        crate::crypto::decrypt_path(b"secret", &dest, &restored)?;
+        assert_eq!(fs::read(&restored)?, data);
+        Ok(())
+    }
+
+    #[test]
+    fn test_consumer_pause_and_resume() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let dest_path = dir.path().join("dest.txt");
+        let control = std::sync::Arc::new(TransferControl::default());
+        control.paused.store(true, Ordering::Relaxed);
+
+        let (sender, receiver) = crossbeam_channel::bounded(1);
+        sender.send(Block {
+            data: b"hello".to_vec(),
+            offset: 0,
+            dest_paths: vec![dest_path.clone()],
+            source_path: dir.path().join("source.txt"),
+            atime: 0,
+            created: None,
+            pending_reason: None,
+            mtime: 0,
+            ctime: 0,
+            permissions: 0,
+            is_last_block: true,
+            file_hash: Some("hash".to_string()),
+            file_size: 5,
+            aborted: false,
+            copy_from: None,
+            file_flags: None,
+        })?;
+        drop(sender);
+
+        let db = std::sync::Arc::new(std::sync::Mutex::new(Database::new(":memory:")?));
+        let log_path = dir.path().join("log.txt");
+        let logger = std::sync::Arc::new(Logger::new(log_path.to_str().unwrap()));
+        let consumer_control = control.clone();
+        let consumer = thread::spawn(move || {
+            run_consumer(
+                PipelineConfig::default(),
+                receiver,
+                db,
+                logger,
+                consumer_control,
+            )
+        });
+
+        // Nothing is written while paused
+        thread::sleep(Duration::from_millis(700));
+        assert!(!dest_path.exists());
+
+        control.paused.store(false, Ordering::Relaxed);
+        consumer.join().unwrap()?;
+        assert_eq!(fs::read(&dest_path)?, b"hello");
+
+        let log = fs::read_to_string(&log_path)?;
+        assert!(log.contains("Transfer paused"));
+        assert!(log.contains("Transfer resumed"));
+        Ok(())
     }
 
     #[test]
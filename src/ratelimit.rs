@@ -0,0 +1,176 @@
+//! Bandwidth pacing helpers, including a limiter shared between processes.
+
+use anyhow::{Context, Result};
+use std::fs::{File, OpenOptions};
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+/// Maximum length of a single pacing sleep, so throttled transfers stay responsive
+const PACING_SLICE: Duration = Duration::from_millis(200);
+
+/// Sleeps for `duration` in short slices, checking `interrupted` between them.
+/// Returns `false` if the sleep was cut short by the interrupt flag.
+pub fn paced_sleep(duration: Duration, interrupted: &AtomicBool) -> bool {
+    let deadline = Instant::now() + duration;
+    loop {
+        if interrupted.load(Ordering::Relaxed) {
+            return false;
+        }
+        let now = Instant::now();
+        if now >= deadline {
+            return true;
+        }
+        thread::sleep((deadline - now).min(PACING_SLICE));
+    }
+}
+
+/// Fraction of the bandwidth limit allowed at the start of a warmup ramp
+const RAMP_START_FRACTION: f64 = 0.1;
+
+/// Bandwidth limit in effect at `elapsed`, ramping linearly from
+/// `RAMP_START_FRACTION` of `limit` up to `limit` over the `ramp` window.
+pub fn effective_limit(limit: u64, ramp: Option<Duration>, elapsed: Duration) -> f64 {
+    let progress = match ramp {
+        Some(ramp) if elapsed < ramp => elapsed.as_secs_f64() / ramp.as_secs_f64(),
+        _ => 1.0,
+    };
+    limit as f64 * (RAMP_START_FRACTION + (1.0 - RAMP_START_FRACTION) * progress)
+}
+
+/// Limiter coordinating several processes through a shared file.
+///
+/// The file holds the Unix time (seconds) at which the shared link is free again.
+/// Each reservation, made under an exclusive file lock, pushes that time forward
+/// by `bytes / cap`, so the combined rate of all participants stays under `cap`.
+pub struct SharedLimiter {
+    path: PathBuf,
+    cap: u64,
+}
+
+impl SharedLimiter {
+    pub fn new(path: &Path, cap: u64) -> Self {
+        Self {
+            path: path.to_path_buf(),
+            cap,
+        }
+    }
+
+    /// Reserves `bytes` of the shared budget and returns how long the caller
+    /// must wait before the reservation is paid off.
+    pub fn reserve(&self, bytes: u64) -> Result<Duration> {
+        let mut file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(&self.path)
+            .with_context(|| format!("Failed to open shared limit file {:?}", self.path))?;
+        file.lock()?;
+        let result = self.reserve_locked(&mut file, bytes);
+        file.unlock()?;
+        result
+    }
+
+    fn reserve_locked(&self, file: &mut File, bytes: u64) -> Result<Duration> {
+        let now = SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs_f64();
+        let mut content = String::new();
+        file.read_to_string(&mut content)?;
+        // A missing or corrupt value means the link is free now
+        let free_at = content.trim().parse::<f64>().unwrap_or(now).max(now);
+        let next_free = free_at + bytes as f64 / self.cap as f64;
+
+        file.set_len(0)?;
+        file.seek(SeekFrom::Start(0))?;
+        write!(file, "{}", next_free)?;
+        Ok(Duration::from_secs_f64(next_free - now))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_effective_limit_ramp() {
+        let ramp = Some(Duration::from_secs(10));
+        let limit = 1000;
+
+        assert!(effective_limit(limit, ramp, Duration::ZERO) < limit as f64);
+        assert!(
+            effective_limit(limit, ramp, Duration::from_secs(5))
+                > effective_limit(limit, ramp, Duration::ZERO)
+        );
+        assert_eq!(
+            effective_limit(limit, ramp, Duration::from_secs(10)),
+            1000.0
+        );
+        assert_eq!(
+            effective_limit(limit, ramp, Duration::from_secs(60)),
+            1000.0
+        );
+
+        // Without a ramp the full limit applies immediately
+        assert_eq!(effective_limit(limit, None, Duration::ZERO), 1000.0);
+    }
+
+    #[test]
+    fn test_paced_sleep_interruptible() {
+        let interrupted = std::sync::Arc::new(AtomicBool::new(false));
+        let flag = interrupted.clone();
+        let setter = thread::spawn(move || {
+            thread::sleep(Duration::from_millis(300));
+            flag.store(true, Ordering::Relaxed);
+        });
+
+        // A 10s pacing sleep must end shortly after the flag is set
+        let start = Instant::now();
+        assert!(!paced_sleep(Duration::from_secs(10), &interrupted));
+        assert!(start.elapsed() < Duration::from_secs(2));
+        setter.join().unwrap();
+
+        // Without interruption the full duration elapses
+        let idle = AtomicBool::new(false);
+        let start = Instant::now();
+        assert!(paced_sleep(Duration::from_millis(250), &idle));
+        assert!(start.elapsed() >= Duration::from_millis(250));
+    }
+
+    #[test]
+    fn test_shared_limiter_caps_combined_rate() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let path = dir.path().join("shared.limit");
+        let cap = 1024 * 1024;
+        let chunk = 64 * 1024;
+        let chunks = 8;
+
+        // Two limiters, as in two processes, each sending 512KiB
+        let start = Instant::now();
+        let handles: Vec<_> = (0..2)
+            .map(|_| {
+                let limiter = SharedLimiter::new(&path, cap);
+                thread::spawn(move || -> Result<()> {
+                    for _ in 0..chunks {
+                        thread::sleep(limiter.reserve(chunk)?);
+                    }
+                    Ok(())
+                })
+            })
+            .collect();
+        for handle in handles {
+            handle.join().unwrap()?;
+        }
+
+        // 1MiB at 1MiB/s combined takes about a second, not half
+        let total = 2 * chunks * chunk;
+        let elapsed = start.elapsed().as_secs_f64();
+        assert!(
+            elapsed >= total as f64 / cap as f64 * 0.95,
+            "elapsed {}",
+            elapsed
+        );
+        Ok(())
+    }
+}
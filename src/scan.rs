@@ -3,22 +3,136 @@
 //! Scans both directories independently to build a backlog of files
 //! that need to be transferred.
 
-use crate::db::{Database, FileStatus};
-use crate::utils::format_bytes;
-use anyhow::Result;
+use crate::crypto::encrypted_len;
+use crate::db::{Database, FileRecord, FileStatus, PendingReason};
+use crate::filter::{has_exclude_marker, IgnoreStack, PathFilter};
+use crate::utils::{creation_time, effective_mode, format_bytes, Logger};
+use anyhow::{bail, Result};
+use clap::ValueEnum;
 use filetime::FileTime;
-use indicatif::{MultiProgress, ProgressBar, ProgressStyle};
+use indicatif::{MultiProgress, ProgressBar, ProgressDrawTarget, ProgressStyle};
 use std::collections::HashMap;
-use std::fs;
-use std::path::PathBuf;
+use std::fs::{self, File};
+use std::io::{self, IsTerminal, Read};
+use std::path::{Component, Path, PathBuf};
 use std::sync::{Arc, Mutex};
 use std::thread;
 use std::time::Duration;
+use unicode_normalization::UnicodeNormalization;
 use walkdir::WalkDir;
 
+/// Deepest source directory the scan enters. Followed links that do not loop back to
+/// an ancestor can still nest a tree into itself through siblings; this bounds it.
+const MAX_SCAN_DEPTH: usize = 256;
+
+/// Filters and destinations applied to the scan
+#[derive(Debug, Clone, Default)]
+pub struct ScanOptions {
+    /// Only include source files modified at or after this Unix timestamp
+    pub newer_than: Option<i64>,
+    /// Only include source files whose detected content type matches one of these
+    /// (see `matches_magic`); empty includes every file
+    pub magic_filter: Vec<String>,
+    /// Treat files already present in the destination as synced, whatever their content
+    pub ignore_existing: bool,
+    /// Only update files already present in the destination, never create new ones
+    pub existing: bool,
+    /// Leave destination files at least as new as the source alone
+    pub update_only: bool,
+    /// Follow symlinks in the source, copying what they point to
+    pub follow_links: bool,
+    /// Recreate source symlinks pointing inside the source tree as symlinks, and
+    /// handle those pointing outside it this way
+    pub unsafe_links: Option<UnsafeLinks>,
+    /// Further destinations kept identical to the primary one; a file is pending
+    /// if any destination lacks it
+    pub mirror_dirs: Vec<PathBuf>,
+    /// Compare same-size files by content, block by block with this block size,
+    /// instead of trusting mtime
+    pub checksum_compare: Option<usize>,
+    /// Also treat a differing permission mode as a reason to transfer
+    pub perms_matter: bool,
+    /// Consider a destination file with the source size synced, whatever its mtime
+    pub size_only: bool,
+    /// Give destination files found synced the source mtime and mode, without
+    /// transferring their content
+    pub refresh_metadata: bool,
+    /// Block size of `--encrypt`: destination files are expected at their encrypted size
+    pub encrypted_block_size: Option<usize>,
+    /// Fail instead of skipping a source directory that cannot be read
+    pub fail_on_inaccessible: bool,
+    /// Abort the scan once the source map is estimated to need more bytes than this
+    pub max_memory: Option<u64>,
+    /// Print the source bytes per subdirectory down to this depth after the scan
+    pub report_tree_depth: Option<usize>,
+    /// Match source and destination names in this Unicode normalization form
+    pub normalize_unicode: UnicodeForm,
+    /// Take the destination state of files recorded as synced from the database
+    /// instead of walking the destinations, checking only the other files on disk
+    pub trust_dest_db: bool,
+    /// Skip source directories holding one of these marker files, with their subtrees
+    pub exclude_if_present: Vec<String>,
+    /// `--exclude`/`--include` globs; excluded paths are never recorded
+    pub path_filter: PathFilter,
+    /// Leave the destinations untouched: no metadata refresh
+    pub dry_run: bool,
+}
+
+/// Handling of source symlinks whose target lies outside the source tree
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum UnsafeLinks {
+    /// Leave them out of the sync (`--safe-links`)
+    Skip,
+    /// Copy the files they point to as regular files (`--copy-unsafe-links`)
+    Copy,
+}
+
+/// Unicode normalization applied to relative paths before source and destination
+/// names are matched, so that e.g. an NFC name on Linux finds its NFD copy on macOS
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, ValueEnum, serde::Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum UnicodeForm {
+    Nfc,
+    Nfd,
+    /// Compare names byte for byte
+    #[default]
+    None,
+}
+
+impl UnicodeForm {
+    /// `name` in this form
+    pub fn normalize_str(self, name: &str) -> String {
+        match self {
+            UnicodeForm::Nfc => name.nfc().collect(),
+            UnicodeForm::Nfd => name.nfd().collect(),
+            UnicodeForm::None => name.to_string(),
+        }
+    }
+
+    /// `path` with every component in this form; components that are not valid
+    /// UTF-8 are kept as they are
+    pub fn normalize(self, path: &Path) -> PathBuf {
+        if self == UnicodeForm::None {
+            return path.to_path_buf();
+        }
+        path.components()
+            .map(|component| match component.as_os_str().to_str() {
+                Some(name) => PathBuf::from(self.normalize_str(name)),
+                None => PathBuf::from(component.as_os_str()),
+            })
+            .collect()
+    }
+}
+
 /// Scan results from the destination directory
-/// Maps relative path to (mtime, size)
-type DestinationMap = HashMap<PathBuf, (i64, u64)>;
+/// Maps relative path, normalized by `--normalize-unicode`, to (mtime, size, permissions,
+/// relative path on disk if the normalization changed it)
+type DestinationMap = HashMap<PathBuf, (i64, u64, u32, Option<PathBuf>)>;
+
+/// Relative path on disk of the destination entry stored under `key`
+fn dest_name<'a>(key: &'a Path, entry: &'a (i64, u64, u32, Option<PathBuf>)) -> &'a Path {
+    entry.3.as_deref().unwrap_or(key)
+}
 
 /// Runs the parallel scan phase, populating the database with file states.
 /// Returns the number of files pending transfer.
@@ -26,8 +140,25 @@ pub fn run_scan(
     source_dir: &PathBuf,
     dest_dir: &PathBuf,
     db: &Arc<Mutex<Database>>,
+    options: &ScanOptions,
 ) -> Result<u64> {
-    let multi_progress = MultiProgress::new();
+    // Off a terminal (log file, pipe) the spinners stay hidden and their final
+    // messages are printed as plain lines instead
+    let interactive = io::stderr().is_terminal();
+    let multi_progress = if interactive {
+        MultiProgress::new()
+    } else {
+        MultiProgress::with_draw_target(ProgressDrawTarget::hidden())
+    };
+    let finish = |pb: &ProgressBar, message: String| {
+        if !interactive {
+            match pb.prefix() {
+                prefix if prefix.is_empty() => println!("{}", message),
+                prefix => println!("{}: {}", prefix, message),
+            }
+        }
+        pb.finish_with_message(message);
+    };
 
     // Create progress bars for source and destination scans
     let source_pb = multi_progress.add(ProgressBar::new_spinner());
@@ -48,59 +179,207 @@ pub fn run_scan(
     dest_pb.set_prefix("Destination");
     dest_pb.enable_steady_tick(Duration::from_millis(100));
 
-    // Scan source and destination in parallel
-    let dest_dir_clone = dest_dir.clone();
+    // Scan source and destination in parallel; trusted destinations are not walked
+    let dest_dir_clone = dest_dir.to_path_buf();
     let dest_pb_clone = dest_pb.clone();
-    let dest_handle = thread::spawn(move || scan_destination(&dest_dir_clone, &dest_pb_clone));
+    let form = options.normalize_unicode;
+    let dest_handle = (!options.trust_dest_db)
+        .then(|| thread::spawn(move || scan_destination(&dest_dir_clone, &dest_pb_clone, form)));
 
-    let source_dir_clone = source_dir.clone();
+    let source_dir_clone = source_dir.to_path_buf();
     let source_pb_clone = source_pb.clone();
-    let source_handle = thread::spawn(move || scan_source(&source_dir_clone, &source_pb_clone));
+    let options_clone = options.clone();
+    let source_handle =
+        thread::spawn(move || scan_source(&source_dir_clone, &source_pb_clone, &options_clone));
+
+    // Mirrors are compared too, without a progress display of their own
+    let mirror_handles: Vec<_> = options
+        .mirror_dirs
+        .iter()
+        .filter(|_| !options.trust_dest_db)
+        .map(|dir| {
+            let dir = dir.to_path_buf();
+            thread::spawn(move || scan_destination(&dir, &ProgressBar::hidden(), form))
+        })
+        .collect();
 
     // Wait for both scans to complete
-    let (dest_map, dest_total_size) = dest_handle.join().unwrap()?;
-    dest_pb.finish_with_message(format!(
-        "{} files found ({})",
-        dest_map.len(),
-        format_bytes(dest_total_size)
-    ));
+    let walked_dest = match dest_handle {
+        Some(handle) => {
+            let (dest_map, dest_total_size) = handle.join().unwrap()?;
+            finish(
+                &dest_pb,
+                format!(
+                    "{} files found ({})",
+                    dest_map.len(),
+                    format_bytes(dest_total_size)
+                ),
+            );
+            Some((dest_map, dest_total_size))
+        }
+        None => None,
+    };
 
-    let (source_map, source_total_size) = source_handle.join().unwrap()?;
-    source_pb.finish_with_message(format!(
-        "{} files found ({})",
-        source_map.len(),
-        format_bytes(source_total_size)
-    ));
+    let (source_map, links, source_total_size) = source_handle.join().unwrap()?;
+    finish(
+        &source_pb,
+        format!(
+            "{} files found ({})",
+            source_map.len(),
+            format_bytes(source_total_size)
+        ),
+    );
+
+    if let Some(depth) = options.report_tree_depth {
+        println!("Source size by directory (depth {}):", depth);
+        for (dir, files, bytes) in tree_sizes(&source_map, depth) {
+            println!(
+                "{:>12}  {:>8} files  {}",
+                format_bytes(bytes),
+                files,
+                dir.display()
+            );
+        }
+    }
+
+    let (dest_maps, dest_total_size) = match walked_dest {
+        Some((dest_map, dest_total_size)) => {
+            let mut dest_maps = vec![dest_map];
+            for handle in mirror_handles {
+                dest_maps.push(handle.join().unwrap()?.0);
+            }
+            (dest_maps, dest_total_size)
+        }
+        None => {
+            let synced: HashMap<String, FileRecord> = db
+                .lock()
+                .unwrap()
+                .get_synced_files()?
+                .into_iter()
+                .map(|record| (record.source_path.clone(), record))
+                .collect();
+            let dest_dirs = std::iter::once(dest_dir.as_path())
+                .chain(options.mirror_dirs.iter().map(PathBuf::as_path));
+            let mut dest_maps = Vec::new();
+            let (mut dest_total_size, mut checked) = (0, 0);
+            for (i, dir) in dest_dirs.enumerate() {
+                let (dest_map, total_size, dir_checked) =
+                    trusted_destination(source_dir, dir, i == 0, &source_map, &synced, options);
+                if i == 0 {
+                    (dest_total_size, checked) = (total_size, dir_checked);
+                }
+                dest_maps.push(dest_map);
+            }
+            finish(
+                &dest_pb,
+                format!(
+                    "{} files known from the database ({}), {} looked up on disk",
+                    dest_maps[0].len(),
+                    format_bytes(dest_total_size),
+                    checked
+                ),
+            );
+            (dest_maps, dest_total_size)
+        }
+    };
 
     // Compare and populate database
     println!("Updating database...");
-    let pending = compare_and_populate(source_dir, dest_dir, &source_map, &dest_map, db)?;
+    let pending = compare_and_populate(source_dir, dest_dir, &source_map, &dest_maps, db, options)?;
+    // Links are written with the transfer, not while scanning
+    if !links.is_empty() {
+        let db_guard = db.lock().unwrap();
+        for (relative_path, target) in &links {
+            db_guard.queue_link(relative_path.to_str().unwrap(), target.to_str().unwrap())?;
+        }
+        println!("{} symlinks kept as links.", links.len());
+    }
 
     // Summary progress bar
     let summary_pb = multi_progress.add(ProgressBar::new_spinner());
     summary_pb.set_style(ProgressStyle::default_spinner().template("{msg}").unwrap());
-    summary_pb.finish_with_message(format!(
-        "Scan complete: {} source files ({}), {} destination files ({}), {} to transfer",
-        source_map.len(),
-        format_bytes(source_total_size),
-        dest_map.len(),
-        format_bytes(dest_total_size),
-        pending
-    ));
+    finish(
+        &summary_pb,
+        format!(
+            "Scan complete: {} source files ({}), {} destination files ({}), {} to transfer",
+            source_map.len(),
+            format_bytes(source_total_size),
+            dest_maps[0].len(),
+            format_bytes(dest_total_size),
+            pending
+        ),
+    );
 
     Ok(pending)
 }
 
-/// Source file metadata: (mtime, atime, size, permissions)
+/// Files and bytes of the source per directory, grouping each file under its first
+/// `depth` parent directories (`.` for files at the top), largest first
+fn tree_sizes(source_map: &SourceMap, depth: usize) -> Vec<(PathBuf, u64, u64)> {
+    let mut totals: HashMap<PathBuf, (u64, u64)> = HashMap::new();
+    for (path, &(_, _, size, _)) in source_map {
+        let dir: PathBuf = path
+            .parent()
+            .map(|parent| parent.components().take(depth).collect())
+            .unwrap_or_default();
+        let dir = if dir.as_os_str().is_empty() {
+            PathBuf::from(".")
+        } else {
+            dir
+        };
+        let total = totals.entry(dir).or_default();
+        total.0 += 1;
+        total.1 += size;
+    }
+    let mut sizes: Vec<_> = totals
+        .into_iter()
+        .map(|(dir, (files, bytes))| (dir, files, bytes))
+        .collect();
+    sizes.sort_by(|a, b| b.2.cmp(&a.2).then_with(|| a.0.cmp(&b.0)));
+    sizes
+}
+
+/// Whether the content type detected from the first bytes of `path` is one of `types`,
+/// each a MIME type (`image/png`), a MIME category (`image`) or an extension (`png`).
+/// Files of unknown or undetectable type never match.
+fn matches_magic(path: &Path, types: &[String]) -> bool {
+    let Ok(Some(kind)) = infer::get_from_path(path) else {
+        return false;
+    };
+    let mime = kind.mime_type();
+    let category = mime.split('/').next().unwrap_or_default();
+    types.iter().map(|t| t.trim()).any(|t| {
+        mime.eq_ignore_ascii_case(t)
+            || category.eq_ignore_ascii_case(t)
+            || kind.extension().eq_ignore_ascii_case(t)
+    })
+}
+
+/// Source file metadata: (mtime, created, size, permissions)
 type SourceFileInfo = (i64, i64, u64, u32);
 
 /// Scan results from the source directory
 /// Maps relative path to file metadata
 type SourceMap = HashMap<PathBuf, SourceFileInfo>;
 
-/// Scans the destination directory and returns a map of relative paths to (mtime, size)
-/// along with the total size of all scanned files.
-fn scan_destination(dest_dir: &PathBuf, pb: &ProgressBar) -> Result<(DestinationMap, u64)> {
+/// Source symlinks kept as links: relative path and link target
+type SourceLinks = Vec<(PathBuf, PathBuf)>;
+
+/// Rough heap cost of one source map entry: key and value plus the path bytes,
+/// with half again on top for the hash table's spare capacity
+fn estimated_entry_bytes(relative_path: &Path) -> u64 {
+    let entry = std::mem::size_of::<(PathBuf, SourceFileInfo)>() as u64
+        + relative_path.as_os_str().len() as u64;
+    entry + entry / 2
+}
+
+/// Scans the destination directory and returns a map of relative paths to (mtime, size, permissions)
+/// along with the total size of all scanned files. Paths are keyed in normalization `form`.
+fn scan_destination(
+    dest_dir: &PathBuf,
+    pb: &ProgressBar,
+    form: UnicodeForm,
+) -> Result<(DestinationMap, u64)> {
     let mut dest_map = HashMap::new();
     let mut count = 0u64;
     let mut total_size = 0u64;
@@ -120,7 +399,15 @@ fn scan_destination(dest_dir: &PathBuf, pb: &ProgressBar) -> Result<(Destination
             if let Ok(metadata) = fs::metadata(path) {
                 let mtime = FileTime::from_last_modification_time(&metadata).unix_seconds();
                 let size = metadata.len();
-                dest_map.insert(relative.to_path_buf(), (mtime, size));
+
+                #[cfg(unix)]
+                let permissions = std::os::unix::fs::MetadataExt::mode(&metadata);
+                #[cfg(not(unix))]
+                let permissions = 0u32;
+
+                let key = form.normalize(relative);
+                let on_disk = (key != relative).then(|| relative.to_path_buf());
+                dest_map.insert(key, (mtime, size, permissions, on_disk));
                 count += 1;
                 total_size += size;
 
@@ -143,20 +430,143 @@ fn scan_destination(dest_dir: &PathBuf, pb: &ProgressBar) -> Result<(Destination
     Ok((dest_map, total_size))
 }
 
+/// Destination state of the source files under `dest_dir` without walking it. A file
+/// recorded as synced with the source's current mtime and size is taken to be as hsync
+/// left it; the others are looked up on disk. Also returns the total size of the files
+/// found and how many were looked up. `primary` is the destination recorded in the
+/// database, whose file names are taken from the records.
+fn trusted_destination(
+    source_dir: &Path,
+    dest_dir: &Path,
+    primary: bool,
+    source_map: &SourceMap,
+    synced: &HashMap<String, FileRecord>,
+    options: &ScanOptions,
+) -> (DestinationMap, u64, u64) {
+    let mut dest_map = HashMap::new();
+    let (mut total_size, mut checked) = (0, 0);
+    for (relative_path, &(mtime, _, size, _)) in source_map {
+        let key = options.normalize_unicode.normalize(relative_path);
+        let record = source_dir
+            .join(relative_path)
+            .to_str()
+            .and_then(|source_path| synced.get(source_path))
+            .filter(|record| record.modified_date == mtime && record.size == size);
+        let entry = match record {
+            Some(record) => {
+                let on_disk = Path::new(&record.dest_path)
+                    .strip_prefix(dest_dir)
+                    .ok()
+                    .filter(|name| primary && *name != key)
+                    .map(Path::to_path_buf);
+                // Encrypted copies are stored at their encrypted size
+                let dest_size = options
+                    .encrypted_block_size
+                    .map_or(size, |block_size| encrypted_len(size, block_size));
+                let dest_mtime = record.dest_mtime.unwrap_or(record.modified_date);
+                (dest_mtime, dest_size, record.permissions, on_disk)
+            }
+            None => {
+                checked += 1;
+                let Ok(metadata) = fs::metadata(dest_dir.join(relative_path)) else {
+                    continue;
+                };
+                #[cfg(unix)]
+                let permissions = std::os::unix::fs::MetadataExt::mode(&metadata);
+                #[cfg(not(unix))]
+                let permissions = 0u32;
+                let dest_mtime = FileTime::from_last_modification_time(&metadata).unix_seconds();
+                let on_disk = (key != *relative_path).then(|| relative_path.clone());
+                (dest_mtime, metadata.len(), permissions, on_disk)
+            }
+        };
+        total_size += entry.1;
+        dest_map.insert(key, entry);
+    }
+    (dest_map, total_size, checked)
+}
+
 /// Scans source directory and returns a map of relative paths to file metadata
 /// along with the total size of all scanned files.
-fn scan_source(source_dir: &PathBuf, pb: &ProgressBar) -> Result<(SourceMap, u64)> {
+fn scan_source(
+    source_dir: &PathBuf,
+    pb: &ProgressBar,
+    options: &ScanOptions,
+) -> Result<(SourceMap, SourceLinks, u64)> {
     let mut source_map = HashMap::new();
+    let mut links = Vec::new();
     let mut count = 0u64;
     let mut total_size = 0u64;
+    let mut estimated_memory = 0u64;
 
-    for entry in WalkDir::new(source_dir) {
+    let mut ignores = IgnoreStack::default();
+    // Followed links that lead back to an ancestor come back as loop errors
+    let mut walker = WalkDir::new(source_dir)
+        .follow_links(options.follow_links)
+        .into_iter();
+    while let Some(entry) = walker.next() {
         let entry = match entry {
             Ok(e) => e,
-            Err(_) => continue,
+            Err(e) => {
+                let message = match (e.path(), e.loop_ancestor(), e.io_error()) {
+                    (Some(path), Some(ancestor), _) => format!(
+                        "Skipping symlink loop: {:?} leads back to {:?}",
+                        path, ancestor
+                    ),
+                    // An unreadable directory is reported once, with none of its files
+                    (Some(path), None, Some(error))
+                        if path.is_dir()
+                            || error.kind() == std::io::ErrorKind::PermissionDenied =>
+                    {
+                        if options.fail_on_inaccessible {
+                            bail!("Source directory inaccessible: {:?} - {}", path, error);
+                        }
+                        format!("Skipping inaccessible: {:?} - {}", path, error)
+                    }
+                    // Anything else, e.g. a followed link gone dangling, costs one file
+                    (Some(path), None, Some(error)) => {
+                        format!("Skipping unreadable file: {:?} - {}", path, error)
+                    }
+                    _ => continue,
+                };
+                report(pb, message);
+                continue;
+            }
         };
 
-        if entry.file_type().is_dir() {
+        // Apply .hsyncignore rules of the directories above this entry
+        let is_dir = entry.file_type().is_dir();
+        ignores.unwind(entry.path());
+        if ignores.is_ignored(entry.path(), is_dir) {
+            if is_dir {
+                walker.skip_current_dir();
+            }
+            continue;
+        }
+
+        if is_dir {
+            let excluded = entry.depth() > 0
+                && entry
+                    .path()
+                    .strip_prefix(source_dir)
+                    .is_ok_and(|relative_path| options.path_filter.skips_dir(relative_path));
+            if excluded || has_exclude_marker(entry.path(), &options.exclude_if_present) {
+                walker.skip_current_dir();
+                continue;
+            }
+            if entry.depth() >= MAX_SCAN_DEPTH {
+                report(
+                    pb,
+                    format!(
+                        "Skipping directory nested deeper than {} levels: {:?}",
+                        MAX_SCAN_DEPTH,
+                        entry.path()
+                    ),
+                );
+                walker.skip_current_dir();
+                continue;
+            }
+            ignores.enter_dir(entry.path());
             continue;
         }
 
@@ -165,14 +575,51 @@ fn scan_source(source_dir: &PathBuf, pb: &ProgressBar) -> Result<(SourceMap, u64
             Ok(p) => p.to_path_buf(),
             Err(_) => continue,
         };
+        if options.path_filter.is_excluded(&relative_path) {
+            continue;
+        }
+
+        if let Some(policy) = options.unsafe_links.filter(|_| entry.path_is_symlink()) {
+            let Ok(target) = fs::read_link(source_path) else {
+                continue;
+            };
+            if link_stays_inside(&relative_path, &target) {
+                links.push((relative_path, target));
+                continue;
+            }
+            // Copied unsafe links fall through to be read like regular files
+            if policy == UnsafeLinks::Skip {
+                report(
+                    pb,
+                    format!(
+                        "Skipping symlink outside the source tree: {:?} -> {:?}",
+                        source_path, target
+                    ),
+                );
+                continue;
+            }
+        }
 
         let metadata = match fs::metadata(source_path) {
             Ok(m) => m,
             Err(_) => continue,
         };
+        // An unfollowed link to a directory is neither walked nor copied
+        if !metadata.is_file() {
+            continue;
+        }
 
         let mtime = FileTime::from_last_modification_time(&metadata).unix_seconds();
-        let atime = FileTime::from_last_access_time(&metadata).unix_seconds();
+        if options.newer_than.is_some_and(|cutoff| mtime < cutoff) {
+            continue;
+        }
+        // Reads the start of the file, so it comes after the metadata filters
+        if !options.magic_filter.is_empty() && !matches_magic(source_path, &options.magic_filter) {
+            continue;
+        }
+        // Access time stands in where no creation time is available
+        let created = creation_time(&metadata)
+            .unwrap_or_else(|| FileTime::from_last_access_time(&metadata).unix_seconds());
         let size = metadata.len();
 
         #[cfg(unix)]
@@ -180,7 +627,19 @@ fn scan_source(source_dir: &PathBuf, pb: &ProgressBar) -> Result<(SourceMap, u64
         #[cfg(not(unix))]
         let permissions = 0u32;
 
-        source_map.insert(relative_path, (mtime, atime, size, permissions));
+        estimated_memory += estimated_entry_bytes(&relative_path);
+        if let Some(budget) = options
+            .max_memory
+            .filter(|&budget| estimated_memory > budget)
+        {
+            bail!(
+                "Source scan needs more than --max-scan-memory ({}) after {} files; \
+                 narrow it with --newer-than or .hsyncignore rules, or raise the budget",
+                format_bytes(budget),
+                count + 1
+            );
+        }
+        source_map.insert(relative_path, (mtime, created, size, permissions));
         count += 1;
         total_size += size;
 
@@ -198,58 +657,301 @@ fn scan_source(source_dir: &PathBuf, pb: &ProgressBar) -> Result<(SourceMap, u64
         count,
         format_bytes(total_size)
     ));
-    Ok((source_map, total_size))
+    Ok((source_map, links, total_size))
 }
 
-/// Compares source and destination maps, populates the database.
-/// Returns the number of pending files.
+/// Prints a scan message above the progress bars
+fn report(pb: &ProgressBar, message: String) {
+    // A hidden bar drops printed lines
+    if pb.is_hidden() {
+        eprintln!("{}", message);
+    } else {
+        pb.println(message);
+    }
+}
+
+/// Whether a symlink at `relative_link` (relative to the source root) pointing to
+/// `target` stays inside the source tree. Absolute targets never do; relative ones
+/// are resolved lexically and must not climb above the root.
+fn link_stays_inside(relative_link: &Path, target: &Path) -> bool {
+    let mut depth = relative_link.components().count().saturating_sub(1);
+    for component in target.components() {
+        match component {
+            Component::ParentDir if depth == 0 => return false,
+            Component::ParentDir => depth -= 1,
+            Component::Normal(_) => depth += 1,
+            Component::CurDir => {}
+            Component::RootDir | Component::Prefix(_) => return false,
+        }
+    }
+    true
+}
+
+/// Makes each destination hold the symlinks queued by the scan, with the source
+/// targets, replacing files or other links in their way. Each change is logged; a
+/// link is forgotten once every destination holds it. Returns the links written.
+pub fn create_pending_links(
+    dest_dirs: &[&Path],
+    db: &Mutex<Database>,
+    logger: &Logger,
+) -> Result<u64> {
+    let links = db.lock().unwrap().get_pending_links()?;
+    let mut created = 0;
+    for (relative_path, target) in &links {
+        let target = Path::new(target);
+        let mut complete = true;
+        for dir in dest_dirs {
+            let dest_path = dir.join(relative_path);
+            if fs::read_link(&dest_path).is_ok_and(|existing| existing == target) {
+                continue;
+            }
+            let replaced = fs::symlink_metadata(&dest_path).is_ok();
+            match replace_with_link(&dest_path, target) {
+                Ok(()) => {
+                    created += 1;
+                    logger.log(&format!(
+                        "{} symlink: {:?} -> {:?}",
+                        if replaced { "Replaced with" } else { "Created" },
+                        dest_path,
+                        target
+                    ))?;
+                }
+                Err(e) => {
+                    complete = false;
+                    logger
+                        .log_error(&format!("Failed to create symlink {:?}: {}", dest_path, e))?;
+                }
+            }
+        }
+        if complete {
+            db.lock().unwrap().remove_link(relative_path)?;
+        }
+    }
+    Ok(created)
+}
+
+fn replace_with_link(dest_path: &Path, target: &Path) -> io::Result<()> {
+    if let Some(parent) = dest_path.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    match fs::symlink_metadata(dest_path) {
+        Ok(metadata) if metadata.is_dir() => {
+            return Err(io::Error::other("a directory is in the way"));
+        }
+        Ok(_) => fs::remove_file(dest_path)?,
+        Err(_) => {}
+    }
+    #[cfg(unix)]
+    {
+        std::os::unix::fs::symlink(target, dest_path)
+    }
+    #[cfg(not(unix))]
+    {
+        Err(io::Error::other(format!(
+            "symlinks to {:?} can only be recreated on Unix",
+            target
+        )))
+    }
+}
+
+/// Compares two files block by block, stopping at the first difference
+pub fn files_equal(a: &Path, b: &Path, block_size: usize) -> io::Result<bool> {
+    streams_equal(File::open(a)?, File::open(b)?, block_size)
+}
+
+fn streams_equal(mut a: impl Read, mut b: impl Read, block_size: usize) -> io::Result<bool> {
+    let mut buf_a = vec![0u8; block_size];
+    let mut buf_b = vec![0u8; block_size];
+    loop {
+        let len_a = read_block(&mut a, &mut buf_a)?;
+        let len_b = read_block(&mut b, &mut buf_b)?;
+        if buf_a[..len_a] != buf_b[..len_b] {
+            return Ok(false);
+        }
+        if len_a < block_size {
+            return Ok(true);
+        }
+    }
+}
+
+/// Fills `buf` unless the end of the stream comes first; returns the bytes read
+pub(crate) fn read_block(reader: &mut impl Read, buf: &mut [u8]) -> io::Result<usize> {
+    let mut filled = 0;
+    while filled < buf.len() {
+        match reader.read(&mut buf[filled..])? {
+            0 => break,
+            n => filled += n,
+        }
+    }
+    Ok(filled)
+}
+
+/// Compares source and destination maps (primary destination first), populates
+/// the database. Returns the number of pending files.
 fn compare_and_populate(
     source_dir: &PathBuf,
     dest_dir: &PathBuf,
     source_map: &SourceMap,
-    dest_map: &DestinationMap,
+    dest_maps: &[DestinationMap],
     db: &Arc<Mutex<Database>>,
+    options: &ScanOptions,
 ) -> Result<u64> {
     let mut pending = 0u64;
+    let mut refreshed = 0u64;
+    let dest_dirs: Vec<&Path> = std::iter::once(dest_dir.as_path())
+        .chain(options.mirror_dirs.iter().map(PathBuf::as_path))
+        .collect();
 
     // Hold lock for entire operation and use a single transaction for performance
     let db_guard = db.lock().unwrap();
     db_guard.begin_transaction()?;
 
-    for (relative_path, &(mtime, atime, size, permissions)) in source_map {
+    for (relative_path, &(mtime, created, size, permissions)) in source_map {
+        if options.path_filter.is_excluded(relative_path) {
+            continue;
+        }
         let source_path = source_dir.join(relative_path);
-        let dest_path = dest_dir.join(relative_path);
+        let key = options.normalize_unicode.normalize(relative_path);
+        // An existing destination file is updated under its own name
+        let dest_path = match dest_maps[0].get(&key) {
+            Some(entry) => dest_dir.join(dest_name(&key, entry)),
+            None => dest_dir.join(relative_path),
+        };
         let ctime = mtime; // ctime fallback
 
-        // Determine status: check if destination exists with matching mtime and size
-        let status = match dest_map.get(relative_path) {
-            Some(&(dest_mtime, dest_size)) if dest_mtime == mtime && dest_size == size => {
-                FileStatus::Synced
-            }
-            _ => {
-                pending += 1;
-                FileStatus::Pending
+        // Files absent from the destination are not recorded, so they are never queued
+        if options.existing && !dest_maps[0].contains_key(&key) {
+            continue;
+        }
+
+        // Per destination: None if the file is missing, otherwise whether it is up to date
+        let states: Vec<Option<bool>> = dest_dirs
+            .iter()
+            .zip(dest_maps)
+            .map(|(dir, dest_map)| {
+                let entry = dest_map.get(&key)?;
+                let &(dest_mtime, dest_size, dest_permissions, _) = entry;
+                let same_mode = effective_mode(permissions) == effective_mode(dest_permissions);
+                // Encrypted copies are larger than their source by a known amount
+                let expected_size = options
+                    .encrypted_block_size
+                    .map_or(size, |block_size| encrypted_len(size, block_size));
+                Some(if options.ignore_existing {
+                    // Any existing destination file counts as synced
+                    true
+                } else if options.update_only && dest_mtime >= mtime {
+                    true
+                } else if options.perms_matter && !same_mode {
+                    false
+                } else if let Some(block_size) = options.checksum_compare {
+                    // Same size: the content decides, whatever the mtime
+                    dest_size == size
+                        && files_equal(&source_path, &dir.join(dest_name(&key, entry)), block_size)
+                            .unwrap_or(false)
+                } else {
+                    dest_size == expected_size && (options.size_only || dest_mtime == mtime)
+                })
+            })
+            .collect();
+        if options.refresh_metadata && !options.dry_run {
+            // Only a match on content, or on size and mtime, vouches for the copy. One
+            // accepted as it is (`ignore_existing`), as newer (`update_only`) or on size
+            // alone keeps its mtime: the source's would roll a newer copy back, or make
+            // a different one pass every later scan as synced.
+            let stamp_mtime = |dest_mtime: i64| {
+                let accepted_as_is =
+                    options.ignore_existing || (options.update_only && dest_mtime >= mtime);
+                !accepted_as_is && (options.checksum_compare.is_some() || !options.size_only)
+            };
+            for ((dir, dest_map), state) in dest_dirs.iter().zip(dest_maps).zip(&states) {
+                if *state != Some(true) {
+                    continue;
+                }
+                let entry = &dest_map[&key];
+                let &(dest_mtime, _, dest_permissions, _) = entry;
+                let dest_file = dir.join(dest_name(&key, entry));
+                let mtime = stamp_mtime(dest_mtime).then_some(mtime);
+                match refresh_metadata(&dest_file, mtime, permissions, dest_mtime, dest_permissions)
+                {
+                    Ok(true) => refreshed += 1,
+                    Ok(false) => {}
+                    Err(e) => eprintln!("Failed to refresh metadata of {:?}: {}", dest_file, e),
+                }
             }
+        }
+
+        let status = if states.iter().all(|state| *state == Some(true)) {
+            FileStatus::Synced
+        } else {
+            pending += 1;
+            FileStatus::Pending
+        };
+        // A plain copy if no outdated destination has the file at all
+        let reason = if states.iter().all(|state| *state != Some(false)) {
+            PendingReason::DestMissing
+        } else {
+            PendingReason::Changed
         };
 
         db_guard.upsert_file(
             source_path.to_str().unwrap(),
             dest_path.to_str().unwrap(),
-            atime, // Using atime as created (not available on all platforms)
+            created,
             ctime,
             mtime,
             permissions,
             size,
             status,
         )?;
+        if status == FileStatus::Pending {
+            db_guard.set_pending_reason(source_path.to_str().unwrap(), reason)?;
+            for (dir, state) in dest_dirs.iter().zip(&states) {
+                if *state == Some(true) {
+                    db_guard
+                        .mark_dest_synced(source_path.to_str().unwrap(), dir.to_str().unwrap())?;
+                }
+            }
+        }
     }
 
     db_guard.commit_transaction()?;
     drop(db_guard);
 
+    if refreshed > 0 {
+        println!(
+            "Refreshed metadata of {} synced destination files.",
+            refreshed
+        );
+    }
     Ok(pending)
 }
 
+/// Applies the source permission mode and, if given, the source mtime to a synced
+/// destination file where they differ. Returns whether anything was changed.
+fn refresh_metadata(
+    dest_file: &Path,
+    mtime: Option<i64>,
+    permissions: u32,
+    dest_mtime: i64,
+    dest_permissions: u32,
+) -> io::Result<bool> {
+    let mut changed = false;
+    #[cfg(unix)]
+    if effective_mode(permissions) != effective_mode(dest_permissions) {
+        use std::os::unix::fs::PermissionsExt;
+        let mode = effective_mode(permissions);
+        fs::set_permissions(dest_file, fs::Permissions::from_mode(mode))?;
+        changed = true;
+    }
+    #[cfg(not(unix))]
+    let _ = (permissions, dest_permissions);
+    if let Some(mtime) = mtime.filter(|mtime| *mtime != dest_mtime) {
+        filetime::set_file_mtime(dest_file, FileTime::from_unix_time(mtime, 0))?;
+        changed = true;
+    }
+    Ok(changed)
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -266,12 +968,145 @@ mod tests {
             &source.path().to_path_buf(),
             &dest.path().to_path_buf(),
             &db,
+            &ScanOptions::default(),
         )?;
 
         assert_eq!(pending, 0);
         Ok(())
     }
 
+    #[test]
+    #[cfg(unix)]
+    fn test_scan_skips_unreadable_file_not_directory() -> Result<()> {
+        let source = tempfile::tempdir()?;
+        let dest = tempfile::tempdir()?;
+        fs::write(source.path().join("file.txt"), b"content")?;
+        // Followed, a dangling link fails to stat: an error for that entry alone
+        std::os::unix::fs::symlink("missing", source.path().join("dangling"))?;
+        let db = Arc::new(Mutex::new(Database::new(":memory:")?));
+
+        let options = ScanOptions {
+            follow_links: true,
+            fail_on_inaccessible: true,
+            ..Default::default()
+        };
+        let pending = run_scan(
+            &source.path().to_path_buf(),
+            &dest.path().to_path_buf(),
+            &db,
+            &options,
+        )?;
+        assert_eq!(pending, 1);
+        Ok(())
+    }
+
+    #[test]
+    fn test_scan_skips_directories_nested_too_deep() -> Result<()> {
+        let source = tempfile::tempdir()?;
+        let dest = tempfile::tempdir()?;
+        let mut dir = source.path().to_path_buf();
+        for _ in 1..MAX_SCAN_DEPTH {
+            dir.push("d");
+        }
+        fs::create_dir_all(dir.join("d"))?;
+        fs::write(dir.join("kept.txt"), b"kept")?;
+        fs::write(dir.join("d").join("deep.txt"), b"deep")?;
+        let db = Arc::new(Mutex::new(Database::new(":memory:")?));
+
+        let pending = run_scan(
+            &source.path().to_path_buf(),
+            &dest.path().to_path_buf(),
+            &db,
+            &ScanOptions::default(),
+        )?;
+        assert_eq!(pending, 1);
+        let files = db.lock().unwrap().get_pending_files()?;
+        assert!(files[0].source_path.ends_with("kept.txt"));
+        Ok(())
+    }
+
+    #[test]
+    fn test_scan_max_memory() -> Result<()> {
+        let source = tempfile::tempdir()?;
+        let dest = tempfile::tempdir()?;
+        for i in 0..20 {
+            File::create(source.path().join(format!("file{}.txt", i)))?;
+        }
+        let db = Arc::new(Mutex::new(Database::new(":memory:")?));
+
+        let options = ScanOptions {
+            max_memory: Some(1024),
+            ..Default::default()
+        };
+        let error = run_scan(
+            &source.path().to_path_buf(),
+            &dest.path().to_path_buf(),
+            &db,
+            &options,
+        )
+        .unwrap_err();
+        assert!(error.to_string().contains("--max-scan-memory"));
+        assert_eq!(db.lock().unwrap().pending_count()?, 0);
+
+        // A budget the tree fits in scans as usual
+        let options = ScanOptions {
+            max_memory: Some(1024 * 1024),
+            ..Default::default()
+        };
+        assert_eq!(
+            run_scan(
+                &source.path().to_path_buf(),
+                &dest.path().to_path_buf(),
+                &db,
+                &options
+            )?,
+            20
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_tree_sizes() -> Result<()> {
+        let source = tempfile::tempdir()?;
+        for (path, size) in [
+            ("top.bin", 10),
+            ("photos/2023/a.jpg", 3000),
+            ("photos/2024/b.jpg", 2000),
+            ("docs/c.txt", 400),
+            ("docs/old/d.txt", 600),
+            ("music/e.mp3", 4000),
+        ] {
+            let path = source.path().join(path);
+            fs::create_dir_all(path.parent().unwrap())?;
+            fs::write(path, vec![0u8; size])?;
+        }
+        let (source_map, _, _) = scan_source(
+            &source.path().to_path_buf(),
+            &ProgressBar::hidden(),
+            &ScanOptions::default(),
+        )?;
+
+        let top = tree_sizes(&source_map, 1);
+        let expected = [
+            ("photos", 2, 5000),
+            ("music", 1, 4000),
+            ("docs", 2, 1000),
+            (".", 1, 10),
+        ];
+        assert_eq!(top.len(), expected.len());
+        for ((dir, files, bytes), (name, n, size)) in top.iter().zip(expected) {
+            assert_eq!((dir.as_path(), *files, *bytes), (Path::new(name), n, size));
+        }
+
+        // Deeper levels split the subtrees further
+        let nested = tree_sizes(&source_map, 2);
+        assert_eq!(nested[0], (PathBuf::from("music"), 1, 4000));
+        assert_eq!(nested[1], (PathBuf::from("photos/2023"), 1, 3000));
+        assert!(nested.contains(&(PathBuf::from("docs/old"), 1, 600)));
+        assert!(nested.contains(&(PathBuf::from("docs"), 1, 400)));
+        Ok(())
+    }
+
     #[test]
     fn test_scan_source_only() -> Result<()> {
         let source = tempfile::tempdir()?;
@@ -287,6 +1122,7 @@ mod tests {
             &source.path().to_path_buf(),
             &dest.path().to_path_buf(),
             &db,
+            &ScanOptions::default(),
         )?;
 
         assert_eq!(pending, 1);
@@ -294,6 +1130,144 @@ mod tests {
         Ok(())
     }
 
+    #[test]
+    fn test_scan_normalize_unicode() -> Result<()> {
+        let source = tempfile::tempdir()?;
+        let dest = tempfile::tempdir()?;
+        // "café.txt" precomposed in the source, decomposed in the destination
+        let source_file = source.path().join("caf\u{e9}.txt");
+        let dest_file = dest.path().join("cafe\u{301}.txt");
+        for path in [&source_file, &dest_file] {
+            fs::write(path, b"hello")?;
+            filetime::set_file_mtime(path, FileTime::from_unix_time(1_600_000_000, 0))?;
+        }
+
+        let scan = |form| -> Result<u64> {
+            let db = Arc::new(Mutex::new(Database::new(":memory:")?));
+            let options = ScanOptions {
+                normalize_unicode: form,
+                ..Default::default()
+            };
+            run_scan(
+                &source.path().to_path_buf(),
+                &dest.path().to_path_buf(),
+                &db,
+                &options,
+            )
+        };
+        assert_eq!(scan(UnicodeForm::None)?, 1);
+        assert_eq!(scan(UnicodeForm::Nfc)?, 0);
+        assert_eq!(scan(UnicodeForm::Nfd)?, 0);
+
+        // A changed file is written over the existing destination name
+        filetime::set_file_mtime(&source_file, FileTime::from_unix_time(1_700_000_000, 0))?;
+        let db = Arc::new(Mutex::new(Database::new(":memory:")?));
+        let options = ScanOptions {
+            normalize_unicode: UnicodeForm::Nfc,
+            ..Default::default()
+        };
+        assert_eq!(
+            run_scan(
+                &source.path().to_path_buf(),
+                &dest.path().to_path_buf(),
+                &db,
+                &options
+            )?,
+            1
+        );
+        let pending = db.lock().unwrap().get_pending_files()?;
+        assert_eq!(pending[0].dest_path, dest_file.to_str().unwrap());
+        Ok(())
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_scan_refresh_metadata() -> Result<()> {
+        use std::os::unix::fs::PermissionsExt;
+        let source = tempfile::tempdir()?;
+        let dest = tempfile::tempdir()?;
+        let source_file = source.path().join("file1.txt");
+        let dest_file = dest.path().join("file1.txt");
+
+        // A destination copy left by another tool: same size, wrong mode and mtime
+        fs::write(&source_file, b"hello")?;
+        fs::set_permissions(&source_file, fs::Permissions::from_mode(0o644))?;
+        filetime::set_file_mtime(&source_file, FileTime::from_unix_time(1_600_000_000, 0))?;
+        fs::write(&dest_file, b"HELLO")?;
+        fs::set_permissions(&dest_file, fs::Permissions::from_mode(0o600))?;
+
+        let db = Arc::new(Mutex::new(Database::new(":memory:")?));
+        let options = ScanOptions {
+            size_only: true,
+            refresh_metadata: true,
+            ..Default::default()
+        };
+        assert_eq!(
+            run_scan(
+                &source.path().to_path_buf(),
+                &dest.path().to_path_buf(),
+                &db,
+                &options
+            )?,
+            0
+        );
+
+        // The mode now matches the source; the content was never transferred, and
+        // as only the size matched, the mtime still tells the copies apart
+        let metadata = fs::metadata(&dest_file)?;
+        assert_eq!(metadata.permissions().mode() & 0o7777, 0o644);
+        assert_ne!(
+            FileTime::from_last_modification_time(&metadata).unix_seconds(),
+            1_600_000_000
+        );
+        assert_eq!(fs::read(&dest_file)?, b"HELLO");
+
+        // A scan comparing mtimes still finds the copy outdated
+        let options = ScanOptions::default();
+        assert_eq!(
+            run_scan(
+                &source.path().to_path_buf(),
+                &dest.path().to_path_buf(),
+                &db,
+                &options
+            )?,
+            1
+        );
+
+        // Nor does a copy accepted as it exists, or as newer, take the source mtime
+        filetime::set_file_mtime(&dest_file, FileTime::from_unix_time(1_700_000_000, 0))?;
+        for options in [
+            ScanOptions {
+                ignore_existing: true,
+                ..Default::default()
+            },
+            ScanOptions {
+                update_only: true,
+                ..Default::default()
+            },
+        ] {
+            let options = ScanOptions {
+                refresh_metadata: true,
+                ..options
+            };
+            assert_eq!(
+                run_scan(
+                    &source.path().to_path_buf(),
+                    &dest.path().to_path_buf(),
+                    &db,
+                    &options
+                )?,
+                0
+            );
+            let metadata = fs::metadata(&dest_file)?;
+            assert_eq!(
+                FileTime::from_last_modification_time(&metadata).unix_seconds(),
+                1_700_000_000
+            );
+        }
+        Ok(())
+    }
+
     #[test]
     fn test_scan_matching_files() -> Result<()> {
         let source = tempfile::tempdir()?;
@@ -319,10 +1293,582 @@ mod tests {
             &source.path().to_path_buf(),
             &dest.path().to_path_buf(),
             &db,
+            &ScanOptions::default(),
         )?;
 
         assert_eq!(pending, 0);
         assert_eq!(db.lock().unwrap().pending_count()?, 0);
         Ok(())
     }
+
+    #[test]
+    fn test_scan_magic_filter() -> Result<()> {
+        let source = tempfile::tempdir()?;
+        let dest = tempfile::tempdir()?;
+
+        // Misleading extensions: only the content decides
+        let files: [(&str, &[u8]); 5] = [
+            ("photo.dat", b"\x89PNG\r\n\x1a\n\0\0\0\rIHDR"),
+            ("scan.txt", b"\xff\xd8\xff\xe0\0\x10JFIF\0"),
+            ("report.jpg", b"%PDF-1.4\n%rest"),
+            ("notes.png", b"plain text, no magic number"),
+            ("empty.png", b""),
+        ];
+        for (name, content) in files {
+            fs::write(source.path().join(name), content)?;
+        }
+
+        let db = Arc::new(Mutex::new(Database::new(":memory:")?));
+        let options = ScanOptions {
+            magic_filter: vec!["image".to_string(), "pdf".to_string()],
+            ..Default::default()
+        };
+        let pending = run_scan(
+            &source.path().to_path_buf(),
+            &dest.path().to_path_buf(),
+            &db,
+            &options,
+        )?;
+
+        assert_eq!(pending, 3);
+        let mut queued: Vec<String> = db
+            .lock()
+            .unwrap()
+            .get_pending_files()?
+            .iter()
+            .map(|f| {
+                Path::new(&f.source_path)
+                    .file_name()
+                    .unwrap()
+                    .to_string_lossy()
+                    .into()
+            })
+            .collect();
+        queued.sort();
+        assert_eq!(queued, ["photo.dat", "report.jpg", "scan.txt"]);
+
+        // A MIME type selects only that type
+        let db = Arc::new(Mutex::new(Database::new(":memory:")?));
+        let options = ScanOptions {
+            magic_filter: vec!["image/png".to_string()],
+            ..Default::default()
+        };
+        assert_eq!(
+            run_scan(
+                &source.path().to_path_buf(),
+                &dest.path().to_path_buf(),
+                &db,
+                &options
+            )?,
+            1
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_scan_newer_than() -> Result<()> {
+        let source = tempfile::tempdir()?;
+        let dest = tempfile::tempdir()?;
+        let cutoff = crate::utils::parse_date("2024-01-01")?;
+
+        // Files before, after and far after (future) the cutoff
+        for (name, mtime) in [
+            ("old.txt", cutoff - 86400),
+            ("new.txt", cutoff + 86400),
+            ("future.txt", cutoff + 100 * 365 * 86400),
+        ] {
+            let path = source.path().join(name);
+            File::create(&path)?.write_all(b"data")?;
+            filetime::set_file_mtime(&path, FileTime::from_unix_time(mtime, 0))?;
+        }
+
+        let db = Arc::new(Mutex::new(Database::new(":memory:")?));
+        let options = ScanOptions {
+            newer_than: Some(cutoff),
+            ..Default::default()
+        };
+        let pending = run_scan(
+            &source.path().to_path_buf(),
+            &dest.path().to_path_buf(),
+            &db,
+            &options,
+        )?;
+
+        assert_eq!(pending, 2);
+        let pending_files = db.lock().unwrap().get_pending_files()?;
+        assert!(pending_files
+            .iter()
+            .all(|f| !f.source_path.ends_with("old.txt")));
+        Ok(())
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn test_scan_symlink_loop() -> Result<()> {
+        use std::os::unix::fs::symlink;
+        let source = tempfile::tempdir()?;
+        let dest = tempfile::tempdir()?;
+        fs::create_dir(source.path().join("a"))?;
+        File::create(source.path().join("a/f.txt"))?;
+        // One link back up to the source root, one sideways into a sibling
+        symlink("..", source.path().join("a/up"))?;
+        symlink("a", source.path().join("alias"))?;
+
+        let db = Arc::new(Mutex::new(Database::new(":memory:")?));
+        let options = ScanOptions {
+            follow_links: true,
+            ..Default::default()
+        };
+        // a/up and alias/up lead back to ancestors and are skipped
+        assert_eq!(
+            run_scan(
+                &source.path().to_path_buf(),
+                &dest.path().to_path_buf(),
+                &db,
+                &options
+            )?,
+            2
+        );
+
+        // Unfollowed, links to directories are not mistaken for files
+        let db = Arc::new(Mutex::new(Database::new(":memory:")?));
+        assert_eq!(
+            run_scan(
+                &source.path().to_path_buf(),
+                &dest.path().to_path_buf(),
+                &db,
+                &ScanOptions::default()
+            )?,
+            1
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_link_stays_inside() {
+        assert!(link_stays_inside(Path::new("a/link"), Path::new("f.txt")));
+        assert!(link_stays_inside(
+            Path::new("a/link"),
+            Path::new("../b/./f.txt")
+        ));
+        assert!(link_stays_inside(
+            Path::new("a/b/link"),
+            Path::new("c/../../f.txt")
+        ));
+        assert!(!link_stays_inside(
+            Path::new("a/link"),
+            Path::new("../../f.txt")
+        ));
+        assert!(!link_stays_inside(
+            Path::new("link"),
+            Path::new("b/../../f.txt")
+        ));
+        assert!(!link_stays_inside(
+            Path::new("a/link"),
+            Path::new("/etc/passwd")
+        ));
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn test_scan_unsafe_links() -> Result<()> {
+        use std::os::unix::fs::symlink;
+        let source = tempfile::tempdir()?;
+        let outside = tempfile::tempdir()?;
+        fs::create_dir(source.path().join("a"))?;
+        fs::write(source.path().join("a/f.txt"), b"inside")?;
+        fs::write(outside.path().join("secret.txt"), b"outside")?;
+        symlink("f.txt", source.path().join("a/in_tree"))?;
+        symlink(
+            outside.path().join("secret.txt"),
+            source.path().join("a/absolute"),
+        )?;
+        symlink("../../secret.txt", source.path().join("a/escaping"))?;
+
+        for (policy, pending) in [(UnsafeLinks::Skip, 1), (UnsafeLinks::Copy, 2)] {
+            let dest = tempfile::tempdir()?;
+            let db = Arc::new(Mutex::new(Database::new(":memory:")?));
+            let options = ScanOptions {
+                unsafe_links: Some(policy),
+                ..Default::default()
+            };
+            // a/f.txt, plus the file behind the absolute link when copied; the
+            // escaping link is dangling, so there is nothing to copy for it
+            assert_eq!(
+                run_scan(
+                    &source.path().to_path_buf(),
+                    &dest.path().to_path_buf(),
+                    &db,
+                    &options
+                )?,
+                pending
+            );
+
+            // The in-tree link is recreated as a link, whatever the policy, but only
+            // with the transfer: the scan itself leaves the destination alone
+            assert!(fs::symlink_metadata(dest.path().join("a/in_tree")).is_err());
+            let logger = Logger::new(outside.path().join("links.log").to_str().unwrap());
+            assert_eq!(create_pending_links(&[dest.path()], &db, &logger)?, 1);
+            assert!(db.lock().unwrap().get_pending_links()?.is_empty());
+            assert_eq!(
+                fs::read_link(dest.path().join("a/in_tree"))?,
+                PathBuf::from("f.txt")
+            );
+            assert!(!dest.path().join("a/escaping").exists());
+            let pending_paths: Vec<String> = db
+                .lock()
+                .unwrap()
+                .get_pending_files()?
+                .into_iter()
+                .map(|file| file.source_path)
+                .collect();
+            let absolute = source.path().join("a/absolute");
+            assert_eq!(
+                pending_paths.contains(&absolute.to_str().unwrap().to_string()),
+                policy == UnsafeLinks::Copy
+            );
+            assert!(!pending_paths.iter().any(|path| path.ends_with("in_tree")));
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_scan_nested_hsyncignore() -> Result<()> {
+        let source = tempfile::tempdir()?;
+        let dest = tempfile::tempdir()?;
+
+        // secret.txt is ignored only below a/, and re-included below a/keep/
+        fs::create_dir_all(source.path().join("a/keep"))?;
+        fs::write(source.path().join("a/.hsyncignore"), "secret.txt\n")?;
+        fs::write(source.path().join("a/keep/.hsyncignore"), "!secret.txt\n")?;
+        for path in [
+            "secret.txt",
+            "a/secret.txt",
+            "a/keep/secret.txt",
+            "a/other.txt",
+        ] {
+            fs::write(source.path().join(path), b"data")?;
+        }
+
+        let db = Arc::new(Mutex::new(Database::new(":memory:")?));
+        run_scan(
+            &source.path().to_path_buf(),
+            &dest.path().to_path_buf(),
+            &db,
+            &ScanOptions::default(),
+        )?;
+
+        let pending: Vec<String> = db
+            .lock()
+            .unwrap()
+            .get_pending_files()?
+            .into_iter()
+            .map(|f| f.source_path)
+            .collect();
+        let queued =
+            |rel: &str| pending.contains(&source.path().join(rel).to_str().unwrap().to_string());
+        assert!(queued("secret.txt"));
+        assert!(!queued("a/secret.txt"));
+        assert!(queued("a/keep/secret.txt"));
+        assert!(queued("a/other.txt"));
+        Ok(())
+    }
+
+    #[test]
+    fn test_scan_exclude_if_present() -> Result<()> {
+        let source = tempfile::tempdir()?;
+        let dest = tempfile::tempdir()?;
+
+        fs::create_dir_all(source.path().join("private/deep"))?;
+        fs::create_dir_all(source.path().join("public"))?;
+        fs::write(source.path().join("private/.nobackup"), b"")?;
+        for path in [
+            "private/a.txt",
+            "private/deep/b.txt",
+            "public/c.txt",
+            "d.txt",
+        ] {
+            fs::write(source.path().join(path), b"data")?;
+        }
+
+        let db = Arc::new(Mutex::new(Database::new(":memory:")?));
+        let options = ScanOptions {
+            exclude_if_present: vec![".nobackup".to_string()],
+            ..Default::default()
+        };
+        run_scan(
+            &source.path().to_path_buf(),
+            &dest.path().to_path_buf(),
+            &db,
+            &options,
+        )?;
+
+        let mut pending: Vec<PathBuf> = db
+            .lock()
+            .unwrap()
+            .get_pending_files()?
+            .into_iter()
+            .map(|f| PathBuf::from(f.source_path))
+            .collect();
+        pending.sort();
+        assert_eq!(
+            pending,
+            vec![
+                source.path().join("d.txt"),
+                source.path().join("public/c.txt")
+            ]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_scan_exclude_and_include_globs() -> Result<()> {
+        let source = tempfile::tempdir()?;
+        let dest = tempfile::tempdir()?;
+
+        for path in [
+            "app/node_modules/pkg/index.js",
+            "app/node_modules/pkg/lib/deep.js",
+            "app/src/main.js",
+            "app/src/scratch.tmp",
+            "app/src/keep.tmp",
+            ".git/config",
+            "build/cache/a.o",
+            "lib/build/cache/b.o",
+        ] {
+            let path = source.path().join(path);
+            fs::create_dir_all(path.parent().unwrap())?;
+            fs::write(path, b"data")?;
+        }
+
+        let db = Arc::new(Mutex::new(Database::new(":memory:")?));
+        let options = ScanOptions {
+            path_filter: PathFilter::new(
+                &[
+                    "node_modules".into(),
+                    ".git".into(),
+                    "*.tmp".into(),
+                    "build/cache".into(),
+                ],
+                &["keep.tmp".into()],
+            ),
+            ..Default::default()
+        };
+        let pending_count = run_scan(
+            &source.path().to_path_buf(),
+            &dest.path().to_path_buf(),
+            &db,
+            &options,
+        )?;
+
+        let mut pending: Vec<PathBuf> = db
+            .lock()
+            .unwrap()
+            .get_pending_files()?
+            .into_iter()
+            .map(|f| PathBuf::from(f.source_path))
+            .collect();
+        pending.sort();
+        // Nested directories go with their excluded parent; the include wins over
+        // the overlapping `*.tmp`, and `build/cache` only matches from the root
+        assert_eq!(
+            pending,
+            vec![
+                source.path().join("app/src/keep.tmp"),
+                source.path().join("app/src/main.js"),
+                source.path().join("lib/build/cache/b.o"),
+            ]
+        );
+        assert_eq!(pending_count, 3);
+        Ok(())
+    }
+
+    #[test]
+    fn test_streams_equal_stops_at_first_difference() -> Result<()> {
+        /// Counts the bytes handed out by the inner reader
+        struct Counting<'a, R>(R, &'a std::cell::Cell<usize>);
+        impl<R: Read> Read for Counting<'_, R> {
+            fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+                let n = self.0.read(buf)?;
+                self.1.set(self.1.get() + n);
+                Ok(n)
+            }
+        }
+
+        let a = vec![1u8; 64 * 1024];
+        let mut b = a.clone();
+        b[10] = 2;
+        let (read_a, read_b) = (std::cell::Cell::new(0), std::cell::Cell::new(0));
+        let equal = streams_equal(Counting(&a[..], &read_a), Counting(&b[..], &read_b), 4096)?;
+
+        assert!(!equal);
+        assert_eq!((read_a.get(), read_b.get()), (4096, 4096));
+        assert!(streams_equal(&a[..], &a[..], 4096)?);
+        assert!(!streams_equal(&a[..], &a[..4096], 4096)?);
+        Ok(())
+    }
+
+    #[test]
+    fn test_scan_checksum_compare() -> Result<()> {
+        let source = tempfile::tempdir()?;
+        let dest = tempfile::tempdir()?;
+
+        // Same size, different mtimes: only the content decides
+        fs::write(source.path().join("same.txt"), b"hello")?;
+        fs::write(dest.path().join("same.txt"), b"hello")?;
+        fs::write(source.path().join("differs.txt"), b"hello")?;
+        fs::write(dest.path().join("differs.txt"), b"jello")?;
+        for name in ["same.txt", "differs.txt"] {
+            filetime::set_file_mtime(dest.path().join(name), FileTime::from_unix_time(0, 0))?;
+        }
+
+        let db = Arc::new(Mutex::new(Database::new(":memory:")?));
+        let options = ScanOptions {
+            checksum_compare: Some(2),
+            ..Default::default()
+        };
+        let pending = run_scan(
+            &source.path().to_path_buf(),
+            &dest.path().to_path_buf(),
+            &db,
+            &options,
+        )?;
+
+        assert_eq!(pending, 1);
+        let pending_files = db.lock().unwrap().get_pending_files()?;
+        assert!(pending_files[0].source_path.ends_with("differs.txt"));
+        Ok(())
+    }
+
+    #[test]
+    fn test_scan_trust_dest_db() -> Result<()> {
+        let source = tempfile::tempdir()?;
+        let dest = tempfile::tempdir()?;
+        let write = |dir: &Path, name: &str, mtime: i64| -> Result<()> {
+            fs::write(dir.join(name), name)?;
+            filetime::set_file_mtime(dir.join(name), FileTime::from_unix_time(mtime, 0))?;
+            Ok(())
+        };
+        for name in ["kept.txt", "removed.txt", "changed.txt"] {
+            write(source.path(), name, 1_600_000_000)?;
+            write(dest.path(), name, 1_600_000_000)?;
+        }
+        let db = Arc::new(Mutex::new(Database::new(":memory:")?));
+        assert_eq!(
+            run_scan(
+                &source.path().to_path_buf(),
+                &dest.path().to_path_buf(),
+                &db,
+                &ScanOptions::default()
+            )?,
+            0
+        );
+
+        // Removed behind hsync's back: only a walk of the destination notices
+        fs::remove_file(dest.path().join("removed.txt"))?;
+        // Changed in the source, new in the source, and copied by hand to both sides
+        write(source.path(), "changed.txt", 1_700_000_000)?;
+        write(source.path(), "new.txt", 1_600_000_000)?;
+        write(source.path(), "copied.txt", 1_600_000_000)?;
+        write(dest.path(), "copied.txt", 1_600_000_000)?;
+
+        let options = ScanOptions {
+            trust_dest_db: true,
+            ..Default::default()
+        };
+        assert_eq!(
+            run_scan(
+                &source.path().to_path_buf(),
+                &dest.path().to_path_buf(),
+                &db,
+                &options
+            )?,
+            2
+        );
+        let mut pending: Vec<String> = db
+            .lock()
+            .unwrap()
+            .get_pending_files()?
+            .into_iter()
+            .map(|record| record.source_path)
+            .collect();
+        pending.sort();
+        let expected: Vec<String> = ["changed.txt", "new.txt"]
+            .iter()
+            .map(|name| source.path().join(name).to_str().unwrap().to_string())
+            .collect();
+        assert_eq!(pending, expected);
+
+        // A full scan finds the removed file
+        assert_eq!(
+            run_scan(
+                &source.path().to_path_buf(),
+                &dest.path().to_path_buf(),
+                &db,
+                &ScanOptions::default()
+            )?,
+            3
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_scan_size_only() -> Result<()> {
+        let source = tempfile::tempdir()?;
+        let dest = tempfile::tempdir()?;
+
+        // Rebuilt artifact: same size, years apart in mtime
+        fs::write(source.path().join("same_size.bin"), b"build 2")?;
+        fs::write(dest.path().join("same_size.bin"), b"build 1")?;
+        fs::write(source.path().join("grown.bin"), b"build 10")?;
+        fs::write(dest.path().join("grown.bin"), b"build 9")?;
+        for name in ["same_size.bin", "grown.bin"] {
+            filetime::set_file_mtime(dest.path().join(name), FileTime::from_unix_time(0, 0))?;
+        }
+
+        let db = Arc::new(Mutex::new(Database::new(":memory:")?));
+        let options = ScanOptions {
+            size_only: true,
+            ..Default::default()
+        };
+        let pending = run_scan(
+            &source.path().to_path_buf(),
+            &dest.path().to_path_buf(),
+            &db,
+            &options,
+        )?;
+
+        assert_eq!(pending, 1);
+        let pending_files = db.lock().unwrap().get_pending_files()?;
+        assert!(pending_files[0].source_path.ends_with("grown.bin"));
+        Ok(())
+    }
+
+    #[test]
+    #[cfg(any(windows, target_os = "macos", target_os = "linux"))]
+    fn test_scan_records_creation_time() -> Result<()> {
+        let source = tempfile::tempdir()?;
+        let dest = tempfile::tempdir()?;
+        let path = source.path().join("file.txt");
+        fs::write(&path, b"content")?;
+        // Older access time so it cannot pass for the creation time
+        filetime::set_file_atime(&path, FileTime::from_unix_time(1_000_000, 0))?;
+
+        // Not every filesystem records a creation time
+        let Some(created) = creation_time(&fs::metadata(&path)?) else {
+            return Ok(());
+        };
+
+        let db = Arc::new(Mutex::new(Database::new(":memory:")?));
+        run_scan(
+            &source.path().to_path_buf(),
+            &dest.path().to_path_buf(),
+            &db,
+            &ScanOptions::default(),
+        )?;
+
+        let pending_files = db.lock().unwrap().get_pending_files()?;
+        assert_eq!(pending_files[0].created, created);
+        Ok(())
+    }
 }
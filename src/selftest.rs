@@ -0,0 +1,140 @@
+//! Built-in consistency checks for the `selftest` subcommand.
+//!
+//! Gives users a quick confidence check of a new platform or build.
+
+use anyhow::{anyhow, Result};
+use clap::Parser;
+use std::fs;
+use std::path::Path;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::pipeline::{create_hasher, HASH_KNOWN_ANSWERS, HASH_KNOWN_ANSWER_INPUT};
+use crate::utils::parse_bandwidth;
+use crate::{run, Args};
+
+/// Expected results of `parse_bandwidth` for representative inputs
+const BANDWIDTH_CASES: [(&str, u64); 5] = [
+    ("100", 100),
+    ("512K", 512 * 1024),
+    ("20M", 20 * 1024 * 1024),
+    ("1G", 1024 * 1024 * 1024),
+    ("1.5m", 1024 * 1024 * 3 / 2),
+];
+
+/// Outcome of each selftest check, in execution order
+#[derive(Debug, Default)]
+pub struct SelftestReport {
+    pub checks: Vec<(String, bool)>,
+}
+
+impl SelftestReport {
+    fn record(&mut self, check: String, passed: bool) {
+        self.checks.push((check, passed));
+    }
+
+    /// Number of failed checks
+    pub fn failed(&self) -> usize {
+        self.checks.iter().filter(|(_, passed)| !passed).count()
+    }
+}
+
+/// Runs all checks. Failures are recorded in the report rather than returned.
+pub fn run_selftest() -> SelftestReport {
+    let mut report = SelftestReport::default();
+
+    for (algo, expected) in HASH_KNOWN_ANSWERS {
+        let mut hasher = create_hasher(algo);
+        hasher.update(HASH_KNOWN_ANSWER_INPUT);
+        let passed = hasher.finalize_hex().is_ok_and(|hash| hash == expected);
+        report.record(format!("{:?} known-answer vector", algo), passed);
+    }
+
+    for (input, expected) in BANDWIDTH_CASES {
+        let passed = parse_bandwidth(input).is_ok_and(|value| value == expected);
+        report.record(format!("parse_bandwidth(\"{}\")", input), passed);
+    }
+    report.record(
+        "parse_bandwidth rejects invalid input".to_string(),
+        parse_bandwidth("abc").is_err(),
+    );
+
+    // Unique per process and call so concurrent selftests do not collide
+    let nanos = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_nanos())
+        .unwrap_or_default();
+    let work_dir =
+        std::env::temp_dir().join(format!("hsync-selftest-{}-{}", std::process::id(), nanos));
+    let result = round_trip_sync(&work_dir);
+    let _ = fs::remove_dir_all(&work_dir);
+    if let Err(e) = &result {
+        eprintln!("Round-trip sync: {}", e);
+    }
+    report.record("round-trip sync".to_string(), result.is_ok());
+
+    report
+}
+
+/// Syncs a few small files between scratch directories and compares the copies
+fn round_trip_sync(work_dir: &Path) -> Result<()> {
+    let source = work_dir.join("source");
+    let dest = work_dir.join("dest");
+    fs::create_dir_all(source.join("nested"))?;
+    fs::create_dir_all(&dest)?;
+
+    // Larger than the block size so multi-block transfers are exercised
+    let files: [(&str, Vec<u8>); 3] = [
+        ("empty.bin", Vec::new()),
+        ("small.txt", b"hsync selftest".to_vec()),
+        (
+            "nested/blocks.bin",
+            (0..10_000u32).map(|i| i as u8).collect(),
+        ),
+    ];
+    for (name, content) in &files {
+        fs::write(source.join(name), content)?;
+    }
+
+    let args = Args::parse_from([
+        "hsync".as_ref(),
+        "--source".as_ref(),
+        source.as_os_str(),
+        "--dest".as_ref(),
+        dest.as_os_str(),
+        "--db".as_ref(),
+        work_dir.join("selftest.db").as_os_str(),
+        "--log".as_ref(),
+        work_dir.join("selftest.log").as_os_str(),
+        "--block-size".as_ref(),
+        "4K".as_ref(),
+        "--retry-attempts".as_ref(),
+        "1".as_ref(),
+    ]);
+    run(args)?;
+
+    for (name, content) in &files {
+        if fs::read(dest.join(name))? != *content {
+            return Err(anyhow!("Content mismatch for {}", name));
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_selftest_passes() {
+        let report = run_selftest();
+        assert_eq!(report.failed(), 0, "{:?}", report.checks);
+        for (algo, _) in HASH_KNOWN_ANSWERS {
+            let check = format!("{:?} known-answer vector", algo);
+            assert!(report.checks.iter().any(|(name, _)| *name == check));
+        }
+        assert!(report
+            .checks
+            .iter()
+            .any(|(name, passed)| name == "round-trip sync" && *passed));
+    }
+}
@@ -1,23 +1,38 @@
+use crate::audit::{AuditChain, MAC_SEPARATOR};
+use crate::pipeline::HashAlgorithm;
 use anyhow::{anyhow, Result};
-use chrono::Local;
-use std::fs::OpenOptions;
+use chrono::{Local, NaiveDate, TimeZone, Utc};
+use clap::ValueEnum;
+use std::fs::{self, File, OpenOptions, TryLockError};
 use std::io::Write;
+use std::path::{Component, Path, PathBuf};
+use std::sync::{Condvar, Mutex};
+use std::time::UNIX_EPOCH;
 
 /// Parses a human-readable bandwidth string (e.g., "20M", "512K") into bytes per second.
-/// Supports suffixes: K/k (1024), M/m (1024²), G/g (1024³). No suffix means bytes.
+/// Supports suffixes: K/k (1024), M/m (1024²), G/g (1024³), each optionally followed by
+/// "B" or "iB" ("10MB", "512KiB"). No suffix, or a bare "B", means bytes.
 pub fn parse_bandwidth(s: &str) -> Result<u64> {
     let s = s.trim();
     if s.is_empty() {
         return Err(anyhow!("Bandwidth value cannot be empty"));
     }
 
-    // Check if the last character is a suffix
-    let last_char = s.chars().last().unwrap();
-    let (num_str, multiplier) = match last_char {
-        'K' | 'k' => (&s[..s.len() - 1], 1024u64),
-        'M' | 'm' => (&s[..s.len() - 1], 1024u64 * 1024),
-        'G' | 'g' => (&s[..s.len() - 1], 1024u64 * 1024 * 1024),
-        _ => (s, 1u64),
+    // The unit is everything from the first letter on
+    let unit_start = s.find(|c: char| c.is_alphabetic()).unwrap_or(s.len());
+    let (num_str, unit) = s.split_at(unit_start);
+    let multiplier = match unit.trim().to_ascii_lowercase().as_str() {
+        "" | "b" => 1u64,
+        "k" | "kb" | "kib" => 1024u64,
+        "m" | "mb" | "mib" => 1024u64 * 1024,
+        "g" | "gb" | "gib" => 1024u64 * 1024 * 1024,
+        _ => {
+            return Err(anyhow!(
+                "Invalid bandwidth unit '{}' in '{}' (expected K, M or G, optionally followed by B or iB)",
+                unit.trim(),
+                s
+            ))
+        }
     };
 
     let num: f64 = num_str
@@ -29,7 +44,11 @@ pub fn parse_bandwidth(s: &str) -> Result<u64> {
         return Err(anyhow!("Bandwidth value cannot be negative"));
     }
 
-    let result = (num * multiplier as f64).round() as u64;
+    let bytes = num * multiplier as f64;
+    if bytes >= u64::MAX as f64 {
+        return Err(anyhow!("Bandwidth value too large: '{}'", s));
+    }
+    let result = bytes.round() as u64;
     if result == 0 && num > 0.0 {
         return Err(anyhow!("Bandwidth value too small"));
     }
@@ -37,6 +56,217 @@ pub fn parse_bandwidth(s: &str) -> Result<u64> {
     Ok(result)
 }
 
+/// Parses a date (YYYY-MM-DD) into the Unix timestamp of local midnight on that day.
+pub fn parse_date(s: &str) -> Result<i64> {
+    let date = NaiveDate::parse_from_str(s.trim(), "%Y-%m-%d")
+        .map_err(|_| anyhow!("Invalid date: '{}' (expected YYYY-MM-DD)", s))?;
+    let midnight = date.and_hms_opt(0, 0, 0).unwrap();
+    Local
+        .from_local_datetime(&midnight)
+        .earliest()
+        .map(|dt| dt.timestamp())
+        .ok_or_else(|| anyhow!("Invalid local time for date: '{}'", s))
+}
+
+/// Parses a `--checksum-override` value of the form `GLOB=ALGO`.
+pub fn parse_checksum_override(s: &str) -> Result<(String, HashAlgorithm)> {
+    let (glob, algo) = s
+        .rsplit_once('=')
+        .filter(|(glob, _)| !glob.is_empty())
+        .ok_or_else(|| anyhow!("Invalid checksum override: '{}' (expected GLOB=ALGO)", s))?;
+    let algo = HashAlgorithm::from_str(algo.trim(), true)
+        .map_err(|_| anyhow!("Unknown checksum algorithm in override: '{}'", s))?;
+    Ok((glob.to_string(), algo))
+}
+
+/// Parses a `--block-size-for` value of the form `GLOB=SIZE`, SIZE as in `--block-size`.
+pub fn parse_block_size_override(s: &str) -> Result<(String, usize)> {
+    let (glob, size) = s
+        .rsplit_once('=')
+        .filter(|(glob, _)| !glob.is_empty())
+        .ok_or_else(|| anyhow!("Invalid block size override: '{}' (expected GLOB=SIZE)", s))?;
+    let size = parse_bandwidth(size)
+        .ok()
+        .filter(|&size| size > 0)
+        .ok_or_else(|| anyhow!("Invalid block size in override: '{}'", s))?;
+    Ok((glob.to_string(), size as usize))
+}
+
+/// Destination root preserving the absolute source path, e.g. `/a/b` synced to
+/// `/dest` becomes `/dest/a/b`.
+pub fn relative_dest_dir(source: &Path, dest: &Path) -> Result<PathBuf> {
+    let source = std::fs::canonicalize(source)?;
+    // Keep only normal components, dropping the root and any drive prefix
+    let components = source
+        .components()
+        .filter(|c| matches!(c, Component::Normal(_)));
+    Ok(dest.join(components.collect::<PathBuf>()))
+}
+
+/// Checks that files can be created in `dir` (creating it if missing) by writing
+/// and removing a marker file, so a read-only destination fails before any work.
+pub fn ensure_writable(dir: &Path) -> Result<()> {
+    let marker = dir.join(format!(".hsync-write-test-{}", std::process::id()));
+    fs::create_dir_all(dir)
+        .and_then(|_| fs::write(&marker, b""))
+        .and_then(|_| fs::remove_file(&marker))
+        .map_err(|e| anyhow!("Destination is not writable: {:?} ({})", dir, e))
+}
+
+/// Mode used for files whose stored mode is unspecified
+pub const DEFAULT_FILE_MODE: u32 = 0o644;
+
+/// Permission bits to apply to a file for a stored mode. Non-Unix scans store 0,
+/// which is treated as unspecified rather than applied literally (it would lock
+/// users out).
+pub fn effective_mode(stored: u32) -> u32 {
+    match stored & 0o7777 {
+        0 => DEFAULT_FILE_MODE,
+        bits => bits,
+    }
+}
+
+/// Whether `path` is the very file `metadata` describes (same device and inode),
+/// e.g. a hardlink of it or the same file reached through a bind mount
+#[cfg(unix)]
+pub fn is_same_file(metadata: &fs::Metadata, path: &Path) -> bool {
+    use std::os::unix::fs::MetadataExt;
+    fs::metadata(path)
+        .is_ok_and(|other| other.dev() == metadata.dev() && other.ino() == metadata.ino())
+}
+
+/// Files cannot be told apart by inode here
+#[cfg(not(unix))]
+pub fn is_same_file(_metadata: &fs::Metadata, _path: &Path) -> bool {
+    false
+}
+
+/// Creation (birth) time in Unix seconds, if the platform and filesystem report one
+pub fn creation_time(metadata: &fs::Metadata) -> Option<i64> {
+    let created = metadata.created().ok()?;
+    Some(created.duration_since(UNIX_EPOCH).ok()?.as_secs() as i64)
+}
+
+/// Sets the creation time of `path` on platforms that allow it (Windows, macOS)
+#[cfg(any(windows, target_os = "macos"))]
+pub fn set_creation_time(path: &Path, created: i64) -> std::io::Result<()> {
+    #[cfg(target_os = "macos")]
+    use std::os::macos::fs::FileTimesExt;
+    #[cfg(windows)]
+    use std::os::windows::fs::FileTimesExt;
+
+    let created = UNIX_EPOCH + std::time::Duration::from_secs(u64::try_from(created).unwrap_or(0));
+    let file = OpenOptions::new().write(true).open(path)?;
+    file.set_times(fs::FileTimes::new().set_created(created))
+}
+
+/// Creation time cannot be set here; the destination keeps its own
+#[cfg(not(any(windows, target_os = "macos")))]
+pub fn set_creation_time(_path: &Path, _created: i64) -> std::io::Result<()> {
+    Ok(())
+}
+
+/// Inode flags mirrored by `--preserve-fileflags`: immutable (`chattr +i`) and
+/// append-only (`chattr +a`)
+pub const PRESERVED_FILE_FLAGS: u32 = 0x10 | 0x20;
+
+#[cfg(target_os = "linux")]
+mod inode_flags {
+    use nix::libc::{c_int, c_long};
+    use std::mem::size_of;
+
+    // The kernel declares these with a `long` argument but reads and writes an `int`
+    nix::ioctl_read_bad!(
+        get_flags,
+        nix::request_code_read!(b'f', 1, size_of::<c_long>()),
+        c_int
+    );
+    nix::ioctl_write_ptr_bad!(
+        set_flags,
+        nix::request_code_write!(b'f', 2, size_of::<c_long>()),
+        c_int
+    );
+}
+
+/// The `PRESERVED_FILE_FLAGS` set on `path`
+#[cfg(target_os = "linux")]
+pub fn file_flags(path: &Path) -> std::io::Result<u32> {
+    use std::os::fd::AsRawFd;
+
+    let file = File::open(path)?;
+    let mut flags = 0;
+    unsafe { inode_flags::get_flags(file.as_raw_fd(), &mut flags) }?;
+    Ok(flags as u32 & PRESERVED_FILE_FLAGS)
+}
+
+/// Sets the `PRESERVED_FILE_FLAGS` of `path` to those in `flags`, leaving its other
+/// flags alone. Changing them takes `CAP_LINUX_IMMUTABLE`.
+#[cfg(target_os = "linux")]
+pub fn set_file_flags(path: &Path, flags: u32) -> std::io::Result<()> {
+    use std::os::fd::AsRawFd;
+
+    let file = File::open(path)?;
+    let mut current = 0;
+    unsafe { inode_flags::get_flags(file.as_raw_fd(), &mut current) }?;
+    let wanted = (current as u32 & !PRESERVED_FILE_FLAGS) | (flags & PRESERVED_FILE_FLAGS);
+    if wanted != current as u32 {
+        unsafe { inode_flags::set_flags(file.as_raw_fd(), &(wanted as i32)) }?;
+    }
+    Ok(())
+}
+
+/// Inode flags are Linux-only; files elsewhere have none
+#[cfg(not(target_os = "linux"))]
+pub fn file_flags(_path: &Path) -> std::io::Result<u32> {
+    Ok(0)
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn set_file_flags(_path: &Path, _flags: u32) -> std::io::Result<()> {
+    Ok(())
+}
+
+/// I/O scheduling class requested with `--io-class`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum, serde::Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum IoClass {
+    /// Only use the disk when no other process needs it
+    Idle,
+    /// The default class, at its default priority level
+    BestEffort,
+}
+
+#[cfg(target_os = "linux")]
+impl IoClass {
+    /// `ioprio` value of the class: class number shifted past the 13 bits of level
+    pub fn ioprio(self) -> i32 {
+        match self {
+            IoClass::Idle => 3 << 13,
+            IoClass::BestEffort => (2 << 13) | 4,
+        }
+    }
+}
+
+/// Puts the calling thread, and the threads it spawns afterwards, in I/O scheduling
+/// class `class`
+#[cfg(target_os = "linux")]
+pub fn set_io_class(class: IoClass) -> std::io::Result<()> {
+    use nix::libc;
+
+    // IOPRIO_WHO_PROCESS with id 0: the calling thread
+    let result = unsafe { libc::syscall(libc::SYS_ioprio_set, 1, 0, class.ioprio()) };
+    if result < 0 {
+        return Err(std::io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+/// I/O scheduling classes are Linux-only
+#[cfg(not(target_os = "linux"))]
+pub fn set_io_class(_class: IoClass) -> std::io::Result<()> {
+    Err(std::io::ErrorKind::Unsupported.into())
+}
+
 /// Formats byte count in human-readable form (e.g., "1.5 GiB")
 pub fn format_bytes(bytes: u64) -> String {
     const KIB: u64 = 1024;
@@ -59,27 +289,188 @@ pub fn format_bytes(bytes: u64) -> String {
 
 pub struct Logger {
     file_path: String,
+    /// Timestamp entries in UTC rather than local time
+    utc: bool,
+    /// File additionally receiving the entries logged with `log_error`
+    error_path: Option<String>,
+    /// With `--audit-key-file`, the MAC chain each entry of the log is appended to
+    audit_chain: Option<Mutex<AuditChain>>,
 }
 
 impl Logger {
     pub fn new(file_path: &str) -> Self {
+        Self::with_utc(file_path, false)
+    }
+
+    /// Logger whose timestamps are in UTC when `utc` is set, so logs written in
+    /// different timezones line up
+    pub fn with_utc(file_path: &str, utc: bool) -> Self {
         Self {
             file_path: file_path.to_string(),
+            utc,
+            error_path: None,
+            audit_chain: None,
         }
     }
 
+    /// Also writes warnings and errors to `error_path` when given
+    pub fn with_error_log(mut self, error_path: Option<&str>) -> Self {
+        self.error_path = error_path.map(str::to_string);
+        self
+    }
+
+    /// Ends every entry with a MAC chained to the previous one, continuing the
+    /// chain already in the log, when `key` is given
+    pub fn with_audit_chain(mut self, key: Option<Vec<u8>>) -> Result<Self> {
+        if let Some(key) = key {
+            let chain = AuditChain::resume(key, Path::new(&self.file_path))?;
+            self.audit_chain = Some(Mutex::new(chain));
+        }
+        Ok(self)
+    }
+
     pub fn log(&self, message: &str) -> Result<()> {
+        let Some(chain) = &self.audit_chain else {
+            return self.append_to(&self.file_path, message);
+        };
+        // Held across the write, so entries and their MACs stay in the same order
+        let mut chain = chain.lock().unwrap();
+        // Verification reads the log line by line
+        let line = self.entry(&message.replace('\n', "\\n"));
+        let mac = chain.next_mac(&line);
+        append_line(
+            &self.file_path,
+            &format!("{}{}{}", line, MAC_SEPARATOR, mac),
+        )?;
+        chain.advance(mac);
+        Ok(())
+    }
+
+    /// Logs the end of the run's part of the audit chain and returns its final MAC,
+    /// which can be kept elsewhere to detect lines removed from the end of the log
+    pub fn seal_audit_chain(&self) -> Result<Option<String>> {
+        if self.audit_chain.is_none() {
+            return Ok(None);
+        }
+        self.log("Audit chain sealed")?;
+        let chain = self.audit_chain.as_ref().unwrap().lock().unwrap();
+        Ok(Some(chain.last_mac().to_string()))
+    }
+
+    /// Logs a warning or error (skip, failure, retry), mirrored to the error log
+    pub fn log_error(&self, message: &str) -> Result<()> {
+        self.log(message)?;
+        match &self.error_path {
+            Some(path) => self.append_to(path, message),
+            None => Ok(()),
+        }
+    }
+
+    fn append_to(&self, path: &str, message: &str) -> Result<()> {
+        append_line(path, &self.entry(message))
+    }
+
+    /// `message` as a timestamped log entry
+    fn entry(&self, message: &str) -> String {
+        let timestamp = if self.utc {
+            Utc::now().format("%Y-%m-%d %H:%M:%S UTC")
+        } else {
+            Local::now().format("%Y-%m-%d %H:%M:%S")
+        };
+        format!("[{}] {}", timestamp, message)
+    }
+}
+
+fn append_line(path: &str, line: &str) -> Result<()> {
+    let mut file = OpenOptions::new().create(true).append(true).open(path)?;
+    writeln!(file, "{}", line)?;
+    Ok(())
+}
+
+/// Appends plain lines without timestamps, e.g. a manifest of transferred paths
+pub struct TransferLog {
+    file_path: String,
+}
+
+impl TransferLog {
+    pub fn new(file_path: &str) -> Self {
+        Self {
+            file_path: file_path.to_string(),
+        }
+    }
+
+    pub fn append(&self, line: &str) -> Result<()> {
         let mut file = OpenOptions::new()
             .create(true)
             .append(true)
             .open(&self.file_path)?;
-
-        let timestamp = Local::now().format("%Y-%m-%d %H:%M:%S");
-        writeln!(file, "[{}] {}", timestamp, message)?;
+        writeln!(file, "{}", line)?;
         Ok(())
     }
 }
 
+/// Counting semaphore capping how many holders may proceed at once
+#[derive(Debug)]
+pub struct Semaphore {
+    available: Mutex<usize>,
+    released: Condvar,
+}
+
+impl Semaphore {
+    /// Semaphore with `permits` slots (at least one)
+    pub fn new(permits: usize) -> Self {
+        Self {
+            available: Mutex::new(permits.max(1)),
+            released: Condvar::new(),
+        }
+    }
+
+    /// Waits for a free slot, held until the returned permit is dropped
+    pub fn acquire(&self) -> SemaphorePermit<'_> {
+        let mut available = self.available.lock().unwrap();
+        while *available == 0 {
+            available = self.released.wait(available).unwrap();
+        }
+        *available -= 1;
+        SemaphorePermit { semaphore: self }
+    }
+}
+
+/// A slot of a `Semaphore`, released on drop
+pub struct SemaphorePermit<'a> {
+    semaphore: &'a Semaphore,
+}
+
+impl Drop for SemaphorePermit<'_> {
+    fn drop(&mut self) {
+        *self.semaphore.available.lock().unwrap() += 1;
+        self.semaphore.released.notify_one();
+    }
+}
+
+/// Exclusive advisory lock held through a file for as long as this value lives.
+/// The file itself is left in place: removing it could let a second process lock
+/// a new file under the same name while a third still holds the old one.
+pub struct LockFile {
+    _file: File,
+}
+
+impl LockFile {
+    /// Takes the lock without waiting. Returns `None` if another process holds it.
+    pub fn try_acquire(path: &Path) -> Result<Option<Self>> {
+        let file = OpenOptions::new()
+            .create(true)
+            .write(true)
+            .truncate(false)
+            .open(path)?;
+        match file.try_lock() {
+            Ok(()) => Ok(Some(Self { _file: file })),
+            Err(TryLockError::WouldBlock) => Ok(None),
+            Err(TryLockError::Error(e)) => Err(e.into()),
+        }
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -124,6 +515,72 @@ mod tests {
         assert!(parse_bandwidth("abc").is_err());
         assert!(parse_bandwidth("M").is_err());
         assert!(parse_bandwidth("-10M").is_err());
+        assert!(parse_bandwidth("inf").is_err());
+        assert!(parse_bandwidth("NaN").is_err());
+        assert!(parse_bandwidth("1e30G").is_err());
+        assert!(parse_bandwidth("10MM").is_err());
+        assert!(parse_bandwidth("10Mi").is_err());
+    }
+
+    #[test]
+    fn test_parse_bandwidth_byte_suffixes() {
+        assert_eq!(parse_bandwidth("10MB").unwrap(), 10 * 1024 * 1024);
+        assert_eq!(parse_bandwidth("10MiB").unwrap(), 10 * 1024 * 1024);
+        assert_eq!(parse_bandwidth("1GiB").unwrap(), 1024 * 1024 * 1024);
+        assert_eq!(parse_bandwidth("512KiB").unwrap(), 512 * 1024);
+        assert_eq!(parse_bandwidth("512kb").unwrap(), 512 * 1024);
+        assert_eq!(parse_bandwidth("100B").unwrap(), 100);
+        assert_eq!(parse_bandwidth("20 MB").unwrap(), 20 * 1024 * 1024);
+
+        let err = parse_bandwidth("10XB").unwrap_err().to_string();
+        assert!(err.contains("unit 'XB'"), "{}", err);
+    }
+
+    #[test]
+    fn test_parse_date() {
+        let day1 = parse_date("2024-01-01").unwrap();
+        let day2 = parse_date("2024-01-02").unwrap();
+        assert_eq!(day2 - day1, 86400);
+
+        assert!(parse_date("").is_err());
+        assert!(parse_date("2024-13-01").is_err());
+        assert!(parse_date("01/01/2024").is_err());
+    }
+
+    #[test]
+    fn test_parse_checksum_override() {
+        let (glob, algo) = parse_checksum_override("**/*.iso=md5").unwrap();
+        assert_eq!(glob, "**/*.iso");
+        assert!(matches!(algo, HashAlgorithm::Md5));
+
+        assert!(parse_checksum_override("*.iso").is_err());
+        assert!(parse_checksum_override("=md5").is_err());
+        assert!(parse_checksum_override("*.iso=crc32").is_err());
+    }
+
+    #[test]
+    fn test_parse_block_size_override() {
+        let (glob, size) = parse_block_size_override("*.mkv=64M").unwrap();
+        assert_eq!(glob, "*.mkv");
+        assert_eq!(size, 64 * 1024 * 1024);
+
+        assert!(parse_block_size_override("*.mkv").is_err());
+        assert!(parse_block_size_override("=4K").is_err());
+        assert!(parse_block_size_override("*.mkv=0").is_err());
+        assert!(parse_block_size_override("*.mkv=big").is_err());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_relative_dest_dir() -> Result<()> {
+        let source = tempfile::tempdir()?;
+        let canonical = std::fs::canonicalize(source.path())?;
+
+        assert_eq!(
+            relative_dest_dir(source.path(), Path::new("/backup"))?,
+            Path::new("/backup").join(canonical.strip_prefix("/")?)
+        );
+        Ok(())
     }
 
     #[test]
@@ -145,6 +602,97 @@ mod tests {
         fs::remove_file(log_path)?;
         Ok(())
     }
+
+    #[test]
+    fn test_logger_utc() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let log_path = dir.path().join("log.txt");
+        let log_path = log_path.to_str().unwrap();
+
+        Logger::with_utc(log_path, true).log("in utc")?;
+        Logger::new(log_path).log("in local time")?;
+
+        let content = fs::read_to_string(log_path)?;
+        let lines: Vec<&str> = content.lines().collect();
+        let utc_stamp = lines[0]
+            .strip_prefix('[')
+            .unwrap()
+            .split(']')
+            .next()
+            .unwrap();
+        let logged = chrono::NaiveDateTime::parse_from_str(utc_stamp, "%Y-%m-%d %H:%M:%S UTC")?;
+        // Written moments ago, whatever the local timezone
+        assert!((Utc::now().naive_utc() - logged).num_seconds().abs() < 60);
+        assert!(!lines[1].contains("UTC"));
+        Ok(())
+    }
+
+    #[test]
+    fn test_effective_mode() {
+        assert_eq!(effective_mode(0), DEFAULT_FILE_MODE);
+        // File type bits from st_mode are dropped
+        assert_eq!(effective_mode(0o100600), 0o600);
+    }
+
+    #[test]
+    fn test_lock_file_exclusive() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let path = dir.path().join("hsync.db.lock");
+
+        let lock = LockFile::try_acquire(&path)?;
+        assert!(lock.is_some());
+        assert!(LockFile::try_acquire(&path)?.is_none());
+
+        // Releasing the lock keeps the file, which the next holder locks again
+        drop(lock);
+        assert!(path.exists());
+        assert!(LockFile::try_acquire(&path)?.is_some());
+        Ok(())
+    }
+
+    #[test]
+    fn test_semaphore_caps_concurrent_holders() {
+        use std::sync::atomic::{AtomicUsize, Ordering};
+        use std::sync::Arc;
+
+        let semaphore = Arc::new(Semaphore::new(2));
+        let (current, peak) = (Arc::new(AtomicUsize::new(0)), Arc::new(AtomicUsize::new(0)));
+        let handles: Vec<_> = (0..8)
+            .map(|_| {
+                let (semaphore, current, peak) = (semaphore.clone(), current.clone(), peak.clone());
+                std::thread::spawn(move || {
+                    for _ in 0..5 {
+                        let _permit = semaphore.acquire();
+                        let holders = current.fetch_add(1, Ordering::SeqCst) + 1;
+                        peak.fetch_max(holders, Ordering::SeqCst);
+                        std::thread::sleep(std::time::Duration::from_millis(2));
+                        current.fetch_sub(1, Ordering::SeqCst);
+                    }
+                })
+            })
+            .collect();
+        for handle in handles {
+            handle.join().unwrap();
+        }
+        assert_eq!(peak.load(Ordering::SeqCst), 2);
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn test_set_io_class() {
+        use nix::libc;
+
+        // On a thread of its own, so the other tests keep their I/O priority
+        std::thread::spawn(|| {
+            for class in [IoClass::Idle, IoClass::BestEffort] {
+                set_io_class(class).unwrap();
+                let ioprio = unsafe { libc::syscall(libc::SYS_ioprio_get, 1, 0) };
+                assert_eq!(ioprio as i32, class.ioprio());
+            }
+        })
+        .join()
+        .unwrap();
+    }
 }
 
 // Global logger instance could be used, or passed around.
@@ -0,0 +1,376 @@
+//! Verification of synced destination files against stored hashes or a manifest.
+
+use crate::db::{Database, FileRecord, PendingReason};
+use crate::pipeline::{hash_file_with, HashAlgorithm, PipelineConfig};
+use crate::utils::Logger;
+use anyhow::{anyhow, Result};
+use clap::ValueEnum;
+use filetime::FileTime;
+use std::fs;
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+
+/// Outcome of a verification run
+#[derive(Debug, Default)]
+pub struct VerifyReport {
+    /// Destination paths that were re-hashed
+    pub verified: Vec<String>,
+    /// Destination paths whose hash differs from the stored one (or that are unreadable)
+    pub mismatched: Vec<String>,
+    /// Destination paths listed in a manifest but absent
+    pub missing: Vec<String>,
+}
+
+/// Re-hashes synced destination files and compares them to the stored hashes.
+/// With `changed_only`, only files whose current mtime differs from the recorded
+/// destination mtime, or whose hash came from another algorithm than the one now
+/// selected, are checked, and a hash cached for the file's current mtime and size is
+/// trusted instead of re-reading it. A full verification always re-reads.
+pub fn run_verify(
+    config: &PipelineConfig,
+    db: &Arc<Mutex<Database>>,
+    logger: &Logger,
+    changed_only: bool,
+) -> Result<VerifyReport> {
+    let files = db.lock().unwrap().get_synced_files()?;
+    let mut report = VerifyReport::default();
+
+    for record in files {
+        let Some(expected) = record.hash.as_deref().filter(|h| !h.is_empty()) else {
+            continue; // Synced by scan without a transfer: nothing to compare
+        };
+        let dest_path = Path::new(&record.dest_path);
+
+        let metadata = fs::metadata(dest_path).ok();
+        let current_mtime = metadata
+            .as_ref()
+            .map(|m| FileTime::from_last_modification_time(m).unix_seconds());
+        // A hash from another algorithm than the one now selected is re-checked even
+        // when the file looks untouched, rather than assumed to still match
+        let algo = stored_algo(&record, config);
+        let algo_changed = config.checksum_command.is_none()
+            && algo != config.hash_algo_for(Path::new(&record.source_path));
+        if changed_only
+            && !algo_changed
+            && current_mtime.is_some()
+            && current_mtime == record.dest_mtime
+        {
+            continue;
+        }
+
+        report.verified.push(record.dest_path.clone());
+        let cache_key = metadata
+            .filter(|_| config.checksum_command.is_none())
+            .zip(current_mtime)
+            .map(|(m, mtime)| (mtime, m.len()));
+        let cached = match cache_key.filter(|_| changed_only) {
+            Some((mtime, size)) => {
+                db.lock()
+                    .unwrap()
+                    .cached_hash(&record.dest_path, mtime, size, algo.name())?
+            }
+            None => None,
+        };
+        let hashed = match cached {
+            Some(hash) => Ok(hash),
+            None => hash_file_with(dest_path, algo, config).inspect(|hash| {
+                // Best effort: a failed cache write only costs a later re-hash
+                if let Some((mtime, size)) = cache_key {
+                    let _ = db.lock().unwrap().cache_hash(
+                        &record.dest_path,
+                        mtime,
+                        size,
+                        algo.name(),
+                        hash,
+                    );
+                }
+            }),
+        };
+        match hashed {
+            Ok(actual) if actual == expected => {
+                // Remember the verified state so the next incremental run skips it
+                if let Some(mtime) = current_mtime {
+                    db.lock()
+                        .unwrap()
+                        .set_dest_mtime(&record.source_path, mtime)?;
+                }
+            }
+            Ok(actual) => {
+                logger.log_error(&format!(
+                    "Verify mismatch: {:?} (expected {}, found {})",
+                    dest_path, expected, actual
+                ))?;
+                report.mismatched.push(record.dest_path.clone());
+            }
+            Err(e) => {
+                logger.log_error(&format!("Verify failed: {:?} - {}", dest_path, e))?;
+                report.mismatched.push(record.dest_path.clone());
+            }
+        }
+    }
+
+    Ok(report)
+}
+
+/// The algorithm recorded with a file's hash, else the one its length identifies
+/// for hashes stored before algorithms were recorded, else the one the file would
+/// use now
+fn stored_algo(record: &FileRecord, config: &PipelineConfig) -> HashAlgorithm {
+    record
+        .hash_algo
+        .as_deref()
+        .and_then(|name| HashAlgorithm::from_str(name, true).ok())
+        .or_else(|| {
+            record
+                .hash
+                .as_deref()
+                .and_then(HashAlgorithm::of_unlabeled_hash)
+        })
+        .unwrap_or_else(|| config.hash_algo_for(Path::new(&record.source_path)))
+}
+
+/// Re-hashes the destinations (mirrors included) of synced files that have a stored
+/// source hash, and queues those whose content no longer matches it for transfer.
+/// Catches destination corruption that leaves size and mtime intact; cached hashes
+/// are never trusted, since such corruption does not invalidate them.
+/// Returns the number of files re-queued.
+pub fn requeue_mismatched(
+    config: &PipelineConfig,
+    db: &Arc<Mutex<Database>>,
+    logger: &Logger,
+) -> Result<u64> {
+    let files = db.lock().unwrap().get_synced_files()?;
+    let mut requeued = 0;
+    for record in files {
+        let Some(expected) = record.hash.as_deref().filter(|h| !h.is_empty()) else {
+            continue;
+        };
+        let algo = stored_algo(&record, config);
+        let source_path = Path::new(&record.source_path);
+        let relative_path = source_path
+            .strip_prefix(&config.source_dir)
+            .unwrap_or(source_path);
+        let dest_paths = std::iter::once(Path::new(&record.dest_path).to_path_buf())
+            .chain(config.mirror_dirs.iter().map(|dir| dir.join(relative_path)));
+        for dest_path in dest_paths {
+            let mismatch = match hash_file_with(&dest_path, algo, config) {
+                Ok(actual) if actual == expected => continue,
+                Ok(actual) => format!("expected {}, found {}", expected, actual),
+                Err(e) => e.to_string(),
+            };
+            logger.log_error(&format!(
+                "Destination hash mismatch, re-queued: {:?} ({})",
+                dest_path, mismatch
+            ))?;
+            db.lock()
+                .unwrap()
+                .mark_pending(&record.source_path, PendingReason::Changed)?;
+            requeued += 1;
+            break;
+        }
+    }
+    Ok(requeued)
+}
+
+/// Hashes the destination files listed in a `path<TAB>hash` manifest, paths being
+/// relative to the destination, and compares them with the listed hashes. The
+/// database is not involved. Blank lines and lines starting with `#` are skipped.
+pub fn verify_manifest(
+    config: &PipelineConfig,
+    manifest: &Path,
+    logger: &Logger,
+) -> Result<VerifyReport> {
+    let content = fs::read_to_string(manifest)?;
+    let mut report = VerifyReport::default();
+
+    for (number, line) in content.lines().enumerate() {
+        if line.trim().is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let (relative_path, expected) = line.split_once('\t').ok_or_else(|| {
+            anyhow!(
+                "Malformed manifest line {} in {:?}: expected path<TAB>hash",
+                number + 1,
+                manifest
+            )
+        })?;
+        let dest_path = config.dest_dir.join(relative_path);
+        let display = dest_path.display().to_string();
+
+        if !dest_path.is_file() {
+            logger.log_error(&format!("Verify missing: {:?}", dest_path))?;
+            report.missing.push(display);
+            continue;
+        }
+        report.verified.push(display.clone());
+        let algo = config.hash_algo_for(Path::new(relative_path));
+        match hash_file_with(&dest_path, algo, config) {
+            Ok(actual) if actual.eq_ignore_ascii_case(expected.trim()) => {}
+            Ok(actual) => {
+                logger.log_error(&format!(
+                    "Verify mismatch: {:?} (expected {}, found {})",
+                    dest_path,
+                    expected.trim(),
+                    actual
+                ))?;
+                report.mismatched.push(display);
+            }
+            Err(e) => {
+                logger.log_error(&format!("Verify failed: {:?} - {}", dest_path, e))?;
+                report.mismatched.push(display);
+            }
+        }
+    }
+
+    Ok(report)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::{FileStatus, SyncedHashes};
+    use crate::pipeline::hash_file;
+
+    #[test]
+    fn test_verify_changed_only() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let config = PipelineConfig {
+            source_dir: dir.path().join("src"),
+            dest_dir: dir.path().join("dest"),
+            block_size: 1024,
+            ..Default::default()
+        };
+        let logger = Logger::new(dir.path().join("verify.log").to_str().unwrap());
+        let db = Arc::new(Mutex::new(Database::new(":memory:")?));
+
+        // Two synced files with recorded hashes and destination mtimes
+        fs::create_dir_all(&config.dest_dir)?;
+        for name in ["a.txt", "b.txt"] {
+            let dest = config.dest_dir.join(name);
+            fs::write(&dest, name)?;
+            let source = config.source_dir.join(name);
+            let db_guard = db.lock().unwrap();
+            db_guard.upsert_file(
+                source.to_str().unwrap(),
+                dest.to_str().unwrap(),
+                0,
+                0,
+                0,
+                0o644,
+                5,
+                FileStatus::Pending,
+            )?;
+            db_guard.mark_synced(
+                source.to_str().unwrap(),
+                SyncedHashes {
+                    hash: Some(&hash_file(&dest, &config)?),
+                    ..Default::default()
+                },
+            )?;
+            let mtime = FileTime::from_last_modification_time(&fs::metadata(&dest)?);
+            db_guard.set_dest_mtime(source.to_str().unwrap(), mtime.unix_seconds())?;
+        }
+
+        // Touch one destination file outside hsync
+        let touched = config.dest_dir.join("b.txt");
+        filetime::set_file_mtime(&touched, FileTime::from_unix_time(1_000_000, 0))?;
+
+        let report = run_verify(&config, &db, &logger, true)?;
+        assert_eq!(report.verified, vec![touched.to_str().unwrap().to_string()]);
+        assert!(report.mismatched.is_empty());
+
+        // The verified mtime is recorded, so nothing is left to check
+        assert!(run_verify(&config, &db, &logger, true)?.verified.is_empty());
+
+        // A full verification checks everything
+        assert_eq!(run_verify(&config, &db, &logger, false)?.verified.len(), 2);
+        Ok(())
+    }
+
+    #[test]
+    fn test_verify_changed_only_rechecks_other_algorithm() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let config = PipelineConfig {
+            source_dir: dir.path().join("src"),
+            dest_dir: dir.path().join("dest"),
+            block_size: 1024,
+            ..Default::default()
+        };
+        let logger = Logger::new(dir.path().join("verify.log").to_str().unwrap());
+        let db = Arc::new(Mutex::new(Database::new(":memory:")?));
+
+        // Untouched since verified, but hashed with MD5 while SHA-256 is now selected
+        fs::create_dir_all(&config.dest_dir)?;
+        let dest = config.dest_dir.join("a.txt");
+        fs::write(&dest, "a")?;
+        let source = config.source_dir.join("a.txt");
+        {
+            let db_guard = db.lock().unwrap();
+            db_guard.upsert_file(
+                source.to_str().unwrap(),
+                dest.to_str().unwrap(),
+                0,
+                0,
+                0,
+                0o644,
+                1,
+                FileStatus::Pending,
+            )?;
+            db_guard.mark_synced(
+                source.to_str().unwrap(),
+                SyncedHashes {
+                    hash: Some(&hash_file_with(&dest, HashAlgorithm::Md5, &config)?),
+                    hash_algo: Some(HashAlgorithm::Md5.name()),
+                    ..Default::default()
+                },
+            )?;
+            let mtime = FileTime::from_last_modification_time(&fs::metadata(&dest)?);
+            db_guard.set_dest_mtime(source.to_str().unwrap(), mtime.unix_seconds())?;
+        }
+
+        // Re-checked with the recorded algorithm rather than skipped
+        let report = run_verify(&config, &db, &logger, true)?;
+        assert_eq!(report.verified, vec![dest.to_str().unwrap().to_string()]);
+        assert!(report.mismatched.is_empty());
+        Ok(())
+    }
+
+    #[test]
+    fn test_verify_manifest() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let config = PipelineConfig {
+            dest_dir: dir.path().join("dest"),
+            block_size: 1024,
+            ..Default::default()
+        };
+        let logger = Logger::new(dir.path().join("verify.log").to_str().unwrap());
+        fs::create_dir_all(config.dest_dir.join("sub"))?;
+        fs::write(config.dest_dir.join("a.txt"), "a")?;
+        fs::write(config.dest_dir.join("sub/b.txt"), "b")?;
+        fs::write(config.dest_dir.join("c.txt"), "c")?;
+
+        let hash = |name: &str| hash_file(&config.dest_dir.join(name), &config);
+        let manifest = dir.path().join("manifest.tsv");
+        fs::write(
+            &manifest,
+            format!(
+                "# reference manifest\na.txt\t{}\nsub/b.txt\t{}\nc.txt\t{}\ngone.txt\t{}\n",
+                hash("a.txt")?.to_uppercase(),
+                hash("sub/b.txt")?,
+                hash("a.txt")?,
+                hash("a.txt")?
+            ),
+        )?;
+
+        let report = verify_manifest(&config, &manifest, &logger)?;
+        assert_eq!(report.verified.len(), 3);
+        let wrong = config.dest_dir.join("c.txt").display().to_string();
+        assert_eq!(report.mismatched, vec![wrong]);
+        let gone = config.dest_dir.join("gone.txt").display().to_string();
+        assert_eq!(report.missing, vec![gone]);
+
+        fs::write(&manifest, "a.txt without a hash\n")?;
+        assert!(verify_manifest(&config, &manifest, &logger).is_err());
+        Ok(())
+    }
+}
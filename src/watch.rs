@@ -0,0 +1,305 @@
+//! Watch mode: keeps the destination in step with the source after the initial sync.
+//!
+//! Source changes are collected until no new event has arrived for the debounce
+//! period, queued in the database and transferred by the usual pipeline.
+
+use crate::cleanup::delete_removed;
+use crate::db::{Database, FileStatus, PendingReason};
+use crate::filter::{has_exclude_marker, IgnoreStack};
+use crate::pipeline::{apply_staged_updates, transfer_backlog, PipelineConfig, TransferControl};
+use crate::utils::{creation_time, Logger};
+use anyhow::{Context, Result};
+use filetime::FileTime;
+use notify::{Event, EventKind, RecursiveMode, Watcher};
+use std::collections::BTreeSet;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{self, RecvTimeoutError};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use walkdir::WalkDir;
+
+/// How often an idle watcher checks whether it was asked to stop
+const STOP_POLL_INTERVAL: Duration = Duration::from_millis(200);
+
+#[derive(Debug, Clone, Default)]
+pub struct WatchOptions {
+    /// Quiet period after the last source event before the changes are synced
+    pub debounce: Duration,
+    /// Delete the destination copies of files removed from the source
+    pub delete: bool,
+    /// Number of block queue slots for each transfer
+    pub queue_capacity: usize,
+    /// Ends the watch once set
+    pub stop: Arc<AtomicBool>,
+}
+
+/// Watches the source directory and syncs each debounced batch of changes until
+/// `options.stop` is set or a cancellation is requested through the database.
+/// Files excluded by `.hsyncignore` are left alone.
+pub fn run_watch(
+    config: &PipelineConfig,
+    db: &Arc<Mutex<Database>>,
+    logger: &Arc<Logger>,
+    options: &WatchOptions,
+) -> Result<()> {
+    let (sender, receiver) = mpsc::channel::<notify::Result<Event>>();
+    let mut watcher = notify::recommended_watcher(sender).context("Failed to start watcher")?;
+    watcher
+        .watch(&config.source_dir, RecursiveMode::Recursive)
+        .with_context(|| format!("Failed to watch {:?}", config.source_dir))?;
+    println!(
+        "Watching {:?} for changes (Ctrl-C to stop)...",
+        config.source_dir
+    );
+
+    let mut changed = BTreeSet::new();
+    while !options.stop.load(Ordering::SeqCst) {
+        let timeout = if changed.is_empty() {
+            STOP_POLL_INTERVAL
+        } else {
+            options.debounce
+        };
+        match receiver.recv_timeout(timeout) {
+            // Reads, including the transfer's own, change nothing
+            Ok(Ok(event)) if matches!(event.kind, EventKind::Access(_)) => {}
+            Ok(Ok(event)) => changed.extend(event.paths),
+            Ok(Err(e)) => {
+                let _ = logger.log_error(&format!("Watch error: {}", e));
+            }
+            Err(RecvTimeoutError::Timeout) if !changed.is_empty() => {
+                sync_changes(std::mem::take(&mut changed), config, db, logger, options)?;
+            }
+            Err(RecvTimeoutError::Timeout) => {
+                if db.lock().unwrap().take_cancel_request()? {
+                    let _ = logger.log("Watch cancelled via the database");
+                    break;
+                }
+            }
+            Err(RecvTimeoutError::Disconnected) => break,
+        }
+    }
+    Ok(())
+}
+
+/// Queues the changed files under `paths`, deletes the destination copies of removed
+/// ones and transfers the queued files
+fn sync_changes(
+    paths: BTreeSet<PathBuf>,
+    config: &PipelineConfig,
+    db: &Arc<Mutex<Database>>,
+    logger: &Arc<Logger>,
+    options: &WatchOptions,
+) -> Result<()> {
+    let mut queued = Vec::new();
+    let mut removed = Vec::new();
+    {
+        let db_guard = db.lock().unwrap();
+        for path in &paths {
+            let Ok(relative_path) = path.strip_prefix(&config.source_dir) else {
+                continue;
+            };
+            if relative_path.as_os_str().is_empty() || is_ignored(config, path) {
+                continue;
+            }
+            match fs::metadata(path) {
+                Ok(metadata) if metadata.is_dir() => {
+                    // A directory created or moved in brings all of its files
+                    for entry in WalkDir::new(path).into_iter().filter_map(|e| e.ok()) {
+                        if entry.file_type().is_file()
+                            && !is_ignored(config, entry.path())
+                            && queue_file(&db_guard, config, entry.path(), logger)?
+                        {
+                            queued
+                                .push(entry.path().strip_prefix(&config.source_dir)?.to_path_buf());
+                        }
+                    }
+                }
+                Ok(metadata) if metadata.is_file() => {
+                    if queue_file(&db_guard, config, path, logger)? {
+                        queued.push(relative_path.to_path_buf());
+                    }
+                }
+                Ok(_) => {}
+                Err(e) if e.kind() == io::ErrorKind::NotFound => {
+                    removed.push(relative_path.to_path_buf());
+                }
+                Err(e) => {
+                    let _ = logger.log_error(&format!("Skipping (read error): {:?} - {}", path, e));
+                }
+            }
+        }
+    }
+
+    if !queued.is_empty() {
+        let control = Arc::new(TransferControl::default());
+        let results = transfer_backlog(config, db, logger, options.queue_capacity, &control);
+        // Files that failed stay pending and go with the next batch
+        for result in [results.0, results.1] {
+            if let Err(e) = result {
+                let _ = logger.log_error(&format!("Watch transfer failed: {}", e));
+            }
+        }
+        apply_staged_updates(std::mem::take(&mut *control.staged.lock().unwrap()), logger)?;
+    }
+
+    let mut deleted = 0;
+    if options.delete {
+        for relative_path in &removed {
+            let source_path = config.source_dir.join(relative_path);
+            if !source_path.exists() {
+                db.lock()
+                    .unwrap()
+                    .remove_file(source_path.to_str().unwrap())?;
+            }
+        }
+        deleted = delete_removed(config, db, logger, &removed, &queued)?;
+    }
+    println!(
+        "Watch: {} changed files synced, {} destination files deleted.",
+        queued.len(),
+        deleted
+    );
+    Ok(())
+}
+
+/// Records a changed source file as pending. Returns false if it vanished or
+/// could not be read in the meantime.
+fn queue_file(
+    db: &Database,
+    config: &PipelineConfig,
+    source_path: &Path,
+    logger: &Logger,
+) -> Result<bool> {
+    let metadata = match fs::metadata(source_path) {
+        Ok(metadata) => metadata,
+        Err(e) => {
+            let _ = logger.log_error(&format!("Skipping (read error): {:?} - {}", source_path, e));
+            return Ok(false);
+        }
+    };
+    let relative_path = source_path.strip_prefix(&config.source_dir)?;
+    let dest_path = config.dest_dir.join(relative_path);
+    let mtime = FileTime::from_last_modification_time(&metadata).unix_seconds();
+
+    #[cfg(unix)]
+    let permissions = std::os::unix::fs::MetadataExt::mode(&metadata);
+    #[cfg(not(unix))]
+    let permissions = 0u32;
+
+    let source = source_path.to_str().unwrap();
+    db.upsert_file(
+        source,
+        dest_path.to_str().unwrap(),
+        creation_time(&metadata).unwrap_or(0),
+        mtime,
+        mtime,
+        permissions,
+        metadata.len(),
+        FileStatus::Pending,
+    )?;
+    let reason = if dest_path.exists() {
+        PendingReason::Changed
+    } else {
+        PendingReason::DestMissing
+    };
+    db.set_pending_reason(source, reason)?;
+    Ok(true)
+}
+
+/// Whether `--exclude` globs, or `.hsyncignore` rules or `--exclude-if-present`
+/// markers of `path`'s ancestors under the source directory exclude it
+fn is_ignored(config: &PipelineConfig, path: &Path) -> bool {
+    let Ok(relative_path) = path.strip_prefix(&config.source_dir) else {
+        return false;
+    };
+    if config.path_filter.is_excluded(relative_path) {
+        return true;
+    }
+    let markers = &config.exclude_if_present;
+    let mut ignores = IgnoreStack::default();
+    let mut dir = config.source_dir.clone();
+    if has_exclude_marker(&dir, markers) {
+        return true;
+    }
+    ignores.enter_dir(&dir);
+    if let Some(parent) = relative_path.parent() {
+        for component in parent.components() {
+            dir.push(component);
+            if ignores.is_ignored(&dir, true) || has_exclude_marker(&dir, markers) {
+                return true;
+            }
+            ignores.enter_dir(&dir);
+        }
+    }
+    let is_dir = path.is_dir();
+    ignores.is_ignored(path, is_dir) || (is_dir && has_exclude_marker(path, markers))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::thread;
+    use std::time::Instant;
+
+    /// Polls `condition` for up to ten seconds
+    fn wait_for(condition: impl Fn() -> bool) -> bool {
+        let deadline = Instant::now() + Duration::from_secs(10);
+        while Instant::now() < deadline {
+            if condition() {
+                return true;
+            }
+            thread::sleep(Duration::from_millis(50));
+        }
+        false
+    }
+
+    #[test]
+    fn test_watch_propagates_changes_and_deletions() -> Result<()> {
+        let source_dir = tempfile::tempdir()?;
+        let dest_dir = tempfile::tempdir()?;
+        let log_dir = tempfile::tempdir()?;
+        let source_file = source_dir.path().join("notes.txt");
+        let dest_file = dest_dir.path().join("notes.txt");
+        fs::write(&source_file, b"first")?;
+        fs::write(&dest_file, b"first")?;
+
+        let config = PipelineConfig {
+            source_dir: source_dir.path().to_path_buf(),
+            dest_dir: dest_dir.path().to_path_buf(),
+            block_size: 1024,
+            ..Default::default()
+        };
+        let db = Arc::new(Mutex::new(Database::new(":memory:")?));
+        let logger = Arc::new(Logger::new(
+            log_dir.path().join("watch.log").to_str().unwrap(),
+        ));
+        let options = WatchOptions {
+            debounce: Duration::from_millis(100),
+            delete: true,
+            queue_capacity: 4,
+            stop: Arc::new(AtomicBool::new(false)),
+        };
+        let handle = {
+            let (config, db, logger, options) =
+                (config.clone(), db.clone(), logger.clone(), options.clone());
+            thread::spawn(move || run_watch(&config, &db, &logger, &options))
+        };
+        // Give the watcher time to register before changing anything
+        thread::sleep(Duration::from_millis(500));
+
+        fs::write(&source_file, b"second version")?;
+        assert!(wait_for(
+            || fs::read(&dest_file).is_ok_and(|content| content == b"second version")
+        ));
+
+        fs::remove_file(&source_file)?;
+        assert!(wait_for(|| !dest_file.exists()));
+
+        options.stop.store(true, Ordering::SeqCst);
+        handle.join().unwrap()?;
+        Ok(())
+    }
+}
@@ -0,0 +1,197 @@
+//! Per-file timeout for block reads and writes that can hang, e.g. on a dead
+//! network mount (`--file-timeout`).
+
+use crossbeam_channel::{bounded, unbounded, Sender};
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, Weak};
+use std::thread;
+use std::time::{Duration, Instant};
+
+type Job = Box<dyn FnOnce() + Send>;
+
+/// A call in flight: the file it works on, when that file last made progress
+/// (the call's start, its previous call having completed), and how to tell the
+/// caller that the file was given up on
+struct Call {
+    file: PathBuf,
+    progress: Instant,
+    give_up: Box<dyn FnOnce() + Send>,
+}
+
+#[derive(Default)]
+struct State {
+    calls: Mutex<HashMap<u64, Call>>,
+    next_id: AtomicU64,
+    /// Files given up on, with the number of their calls still running
+    stuck: Mutex<HashMap<PathBuf, usize>>,
+    /// I/O threads waiting for their next call
+    idle: Mutex<Vec<Sender<Job>>>,
+}
+
+/// Gives up on a file when one of its block reads or writes makes no progress
+/// within the timeout. Calls run on long-lived I/O threads, reused from one call to
+/// the next, and a single watchdog thread checks the progress timestamp of every
+/// file with a call in flight. A call given up on keeps its thread until it returns
+/// on its own, then undoes its effect; until then its file counts as stuck, so that
+/// no later attempt on it can race the late call.
+pub struct Watchdog {
+    timeout: Duration,
+    state: Arc<State>,
+}
+
+impl Watchdog {
+    pub fn new(timeout: Duration) -> Self {
+        let state = Arc::new(State::default());
+        let watched = Arc::downgrade(&state);
+        let tick = (timeout / 10).clamp(Duration::from_millis(1), Duration::from_millis(100));
+        thread::spawn(move || watch(watched, timeout, tick));
+        Self { timeout, state }
+    }
+
+    pub fn timeout(&self) -> Duration {
+        self.timeout
+    }
+
+    /// Whether a call on `file` that was given up on is still running
+    pub fn is_stuck(&self, file: &Path) -> bool {
+        self.state.stuck.lock().unwrap().contains_key(file)
+    }
+
+    /// Runs `call` for `file` on an I/O thread, passing it a check of whether the
+    /// file was given up on meanwhile. Returns None if the file made no progress
+    /// within the timeout; once such a call returns, its result goes to `undo`.
+    pub fn run<T: Send + 'static>(
+        &self,
+        file: &Path,
+        call: impl FnOnce(&dyn Fn() -> bool) -> T + Send + 'static,
+        undo: impl FnOnce(T) + Send + 'static,
+    ) -> Option<T> {
+        let (sender, receiver) = bounded(1);
+        let id = self.state.next_id.fetch_add(1, Ordering::Relaxed);
+        let notify = sender.clone();
+        self.state.calls.lock().unwrap().insert(
+            id,
+            Call {
+                file: file.to_path_buf(),
+                progress: Instant::now(),
+                give_up: Box::new(move || {
+                    let _ = notify.send(None);
+                }),
+            },
+        );
+
+        let worker = self.state.idle.lock().unwrap().pop();
+        let worker = worker.unwrap_or_else(spawn_worker);
+        let state = self.state.clone();
+        let file = file.to_path_buf();
+        let returned = worker.clone();
+        let job: Job = Box::new(move || {
+            let result = call(&|| !state.calls.lock().unwrap().contains_key(&id));
+            // Whoever removes the call decides: this thread in time, or the watchdog
+            let in_time = state.calls.lock().unwrap().remove(&id).is_some();
+            if in_time {
+                // Idle again before the caller can make its next call
+                state.idle.lock().unwrap().push(returned);
+                let _ = sender.send(Some(result));
+            } else {
+                undo(result);
+                state.idle.lock().unwrap().push(returned);
+                let mut stuck = state.stuck.lock().unwrap();
+                if let Some(count) = stuck.get_mut(&file) {
+                    *count -= 1;
+                    if *count == 0 {
+                        stuck.remove(&file);
+                    }
+                }
+            }
+        });
+        // A thread that died with its call is given up on by the watchdog
+        let _ = worker.send(job);
+        receiver.recv().ok().flatten()
+    }
+}
+
+/// An I/O thread running the jobs sent to it until its sender is dropped
+fn spawn_worker() -> Sender<Job> {
+    let (sender, receiver) = unbounded::<Job>();
+    thread::spawn(move || {
+        for job in receiver {
+            job();
+        }
+    });
+    sender
+}
+
+/// Gives up on the calls whose file made no progress within `timeout`, every `tick`,
+/// until the watchdog is dropped
+fn watch(state: Weak<State>, timeout: Duration, tick: Duration) {
+    while let Some(state) = state.upgrade() {
+        let now = Instant::now();
+        let expired: Vec<Call> = {
+            let mut calls = state.calls.lock().unwrap();
+            let ids: Vec<u64> = calls
+                .iter()
+                .filter(|(_, call)| now.duration_since(call.progress) >= timeout)
+                .map(|(id, _)| *id)
+                .collect();
+            let expired: Vec<Call> = ids.iter().filter_map(|id| calls.remove(id)).collect();
+            // Counted while the calls are still locked, so that a late call cannot
+            // finish before its file is marked stuck
+            let mut stuck = state.stuck.lock().unwrap();
+            for call in &expired {
+                *stuck.entry(call.file.clone()).or_default() += 1;
+            }
+            expired
+        };
+        for call in expired {
+            (call.give_up)();
+        }
+        drop(state);
+        thread::sleep(tick);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::AtomicBool;
+
+    #[test]
+    fn test_watchdog_undoes_late_call() {
+        let watchdog = Watchdog::new(Duration::from_millis(50));
+        let file = Path::new("/src/slow");
+        let release = Arc::new(AtomicBool::new(false));
+        let undone = Arc::new(AtomicBool::new(false));
+
+        // A call outliving the timeout is given up on; its file stays stuck until
+        // the call returns, and its result is then undone
+        let (hold, flag) = (release.clone(), undone.clone());
+        let result = watchdog.run(
+            file,
+            move |given_up| {
+                while !hold.load(Ordering::SeqCst) {
+                    thread::sleep(Duration::from_millis(5));
+                }
+                given_up()
+            },
+            move |given_up| flag.store(given_up, Ordering::SeqCst),
+        );
+        assert_eq!(result, None);
+        assert!(watchdog.is_stuck(file));
+        release.store(true, Ordering::SeqCst);
+        let start = Instant::now();
+        while watchdog.is_stuck(file) {
+            assert!(start.elapsed() < Duration::from_secs(5));
+            thread::sleep(Duration::from_millis(5));
+        }
+        assert!(undone.load(Ordering::SeqCst));
+
+        // Calls in time return their result, on the same thread
+        let first = watchdog.run(file, |_| thread::current().id(), |_| {});
+        let second = watchdog.run(file, |_| thread::current().id(), |_| {});
+        assert!(first.is_some());
+        assert_eq!(first, second);
+    }
+}
@@ -1,9 +1,25 @@
 use anyhow::Result;
+use clap::Parser;
 use filetime::FileTime;
-use hsync::{pipeline::HashAlgorithm, run, Args};
+use hsync::{run, run_with_stats, Args};
 use std::fs::{self, File};
 use std::io::Write;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
+
+/// Builds `Args` with CLI defaults for the given paths
+fn test_args(source: &Path, dest: &Path, db: &str, log: &str) -> Args {
+    Args::parse_from([
+        "hsync".as_ref(),
+        "--source".as_ref(),
+        source.as_os_str(),
+        "--dest".as_ref(),
+        dest.as_os_str(),
+        "--db".as_ref(),
+        db.as_ref(),
+        "--log".as_ref(),
+        log.as_ref(),
+    ])
+}
 
 #[test]
 fn test_integration_full_flow() -> Result<()> {
@@ -21,6 +37,7 @@ fn test_integration_full_flow() -> Result<()> {
     }
     if std::path::Path::new(db_path).exists() {
         fs::remove_file(db_path)?;
+        let _ = fs::remove_file(format!("{}.lock", db_path));
     }
     if std::path::Path::new(log_path).exists() {
         fs::remove_file(log_path)?;
@@ -39,20 +56,7 @@ fn test_integration_full_flow() -> Result<()> {
     File::create(source_dir.join("empty.txt"))?;
 
     // 2. Run Sync (fresh scan, then transfer)
-    let args = Args {
-        source: source_dir.clone(),
-        dest: dest_dir.clone(),
-        db: db_path.to_string(),
-        log: log_path.to_string(),
-        bwlimit: None,
-        checksum: HashAlgorithm::Sha256,
-        delete_extras: false,
-        rescan: false,
-        block_size: "5M".to_string(),
-        queue_capacity: 20,
-        retry_attempts: 10,
-        retry_interval_seconds: 60,
-    };
+    let args = test_args(&source_dir, &dest_dir, db_path, log_path);
     run(args.clone())?;
 
     // Verify files transferred
@@ -83,36 +87,16 @@ fn test_integration_full_flow() -> Result<()> {
     // 5. Mirroring Test
     File::create(dest_dir.join("extra.txt"))?;
     let args_mirror = Args {
-        source: source_dir.clone(),
-        dest: dest_dir.clone(),
-        db: db_path.to_string(),
-        log: log_path.to_string(),
-        bwlimit: None,
-        checksum: HashAlgorithm::Sha256,
         delete_extras: true,
-        rescan: false,
-        block_size: "5M".to_string(),
-        queue_capacity: 20,
-        retry_attempts: 10,
-        retry_interval_seconds: 60,
+        ..test_args(&source_dir, &dest_dir, db_path, log_path)
     };
     run(args_mirror)?;
     assert!(!dest_dir.join("extra.txt").exists());
 
     // 6. Forced Rescan Test - use --rescan to force re-evaluation
     let args_rescan = Args {
-        source: source_dir.clone(),
-        dest: dest_dir.clone(),
-        db: db_path.to_string(),
-        log: log_path.to_string(),
-        bwlimit: None,
-        checksum: HashAlgorithm::Sha256,
-        delete_extras: false,
         rescan: true,
-        block_size: "5M".to_string(),
-        queue_capacity: 20,
-        retry_attempts: 10,
-        retry_interval_seconds: 60,
+        ..test_args(&source_dir, &dest_dir, db_path, log_path)
     };
     run(args_rescan)?;
 
@@ -123,18 +107,9 @@ fn test_integration_full_flow() -> Result<()> {
     f1.write_all(b"Hello World Updated")?;
 
     let args_bw = Args {
-        source: source_dir.clone(),
-        dest: dest_dir.clone(),
-        db: db_path.to_string(),
-        log: log_path.to_string(),
         bwlimit: Some("10M".to_string()), // 10MiB/s
-        checksum: HashAlgorithm::Sha256,
-        delete_extras: false,
-        rescan: true, // Force rescan to detect the change
-        block_size: "5M".to_string(),
-        queue_capacity: 20,
-        retry_attempts: 10,
-        retry_interval_seconds: 60,
+        rescan: true,                     // Force rescan to detect the change
+        ..test_args(&source_dir, &dest_dir, db_path, log_path)
     };
     run(args_bw)?;
 
@@ -142,6 +117,7 @@ fn test_integration_full_flow() -> Result<()> {
     fs::remove_dir_all(source_dir)?;
     fs::remove_dir_all(dest_dir)?;
     fs::remove_file(db_path)?;
+    let _ = fs::remove_file(format!("{}.lock", db_path));
     fs::remove_file(log_path)?;
 
     Ok(())
@@ -166,6 +142,7 @@ fn test_size_change_without_mtime_change() -> Result<()> {
     }
     if std::path::Path::new(db_path).exists() {
         fs::remove_file(db_path)?;
+        let _ = fs::remove_file(format!("{}.lock", db_path));
     }
     if std::path::Path::new(log_path).exists() {
         fs::remove_file(log_path)?;
@@ -184,20 +161,7 @@ fn test_size_change_without_mtime_change() -> Result<()> {
     let original_mtime = FileTime::from_last_modification_time(&fs::metadata(&file_path)?);
 
     // First sync
-    let args = Args {
-        source: source_dir.clone(),
-        dest: dest_dir.clone(),
-        db: db_path.to_string(),
-        log: log_path.to_string(),
-        bwlimit: None,
-        checksum: HashAlgorithm::Sha256,
-        delete_extras: false,
-        rescan: false,
-        block_size: "5M".to_string(),
-        queue_capacity: 20,
-        retry_attempts: 10,
-        retry_interval_seconds: 60,
-    };
+    let args = test_args(&source_dir, &dest_dir, db_path, log_path);
     run(args.clone())?;
 
     // Verify initial transfer
@@ -232,6 +196,7 @@ fn test_size_change_without_mtime_change() -> Result<()> {
     fs::remove_dir_all(source_dir)?;
     fs::remove_dir_all(dest_dir)?;
     fs::remove_file(db_path)?;
+    let _ = fs::remove_file(format!("{}.lock", db_path));
     fs::remove_file(log_path)?;
 
     Ok(())
@@ -254,6 +219,7 @@ fn test_resume_from_backlog() -> Result<()> {
     }
     if std::path::Path::new(db_path).exists() {
         fs::remove_file(db_path)?;
+        let _ = fs::remove_file(format!("{}.lock", db_path));
     }
     if std::path::Path::new(log_path).exists() {
         fs::remove_file(log_path)?;
@@ -268,20 +234,7 @@ fn test_resume_from_backlog() -> Result<()> {
     f2.write_all(b"Content 2")?;
 
     // First run - scan and transfer
-    let args = Args {
-        source: source_dir.clone(),
-        dest: dest_dir.clone(),
-        db: db_path.to_string(),
-        log: log_path.to_string(),
-        bwlimit: None,
-        checksum: HashAlgorithm::Sha256,
-        delete_extras: false,
-        rescan: false,
-        block_size: "5M".to_string(),
-        queue_capacity: 20,
-        retry_attempts: 10,
-        retry_interval_seconds: 60,
-    };
+    let args = test_args(&source_dir, &dest_dir, db_path, log_path);
     run(args.clone())?;
 
     // Both files should be transferred
@@ -292,6 +245,7 @@ fn test_resume_from_backlog() -> Result<()> {
     fs::remove_dir_all(source_dir)?;
     fs::remove_dir_all(dest_dir)?;
     fs::remove_file(db_path)?;
+    let _ = fs::remove_file(format!("{}.lock", db_path));
     fs::remove_file(log_path)?;
 
     Ok(())
@@ -322,6 +276,7 @@ fn test_rescan_detects_new_files() -> Result<()> {
     }
     if std::path::Path::new(db_path).exists() {
         fs::remove_file(db_path)?;
+        let _ = fs::remove_file(format!("{}.lock", db_path));
     }
     if std::path::Path::new(log_path).exists() {
         fs::remove_file(log_path)?;
@@ -334,20 +289,7 @@ fn test_rescan_detects_new_files() -> Result<()> {
     f1.write_all(b"Content 1")?;
 
     // First run - creates database with file1.txt
-    let args = Args {
-        source: source_dir.clone(),
-        dest: dest_dir.clone(),
-        db: db_path.to_string(),
-        log: log_path.to_string(),
-        bwlimit: None,
-        checksum: HashAlgorithm::Sha256,
-        delete_extras: false,
-        rescan: false,
-        block_size: "5M".to_string(),
-        queue_capacity: 20,
-        retry_attempts: 10,
-        retry_interval_seconds: 60,
-    };
+    let args = test_args(&source_dir, &dest_dir, db_path, log_path);
     run(args.clone())?;
 
     assert!(dest_dir.join("file1.txt").exists());
@@ -378,6 +320,7 @@ fn test_rescan_detects_new_files() -> Result<()> {
     fs::remove_dir_all(source_dir)?;
     fs::remove_dir_all(dest_dir)?;
     fs::remove_file(db_path)?;
+    let _ = fs::remove_file(format!("{}.lock", db_path));
     fs::remove_file(log_path)?;
 
     Ok(())
@@ -409,6 +352,7 @@ fn test_missing_source_file_skipped() -> Result<()> {
     }
     if std::path::Path::new(db_path).exists() {
         fs::remove_file(db_path)?;
+        let _ = fs::remove_file(format!("{}.lock", db_path));
     }
     if std::path::Path::new(log_path).exists() {
         fs::remove_file(log_path)?;
@@ -452,18 +396,9 @@ fn test_missing_source_file_skipped() -> Result<()> {
 
     // Run with resume (database has pending files)
     let args = Args {
-        source: source_dir.clone(),
-        dest: dest_dir.clone(),
-        db: db_path.to_string(),
-        log: log_path.to_string(),
-        bwlimit: None,
-        checksum: HashAlgorithm::Sha256,
-        delete_extras: false,
-        rescan: false, // Resume from backlog
-        block_size: "5M".to_string(),
-        queue_capacity: 20,
         retry_attempts: 1,
         retry_interval_seconds: 0,
+        ..test_args(&source_dir, &dest_dir, db_path, log_path)
     };
     run(args)?;
 
@@ -484,7 +419,1712 @@ fn test_missing_source_file_skipped() -> Result<()> {
     fs::remove_dir_all(source_dir)?;
     fs::remove_dir_all(dest_dir)?;
     fs::remove_file(db_path)?;
+    let _ = fs::remove_file(format!("{}.lock", db_path));
+    fs::remove_file(log_path)?;
+
+    Ok(())
+}
+
+/// Test that --checksum-command pipes file contents to the command and stores its output.
+#[cfg(unix)]
+#[test]
+fn test_checksum_command() -> Result<()> {
+    use hsync::db::Database;
+
+    let source_dir = PathBuf::from("test_cmdhash_source");
+    let dest_dir = PathBuf::from("test_cmdhash_dest");
+    let db_path = "test_cmdhash.db";
+    let log_path = "test_cmdhash.log";
+
+    // Cleanup
+    let _ = fs::remove_dir_all(&source_dir);
+    let _ = fs::remove_dir_all(&dest_dir);
+    let _ = fs::remove_file(db_path);
+    let _ = fs::remove_file(log_path);
+
+    fs::create_dir_all(&source_dir)?;
+    fs::write(source_dir.join("hello.txt"), b"hello")?;
+    File::create(source_dir.join("empty.txt"))?;
+
+    let args = Args {
+        checksum_command: Some("cat | sha1sum | cut -d' ' -f1".to_string()),
+        ..test_args(&source_dir, &dest_dir, db_path, log_path)
+    };
+    run(args)?;
+
+    let db = Database::new(db_path)?;
+    assert_eq!(
+        db.get_file_hash(source_dir.join("hello.txt").to_str().unwrap())?,
+        Some("aaf4c61ddcc5e8a2dabede0f3b482cd9aea9434d".to_string())
+    );
+    assert_eq!(
+        db.get_file_hash(source_dir.join("empty.txt").to_str().unwrap())?,
+        Some("da39a3ee5e6b4b0d3255bfef95601890afd80709".to_string())
+    );
+    drop(db);
+
+    // Cleanup
+    fs::remove_dir_all(source_dir)?;
+    fs::remove_dir_all(dest_dir)?;
+    fs::remove_file(db_path)?;
+    let _ = fs::remove_file(format!("{}.lock", db_path));
+    fs::remove_file(log_path)?;
+
+    Ok(())
+}
+
+/// Test that --transfer-log receives exactly the relative paths of transferred files.
+#[test]
+fn test_transfer_log() -> Result<()> {
+    let source_dir = PathBuf::from("test_xferlog_source");
+    let dest_dir = PathBuf::from("test_xferlog_dest");
+    let db_path = "test_xferlog.db";
+    let log_path = "test_xferlog.log";
+    let transfer_log_path = "test_xferlog_transfers.txt";
+
+    // Cleanup
+    let _ = fs::remove_dir_all(&source_dir);
+    let _ = fs::remove_dir_all(&dest_dir);
+    let _ = fs::remove_file(db_path);
+    let _ = fs::remove_file(log_path);
+    let _ = fs::remove_file(transfer_log_path);
+
+    fs::create_dir_all(source_dir.join("sub"))?;
+    fs::write(source_dir.join("a.txt"), b"A")?;
+    fs::write(source_dir.join("sub/b.txt"), b"B")?;
+
+    let args = Args {
+        transfer_log: Some(transfer_log_path.to_string()),
+        ..test_args(&source_dir, &dest_dir, db_path, log_path)
+    };
+    run(args.clone())?;
+
+    let mut lines: Vec<String> = fs::read_to_string(transfer_log_path)?
+        .lines()
+        .map(String::from)
+        .collect();
+    lines.sort();
+    let expected = vec![
+        "a.txt".to_string(),
+        Path::new("sub").join("b.txt").display().to_string(),
+    ];
+    assert_eq!(lines, expected);
+
+    // A second run transfers nothing and adds nothing
+    run(args)?;
+    assert_eq!(fs::read_to_string(transfer_log_path)?.lines().count(), 2);
+
+    // Cleanup
+    fs::remove_dir_all(source_dir)?;
+    fs::remove_dir_all(dest_dir)?;
+    fs::remove_file(db_path)?;
+    let _ = fs::remove_file(format!("{}.lock", db_path));
+    fs::remove_file(log_path)?;
+    fs::remove_file(transfer_log_path)?;
+
+    Ok(())
+}
+
+/// Test that --relative recreates the absolute source path under the destination.
+#[test]
+fn test_relative_layout() -> Result<()> {
+    use hsync::db::{Database, FileStatus};
+
+    let source_dir = PathBuf::from("test_relative_source");
+    let dest_dir = PathBuf::from("test_relative_dest");
+    let db_path = "test_relative.db";
+    let log_path = "test_relative.log";
+
+    // Cleanup
+    let _ = fs::remove_dir_all(&source_dir);
+    let _ = fs::remove_dir_all(&dest_dir);
+    let _ = fs::remove_file(db_path);
+    let _ = fs::remove_file(log_path);
+
+    fs::create_dir_all(source_dir.join("nested"))?;
+    fs::write(source_dir.join("nested/file.txt"), b"relative")?;
+
+    let args = Args {
+        relative: true,
+        ..test_args(&source_dir, &dest_dir, db_path, log_path)
+    };
+    run(args.clone())?;
+
+    // Absolute source path components appear below the destination root
+    let absolute = fs::canonicalize(&source_dir)?;
+    let expected_root: PathBuf = dest_dir.join(
+        absolute
+            .components()
+            .filter(|c| matches!(c, std::path::Component::Normal(_)))
+            .collect::<PathBuf>(),
+    );
+    assert_eq!(
+        fs::read_to_string(expected_root.join("nested/file.txt"))?,
+        "relative"
+    );
+    assert!(!dest_dir.join("nested").exists());
+
+    // A second run finds everything synced
+    run(Args {
+        rescan: true,
+        ..args
+    })?;
+    let transfers = fs::read_to_string(log_path)?.matches("Transferred").count();
+    assert_eq!(transfers, 1);
+    let counts = Database::new(db_path)?.count_by_status()?;
+    assert_eq!(counts.get(&FileStatus::Pending), None);
+
+    // Cleanup
+    fs::remove_dir_all(source_dir)?;
+    fs::remove_dir_all(dest_dir)?;
+    fs::remove_file(db_path)?;
+    let _ = fs::remove_file(format!("{}.lock", db_path));
+    fs::remove_file(log_path)?;
+
+    Ok(())
+}
+
+#[test]
+fn test_run_with_stats_timings() -> Result<()> {
+    let source_dir = PathBuf::from("test_stats_source");
+    let dest_dir = PathBuf::from("test_stats_dest");
+    let db_path = "test_stats.db";
+    let log_path = "test_stats.log";
+
+    // Cleanup
+    let _ = fs::remove_dir_all(&source_dir);
+    let _ = fs::remove_dir_all(&dest_dir);
+    let _ = fs::remove_file(db_path);
+    let _ = fs::remove_file(log_path);
+
+    fs::create_dir_all(&source_dir)?;
+    fs::write(source_dir.join("data.bin"), vec![7u8; 256 * 1024])?;
+
+    // 8 blocks at 512K/s: the run is dominated by pacing sleeps
+    let args = Args {
+        bwlimit: Some("512K".to_string()),
+        block_size: "32K".to_string(),
+        ..test_args(&source_dir, &dest_dir, db_path, log_path)
+    };
+    let stats = run_with_stats(args)?;
+
+    assert_eq!(stats.blocks, 8);
+    assert!(stats.hash_algorithm.is_some());
+    assert!(stats.read_time > std::time::Duration::ZERO);
+    assert!(stats.hash_time > std::time::Duration::ZERO);
+    assert!(stats.write_time > std::time::Duration::ZERO);
+    assert!(stats.sleep_time > std::time::Duration::ZERO);
+    let stage_total = stats.read_time + stats.hash_time + stats.write_time + stats.sleep_time;
+    assert!(
+        stage_total > stats.duration / 2 && stage_total < stats.duration * 5 / 4,
+        "stages {:?} vs run {:?}",
+        stage_total,
+        stats.duration
+    );
+
+    // The limiter held: the achieved average stays at the limit, give or take timing
+    let achieved = stats.achieved_rate().unwrap();
+    assert_eq!(stats.bytes_transferred, 256 * 1024);
+    assert!(
+        achieved <= 512.0 * 1024.0 * 1.05,
+        "achieved {:.0} B/s",
+        achieved
+    );
+    assert!(
+        achieved > 512.0 * 1024.0 / 4.0,
+        "achieved {:.0} B/s",
+        achieved
+    );
+
+    // Cleanup
+    fs::remove_dir_all(source_dir)?;
+    fs::remove_dir_all(dest_dir)?;
+    fs::remove_file(db_path)?;
+    let _ = fs::remove_file(format!("{}.lock", db_path));
+    fs::remove_file(log_path)?;
+
+    Ok(())
+}
+
+#[test]
+fn test_compare_dest_hash_requeues_corrupted_file() -> Result<()> {
+    let source_dir = PathBuf::from("test_cmpdest_source");
+    let dest_dir = PathBuf::from("test_cmpdest_dest");
+    let db_path = "test_cmpdest.db";
+    let log_path = "test_cmpdest.log";
+
+    // Cleanup
+    let _ = fs::remove_dir_all(&source_dir);
+    let _ = fs::remove_dir_all(&dest_dir);
+    let _ = fs::remove_file(db_path);
+    let _ = fs::remove_file(log_path);
+
+    fs::create_dir_all(&source_dir)?;
+    fs::write(source_dir.join("data.txt"), b"original content")?;
+    fs::write(source_dir.join("other.txt"), b"untouched")?;
+    let args = test_args(&source_dir, &dest_dir, db_path, log_path);
+    run(args.clone())?;
+
+    // Bitrot: same size, mtime put back
+    let corrupted = dest_dir.join("data.txt");
+    let mtime = FileTime::from_last_modification_time(&fs::metadata(&corrupted)?);
+    fs::write(&corrupted, b"origXnal content")?;
+    filetime::set_file_mtime(&corrupted, mtime)?;
+
+    // A routine sync trusts size and mtime
+    run(args.clone())?;
+    assert_eq!(fs::read(&corrupted)?, b"origXnal content");
+
+    let stats = run_with_stats(Args {
+        compare_dest_hash: true,
+        ..args
+    })?;
+    assert_eq!(fs::read(&corrupted)?, b"original content");
+    assert_eq!(stats.files_transferred, 1);
+    let log = fs::read_to_string(log_path)?;
+    assert!(log.contains("Destination hash mismatch, re-queued"));
+
+    // Cleanup
+    fs::remove_dir_all(source_dir)?;
+    fs::remove_dir_all(dest_dir)?;
+    fs::remove_file(db_path)?;
+    let _ = fs::remove_file(format!("{}.lock", db_path));
+    fs::remove_file(log_path)?;
+
+    Ok(())
+}
+
+#[test]
+fn test_ignore_existing() -> Result<()> {
+    let source_dir = PathBuf::from("test_ignexist_source");
+    let dest_dir = PathBuf::from("test_ignexist_dest");
+    let db_path = "test_ignexist.db";
+    let log_path = "test_ignexist.log";
+
+    // Cleanup
+    let _ = fs::remove_dir_all(&source_dir);
+    let _ = fs::remove_dir_all(&dest_dir);
+    let _ = fs::remove_file(db_path);
+    let _ = fs::remove_file(log_path);
+
+    fs::create_dir_all(&source_dir)?;
+    fs::create_dir_all(&dest_dir)?;
+    fs::write(source_dir.join("existing.txt"), b"new source content")?;
+    fs::write(source_dir.join("missing.txt"), b"fresh")?;
+    fs::write(dest_dir.join("existing.txt"), b"archived")?;
+
+    let args = Args {
+        ignore_existing: true,
+        ..test_args(&source_dir, &dest_dir, db_path, log_path)
+    };
+    run(args)?;
+
+    assert_eq!(fs::read(dest_dir.join("existing.txt"))?, b"archived");
+    assert_eq!(fs::read(dest_dir.join("missing.txt"))?, b"fresh");
+
+    // Cleanup
+    fs::remove_dir_all(source_dir)?;
+    fs::remove_dir_all(dest_dir)?;
+    fs::remove_file(db_path)?;
+    let _ = fs::remove_file(format!("{}.lock", db_path));
+    fs::remove_file(log_path)?;
+
+    Ok(())
+}
+
+#[test]
+fn test_existing_only() -> Result<()> {
+    let source_dir = PathBuf::from("test_existing_source");
+    let dest_dir = PathBuf::from("test_existing_dest");
+    let db_path = "test_existing.db";
+    let log_path = "test_existing.log";
+
+    // Cleanup
+    let _ = fs::remove_dir_all(&source_dir);
+    let _ = fs::remove_dir_all(&dest_dir);
+    let _ = fs::remove_file(db_path);
+    let _ = fs::remove_file(log_path);
+
+    fs::create_dir_all(&source_dir)?;
+    fs::create_dir_all(&dest_dir)?;
+    fs::write(source_dir.join("changed.txt"), b"updated")?;
+    fs::write(source_dir.join("new.txt"), b"new")?;
+    fs::write(dest_dir.join("changed.txt"), b"old")?;
+
+    let args = Args {
+        existing: true,
+        ..test_args(&source_dir, &dest_dir, db_path, log_path)
+    };
+    run(args)?;
+
+    assert_eq!(fs::read(dest_dir.join("changed.txt"))?, b"updated");
+    assert!(!dest_dir.join("new.txt").exists());
+
+    // Cleanup
+    fs::remove_dir_all(source_dir)?;
+    fs::remove_dir_all(dest_dir)?;
+    fs::remove_file(db_path)?;
+    let _ = fs::remove_file(format!("{}.lock", db_path));
+    fs::remove_file(log_path)?;
+
+    Ok(())
+}
+
+#[cfg(unix)]
+#[test]
+fn test_read_only_destination() -> Result<()> {
+    use std::os::unix::fs::PermissionsExt;
+
+    let source_dir = PathBuf::from("test_rodest_source");
+    let dest_dir = PathBuf::from("test_rodest_dest");
+    let db_path = "test_rodest.db";
+    let log_path = "test_rodest.log";
+
+    // Cleanup
+    let _ = fs::remove_dir_all(&source_dir);
+    if dest_dir.exists() {
+        fs::set_permissions(&dest_dir, fs::Permissions::from_mode(0o755))?;
+        fs::remove_dir_all(&dest_dir)?;
+    }
+    let _ = fs::remove_file(db_path);
+    let _ = fs::remove_file(log_path);
+
+    fs::create_dir_all(&source_dir)?;
+    fs::write(source_dir.join("file.txt"), b"data")?;
+    fs::create_dir_all(&dest_dir)?;
+    fs::set_permissions(&dest_dir, fs::Permissions::from_mode(0o555))?;
+
+    // Privileged users bypass directory permissions; nothing to check then
+    let writable = fs::write(dest_dir.join("probe"), b"").is_ok();
+    let result = if writable {
+        None
+    } else {
+        Some(run(test_args(&source_dir, &dest_dir, db_path, log_path)))
+    };
+
+    // Cleanup
+    fs::set_permissions(&dest_dir, fs::Permissions::from_mode(0o755))?;
+    fs::remove_dir_all(source_dir)?;
+    fs::remove_dir_all(dest_dir)?;
+    let _ = fs::remove_file(db_path);
+    let _ = fs::remove_file(log_path);
+
+    if let Some(result) = result {
+        let err = result.expect_err("read-only destination must fail");
+        assert!(err.to_string().contains("Destination is not writable"));
+    }
+    Ok(())
+}
+
+#[test]
+fn test_numeric_progress_output() -> Result<()> {
+    let source_dir = PathBuf::from("test_numprog_source");
+    let dest_dir = PathBuf::from("test_numprog_dest");
+    let db_path = "test_numprog.db";
+    let log_path = "test_numprog.log";
+
+    // Cleanup
+    let _ = fs::remove_dir_all(&source_dir);
+    let _ = fs::remove_dir_all(&dest_dir);
+    let _ = fs::remove_file(db_path);
+    let _ = fs::remove_file(log_path);
+
+    fs::create_dir_all(&source_dir)?;
+    fs::write(source_dir.join("a.bin"), vec![1u8; 16 * 1024])?;
+    fs::write(source_dir.join("b.bin"), vec![2u8; 16 * 1024])?;
+
+    // An interval of 0 reports after every block
+    let output = std::process::Command::new(env!("CARGO_BIN_EXE_hsync"))
+        .args([
+            "--source",
+            "test_numprog_source",
+            "--dest",
+            "test_numprog_dest",
+        ])
+        .args(["--db", db_path, "--log", log_path])
+        .args(["--block-size", "4K", "--numeric-progress", "0"])
+        .output()?;
+    assert!(output.status.success());
+
+    let stdout = String::from_utf8(output.stdout)?;
+    let percents: Vec<u32> = stdout
+        .lines()
+        .filter_map(|line| line.strip_prefix("Progress: "))
+        .map(|rest| rest.split('%').next().unwrap().parse().unwrap())
+        .collect();
+    assert!(percents.len() >= 8, "{}", stdout);
+    assert!(percents.windows(2).all(|w| w[0] <= w[1]));
+    assert!(percents.first() < percents.last());
+    assert_eq!(percents.last(), Some(&100));
+
+    // Cleanup
+    fs::remove_dir_all(source_dir)?;
+    fs::remove_dir_all(dest_dir)?;
+    fs::remove_file(db_path)?;
+    let _ = fs::remove_file(format!("{}.lock", db_path));
+    fs::remove_file(log_path)?;
+
+    Ok(())
+}
+
+#[test]
+fn test_plain_output_off_terminal() -> Result<()> {
+    let source_dir = PathBuf::from("test_plain_output_source");
+    let dest_dir = PathBuf::from("test_plain_output_dest");
+    let db_path = "test_plain_output.db";
+    let log_path = "test_plain_output.log";
+
+    // Cleanup
+    let _ = fs::remove_dir_all(&source_dir);
+    let _ = fs::remove_dir_all(&dest_dir);
+    let _ = fs::remove_file(db_path);
+    let _ = fs::remove_file(log_path);
+
+    fs::create_dir_all(&source_dir)?;
+    fs::write(source_dir.join("a.bin"), vec![1u8; 16 * 1024])?;
+
+    // Captured output is a pipe, not a terminal
+    let output = std::process::Command::new(env!("CARGO_BIN_EXE_hsync"))
+        .args([
+            "--source",
+            "test_plain_output_source",
+            "--dest",
+            "test_plain_output_dest",
+        ])
+        .args(["--db", db_path, "--log", log_path])
+        .output()?;
+    assert!(output.status.success());
+
+    let stdout = String::from_utf8(output.stdout)?;
+    let stderr = String::from_utf8(output.stderr)?;
+    assert!(!stdout.contains('\x1b') && !stderr.contains('\x1b'));
+    assert!(stdout.contains("Source: 1 files found"), "{}", stdout);
+    assert!(
+        stdout.contains("Scan complete: 1 source files"),
+        "{}",
+        stdout
+    );
+    assert!(stdout.contains("Progress: 100% (1/1 files"), "{}", stdout);
+
+    // Cleanup
+    fs::remove_dir_all(source_dir)?;
+    fs::remove_dir_all(dest_dir)?;
+    fs::remove_file(db_path)?;
+    let _ = fs::remove_file(format!("{}.lock", db_path));
+    fs::remove_file(log_path)?;
+
+    Ok(())
+}
+
+#[test]
+fn test_stats_interval_snapshots() -> Result<()> {
+    let source_dir = PathBuf::from("test_stats_interval_source");
+    let dest_dir = PathBuf::from("test_stats_interval_dest");
+    let db_path = "test_stats_interval.db";
+    let log_path = "test_stats_interval.log";
+
+    // Cleanup
+    let _ = fs::remove_dir_all(&source_dir);
+    let _ = fs::remove_dir_all(&dest_dir);
+    let _ = fs::remove_file(db_path);
+    let _ = fs::remove_file(log_path);
+
+    fs::create_dir_all(&source_dir)?;
+    fs::write(source_dir.join("a.bin"), vec![1u8; 16 * 1024])?;
+    fs::write(source_dir.join("b.bin"), vec![2u8; 16 * 1024])?;
+
+    // Snapshots need a positive interval
+    let err = run(Args {
+        stats_interval: Some(0),
+        ..test_args(&source_dir, &dest_dir, db_path, log_path)
+    })
+    .unwrap_err();
+    assert!(err
+        .to_string()
+        .contains("--stats-interval must be positive"));
+
+    // A transfer of a few seconds, the reader held back by a short queue, gets a
+    // snapshot each second, then the final one
+    run(Args {
+        stats_interval: Some(1),
+        block_size: "4K".to_string(),
+        bwlimit: Some("12K".to_string()),
+        queue_capacity: 1,
+        ..test_args(&source_dir, &dest_dir, db_path, log_path)
+    })?;
+
+    let log = fs::read_to_string(log_path)?;
+    let snapshots: Vec<&str> = log
+        .lines()
+        .filter_map(|line| line.split_once("Stats: ").map(|(_, stats)| stats))
+        .collect();
+    assert!(snapshots.len() >= 2, "{}", log);
+    assert!(snapshots[0].contains("files_total=2"), "{}", log);
+    assert!(!snapshots[0].contains("bytes_done=32768"), "{}", log);
+    let bytes_done: Vec<u64> = snapshots
+        .iter()
+        .map(|stats| {
+            let field = stats
+                .split(' ')
+                .find_map(|field| field.strip_prefix("bytes_done="))
+                .unwrap();
+            field.parse().unwrap()
+        })
+        .collect();
+    assert!(bytes_done.windows(2).all(|w| w[0] <= w[1]));
+    assert_eq!(bytes_done.last(), Some(&(32 * 1024)));
+    assert!(snapshots
+        .last()
+        .unwrap()
+        .contains("files_done=2 files_total=2"));
+
+    // Cleanup
+    fs::remove_dir_all(source_dir)?;
+    fs::remove_dir_all(dest_dir)?;
+    fs::remove_file(db_path)?;
+    let _ = fs::remove_file(format!("{}.lock", db_path));
     fs::remove_file(log_path)?;
 
     Ok(())
 }
+
+#[test]
+fn test_done_file() -> Result<()> {
+    let source_dir = PathBuf::from("test_donefile_source");
+    let dest_dir = PathBuf::from("test_donefile_dest");
+    let db_path = "test_donefile.db";
+    let log_path = "test_donefile.log";
+    let done_path = PathBuf::from("test_donefile.done");
+    let blocker = PathBuf::from("test_donefile_blocker");
+
+    // Cleanup
+    let _ = fs::remove_dir_all(&source_dir);
+    let _ = fs::remove_dir_all(&dest_dir);
+    let _ = fs::remove_file(db_path);
+    let _ = fs::remove_file(log_path);
+    let _ = fs::remove_file(&done_path);
+    let _ = fs::remove_file(&blocker);
+
+    fs::create_dir_all(&source_dir)?;
+    fs::write(source_dir.join("a.txt"), b"A")?;
+    fs::write(source_dir.join("b.txt"), b"B")?;
+
+    let args = Args {
+        done_file: Some(done_path.clone()),
+        ..test_args(&source_dir, &dest_dir, db_path, log_path)
+    };
+    run(args.clone())?;
+
+    let content = fs::read_to_string(&done_path)?;
+    assert!(content.contains("files_synced=2\n"));
+    assert!(content.contains("files_pending=0\n"));
+    assert!(content.contains("blocks_transferred=2\n"));
+
+    // A failing run (destination below a regular file) removes the stale marker
+    fs::write(&blocker, b"")?;
+    let failing = Args {
+        dest: vec![blocker.join("dest")],
+        ..args
+    };
+    assert!(run(failing).is_err());
+    assert!(!done_path.exists());
+
+    // Cleanup
+    fs::remove_dir_all(source_dir)?;
+    fs::remove_dir_all(dest_dir)?;
+    fs::remove_file(db_path)?;
+    let _ = fs::remove_file(format!("{}.lock", db_path));
+    fs::remove_file(log_path)?;
+    fs::remove_file(blocker)?;
+
+    Ok(())
+}
+
+#[test]
+fn test_metrics_file() -> Result<()> {
+    let source_dir = PathBuf::from("test_metrics_source");
+    let dest_dir = PathBuf::from("test_metrics_dest");
+    let db_path = "test_metrics.db";
+    let log_path = "test_metrics.log";
+    let metrics_path = PathBuf::from("test_metrics.prom");
+
+    // Cleanup
+    let _ = fs::remove_dir_all(&source_dir);
+    let _ = fs::remove_dir_all(&dest_dir);
+    let _ = fs::remove_file(db_path);
+    let _ = fs::remove_file(log_path);
+    let _ = fs::remove_file(&metrics_path);
+
+    fs::create_dir_all(&source_dir)?;
+    fs::write(source_dir.join("a.txt"), b"hello")?;
+    fs::write(source_dir.join("b.txt"), b"world!")?;
+
+    run(Args {
+        metrics_file: Some(metrics_path.clone()),
+        ..test_args(&source_dir, &dest_dir, db_path, log_path)
+    })?;
+
+    let metrics = fs::read_to_string(&metrics_path)?;
+    let value = |name: &str| -> f64 {
+        let prefix = format!("{} ", name);
+        let line = metrics
+            .lines()
+            .find(|line| line.starts_with(&prefix))
+            .unwrap();
+        line[prefix.len()..].parse().unwrap()
+    };
+    assert!(metrics.contains("# TYPE hsync_files_transferred_total counter\n"));
+    assert!(metrics.contains("# TYPE hsync_pending_files gauge\n"));
+    assert_eq!(value("hsync_files_transferred_total"), 2.0);
+    assert_eq!(value("hsync_bytes_transferred_total"), 11.0);
+    assert_eq!(value("hsync_files_failed_total"), 0.0);
+    assert_eq!(value("hsync_pending_files"), 0.0);
+    assert!(value("hsync_run_duration_seconds") > 0.0);
+    assert!(!PathBuf::from("test_metrics.prom.tmp").exists());
+
+    // Cleanup
+    fs::remove_dir_all(source_dir)?;
+    fs::remove_dir_all(dest_dir)?;
+    fs::remove_file(db_path)?;
+    let _ = fs::remove_file(format!("{}.lock", db_path));
+    fs::remove_file(log_path)?;
+    fs::remove_file(metrics_path)?;
+
+    Ok(())
+}
+
+#[test]
+fn test_multiple_destinations() -> Result<()> {
+    let source_dir = PathBuf::from("test_fanout_source");
+    let dest_dirs = [
+        PathBuf::from("test_fanout_dest1"),
+        PathBuf::from("test_fanout_dest2"),
+    ];
+    let db_path = "test_fanout.db";
+    let log_path = "test_fanout.log";
+
+    // Cleanup
+    let _ = fs::remove_dir_all(&source_dir);
+    for dir in &dest_dirs {
+        let _ = fs::remove_dir_all(dir);
+    }
+    let _ = fs::remove_file(db_path);
+    let _ = fs::remove_file(log_path);
+
+    fs::create_dir_all(source_dir.join("sub"))?;
+    let content: Vec<u8> = (0..10_000u32).map(|i| (i % 251) as u8).collect();
+    fs::write(source_dir.join("sub/data.bin"), &content)?;
+
+    let args = Args {
+        dest: dest_dirs.to_vec(),
+        block_size: "4K".to_string(),
+        ..test_args(&source_dir, &dest_dirs[0], db_path, log_path)
+    };
+    let stats = run_with_stats(args.clone())?;
+
+    for dir in &dest_dirs {
+        assert_eq!(fs::read(dir.join("sub/data.bin"))?, content);
+    }
+    // Each block was read once and written to both destinations
+    assert_eq!(stats.blocks, 3);
+
+    // A mirror missing a file makes it pending again
+    fs::remove_file(dest_dirs[1].join("sub/data.bin"))?;
+    run(args.clone())?;
+    assert_eq!(fs::read(dest_dirs[1].join("sub/data.bin"))?, content);
+
+    // Each destination has its own status: a new file the mirror already holds
+    // (same size and mtime) is only written to the primary destination
+    let new_file = source_dir.join("sub/new.bin");
+    fs::write(&new_file, b"fresh data")?;
+    fs::write(dest_dirs[1].join("sub/new.bin"), b"mirror own")?;
+    let mtime = FileTime::from_last_modification_time(&fs::metadata(&new_file)?);
+    filetime::set_file_mtime(dest_dirs[1].join("sub/new.bin"), mtime)?;
+    run(args)?;
+    assert_eq!(fs::read(dest_dirs[0].join("sub/new.bin"))?, b"fresh data");
+    assert_eq!(fs::read(dest_dirs[1].join("sub/new.bin"))?, b"mirror own");
+    let db = hsync::db::Database::new(db_path)?;
+    for dir in &dest_dirs {
+        assert_eq!(db.pending_count_for_dest(dir.to_str().unwrap())?, 0);
+        assert!(db.dest_synced(new_file.to_str().unwrap(), dir.to_str().unwrap())?);
+    }
+    drop(db);
+
+    // Cleanup
+    fs::remove_dir_all(source_dir)?;
+    for dir in &dest_dirs {
+        fs::remove_dir_all(dir)?;
+    }
+    fs::remove_file(db_path)?;
+    let _ = fs::remove_file(format!("{}.lock", db_path));
+    fs::remove_file(log_path)?;
+
+    Ok(())
+}
+
+#[test]
+fn test_no_hash_on_copy() -> Result<()> {
+    let source_dir = PathBuf::from("test_nohash_source");
+    let dest_dir = PathBuf::from("test_nohash_dest");
+    let db_path = "test_nohash.db";
+    let log_path = "test_nohash.log";
+
+    // Cleanup
+    let _ = fs::remove_dir_all(&source_dir);
+    let _ = fs::remove_dir_all(&dest_dir);
+    let _ = fs::remove_file(db_path);
+    let _ = fs::remove_file(log_path);
+
+    fs::create_dir_all(&source_dir)?;
+    fs::create_dir_all(&dest_dir)?;
+    fs::write(source_dir.join("missing.txt"), b"copy me")?;
+    fs::write(source_dir.join("changed.txt"), b"new content")?;
+    fs::write(dest_dir.join("changed.txt"), b"old")?;
+
+    let args = Args {
+        no_hash_on_copy: true,
+        ..test_args(&source_dir, &dest_dir, db_path, log_path)
+    };
+    run(args)?;
+
+    assert_eq!(fs::read(dest_dir.join("missing.txt"))?, b"copy me");
+    assert_eq!(fs::read(dest_dir.join("changed.txt"))?, b"new content");
+    let synced = hsync::db::Database::new(db_path)?.get_synced_files()?;
+    let hash_of = |name: &str| {
+        synced
+            .iter()
+            .find(|f| f.source_path.ends_with(name))
+            .unwrap()
+            .hash
+            .clone()
+    };
+    // Only the content change is hashed
+    assert_eq!(hash_of("missing.txt"), None);
+    assert!(hash_of("changed.txt").is_some());
+
+    // Cleanup
+    fs::remove_dir_all(source_dir)?;
+    fs::remove_dir_all(dest_dir)?;
+    fs::remove_file(db_path)?;
+    let _ = fs::remove_file(format!("{}.lock", db_path));
+    fs::remove_file(log_path)?;
+
+    Ok(())
+}
+
+#[test]
+fn test_failed_attempts_counted() -> Result<()> {
+    let source_dir = PathBuf::from("test_attempts_source");
+    let dest_dir = PathBuf::from("test_attempts_dest");
+    let db_path = "test_attempts.db";
+    let log_path = "test_attempts.log";
+
+    // Cleanup
+    let _ = fs::remove_dir_all(&source_dir);
+    let _ = fs::remove_dir_all(&dest_dir);
+    let _ = fs::remove_file(db_path);
+    let _ = fs::remove_file(log_path);
+
+    fs::create_dir_all(&source_dir)?;
+    fs::write(source_dir.join("blocked.txt"), b"content")?;
+    // A directory in the way makes every write of the file fail
+    fs::create_dir_all(dest_dir.join("blocked.txt"))?;
+
+    let args = Args {
+        retry_attempts: 2,
+        retry_interval_seconds: 0,
+        ..test_args(&source_dir, &dest_dir, db_path, log_path)
+    };
+    assert!(run(args).is_err());
+
+    let pending = hsync::db::Database::new(db_path)?.get_pending_files()?;
+    assert_eq!(pending.len(), 1);
+    assert_eq!(pending[0].attempts, 2);
+    // The second attempt names the file that failed the first
+    let log = fs::read_to_string(log_path)?;
+    assert!(log.contains("blocked.txt (failed attempts: 1)"));
+
+    // Cleanup
+    fs::remove_dir_all(source_dir)?;
+    fs::remove_dir_all(dest_dir)?;
+    fs::remove_file(db_path)?;
+    let _ = fs::remove_file(format!("{}.lock", db_path));
+    fs::remove_file(log_path)?;
+
+    Ok(())
+}
+
+#[test]
+fn test_append_transfers_tail() -> Result<()> {
+    let source_dir = PathBuf::from("test_append_source");
+    let dest_dir = PathBuf::from("test_append_dest");
+    let db_path = "test_append.db";
+    let log_path = "test_append.log";
+
+    // Cleanup
+    let _ = fs::remove_dir_all(&source_dir);
+    let _ = fs::remove_dir_all(&dest_dir);
+    let _ = fs::remove_file(db_path);
+    let _ = fs::remove_file(log_path);
+
+    fs::create_dir_all(&source_dir)?;
+    let prefix: Vec<u8> = (0..40_000u32).map(|i| i as u8).collect();
+    fs::write(source_dir.join("app.log"), &prefix)?;
+    fs::write(source_dir.join("rewritten.log"), &prefix)?;
+
+    let args = Args {
+        append: true,
+        block_size: "4K".to_string(),
+        ..test_args(&source_dir, &dest_dir, db_path, log_path)
+    };
+    run(args.clone())?;
+
+    // Grow one log; rewrite the other so its old bytes no longer match
+    let mut log = fs::OpenOptions::new()
+        .append(true)
+        .open(source_dir.join("app.log"))?;
+    log.write_all(b"new line\n")?;
+    drop(log);
+    let mut rewritten = vec![0xffu8; 4096];
+    rewritten.extend_from_slice(&prefix);
+    fs::write(source_dir.join("rewritten.log"), &rewritten)?;
+
+    let stats = run_with_stats(Args {
+        rescan: true,
+        ..args
+    })?;
+
+    // One block for the appended tail, the rewritten file in full
+    assert_eq!(stats.blocks, 1 + rewritten.len().div_ceil(4096) as u64);
+    let mut expected = prefix.clone();
+    expected.extend_from_slice(b"new line\n");
+    assert_eq!(fs::read(dest_dir.join("app.log"))?, expected);
+    assert_eq!(fs::read(dest_dir.join("rewritten.log"))?, rewritten);
+    // The stored hash covers the whole file, kept prefix included
+    run(Args {
+        command: Some(hsync::Command::Verify {
+            changed_only: false,
+            manifest: None,
+        }),
+        ..test_args(&source_dir, &dest_dir, db_path, log_path)
+    })?;
+
+    // Cleanup
+    fs::remove_dir_all(source_dir)?;
+    fs::remove_dir_all(dest_dir)?;
+    fs::remove_file(db_path)?;
+    let _ = fs::remove_file(format!("{}.lock", db_path));
+    fs::remove_file(log_path)?;
+
+    Ok(())
+}
+
+#[test]
+fn test_whole_file_resends_changed_file() -> Result<()> {
+    let source_dir = PathBuf::from("test_whole_file_source");
+    let dest_dir = PathBuf::from("test_whole_file_dest");
+    let db_path = "test_whole_file.db";
+    let log_path = "test_whole_file.log";
+
+    // Cleanup
+    let _ = fs::remove_dir_all(&source_dir);
+    let _ = fs::remove_dir_all(&dest_dir);
+    let _ = fs::remove_file(db_path);
+    let _ = fs::remove_file(log_path);
+
+    fs::create_dir_all(&source_dir)?;
+    let mut content: Vec<u8> = (0..40_000u32).map(|i| i as u8).collect();
+    fs::write(source_dir.join("big.log"), &content)?;
+
+    let args = Args {
+        append: true,
+        only_write_changed: true,
+        whole_file: true,
+        block_size: "4K".to_string(),
+        ..test_args(&source_dir, &dest_dir, db_path, log_path)
+    };
+    run(args.clone())?;
+
+    // Grown past an identical prefix: --append alone would send only the tail
+    content.extend_from_slice(b"new line\n");
+    fs::write(source_dir.join("big.log"), &content)?;
+
+    let stats = run_with_stats(Args {
+        rescan: true,
+        ..args
+    })?;
+
+    // Every block sent and written again
+    assert_eq!(stats.blocks, content.len().div_ceil(4096) as u64);
+    assert_eq!(stats.unchanged_blocks, 0);
+    assert_eq!(fs::read(dest_dir.join("big.log"))?, content);
+
+    // Cleanup
+    fs::remove_dir_all(source_dir)?;
+    fs::remove_dir_all(dest_dir)?;
+    fs::remove_file(db_path)?;
+    let _ = fs::remove_file(format!("{}.lock", db_path));
+    fs::remove_file(log_path)?;
+
+    Ok(())
+}
+
+#[test]
+fn test_encrypt_round_trip() -> Result<()> {
+    let source_dir = PathBuf::from("test_encrypt_source");
+    let dest_dir = PathBuf::from("test_encrypt_dest");
+    let restore_dir = PathBuf::from("test_encrypt_restore");
+    let db_path = "test_encrypt.db";
+    let log_path = "test_encrypt.log";
+    let password_file = PathBuf::from("test_encrypt.pass");
+
+    // Cleanup
+    let _ = fs::remove_dir_all(&source_dir);
+    let _ = fs::remove_dir_all(&dest_dir);
+    let _ = fs::remove_dir_all(&restore_dir);
+    let _ = fs::remove_file(db_path);
+    let _ = fs::remove_file(log_path);
+
+    fs::create_dir_all(source_dir.join("sub"))?;
+    let data: Vec<u8> = (0..10_000u32).map(|i| (i % 251) as u8).collect();
+    fs::write(source_dir.join("data.bin"), &data)?;
+    fs::write(source_dir.join("sub/empty.txt"), b"")?;
+    fs::write(&password_file, "correct horse battery staple\n")?;
+
+    let args = Args {
+        encrypt: true,
+        password_file: Some(password_file.clone()),
+        block_size: "4K".to_string(),
+        ..test_args(&source_dir, &dest_dir, db_path, log_path)
+    };
+    run(args.clone())?;
+
+    // Stored encrypted: larger by header and tags, plaintext nowhere to be seen
+    let at_rest = fs::read(dest_dir.join("data.bin"))?;
+    assert_eq!(
+        at_rest.len() as u64,
+        hsync::crypto::encrypted_len(data.len() as u64, 4096)
+    );
+    assert!(!at_rest
+        .windows(32)
+        .any(|w| data.windows(32).any(|p| p == w)));
+    let db = hsync::db::Database::new(db_path)?;
+    for file in db.get_synced_files()? {
+        let (nonce, encrypted_size) = db.get_encryption(&file.source_path)?.unwrap();
+        assert_eq!(nonce.len(), 14);
+        assert_eq!(
+            encrypted_size,
+            hsync::crypto::encrypted_len(file.size, 4096)
+        );
+    }
+    drop(db);
+
+    // Encrypted copies count as up to date
+    let stats = run_with_stats(Args {
+        rescan: true,
+        ..args.clone()
+    })?;
+    assert_eq!(stats.blocks, 0);
+
+    run(Args {
+        command: Some(hsync::Command::Decrypt {
+            input: dest_dir.clone(),
+            output: restore_dir.clone(),
+        }),
+        ..args
+    })?;
+    assert_eq!(fs::read(restore_dir.join("data.bin"))?, data);
+    assert_eq!(fs::read(restore_dir.join("sub/empty.txt"))?, b"");
+
+    // Cleanup
+    fs::remove_dir_all(source_dir)?;
+    fs::remove_dir_all(dest_dir)?;
+    fs::remove_dir_all(restore_dir)?;
+    fs::remove_file(db_path)?;
+    let _ = fs::remove_file(format!("{}.lock", db_path));
+    fs::remove_file(log_path)?;
+    fs::remove_file(password_file)?;
+
+    Ok(())
+}
+
+#[test]
+fn test_reverse_restores_from_destination() -> Result<()> {
+    let source_dir = PathBuf::from("test_reverse_source");
+    let dest_dir = PathBuf::from("test_reverse_dest");
+    let db_path = "test_reverse.db";
+    let log_path = "test_reverse.log";
+
+    // Cleanup
+    let _ = fs::remove_dir_all(&source_dir);
+    let _ = fs::remove_dir_all(&dest_dir);
+    let _ = fs::remove_file(db_path);
+    let _ = fs::remove_file(log_path);
+
+    fs::create_dir_all(source_dir.join("sub"))?;
+    fs::write(source_dir.join("sub/lost.txt"), b"backed up")?;
+    fs::write(source_dir.join("edited.txt"), b"old")?;
+    run(test_args(&source_dir, &dest_dir, db_path, log_path))?;
+
+    // Disaster strikes one file; another is edited after the backup
+    fs::remove_file(source_dir.join("sub/lost.txt"))?;
+    fs::write(source_dir.join("edited.txt"), b"newer")?;
+    let later = FileTime::from_unix_time(FileTime::now().unix_seconds() + 60, 0);
+    filetime::set_file_mtime(source_dir.join("edited.txt"), later)?;
+
+    run(Args {
+        reverse: true,
+        ..test_args(&source_dir, &dest_dir, db_path, log_path)
+    })?;
+
+    assert_eq!(fs::read(source_dir.join("sub/lost.txt"))?, b"backed up");
+    assert_eq!(fs::read(source_dir.join("edited.txt"))?, b"newer");
+    assert_eq!(fs::read(dest_dir.join("edited.txt"))?, b"old");
+
+    // Deleting is never part of a restore
+    let error = run(Args {
+        reverse: true,
+        delete_extras: true,
+        ..test_args(&source_dir, &dest_dir, db_path, log_path)
+    })
+    .unwrap_err();
+    assert!(error.to_string().contains("--delete-extras"));
+
+    // Cleanup
+    fs::remove_dir_all(source_dir)?;
+    fs::remove_dir_all(dest_dir)?;
+    fs::remove_file(db_path)?;
+    let _ = fs::remove_file(format!("{}.lock", db_path));
+    fs::remove_file(log_path)?;
+
+    Ok(())
+}
+
+#[test]
+fn test_list_failures() -> Result<()> {
+    let source_dir = PathBuf::from("test_listfail_source");
+    let dest_dir = PathBuf::from("test_listfail_dest");
+    let db_path = "test_listfail.db";
+    let log_path = "test_listfail.log";
+
+    // Cleanup
+    let _ = fs::remove_dir_all(&source_dir);
+    let _ = fs::remove_dir_all(&dest_dir);
+    let _ = fs::remove_file(db_path);
+    let _ = fs::remove_file(log_path);
+
+    fs::create_dir_all(&source_dir)?;
+    fs::write(source_dir.join("ok.txt"), b"fine")?;
+    let args = Args {
+        retry_attempts: 2,
+        retry_interval_seconds: 0,
+        ..test_args(&source_dir, &dest_dir, db_path, log_path)
+    };
+    run(args.clone())?;
+
+    fs::write(source_dir.join("blocked.txt"), b"content")?;
+    // A directory in the way makes every write of the file fail
+    fs::create_dir_all(dest_dir.join("blocked.txt"))?;
+    assert!(run(args).is_err());
+
+    let output = std::process::Command::new(env!("CARGO_BIN_EXE_hsync"))
+        .args(["--db", db_path, "list-failures"])
+        .output()?;
+    assert!(output.status.success());
+
+    let stdout = String::from_utf8(output.stdout)?;
+    let pending: Vec<&str> = stdout
+        .lines()
+        .filter(|line| line.starts_with("Pending: "))
+        .collect();
+    assert_eq!(pending.len(), 1, "{}", stdout);
+    assert!(pending[0].ends_with("blocked.txt (7 B, reason: dest_missing, failed attempts: 2)"));
+    assert!(stdout.contains("1 files not synced (7 B)."));
+
+    // Cleanup
+    fs::remove_dir_all(source_dir)?;
+    fs::remove_dir_all(dest_dir)?;
+    fs::remove_file(db_path)?;
+    let _ = fs::remove_file(format!("{}.lock", db_path));
+    fs::remove_file(log_path)?;
+
+    Ok(())
+}
+
+#[test]
+#[cfg(unix)]
+fn test_perms_matter() -> Result<()> {
+    use std::os::unix::fs::PermissionsExt;
+
+    let source_dir = PathBuf::from("test_perms_source");
+    let dest_dir = PathBuf::from("test_perms_dest");
+    let db_path = "test_perms.db";
+    let log_path = "test_perms.log";
+
+    // Cleanup
+    let _ = fs::remove_dir_all(&source_dir);
+    let _ = fs::remove_dir_all(&dest_dir);
+    let _ = fs::remove_file(db_path);
+    let _ = fs::remove_file(log_path);
+
+    fs::create_dir_all(&source_dir)?;
+    let source_file = source_dir.join("script.sh");
+    fs::write(&source_file, b"echo hi")?;
+    fs::set_permissions(&source_file, fs::Permissions::from_mode(0o640))?;
+    let dest_mode = || -> Result<u32> {
+        Ok(fs::metadata(dest_dir.join("script.sh"))?
+            .permissions()
+            .mode()
+            & 0o7777)
+    };
+
+    let args = Args {
+        perms_matter: true,
+        ..test_args(&source_dir, &dest_dir, db_path, log_path)
+    };
+    run(args.clone())?;
+    assert_eq!(dest_mode()?, 0o640);
+
+    // Only the mode changes: same content, size and mtime
+    fs::set_permissions(&source_file, fs::Permissions::from_mode(0o750))?;
+    run(args)?;
+    assert_eq!(dest_mode()?, 0o750);
+
+    // Cleanup
+    fs::remove_dir_all(source_dir)?;
+    fs::remove_dir_all(dest_dir)?;
+    fs::remove_file(db_path)?;
+    let _ = fs::remove_file(format!("{}.lock", db_path));
+    fs::remove_file(log_path)?;
+
+    Ok(())
+}
+
+#[test]
+fn test_algorithms_command() -> Result<()> {
+    let output = std::process::Command::new(env!("CARGO_BIN_EXE_hsync"))
+        .arg("algorithms")
+        .output()?;
+    assert!(output.status.success());
+
+    let stdout = String::from_utf8(output.stdout)?;
+    let names: Vec<&str> = stdout
+        .lines()
+        .filter_map(|line| line.split('\t').next())
+        .collect();
+    for algo in ["md5", "sha1", "sha256", "blake2b", "blake3"] {
+        assert!(names.contains(&algo), "{}", stdout);
+    }
+    assert!(stdout.contains("sha256\t256 bits (64 hex digits)"));
+
+    // Unknown algorithms are rejected with the valid choices
+    let error = Args::try_parse_from(["hsync", "--checksum", "sha512"]).unwrap_err();
+    assert!(error
+        .to_string()
+        .contains("[possible values: md5, sha1, sha256, blake2b, blake3]"));
+    Ok(())
+}
+
+#[test]
+fn test_checksum_override_per_file_algorithm() -> Result<()> {
+    let source_dir = PathBuf::from("test_checksum_override_source");
+    let dest_dir = PathBuf::from("test_checksum_override_dest");
+    let db_path = "test_checksum_override.db";
+    let log_path = "test_checksum_override.log";
+
+    // Cleanup
+    let _ = fs::remove_dir_all(&source_dir);
+    let _ = fs::remove_dir_all(&dest_dir);
+    let _ = fs::remove_file(db_path);
+    let _ = fs::remove_file(log_path);
+
+    fs::create_dir_all(source_dir.join("critical"))?;
+    fs::write(source_dir.join("critical/keys.bin"), "critical")?;
+    fs::write(source_dir.join("notes.txt"), "ordinary")?;
+
+    let args = Args {
+        checksum_override: vec!["critical/*.bin=md5".to_string()],
+        ..test_args(&source_dir, &dest_dir, db_path, log_path)
+    };
+    run(args.clone())?;
+
+    let db = hsync::db::Database::new(db_path)?;
+    for record in db.get_synced_files()? {
+        let hash = record.hash.as_deref().unwrap_or_default();
+        if record.source_path.ends_with("keys.bin") {
+            assert_eq!(record.hash_algo.as_deref(), Some("md5"));
+            assert_eq!(hash.len(), 32);
+        } else {
+            assert_eq!(record.hash_algo.as_deref(), Some("sha256"));
+            assert_eq!(hash.len(), 64);
+        }
+    }
+    drop(db);
+
+    // Verification re-hashes each file with its recorded algorithm
+    run(Args {
+        command: Some(hsync::Command::Verify {
+            changed_only: false,
+            manifest: None,
+        }),
+        ..args
+    })?;
+
+    // Cleanup
+    fs::remove_dir_all(source_dir)?;
+    fs::remove_dir_all(dest_dir)?;
+    fs::remove_file(db_path)?;
+    let _ = fs::remove_file(format!("{}.lock", db_path));
+    fs::remove_file(log_path)?;
+
+    Ok(())
+}
+
+#[test]
+fn test_error_log_receives_only_errors() -> Result<()> {
+    let source_dir = PathBuf::from("test_error_log_source");
+    let dest_dir = PathBuf::from("test_error_log_dest");
+    let db_path = "test_error_log.db";
+    let log_path = "test_error_log.log";
+    let error_log_path = "test_error_log.errors.log";
+
+    // Cleanup
+    let _ = fs::remove_dir_all(&source_dir);
+    let _ = fs::remove_dir_all(&dest_dir);
+    let _ = fs::remove_file(db_path);
+    let _ = fs::remove_file(log_path);
+    let _ = fs::remove_file(error_log_path);
+
+    fs::create_dir_all(&source_dir)?;
+    fs::write(source_dir.join("ok.txt"), b"fine")?;
+    let args = Args {
+        retry_attempts: 1,
+        retry_interval_seconds: 0,
+        error_log: Some(error_log_path.to_string()),
+        ..test_args(&source_dir, &dest_dir, db_path, log_path)
+    };
+    run(args.clone())?;
+
+    fs::write(source_dir.join("blocked.txt"), b"content")?;
+    // A directory in the way makes every write of the file fail
+    fs::create_dir_all(dest_dir.join("blocked.txt"))?;
+    assert!(run(args).is_err());
+
+    let main_log = fs::read_to_string(log_path)?;
+    let error_log = fs::read_to_string(error_log_path)?;
+    assert!(main_log.contains("Transferred: "), "{}", main_log);
+    assert!(main_log.contains("Transfer failed after 1 attempts"));
+    assert!(error_log.contains("Transfer failed after 1 attempts"));
+    assert!(!error_log.contains("Transferred: "), "{}", error_log);
+
+    // Cleanup
+    fs::remove_dir_all(source_dir)?;
+    fs::remove_dir_all(dest_dir)?;
+    fs::remove_file(db_path)?;
+    let _ = fs::remove_file(format!("{}.lock", db_path));
+    fs::remove_file(log_path)?;
+    fs::remove_file(error_log_path)?;
+
+    Ok(())
+}
+
+#[test]
+fn test_max_open_files() -> Result<()> {
+    let source_dir = PathBuf::from("test_max_open_files_source");
+    let dest_dir = PathBuf::from("test_max_open_files_dest");
+    let mirror_dir = PathBuf::from("test_max_open_files_mirror");
+    let db_path = "test_max_open_files.db";
+    let log_path = "test_max_open_files.log";
+
+    // Cleanup
+    let _ = fs::remove_dir_all(&source_dir);
+    let _ = fs::remove_dir_all(&dest_dir);
+    let _ = fs::remove_dir_all(&mirror_dir);
+    let _ = fs::remove_file(db_path);
+    let _ = fs::remove_file(log_path);
+
+    fs::create_dir_all(&source_dir)?;
+    for i in 0..40 {
+        fs::write(source_dir.join(format!("{}.bin", i)), vec![i as u8; 3000])?;
+    }
+
+    // A single slot shared by the destination and its mirror
+    run(Args {
+        dest: vec![dest_dir.clone(), mirror_dir.clone()],
+        max_open_files: Some(1),
+        block_size: "1K".to_string(),
+        ..test_args(&source_dir, &dest_dir, db_path, log_path)
+    })?;
+
+    for i in 0..40 {
+        let name = format!("{}.bin", i);
+        let expected = vec![i as u8; 3000];
+        assert_eq!(fs::read(dest_dir.join(&name))?, expected);
+        assert_eq!(fs::read(mirror_dir.join(&name))?, expected);
+    }
+
+    // Cleanup
+    fs::remove_dir_all(source_dir)?;
+    fs::remove_dir_all(dest_dir)?;
+    fs::remove_dir_all(mirror_dir)?;
+    fs::remove_file(db_path)?;
+    let _ = fs::remove_file(format!("{}.lock", db_path));
+    fs::remove_file(log_path)?;
+
+    Ok(())
+}
+
+#[test]
+fn test_crash_requeues_file_in_progress() -> Result<()> {
+    let source_dir = PathBuf::from("test_crash_requeue_source");
+    let dest_dir = PathBuf::from("test_crash_requeue_dest");
+    let db_path = "test_crash_requeue.db";
+    let log_path = "test_crash_requeue.log";
+
+    // Cleanup
+    let _ = fs::remove_dir_all(&source_dir);
+    let _ = fs::remove_dir_all(&dest_dir);
+    let _ = fs::remove_file(db_path);
+    let _ = fs::remove_file(log_path);
+
+    fs::create_dir_all(&source_dir)?;
+    let source_file = source_dir.join("large.bin");
+    let dest_file = dest_dir.join("large.bin");
+    fs::write(&source_file, vec![7u8; 5000])?;
+    run(test_args(&source_dir, &dest_dir, db_path, log_path))?;
+
+    // A crash mid-file: the flag was left false with the file still marked in
+    // progress, and its copy has the right size and mtime but not all the content
+    {
+        let db = hsync::db::Database::new(db_path)?;
+        db.set_clean_shutdown(false)?;
+        db.set_current_file(Some(source_file.to_str().unwrap()))?;
+    }
+    let mut partial = vec![7u8; 2000];
+    partial.resize(5000, 0);
+    fs::write(&dest_file, &partial)?;
+    let mtime = FileTime::from_last_modification_time(&fs::metadata(&source_file)?);
+    filetime::set_file_mtime(&dest_file, mtime)?;
+
+    run(test_args(&source_dir, &dest_dir, db_path, log_path))?;
+    assert_eq!(fs::read(&dest_file)?, vec![7u8; 5000]);
+    let db = hsync::db::Database::new(db_path)?;
+    assert!(db.clean_shutdown()?);
+    assert_eq!(db.current_file()?, None);
+    drop(db);
+
+    // Cleanup
+    fs::remove_dir_all(source_dir)?;
+    fs::remove_dir_all(dest_dir)?;
+    fs::remove_file(db_path)?;
+    let _ = fs::remove_file(format!("{}.lock", db_path));
+    fs::remove_file(log_path)?;
+
+    Ok(())
+}
+
+#[test]
+fn test_crash_resumes_encrypted_transfer() -> Result<()> {
+    let source_dir = PathBuf::from("test_crash_resume_source");
+    let dest_dir = PathBuf::from("test_crash_resume_dest");
+    let restore_dir = PathBuf::from("test_crash_resume_restore");
+    let db_path = "test_crash_resume.db";
+    let log_path = "test_crash_resume.log";
+    let password_file = PathBuf::from("test_crash_resume.pass");
+
+    // Cleanup
+    let _ = fs::remove_dir_all(&source_dir);
+    let _ = fs::remove_dir_all(&dest_dir);
+    let _ = fs::remove_dir_all(&restore_dir);
+    let _ = fs::remove_file(db_path);
+    let _ = fs::remove_file(log_path);
+
+    fs::create_dir_all(&source_dir)?;
+    let data: Vec<u8> = (0..256 * 1024u32).map(|i| (i % 251) as u8).collect();
+    fs::write(source_dir.join("data.bin"), &data)?;
+    fs::write(&password_file, "correct horse battery staple\n")?;
+
+    // A slow first run, killed once some sealed blocks are recorded at rest
+    let mut child = std::process::Command::new(env!("CARGO_BIN_EXE_hsync"))
+        .args([
+            "--source",
+            "test_crash_resume_source",
+            "--dest",
+            "test_crash_resume_dest",
+        ])
+        .args(["--db", db_path, "--log", log_path])
+        .args(["--encrypt", "--password-file", "test_crash_resume.pass"])
+        .args(["--block-size", "4K", "--bwlimit", "64K"])
+        .stdout(std::process::Stdio::null())
+        .spawn()?;
+    let started = std::time::Instant::now();
+    let progress = loop {
+        std::thread::sleep(std::time::Duration::from_millis(50));
+        assert!(started.elapsed().as_secs() < 30, "no progress recorded");
+        let Ok(db) = hsync::db::Database::new(db_path) else {
+            continue;
+        };
+        let Ok(Some(path)) = db.current_file() else {
+            continue;
+        };
+        if let Ok(Some((_, _, offset))) = db.get_encryption_progress(&path) {
+            break offset;
+        }
+    };
+    child.kill()?;
+    child.wait()?;
+    assert!(progress > 0 && progress < data.len() as u64);
+
+    let args = Args {
+        encrypt: true,
+        password_file: Some(password_file.clone()),
+        block_size: "4K".to_string(),
+        ..test_args(&source_dir, &dest_dir, db_path, log_path)
+    };
+    let stats = run_with_stats(args.clone())?;
+    let log = fs::read_to_string(log_path)?;
+    assert!(
+        log.contains("Resuming encrypted transfer at byte"),
+        "{}",
+        log
+    );
+    assert!(stats.bytes_transferred < data.len() as u64);
+
+    run(Args {
+        command: Some(hsync::Command::Decrypt {
+            input: dest_dir.clone(),
+            output: restore_dir.clone(),
+        }),
+        ..args
+    })?;
+    assert_eq!(fs::read(restore_dir.join("data.bin"))?, data);
+
+    // Cleanup
+    fs::remove_dir_all(source_dir)?;
+    fs::remove_dir_all(dest_dir)?;
+    fs::remove_dir_all(restore_dir)?;
+    fs::remove_file(db_path)?;
+    let _ = fs::remove_file(format!("{}.lock", db_path));
+    fs::remove_file(log_path)?;
+    fs::remove_file(password_file)?;
+
+    Ok(())
+}
+
+#[test]
+fn test_dry_run_leaves_destination_untouched() -> Result<()> {
+    let source_dir = PathBuf::from("test_dry_run_source");
+    let dest_dir = PathBuf::from("test_dry_run_dest");
+    let db_path = "test_dry_run.db";
+    let log_path = "test_dry_run.log";
+
+    // Cleanup
+    let _ = fs::remove_dir_all(&source_dir);
+    let _ = fs::remove_dir_all(&dest_dir);
+    let _ = fs::remove_file(db_path);
+    let _ = fs::remove_file(log_path);
+
+    // A synced file whose destination mtime then drifts away
+    fs::create_dir_all(source_dir.join("sub"))?;
+    fs::write(source_dir.join("kept.txt"), b"kept")?;
+    run(test_args(&source_dir, &dest_dir, db_path, log_path))?;
+    filetime::set_file_mtime(
+        dest_dir.join("kept.txt"),
+        FileTime::from_unix_time(1_000_000, 0),
+    )?;
+
+    fs::write(source_dir.join("new.txt"), b"new file")?;
+    fs::write(source_dir.join("sub/changed.txt"), b"new content")?;
+    fs::write(dest_dir.join("extra.txt"), b"extra")?;
+    fs::create_dir_all(dest_dir.join("sub"))?;
+    fs::write(dest_dir.join("sub/changed.txt"), b"old")?;
+
+    // Snapshot of every destination entry with its content and mtime
+    let snapshot = |dir: &Path| -> Result<Vec<(PathBuf, Vec<u8>, i64)>> {
+        let mut entries = Vec::new();
+        for entry in walkdir::WalkDir::new(dir).sort_by_file_name() {
+            let entry = entry?;
+            let metadata = entry.metadata()?;
+            let content = if metadata.is_file() {
+                fs::read(entry.path())?
+            } else {
+                Vec::new()
+            };
+            let mtime = FileTime::from_last_modification_time(&metadata).unix_seconds();
+            entries.push((entry.path().to_path_buf(), content, mtime));
+        }
+        Ok(entries)
+    };
+    let before = snapshot(&dest_dir)?;
+    let db_before = fs::read(db_path)?;
+
+    // Steps that would rewrite the database or touch synced files change nothing
+    run(Args {
+        dry_run: true,
+        delete_extras: true,
+        compact_db: true,
+        size_only: true,
+        refresh_metadata: true,
+        ..test_args(&source_dir, &dest_dir, db_path, log_path)
+    })?;
+    assert_eq!(snapshot(&dest_dir)?, before);
+    assert_eq!(fs::read(db_path)?, db_before);
+    let log = fs::read_to_string(log_path)?;
+    assert!(
+        log.contains(
+            "Dry run: 2 files (19 B) would be transferred, 1 files (5 B) would be deleted."
+        ),
+        "{}",
+        log
+    );
+
+    // The real run then does what the dry run listed
+    run(Args {
+        delete_extras: true,
+        ..test_args(&source_dir, &dest_dir, db_path, log_path)
+    })?;
+    assert_eq!(fs::read(dest_dir.join("new.txt"))?, b"new file");
+    assert_eq!(fs::read(dest_dir.join("sub/changed.txt"))?, b"new content");
+    assert!(!dest_dir.join("extra.txt").exists());
+
+    // Cleanup
+    fs::remove_dir_all(source_dir)?;
+    fs::remove_dir_all(dest_dir)?;
+    fs::remove_file(db_path)?;
+    let _ = fs::remove_file(format!("{}.lock", db_path));
+    fs::remove_file(log_path)?;
+
+    Ok(())
+}
+
+#[test]
+#[cfg(unix)]
+fn test_compact_db_rejects_non_utf8_root() -> Result<()> {
+    use std::os::unix::ffi::OsStrExt;
+    let source_dir = PathBuf::from(std::ffi::OsStr::from_bytes(b"test_compact_non_utf8_\xff"));
+    let dest_dir = PathBuf::from("test_compact_non_utf8_dest");
+    let db_path = "test_compact_non_utf8.db";
+    let log_path = "test_compact_non_utf8.log";
+    fs::create_dir_all(&source_dir)?;
+    fs::write(source_dir.join("file.txt"), b"content")?;
+
+    // Compact paths are stored as text: a root that is not UTF-8 is refused up front
+    let result = run(Args {
+        compact_db: true,
+        ..test_args(&source_dir, &dest_dir, db_path, log_path)
+    });
+    let err = result.unwrap_err().to_string();
+    assert!(err.contains("--compact-db needs a UTF-8 root"), "{}", err);
+    assert!(!dest_dir.join("file.txt").exists());
+
+    // Cleanup
+    fs::remove_dir_all(source_dir)?;
+    let _ = fs::remove_dir_all(dest_dir);
+    let _ = fs::remove_file(db_path);
+    let _ = fs::remove_file(format!("{}.lock", db_path));
+    let _ = fs::remove_file(log_path);
+
+    Ok(())
+}
+
+#[test]
+#[cfg(unix)]
+fn test_sigterm_stops_transfer_cleanly() -> Result<()> {
+    let source_dir = PathBuf::from("test_sigterm_source");
+    let dest_dir = PathBuf::from("test_sigterm_dest");
+    let db_path = "test_sigterm.db";
+    let log_path = "test_sigterm.log";
+
+    // Cleanup
+    let _ = fs::remove_dir_all(&source_dir);
+    let _ = fs::remove_dir_all(&dest_dir);
+    let _ = fs::remove_file(db_path);
+    let _ = fs::remove_file(log_path);
+
+    fs::create_dir_all(&source_dir)?;
+    let data: Vec<u8> = (0..256 * 1024u32).map(|i| (i % 251) as u8).collect();
+    fs::write(source_dir.join("data.bin"), &data)?;
+
+    // A slow run, signalled once it is writing the file
+    let mut child = std::process::Command::new(env!("CARGO_BIN_EXE_hsync"))
+        .args([
+            "--source",
+            "test_sigterm_source",
+            "--dest",
+            "test_sigterm_dest",
+        ])
+        .args(["--db", db_path, "--log", log_path])
+        .args(["--block-size", "4K", "--bwlimit", "64K"])
+        .stdout(std::process::Stdio::null())
+        .stderr(std::process::Stdio::null())
+        .spawn()?;
+    let started = std::time::Instant::now();
+    loop {
+        std::thread::sleep(std::time::Duration::from_millis(50));
+        assert!(started.elapsed().as_secs() < 30, "transfer never started");
+        let Ok(db) = hsync::db::Database::new(db_path) else {
+            continue;
+        };
+        if let Ok(Some(_)) = db.current_file() {
+            break;
+        }
+    }
+    let status = std::process::Command::new("kill")
+        .args(["-TERM", &child.id().to_string()])
+        .status()?;
+    assert!(status.success());
+
+    // The process exits on its own, well before the transfer would have finished,
+    // with the file back in the backlog
+    let status = child.wait()?;
+    assert_eq!(status.code(), Some(1));
+    assert!(started.elapsed().as_secs() < 4);
+    let log = fs::read_to_string(log_path)?;
+    assert!(
+        log.contains("Transfer interrupted: 1 files left pending"),
+        "{}",
+        log
+    );
+    let db = hsync::db::Database::new(db_path)?;
+    assert_eq!(db.pending_count()?, 1);
+    assert_eq!(db.current_file()?, None);
+    drop(db);
+
+    run(test_args(&source_dir, &dest_dir, db_path, log_path))?;
+    assert_eq!(fs::read(dest_dir.join("data.bin"))?, data);
+
+    // Cleanup
+    fs::remove_dir_all(source_dir)?;
+    fs::remove_dir_all(dest_dir)?;
+    fs::remove_file(db_path)?;
+    let _ = fs::remove_file(format!("{}.lock", db_path));
+    fs::remove_file(log_path)?;
+
+    Ok(())
+}
+
+#[test]
+fn test_invalid_progress_template_rejected_up_front() -> Result<()> {
+    let source_dir = PathBuf::from("test_bad_template_source");
+    let dest_dir = PathBuf::from("test_bad_template_dest");
+    let db_path = "test_bad_template.db";
+    let log_path = "test_bad_template.log";
+    fs::create_dir_all(&source_dir)?;
+    fs::write(source_dir.join("file.txt"), b"content")?;
+
+    // Refused before the scan, not left to every producer run to report
+    let result = run(Args {
+        progress_template: Some("{bar:x}".to_string()),
+        ..test_args(&source_dir, &dest_dir, db_path, log_path)
+    });
+    let err = result.unwrap_err().to_string();
+    assert!(err.contains("Invalid --progress-template"), "{}", err);
+    assert!(!Path::new(db_path).exists());
+    assert!(!dest_dir.exists());
+
+    // Cleanup
+    fs::remove_dir_all(source_dir)?;
+    let _ = fs::remove_file(log_path);
+
+    Ok(())
+}